@@ -0,0 +1,325 @@
+//! Entry-point attributes for programs loaded into `BPFilter`, `BPFClassifier` and `BPFRewriter`,
+//! and `#[derive(MapKey)]`.
+//!
+//! Each attribute turns a function `fn(&mut Packet) -> Result<_, Error>` into the `main` symbol in
+//! the `bpffilter` section that the elements load, and maps `Err` to a fixed verdict. They are
+//! re-exported as `bpf_element::macros`.
+//!
+//! All of them take `schema = Type`, a `bpf_element::migration::Schema` for the program's maps.
+//! `main` then migrates the maps instead of running the function when the element calls it with
+//! `MIGRATE_PORT`, returning the success verdict (`Pass`, `Success` or output 0) or `Abort`.
+//!
+//! `main` counts every packet and its verdict in `bpf_element::telemetry::TELEMETRY`, unless the
+//! attribute says `telemetry = false`.
+
+mod map_key;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, DeriveInput, FnArg, Ident, ItemFn, LitBool, LitInt, Path, ReturnType,
+};
+
+/// Entry point of a `BPFilter` program.
+///
+/// The function returns `Result<FilterResult, Error>`. `Err` is turned into
+/// `FilterResult::Abort`, which makes `BPFilter` drop the packet and log an error. Use
+/// `#[bpf_filter(on_error = Drop)]` or `on_error = Pass` to pick another verdict.
+///
+/// ```ignore
+/// #[bpf_filter]
+/// fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+///     Ok(FilterResult::Pass)
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn bpf_filter(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut on_error = Ident::new("Abort", Span::call_site());
+    let mut schema: Option<Path> = None;
+    let mut telemetry = true;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("on_error") {
+            on_error = meta.value()?.parse()?;
+            Ok(())
+        } else if meta.path.is_ident("schema") {
+            schema = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("telemetry") {
+            telemetry = meta.value()?.parse::<LitBool>()?.value;
+            Ok(())
+        } else {
+            Err(meta.error("expected `on_error`, `schema` or `telemetry`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let func = parse_macro_input!(item as ItemFn);
+
+    let ret = quote!(::bpf_element::filter::FilterResult);
+    let on_error = quote!(::bpf_element::filter::FilterResult::#on_error);
+    let schema = schema.map(|path| Schema {
+        path,
+        migrated: quote!(#ret::Pass),
+        failed: quote!(#ret::Abort),
+    });
+    entry(func, Some(&ret), &ret, schema, telemetry, |result| {
+        quote! {
+            match #result {
+                Ok(verdict) => verdict,
+                Err(_) => #on_error,
+            }
+        }
+    })
+    .into()
+}
+
+/// Entry point of a `BPFClassifier` program with `outputs` output ports.
+///
+/// The function returns `Result<T, Error>` where `T` is `Output<N>` or `ClassifierResult<N>` from
+/// `bpf_element::classifier`, with `N` equal to `outputs`. `Err` makes `BPFClassifier` abort on the
+/// packet; `on_error = Drop` drops it silently and `on_error = <port>` sends it to an output.
+///
+/// ```ignore
+/// #[bpf_classifier(outputs = 2)]
+/// fn try_classify(packet: &mut Packet) -> Result<Output<2>, Error> {
+///     Ok(Output::wrapping(packet.port()))
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn bpf_classifier(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut outputs: Option<LitInt> = None;
+    let mut on_error: Option<OnError> = None;
+    let mut schema: Option<Path> = None;
+    let mut telemetry = true;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("outputs") {
+            outputs = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("on_error") {
+            let value = meta.value()?;
+            on_error = Some(if value.peek(LitInt) {
+                OnError::Port(value.parse()?)
+            } else {
+                OnError::Verdict(value.parse()?)
+            });
+            Ok(())
+        } else if meta.path.is_ident("schema") {
+            schema = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("telemetry") {
+            telemetry = meta.value()?.parse::<LitBool>()?.value;
+            Ok(())
+        } else {
+            Err(meta.error("expected `outputs`, `on_error`, `schema` or `telemetry`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let func = parse_macro_input!(item as ItemFn);
+
+    let Some(outputs) = outputs else {
+        return syn::Error::new(Span::call_site(), "missing `outputs = N`")
+            .to_compile_error()
+            .into();
+    };
+    let n = match outputs.base10_parse::<u32>() {
+        Ok(0) => {
+            return syn::Error::new(outputs.span(), "a classifier needs at least one output")
+                .to_compile_error()
+                .into()
+        }
+        Ok(n) => n,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let result_ty = quote!(::bpf_element::classifier::ClassifierResult::<#n>);
+    let on_error = match on_error {
+        None => quote!(#result_ty::Abort),
+        Some(OnError::Verdict(verdict)) if verdict == "Drop" || verdict == "Abort" => {
+            quote!(#result_ty::#verdict)
+        }
+        Some(OnError::Verdict(verdict)) => {
+            return syn::Error::new(verdict.span(), "expected `Drop`, `Abort` or an output index")
+                .to_compile_error()
+                .into()
+        }
+        Some(OnError::Port(port)) => match port.base10_parse::<u32>() {
+            Ok(p) if p < n => quote! {
+                #result_ty::Output(::bpf_element::classifier::Output::<#n>::at::<#p>())
+            },
+            Ok(_) => {
+                return syn::Error::new(port.span(), "`on_error` must be a valid output index")
+                    .to_compile_error()
+                    .into()
+            }
+            Err(err) => return err.to_compile_error().into(),
+        },
+    };
+
+    let schema = schema.map(|path| Schema {
+        path,
+        migrated: quote! {
+            #result_ty::Output(::bpf_element::classifier::Output::<#n>::at::<0>()).to_raw()
+        },
+        failed: quote!(#result_ty::Abort.to_raw()),
+    });
+    entry(func, None, &quote!(u32), schema, telemetry, |result| {
+        quote! {
+            match #result {
+                Ok(result) => #result_ty::from(result).to_raw(),
+                Err(_) => #on_error.to_raw(),
+            }
+        }
+    })
+    .into()
+}
+
+enum OnError {
+    Port(LitInt),
+    Verdict(Ident),
+}
+
+/// Entry point of a `BPFRewriter` program.
+///
+/// The function returns `Result<RewriterResult, Error>`. `Err` is turned into
+/// `RewriterResult::Abort`, which makes `BPFRewriter` drop the packet and log an error. With
+/// `#[bpf_rewriter(on_error = Success)]` the packet is forwarded as far as it was rewritten,
+/// `on_error = Drop` drops it silently.
+#[proc_macro_attribute]
+pub fn bpf_rewriter(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut on_error = Ident::new("Abort", Span::call_site());
+    let mut schema: Option<Path> = None;
+    let mut telemetry = true;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("on_error") {
+            on_error = meta.value()?.parse()?;
+            Ok(())
+        } else if meta.path.is_ident("schema") {
+            schema = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("telemetry") {
+            telemetry = meta.value()?.parse::<LitBool>()?.value;
+            Ok(())
+        } else {
+            Err(meta.error("expected `on_error`, `schema` or `telemetry`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let func = parse_macro_input!(item as ItemFn);
+
+    let ret = quote!(::bpf_element::rewriter::RewriterResult);
+    let on_error = quote!(::bpf_element::rewriter::RewriterResult::#on_error);
+    let schema = schema.map(|path| Schema {
+        path,
+        migrated: quote!(#ret::Success),
+        failed: quote!(#ret::Abort),
+    });
+    entry(func, Some(&ret), &ret, schema, telemetry, |result| {
+        quote! {
+            match #result {
+                Ok(result) => result,
+                Err(_) => #on_error,
+            }
+        }
+    })
+    .into()
+}
+
+/// Implements `bpf_element::maps::MapKey` for a struct, see there for how fields are packed.
+///
+/// Fields of `bool`, `u8` to `u128`, `Ipv4Addr` and `Ipv6Addr` have their natural width, other
+/// `KeyField` types need `#[key(bits = N)]`.
+#[proc_macro_derive(MapKey, attributes(key))]
+pub fn derive_map_key(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    map_key::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// The `schema = ...` of an entry attribute, with what `main` returns after migrating.
+struct Schema {
+    path: Path,
+    migrated: TokenStream2,
+    failed: TokenStream2,
+}
+
+/// Emits `func` next to a `main` that calls it with the packet and converts its result with
+/// `convert`, counting the packet in the telemetry map if `telemetry` is set.
+fn entry(
+    func: ItemFn,
+    ok: Option<&TokenStream2>,
+    ret: &TokenStream2,
+    schema: Option<Schema>,
+    telemetry: bool,
+    convert: impl FnOnce(&Ident) -> TokenStream2,
+) -> TokenStream2 {
+    if let Err(err) = check_signature(&func) {
+        return err.to_compile_error();
+    }
+
+    let name = &func.sig.ident;
+    let result = Ident::new("result", Span::call_site());
+    // point type errors at the declared return type rather than the attribute
+    let ok = ok.map_or_else(|| quote!(_), Clone::clone);
+    let declared = quote_spanned! {func.sig.output.span()=>
+        let #result: ::core::result::Result<#ok, ::bpf_element::Error> = #name(&mut packet);
+    };
+    let body = convert(&result);
+    let (len, body) = if telemetry {
+        let len = quote!(let len = packet.len(););
+        let body = quote! {
+            let error = #result.as_ref().err().copied();
+            let verdict = #body;
+            ::bpf_element::telemetry::record(len, verdict, error);
+            verdict
+        };
+        (Some(len), body)
+    } else {
+        (None, body)
+    };
+    let migrate = schema.map(|Schema { path, migrated, failed }| {
+        quote! {
+            if unsafe { (*ctx).port } == ::bpf_element::migration::MIGRATE_PORT {
+                return match ::bpf_element::migration::migrate::<#path>() {
+                    Ok(()) => #migrated,
+                    Err(_) => #failed,
+                };
+            }
+        }
+    });
+
+    quote! {
+        #[inline(always)]
+        #func
+
+        // in unit tests, libtest provides the `main` symbol and tests call this one directly
+        #[cfg_attr(not(test), no_mangle)]
+        #[link_section = "bpffilter"]
+        // the runtime only ever passes a valid context
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern "C" fn main(ctx: *mut ::bpf_element::BpfContext) -> #ret {
+            #migrate
+            let mut packet = unsafe { ::bpf_element::Packet::new(*ctx) };
+            #len
+            #declared
+            #body
+        }
+    }
+}
+
+fn check_signature(func: &ItemFn) -> syn::Result<()> {
+    let sig = &func.sig;
+    if sig.asyncness.is_some() || sig.constness.is_some() || sig.unsafety.is_some() {
+        return Err(syn::Error::new(sig.span(), "entry functions must be plain `fn`s"));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(sig.generics.span(), "entry functions can't be generic"));
+    }
+    if sig.inputs.len() != 1 || !matches!(sig.inputs.first(), Some(FnArg::Typed(_))) {
+        return Err(syn::Error::new(sig.inputs.span(), "expected a single `&mut Packet` argument"));
+    }
+    if let ReturnType::Default = sig.output {
+        return Err(syn::Error::new(sig.span(), "expected a `Result<_, Error>` return type"));
+    }
+    Ok(())
+}
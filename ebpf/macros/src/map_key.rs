@@ -0,0 +1,197 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Fields, LitInt, Type};
+
+struct Field<'a> {
+    ident: &'a syn::Ident,
+    ty: &'a Type,
+    bits: u32,
+}
+
+pub fn derive(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "map keys can't be generic",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "`MapKey` can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(syn::Error::new(data.fields.span(), "expected named fields"));
+    };
+
+    let mut fields = Vec::new();
+    for field in &named.named {
+        let ident = field.ident.as_ref().expect("named field");
+        let mut bits = None;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("key"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("bits") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    match lit.base10_parse::<u32>()? {
+                        0 => Err(syn::Error::new(
+                            lit.span(),
+                            "a field needs at least one bit",
+                        )),
+                        n => {
+                            bits = Some(n);
+                            Ok(())
+                        }
+                    }
+                } else {
+                    Err(meta.error("expected `bits`"))
+                }
+            })?;
+        }
+        let bits = match bits.or_else(|| known_bits(&field.ty)) {
+            Some(bits) => bits,
+            None => {
+                return Err(syn::Error::new(
+                    field.ty.span(),
+                    "unknown width, add `#[key(bits = N)]`",
+                ))
+            }
+        };
+        fields.push(Field {
+            ident,
+            ty: &field.ty,
+            bits,
+        });
+    }
+    if fields.is_empty() {
+        return Err(syn::Error::new(
+            named.span(),
+            "a map key needs at least one field",
+        ));
+    }
+
+    let total: u32 = fields.iter().map(|field| field.bits).sum();
+    let (key, to_key, from_key) = if total <= 128 {
+        numeric(&fields, total)
+    } else {
+        bytes(&fields)
+    };
+
+    let name = &input.ident;
+    let checks = fields.iter().map(|Field { ident, ty, bits }| {
+        let msg = format!("`{ident}` has more bits than its type");
+        quote!(assert!(#bits <= <#ty as ::bpf_element::maps::KeyField>::BITS, #msg);)
+    });
+    let idents = fields.iter().map(|field| field.ident);
+    let locals = fields.iter().map(|field| local(field.ident));
+
+    Ok(quote! {
+        const _: () = { #(#checks)* };
+
+        impl ::bpf_element::maps::MapKey for #name {
+            type Key = #key;
+
+            #[inline(always)]
+            fn to_key(&self) -> Self::Key {
+                #to_key
+            }
+
+            #[inline(always)]
+            fn from_key(__key: Self::Key) -> Self {
+                #from_key
+                Self { #(#idents: #locals),* }
+            }
+        }
+    })
+}
+
+/// Name of the local a field is unpacked into, so fields can't shadow the generated code's locals.
+fn local(ident: &syn::Ident) -> syn::Ident {
+    format_ident!("__{}", ident)
+}
+
+/// Width of the types `KeyField` is implemented for, by name.
+fn known_bits(ty: &Type) -> Option<u32> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let ident = &path.path.segments.last()?.ident;
+    Some(match ident.to_string().as_str() {
+        "bool" => 1,
+        "u8" => 8,
+        "u16" => 16,
+        "u32" | "Ipv4Addr" => 32,
+        "u64" => 64,
+        "u128" | "Ipv6Addr" => 128,
+        _ => return None,
+    })
+}
+
+/// Packs all fields into the smallest unsigned integer that holds `total` bits.
+fn numeric(fields: &[Field], total: u32) -> (TokenStream2, TokenStream2, TokenStream2) {
+    let width = total.next_power_of_two().max(8);
+    let key = format_ident!("u{}", width);
+
+    let mut shift = 0;
+    let mut pack = Vec::new();
+    let mut unpack = Vec::new();
+    for Field { ident, ty, bits } in fields.iter().rev() {
+        let mask = quote!((#key::MAX >> (#width - #bits)));
+        let local = local(ident);
+        pack.push(quote! {
+            __key |= ((<#ty as ::bpf_element::maps::KeyField>::to_bits(self.#ident) as #key) & #mask) << #shift;
+        });
+        unpack.push(quote! {
+            let #local = <#ty as ::bpf_element::maps::KeyField>::from_bits(((__key >> #shift) & #mask) as u128);
+        });
+        shift += bits;
+    }
+
+    let to_key = quote! {
+        let mut __key: #key = 0;
+        #(#pack)*
+        __key
+    };
+    (quote!(#key), to_key, quote!(#(#unpack)*))
+}
+
+/// Packs the fields into a byte array, each rounded up to whole bytes, big-endian.
+fn bytes(fields: &[Field]) -> (TokenStream2, TokenStream2, TokenStream2) {
+    let len: usize = fields
+        .iter()
+        .map(|field| field.bits.div_ceil(8) as usize)
+        .sum();
+
+    let mut offset = 0usize;
+    let mut pack = Vec::new();
+    let mut unpack = Vec::new();
+    for Field { ident, ty, bits } in fields {
+        let n = bits.div_ceil(8) as usize;
+        let end = offset + n;
+        let start = 16 - n;
+        let mask = quote!((u128::MAX >> (128 - #bits)));
+        let local = local(ident);
+        pack.push(quote! {
+            let __bits = <#ty as ::bpf_element::maps::KeyField>::to_bits(self.#ident) & #mask;
+            __key[#offset..#end].copy_from_slice(&__bits.to_be_bytes()[#start..]);
+        });
+        unpack.push(quote! {
+            let mut __bits = [0u8; 16];
+            __bits[#start..].copy_from_slice(&__key[#offset..#end]);
+            let #local = <#ty as ::bpf_element::maps::KeyField>::from_bits(u128::from_be_bytes(__bits) & #mask);
+        });
+        offset = end;
+    }
+
+    let to_key = quote! {
+        let mut __key = [0u8; #len];
+        #(#pack)*
+        __key
+    };
+    (quote!([u8; #len]), to_key, quote!(#(#unpack)*))
+}
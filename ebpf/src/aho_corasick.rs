@@ -0,0 +1,348 @@
+//! Multi-pattern matching with an Aho-Corasick automaton built at compile time.
+//!
+//! The automaton is a full DFA: every state has a transition for every byte, with the failure
+//! links already folded in, so matching is one table load per byte and the scan is a single
+//! bounded loop. It is built by `const fn`s and lands in `.rodata`, which MorphOS copies when it
+//! loads the program:
+//!
+//! ```ignore
+//! const SIGNATURES: &[u8] = include_bytes!("signatures.txt");
+//! const PATTERNS: [&[u8]; aho_corasick::line_count(SIGNATURES)] = aho_corasick::lines(SIGNATURES);
+//! static MATCHER: Automaton<{ aho_corasick::states(&PATTERNS) }> = Automaton::new(&PATTERNS);
+//! ```
+//!
+//! Matching follows the native `StringMatcher` element: patterns are case-sensitive bytes of 1 to
+//! [`MAX_PATTERN_LEN`] bytes, matched anywhere in the data, and [`Automaton::find`] reports the
+//! pattern that ends first. Each state takes 514 bytes, a row of 256 `u16` transitions and its
+//! match, so the automaton of 64 signatures of 64 bytes without common prefixes takes 4097 states
+//! and 2,105,858 bytes. Automata are limited to [`MAX_STATES`].
+
+use crate::{Error, Packet, MAX_SLICE_LEN};
+
+/// Longest pattern, as `AC_PATTRN_MAX_LENGTH` of the native matcher.
+pub const MAX_PATTERN_LEN: usize = 1024;
+
+/// Most states of an automaton, 2 MiB of tables. Twice as many states take longer than rustc
+/// allows a constant to evaluate.
+pub const MAX_STATES: usize = 4096;
+
+/// Marks states no pattern ends in.
+const NO_MATCH: u16 = u16::MAX;
+
+/// Number of automaton states for `patterns`: the root plus one state per distinct prefix.
+pub const fn states(patterns: &[&[u8]]) -> usize {
+    let mut states = 1;
+    let mut i = 0;
+    while i < patterns.len() {
+        // prefixes shared with an earlier pattern already have their state
+        let mut shared = 0;
+        let mut j = 0;
+        while j < i {
+            let common = common_prefix(patterns[i], patterns[j]);
+            if common > shared {
+                shared = common;
+            }
+            j += 1;
+        }
+        states += patterns[i].len() - shared;
+        i += 1;
+    }
+    states
+}
+
+const fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let mut i = 0;
+    while i < a.len() && i < b.len() && a[i] == b[i] {
+        i += 1;
+    }
+    i
+}
+
+/// Number of patterns in a signature file, see [`lines`].
+pub const fn line_count(text: &[u8]) -> usize {
+    let mut count = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (line, next) = next_line(rest);
+        if is_pattern(line) {
+            count += 1;
+        }
+        rest = next;
+    }
+    count
+}
+
+/// Patterns of a signature file: one per line, ignoring empty lines and lines starting with `#`.
+pub const fn lines<const N: usize>(text: &[u8]) -> [&[u8]; N] {
+    let mut patterns: [&[u8]; N] = [&[]; N];
+    let mut count = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (line, next) = next_line(rest);
+        if is_pattern(line) {
+            assert!(count < N, "more patterns than line_count");
+            patterns[count] = line;
+            count += 1;
+        }
+        rest = next;
+    }
+    assert!(count == N, "fewer patterns than line_count");
+    patterns
+}
+
+/// The first line of `text` without its line ending, and the text after it.
+const fn next_line(text: &[u8]) -> (&[u8], &[u8]) {
+    let mut end = 0;
+    while end < text.len() && text[end] != b'\n' {
+        end += 1;
+    }
+    let (line, rest) = text.split_at(end);
+    let rest = if rest.is_empty() {
+        rest
+    } else {
+        rest.split_at(1).1
+    };
+    let line = match line {
+        [line @ .., b'\r'] => line,
+        line => line,
+    };
+    (line, rest)
+}
+
+const fn is_pattern(line: &[u8]) -> bool {
+    !line.is_empty() && line[0] != b'#'
+}
+
+/// A DFA with `N` states that finds any of a set of patterns.
+pub struct Automaton<const N: usize> {
+    /// Next state for the current state and input byte.
+    next: [[u16; 256]; N],
+    /// Lowest index of the patterns ending in a state, [`NO_MATCH`] if none does.
+    matches: [u16; N],
+}
+
+impl<const N: usize> Automaton<N> {
+    /// Builds the automaton for `patterns`, with `N` from [`states`]. Fails to compile if a pattern
+    /// is empty or longer than [`MAX_PATTERN_LEN`], or if `N` is more than [`MAX_STATES`].
+    ///
+    /// Duplicates are allowed; a match reports the first of them. A larger `N` only wastes space.
+    pub const fn new(patterns: &[&[u8]]) -> Self {
+        assert!(N >= states(patterns), "N must be at least states(patterns)");
+        assert!(
+            N <= MAX_STATES,
+            "more than MAX_STATES (4096) states, the automaton would take over 2 MiB"
+        );
+        assert!(patterns.len() < NO_MATCH as usize, "too many patterns");
+
+        let mut next = [[0u16; 256]; N];
+        let mut matches = [NO_MATCH; N];
+
+        // the trie; 0 means no edge, as no edge leads back to the root
+        let mut count = 1;
+        let mut i = 0;
+        while i < patterns.len() {
+            let pattern = patterns[i];
+            assert!(!pattern.is_empty(), "empty pattern");
+            assert!(pattern.len() <= MAX_PATTERN_LEN, "pattern too long");
+
+            let mut state = 0;
+            let mut j = 0;
+            while j < pattern.len() {
+                let byte = pattern[j] as usize;
+                if next[state][byte] == 0 {
+                    next[state][byte] = count as u16;
+                    count += 1;
+                }
+                state = next[state][byte] as usize;
+                j += 1;
+            }
+            if (i as u16) < matches[state] {
+                matches[state] = i as u16;
+            }
+            i += 1;
+        }
+
+        // Breadth first, so the state a failure link leads to, which is shallower, is complete.
+        // Missing edges take the transition of the failure state, and states inherit the matches
+        // of their failure state, which are the patterns ending in a suffix of theirs.
+        let mut fail = [0u16; N];
+        let mut queue = [0u16; N];
+        let (mut head, mut tail) = (0, 0);
+        let mut byte = 0;
+        while byte < 256 {
+            let child = next[0][byte];
+            if child != 0 {
+                queue[tail] = child;
+                tail += 1;
+            }
+            byte += 1;
+        }
+        while head < tail {
+            let state = queue[head] as usize;
+            head += 1;
+            let failure = fail[state] as usize;
+            if matches[failure] < matches[state] {
+                matches[state] = matches[failure];
+            }
+
+            let mut byte = 0;
+            while byte < 256 {
+                let child = next[state][byte];
+                if child != 0 {
+                    fail[child as usize] = next[failure][byte];
+                    queue[tail] = child;
+                    tail += 1;
+                } else {
+                    next[state][byte] = next[failure][byte];
+                }
+                byte += 1;
+            }
+        }
+
+        Self { next, matches }
+    }
+
+    /// Index of the pattern that ends first in `data`, the lowest index if several end at the same
+    /// byte. Only the first [`MAX_SLICE_LEN`] bytes are searched.
+    #[inline(always)]
+    pub fn find(&self, data: &[u8]) -> Option<u16> {
+        let mut state = 0;
+        for i in 0..MAX_SLICE_LEN {
+            if i >= data.len() {
+                break;
+            }
+            // states come from the table, the lookup can't fail
+            let Some(next) = self.next.get(state) else {
+                break;
+            };
+            state = next[data[i] as usize] as usize;
+            match self.matches.get(state) {
+                Some(&NO_MATCH) | None => {}
+                Some(&pattern) => return Some(pattern),
+            }
+        }
+        None
+    }
+
+    /// [`Automaton::find`] on the packet from `offset` on.
+    #[inline(always)]
+    pub fn find_in(&self, packet: &Packet, offset: usize) -> Result<Option<u16>, Error> {
+        let len = packet.len().saturating_sub(offset).min(MAX_SLICE_LEN);
+        Ok(self.find(packet.slice(offset, len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const WORDS: [&[u8]; 4] = [b"he", b"she", b"his", b"hers"];
+    static WORDS_MATCHER: Automaton<{ states(&WORDS) }> = Automaton::new(&WORDS);
+
+    /// The first pattern ending in `data`, by brute force.
+    fn naive(patterns: &[&[u8]], data: &[u8]) -> Option<u16> {
+        (1..=data.len()).find_map(|end| {
+            patterns
+                .iter()
+                .position(|pattern| data[..end].ends_with(pattern))
+                .map(|i| i as u16)
+        })
+    }
+
+    #[test]
+    fn state_count() {
+        // h, he, her, hers, hi, his, s, sh, she
+        assert_eq!(states(&WORDS), 10);
+        assert_eq!(states(&[b"abc", b"abc"]), 4);
+    }
+
+    #[test]
+    fn classic_example() {
+        // "she" and "he" end at the same byte, "he" comes first in the list
+        assert_eq!(WORDS_MATCHER.find(b"ushers"), Some(0));
+        assert_eq!(WORDS_MATCHER.find(b"ahishers"), Some(2));
+        assert_eq!(WORDS_MATCHER.find(b"sh"), None);
+        assert_eq!(WORDS_MATCHER.find(b""), None);
+    }
+
+    #[test]
+    fn overlapping_prefixes() {
+        const PATTERNS: [&[u8]; 1] = [b"leetcode"];
+        let matcher = Automaton::<{ states(&PATTERNS) }>::new(&PATTERNS);
+        assert_eq!(matcher.find(b"leeleetcode"), Some(0));
+        assert_eq!(matcher.find(b"lleetcode"), Some(0));
+        assert_eq!(matcher.find(b"leetcodeleetcod"), Some(0));
+        assert_eq!(matcher.find(b"leetcod"), None);
+        assert_eq!(matcher.find(b"LEETCODE"), None);
+    }
+
+    #[test]
+    fn duplicates_report_the_first() {
+        const PATTERNS: [&[u8]; 3] = [b"xnet", b"text1", b"xnet"];
+        let matcher = Automaton::<{ states(&PATTERNS) }>::new(&PATTERNS);
+        assert_eq!(matcher.find(b"...xnet..."), Some(0));
+        assert_eq!(matcher.find(b"text1"), Some(1));
+    }
+
+    #[test]
+    fn signature_files() {
+        const TEXT: &[u8] = b"# comment\r\nfoo\r\n\nbar\n#baz\nqux";
+        const PATTERNS: [&[u8]; line_count(TEXT)] = lines(TEXT);
+        assert_eq!(PATTERNS, [&b"foo"[..], b"bar", b"qux"]);
+    }
+
+    #[test]
+    fn matches_like_brute_force() {
+        // the automaton is built on the stack, which is too small in test threads
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(brute_force)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn refuses_more_than_max_states() {
+        let error = std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(|| Automaton::<{ MAX_STATES + 1 }>::new(&WORDS).find(b"hers"))
+            .unwrap()
+            .join()
+            .unwrap_err();
+        let message = error.downcast_ref::<&str>().unwrap();
+        assert!(
+            message.starts_with("more than MAX_STATES (4096)"),
+            "{message}"
+        );
+    }
+
+    /// A few hundred short patterns over a small alphabet, so they share prefixes and suffixes,
+    /// against a brute force search of pseudo-random texts.
+    fn brute_force() {
+        let mut seed = 0x2545_f491u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let owned: Vec<Vec<u8>> = (0..250)
+            .map(|_| {
+                let len = 3 + random() as usize % 6;
+                (0..len).map(|_| b'a' + (random() % 4) as u8).collect()
+            })
+            .collect();
+        let patterns: Vec<&[u8]> = owned.iter().map(|p| p.as_slice()).collect();
+        assert!(states(&patterns) <= 1024);
+
+        let matcher = Automaton::<1024>::new(&patterns);
+        for _ in 0..500 {
+            let len = random() as usize % 40;
+            let text: Vec<u8> = (0..len).map(|_| b'a' + (random() % 5) as u8).collect();
+            assert_eq!(matcher.find(&text), naive(&patterns, &text), "{text:?}");
+        }
+    }
+}
@@ -0,0 +1,184 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+
+use aya_ebpf::macros::map;
+use bpf_element::filter::FilterResult;
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::{Config, IpPrefixMap};
+use bpf_element::parse::LinkLayer;
+use bpf_element::{Error, Packet};
+
+const MAX_PREFIXES: u32 = 65536;
+
+/// Actions of source prefixes, see [`FilterResult::from_action`], e.g. a blocklist `helper blocklist` loads.
+#[map(name = "SOURCES")]
+static SOURCES: IpPrefixMap<u32> = IpPrefixMap::with_max_entries(MAX_PREFIXES, 0);
+
+/// Actions of destination prefixes, for packets whose source has no prefix.
+#[map(name = "DESTINATIONS")]
+static DESTINATIONS: IpPrefixMap<u32> = IpPrefixMap::with_max_entries(MAX_PREFIXES, 0);
+
+#[repr(C)]
+struct Settings {
+    /// Action for addresses without a prefix. 0 drops them.
+    default_action: u32,
+}
+
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
+
+/// The longest source prefix decides, then the longest destination prefix, then the default.
+#[bpf_filter(on_error = Drop)]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+    let headers = packet.parse(LinkLayer::Ethernet)?;
+    // IPv4 or IPv6 only
+    let (src, dst) = headers.addrs().ok_or(Error::Unsupported)?;
+
+    if let Some(action) = SOURCES.get(src) {
+        return FilterResult::from_action(*action).ok_or(Error::Map);
+    }
+    if let Some(action) = DESTINATIONS.get(dst) {
+        return FilterResult::from_action(*action).ok_or(Error::Map);
+    }
+    match CONFIG.get()?.default_action {
+        0 => Ok(FilterResult::Drop),
+        action => FilterResult::from_action(action).ok_or(Error::Map),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use bpf_element::testing::PacketBuilder;
+
+    use super::*;
+
+    const DROP: u32 = FilterResult::Drop as u32;
+    const PASS: u32 = FilterResult::Pass as u32;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 1, 2, 3);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    fn ipv4(src: Ipv4Addr, dst: Ipv4Addr) -> FilterResult {
+        PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(src, dst)
+            .udp(40000, 53)
+            .build()
+            .run(main)
+    }
+
+    fn ipv6(src: Ipv6Addr, dst: Ipv6Addr) -> FilterResult {
+        PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv6(src, dst)
+            .tcp(40000, 443)
+            .build()
+            .run(main)
+    }
+
+    fn allow_by_default() {
+        CONFIG.set(Settings {
+            default_action: PASS,
+        });
+    }
+
+    #[test]
+    fn longest_source_prefix_wins() {
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8, &PASS)
+            .unwrap();
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::new(10, 1, 0, 0)), 16, &DROP)
+            .unwrap();
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)), 32, &PASS)
+            .unwrap();
+        assert_eq!(ipv4(Ipv4Addr::new(10, 9, 9, 9), SERVER), FilterResult::Pass);
+        assert_eq!(ipv4(Ipv4Addr::new(10, 1, 9, 9), SERVER), FilterResult::Drop);
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Pass);
+        assert_eq!(ipv4(Ipv4Addr::new(11, 0, 0, 1), SERVER), FilterResult::Drop);
+    }
+
+    #[test]
+    fn blocks_listed_sources() {
+        allow_by_default();
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)), 24, &DROP)
+            .unwrap();
+        let blocked: Ipv6Addr = "2001:db8:bad::".parse().unwrap();
+        SOURCES.insert(IpAddr::V6(blocked), 48, &DROP).unwrap();
+
+        assert_eq!(
+            ipv4(Ipv4Addr::new(203, 0, 113, 7), SERVER),
+            FilterResult::Drop
+        );
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Pass);
+        let attacker = "2001:db8:bad:1::1".parse().unwrap();
+        assert_eq!(ipv6(attacker, Ipv6Addr::LOCALHOST), FilterResult::Drop);
+        let client = "2001:db8:900d::1".parse().unwrap();
+        assert_eq!(ipv6(client, Ipv6Addr::LOCALHOST), FilterResult::Pass);
+    }
+
+    #[test]
+    fn keeps_address_families_apart() {
+        allow_by_default();
+        SOURCES
+            .insert(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0, &DROP)
+            .unwrap();
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Pass);
+        assert_eq!(
+            ipv6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST),
+            FilterResult::Drop
+        );
+
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, &DROP)
+            .unwrap();
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Drop);
+    }
+
+    #[test]
+    fn falls_back_to_destination_prefixes() {
+        DESTINATIONS
+            .insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)), 24, &PASS)
+            .unwrap();
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::new(10, 66, 0, 0)), 16, &DROP)
+            .unwrap();
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Pass);
+        assert_eq!(
+            ipv4(Ipv4Addr::new(10, 66, 0, 1), SERVER),
+            FilterResult::Drop
+        );
+        assert_eq!(
+            ipv4(CLIENT, Ipv4Addr::new(198, 51, 100, 1)),
+            FilterResult::Drop
+        );
+    }
+
+    #[test]
+    fn removes_exact_prefixes() {
+        allow_by_default();
+        SOURCES
+            .insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8, &DROP)
+            .unwrap();
+        // not stored, so nothing to remove
+        SOURCES
+            .remove(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 16)
+            .unwrap();
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Drop);
+        SOURCES
+            .remove(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8)
+            .unwrap();
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Pass);
+    }
+
+    #[test]
+    fn drops_on_invalid_actions() {
+        allow_by_default();
+        SOURCES.insert(IpAddr::V4(CLIENT), 32, &7).unwrap();
+        assert_eq!(ipv4(CLIENT, SERVER), FilterResult::Drop);
+    }
+}
@@ -1,72 +1,159 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use flex_dns::name::DnsName;
-use flex_dns::{dns_name, DnsMessage};
+use aya_ebpf::macros::map;
 
+use bpf_element::dns::{read_name, Query, SuffixKey};
 use bpf_element::filter::FilterResult;
-use bpf_element::BpfContext;
-use network_types::eth::{EthHdr, EtherType};
-use network_types::ip::{IpProto, Ipv4Hdr};
-use network_types::udp::UdpHdr;
-
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> FilterResult {
-    let ctx = unsafe { *ctx };
-    try_filter(&ctx).unwrap_or_else(|_| FilterResult::Abort)
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::Config;
+use bpf_element::parse::LinkLayer;
+use bpf_element::{Error, Packet};
+
+/// Blocked domain until `CONFIG` sets one.
+const DEFAULT_BLOCKED: &[u8] = b"\x03lmu\x02de\x00";
+
+#[repr(C)]
+struct Settings {
+    /// Blocked domain in DNS wire format, e.g. `\x03lmu\x02de\x00`. Its subdomains are blocked as
+    /// well. The root name (a leading 0) stands for [`DEFAULT_BLOCKED`].
+    name: [u8; 256],
+    /// What the packets start with, see [`LinkLayer::from_config`].
+    link_layer: u32,
 }
 
-#[inline(always)]
-fn try_filter(ctx: &BpfContext) -> Result<FilterResult, ()> {
-    let ethhdr: *const EthHdr = unsafe { ctx.get_ptr(0)? };
-    let ether_type = unsafe { *ethhdr }.ether_type;
-    if ether_type != EtherType::Ipv4 {
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
+
+/// Drops queries for the blocked domain and malformed queries, which could hide them. The
+/// `dns-policy` rewriter answers them instead and takes a list of domains.
+#[bpf_filter(on_error = Drop)]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+    let settings = CONFIG.get()?;
+    let link = LinkLayer::from_config(settings.link_layer).ok_or(Error::Map)?;
+    let headers = packet.parse(link)?;
+    let Some(query) = Query::parse(packet, &headers)? else {
         return Ok(FilterResult::Pass);
+    };
+
+    if query.is_in(&blocked(settings)?) {
+        return Ok(FilterResult::Drop);
     }
+    Ok(FilterResult::Pass)
+}
 
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ctx.get_ptr(EthHdr::LEN)? };
-    let proto = unsafe { *ipv4hdr }.proto;
-    let ipv4hdr_len = unsafe { *ipv4hdr }.ihl() as usize * 4;
-    if ipv4hdr_len < Ipv4Hdr::LEN {
-        return Ok(FilterResult::Pass);
+#[inline(always)]
+fn blocked(settings: &Settings) -> Result<SuffixKey, Error> {
+    let name = match settings.name[0] {
+        0 => DEFAULT_BLOCKED,
+        _ => &settings.name,
+    };
+    read_name(name).map(|(key, _)| key)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
+
+    use bpf_element::dns::wire_name;
+    use bpf_element::testing::PacketBuilder;
+
+    use super::*;
+
+    /// A standard query with one `A` question for `name`.
+    fn query(name: &str) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&wire_name(name));
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message
     }
 
-    // check UDP
-    if proto != IpProto::Udp {
-        return Ok(FilterResult::Pass);
+    fn packet(dst_port: u16, payload: &[u8]) -> PacketBuilder {
+        PacketBuilder::new()
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 53))
+            .udp(40000, dst_port)
+            .payload(payload)
     }
 
-    // check src & dst port equal to 53
-    const DNS_PORT: u16 = 53;
-    let udphdr: *const UdpHdr = unsafe { ctx.get_ptr(EthHdr::LEN + ipv4hdr_len)? };
-    let dst_port = u16::from_be(unsafe { *udphdr }.dest);
-    if dst_port != DNS_PORT {
-        return Ok(FilterResult::Pass);
+    #[test]
+    fn drops_queries_for_blocked_name() {
+        let mut packet = packet(53, &query("lmu.de")).build();
+        assert_eq!(packet.run(main), FilterResult::Drop);
     }
 
-    // parse DNS query
-    let udp_data_len = u16::from_be(unsafe { *udphdr }.len);
-    let udp_data_offset = EthHdr::LEN + ipv4hdr_len + UdpHdr::LEN;
-    let udp_data = unsafe { ctx.get_slice(udp_data_len as usize, udp_data_offset)? };
+    #[test]
+    fn drops_queries_for_subdomains() {
+        for name in ["www.lmu.de", "A.B.LMU.DE"] {
+            let mut packet = packet(53, &query(name)).build();
+            assert_eq!(packet.run(main), FilterResult::Drop, "{name}");
+        }
+        let mut other = packet(53, &query("notlmu.de")).build();
+        assert_eq!(other.run(main), FilterResult::Pass);
+    }
 
-    let dns_message: DnsMessage<8, 0, _> = DnsMessage::new(udp_data).unwrap();
+    #[test]
+    fn drops_queries_over_tcp() {
+        let message = query("lmu.de");
+        let mut payload = (message.len() as u16).to_be_bytes().to_vec();
+        payload.extend_from_slice(&message);
+        let mut packet = PacketBuilder::new()
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 53))
+            .tcp_flags(40000, 53, 0x18)
+            .payload(&payload)
+            .build();
+        assert_eq!(packet.run(main), FilterResult::Drop);
+    }
 
-    let mut questions = dns_message.questions();
-    let Ok(questions) = questions.iter() else {
-        return Ok(FilterResult::Drop);
-    };
+    #[test]
+    fn drops_malformed_queries() {
+        let message = query("example.org");
+        for payload in [&message[..5], &message[..16]] {
+            let mut packet = packet(53, payload).build();
+            assert_eq!(packet.run(main), FilterResult::Drop);
+        }
+    }
 
-    for question in questions {
-        let Ok(question) = question else {
-            return Ok(FilterResult::Drop);
-        };
+    #[test]
+    fn reads_ethernet_frames_when_configured() {
+        CONFIG.set(Settings {
+            name: [0; 256],
+            link_layer: LinkLayer::Ethernet as u32,
+        });
+        let mut packet = PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 53))
+            .udp(40000, 53)
+            .payload(&query("lmu.de"))
+            .build();
+        assert_eq!(packet.run(main), FilterResult::Drop);
+    }
 
-        const BLOCKED: DnsName = dns_name!(b"lmu.de");
-        if question.name == BLOCKED {
-            return Ok(FilterResult::Drop);
-        }
+    #[test]
+    fn passes_other_queries() {
+        let mut packet = packet(53, &query("example.org")).build();
+        assert_eq!(packet.run(main), FilterResult::Pass);
     }
 
-    Ok(FilterResult::Pass)
+    #[test]
+    fn passes_other_ports() {
+        let mut packet = packet(5353, &query("lmu.de")).build();
+        assert_eq!(packet.run(main), FilterResult::Pass);
+    }
+
+    #[test]
+    fn reads_blocked_name_from_config() {
+        let mut name = [0; 256];
+        let wire = wire_name("example.org");
+        name[..wire.len()].copy_from_slice(&wire);
+        CONFIG.set(Settings {
+            name,
+            link_layer: LinkLayer::None as u32,
+        });
+
+        let mut blocked = packet(53, &query("example.org")).build();
+        assert_eq!(blocked.run(main), FilterResult::Drop);
+
+        let mut default = packet(53, &query("lmu.de")).build();
+        assert_eq!(default.run(main), FilterResult::Pass);
+    }
 }
@@ -0,0 +1,345 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use aya_ebpf::macros::map;
+use bpf_element::dns::{self, Answer, Query, SuffixMap, CLASS_IN, TYPE_A, TYPE_AAAA};
+use bpf_element::filter::FilterResult;
+use bpf_element::macros::bpf_rewriter;
+use bpf_element::maps::Config;
+use bpf_element::parse::LinkLayer;
+use bpf_element::rewriter::RewriterResult;
+use bpf_element::{Error, Packet};
+
+/// Actions of a domain in `BLOCKLIST`, as the [`FilterResult`]s `helper blocklist` writes.
+const BLOCK: u32 = FilterResult::Drop as u32;
+const ALLOW: u32 = FilterResult::Pass as u32;
+
+/// How blocked queries are answered, see [`Settings::response`].
+const RESPOND_NXDOMAIN: u32 = 0;
+const RESPOND_SINKHOLE: u32 = 1;
+const RESPOND_REFUSED: u32 = 2;
+const RESPOND_DROP: u32 = 3;
+
+/// TTL of sinkhole answers until `CONFIG` sets one, in seconds.
+const DEFAULT_TTL: u32 = 60;
+
+#[repr(C)]
+struct Settings {
+    /// What the packets start with, see [`LinkLayer::from_config`].
+    link_layer: u32,
+    /// [`RESPOND_NXDOMAIN`] (0), [`RESPOND_SINKHOLE`], [`RESPOND_REFUSED`] or [`RESPOND_DROP`].
+    response: u32,
+    /// TTL of sinkhole answers, 0 for [`DEFAULT_TTL`].
+    ttl: u32,
+    /// Addresses sinkhole answers to A and AAAA queries point to. Without one (0), and for other
+    /// queries, the answer has no records.
+    sinkhole_ipv4: [u8; 4],
+    sinkhole_ipv6: [u8; 16],
+}
+
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
+
+/// Domains to [`BLOCK`], and subdomains of them to [`ALLOW`] again. The longest domain a queried
+/// name is in decides.
+#[map(name = "BLOCKLIST")]
+static BLOCKLIST: SuffixMap<u32> = SuffixMap::with_max_entries(65536, 0);
+
+/// Answers blocked queries in place of the server, so they leave towards the client they came
+/// from. Malformed queries are dropped, everything else passes unchanged.
+#[bpf_rewriter(on_error = Drop)]
+fn try_rewrite(packet: &mut Packet) -> Result<RewriterResult, Error> {
+    let settings = CONFIG.get()?;
+    let link = LinkLayer::from_config(settings.link_layer).ok_or(Error::Map)?;
+    let headers = packet.parse(link)?;
+    let Some(query) = Query::parse(packet, &headers)? else {
+        return Ok(RewriterResult::Success);
+    };
+    match BLOCKLIST.get(&query).copied() {
+        None | Some(ALLOW) => return Ok(RewriterResult::Success),
+        Some(BLOCK) => {}
+        Some(_) => return Err(Error::Map),
+    }
+
+    let ttl = match settings.ttl {
+        0 => DEFAULT_TTL,
+        ttl => ttl,
+    };
+    let (rcode, answer) = match settings.response {
+        RESPOND_NXDOMAIN => (dns::RCODE_NXDOMAIN, Answer::None),
+        RESPOND_SINKHOLE => (dns::RCODE_NOERROR, sinkhole(&query, settings, ttl)),
+        RESPOND_REFUSED => (dns::RCODE_REFUSED, Answer::None),
+        RESPOND_DROP => return Ok(RewriterResult::Drop),
+        _ => return Err(Error::Map),
+    };
+    let layout = headers.layout();
+    dns::respond(packet, link, &layout, &query, rcode, answer)?;
+    Ok(RewriterResult::Success)
+}
+
+#[inline(always)]
+fn sinkhole(query: &Query, settings: &Settings, ttl: u32) -> Answer {
+    let ipv4 = Ipv4Addr::from(settings.sinkhole_ipv4);
+    let ipv6 = Ipv6Addr::from(settings.sinkhole_ipv6);
+    match (query.qclass, query.qtype) {
+        (CLASS_IN, TYPE_A) if !ipv4.is_unspecified() => Answer::A(ipv4, ttl),
+        (CLASS_IN, TYPE_AAAA) if !ipv6.is_unspecified() => Answer::Aaaa(ipv6, ttl),
+        _ => Answer::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use bpf_element::checksum::{fold, pseudo_header_v4, sum};
+    use bpf_element::dns::wire_name;
+    use bpf_element::parse::IPPROTO_TCP;
+    use bpf_element::testing::PacketBuilder;
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 53);
+    const SINKHOLE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    /// A recursive query with one question for `name`, followed by an EDNS OPT record.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        message.extend_from_slice(&wire_name(name));
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&[0, 1]);
+        message.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        message
+    }
+
+    /// The response to [`query`] with `rcode` and `answer`, a resource record without its name.
+    fn response(name: &str, qtype: u16, rcode: u8, answer: &[u8]) -> Vec<u8> {
+        let ancount = !answer.is_empty() as u8;
+        let mut message = vec![0x12, 0x34, 0x81, 0x80 | rcode, 0, 1, 0, ancount, 0, 0, 0, 0];
+        message.extend_from_slice(&wire_name(name));
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&[0, 1]);
+        if !answer.is_empty() {
+            message.extend_from_slice(&[0xc0, 12]);
+            message.extend_from_slice(answer);
+        }
+        message
+    }
+
+    fn udp_query(name: &str, qtype: u16) -> PacketBuilder {
+        PacketBuilder::new()
+            .ipv4(CLIENT, SERVER)
+            .udp(40000, 53)
+            .payload(&query(name, qtype))
+    }
+
+    fn udp_response(message: &[u8]) -> Vec<u8> {
+        PacketBuilder::new()
+            .ipv4(SERVER, CLIENT)
+            .udp(53, 40000)
+            .payload(message)
+            .bytes()
+    }
+
+    fn configure(link: LinkLayer, response: u32) {
+        CONFIG.set(Settings {
+            link_layer: link as u32,
+            response,
+            ttl: 0,
+            sinkhole_ipv4: SINKHOLE.octets(),
+            sinkhole_ipv6: [0; 16],
+        });
+    }
+
+    #[test]
+    fn answers_blocked_domains_with_nxdomain() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        for name in ["lmu.de", "www.LMU.de", "a.b.lmu.de"] {
+            let mut packet = udp_query(name, TYPE_A).build();
+            assert_eq!(packet.run(main), RewriterResult::Success);
+            let nxdomain = response(name, TYPE_A, dns::RCODE_NXDOMAIN, &[]);
+            assert_eq!(packet.data(), udp_response(&nxdomain), "{name}");
+        }
+    }
+
+    #[test]
+    fn passes_other_domains() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        for name in ["example.org", "de", "notlmu.de", "lmu.de.example.org"] {
+            let mut packet = udp_query(name, TYPE_A).build();
+            assert_eq!(packet.run(main), RewriterResult::Success);
+            assert_eq!(packet.data(), udp_query(name, TYPE_A).bytes(), "{name}");
+        }
+    }
+
+    #[test]
+    fn allows_subdomains_of_blocked_domains() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        BLOCKLIST.insert("www.lmu.de", &ALLOW).unwrap();
+        let mut allowed = udp_query("www.lmu.de", TYPE_A).build();
+        allowed.run(main);
+        assert_eq!(allowed.data(), udp_query("www.lmu.de", TYPE_A).bytes());
+
+        let mut blocked = udp_query("mail.lmu.de", TYPE_A).build();
+        blocked.run(main);
+        assert_ne!(blocked.data(), udp_query("mail.lmu.de", TYPE_A).bytes());
+    }
+
+    #[test]
+    fn answers_with_the_sinkhole() {
+        configure(LinkLayer::None, RESPOND_SINKHOLE);
+        BLOCKLIST.insert("ads.example", &BLOCK).unwrap();
+
+        let mut a = udp_query("ads.example", TYPE_A).build();
+        assert_eq!(a.run(main), RewriterResult::Success);
+        let mut record = vec![0, 1, 0, 1, 0, 0, 0, 60, 0, 4];
+        record.extend_from_slice(&SINKHOLE.octets());
+        let answer = response("ads.example", TYPE_A, dns::RCODE_NOERROR, &record);
+        assert_eq!(a.data(), udp_response(&answer));
+
+        // no IPv6 sinkhole configured
+        let mut aaaa = udp_query("ads.example", TYPE_AAAA).build();
+        assert_eq!(aaaa.run(main), RewriterResult::Success);
+        let nodata = response("ads.example", TYPE_AAAA, dns::RCODE_NOERROR, &[]);
+        assert_eq!(aaaa.data(), udp_response(&nodata));
+    }
+
+    #[test]
+    fn answers_over_ipv6_and_ethernet() {
+        CONFIG.set(Settings {
+            link_layer: LinkLayer::Ethernet as u32,
+            response: RESPOND_SINKHOLE,
+            ttl: 300,
+            sinkhole_ipv4: [0; 4],
+            sinkhole_ipv6: Ipv6Addr::LOCALHOST.octets(),
+        });
+        BLOCKLIST.insert("ads.example", &BLOCK).unwrap();
+        let (client, server): (Ipv6Addr, Ipv6Addr) = (
+            "2001:db8::2".parse().unwrap(),
+            "2001:db8::53".parse().unwrap(),
+        );
+        let (client_mac, server_mac) = ([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]);
+
+        let mut packet = PacketBuilder::new()
+            .ethernet(client_mac, server_mac)
+            .ipv6(client, server)
+            .udp(40000, 53)
+            .payload(&query("ads.example", TYPE_AAAA))
+            .build();
+        assert_eq!(packet.run(main), RewriterResult::Success);
+
+        let mut record = vec![0, 28, 0, 1, 0, 0, 1, 44, 0, 16];
+        record.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        let answer = response("ads.example", TYPE_AAAA, dns::RCODE_NOERROR, &record);
+        let expected = PacketBuilder::new()
+            .ethernet(server_mac, client_mac)
+            .ipv6(server, client)
+            .udp(53, 40000)
+            .payload(&answer)
+            .bytes();
+        assert_eq!(packet.data(), expected);
+    }
+
+    #[test]
+    fn refuses_or_drops_when_configured() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        configure(LinkLayer::None, RESPOND_REFUSED);
+        let mut refused = udp_query("lmu.de", TYPE_A).build();
+        assert_eq!(refused.run(main), RewriterResult::Success);
+        let answer = response("lmu.de", TYPE_A, dns::RCODE_REFUSED, &[]);
+        assert_eq!(refused.data(), udp_response(&answer));
+
+        configure(LinkLayer::None, RESPOND_DROP);
+        assert_eq!(
+            udp_query("lmu.de", TYPE_A).build().run(main),
+            RewriterResult::Drop
+        );
+    }
+
+    #[test]
+    fn resets_blocked_queries_over_tcp() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        let message = query("lmu.de", TYPE_A);
+        let mut payload = (message.len() as u16).to_be_bytes().to_vec();
+        payload.extend_from_slice(&message);
+        let mut packet = PacketBuilder::new()
+            .ipv4(CLIENT, SERVER)
+            .tcp_flags(40000, 53, 0x18)
+            .payload(&payload)
+            .build();
+        assert_eq!(packet.run(main), RewriterResult::Success);
+
+        let data = packet.data();
+        assert_eq!(data.len(), 40);
+        assert_eq!(&data[2..4], &40u16.to_be_bytes());
+        assert_eq!(&data[12..16], &SERVER.octets());
+        assert_eq!(&data[16..20], &CLIENT.octets());
+        assert_eq!(fold(sum(&data[..20], 0)), 0xffff);
+        let segment = &data[20..];
+        assert_eq!(&segment[0..4], &[0, 53, 0x9c, 0x40]);
+        // the builder's sequence and acknowledgment numbers are 0
+        assert_eq!(&segment[4..8], &[0, 0, 0, 0]);
+        assert_eq!(&segment[8..12], &(payload.len() as u32).to_be_bytes());
+        assert_eq!(segment[13], 0x14);
+        let pseudo = pseudo_header_v4(SERVER, CLIENT, IPPROTO_TCP, 20);
+        assert_eq!(fold(sum(segment, pseudo)), 0xffff);
+    }
+
+    #[test]
+    fn passes_tcp_handshakes() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        let syn = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(40000, 53);
+        let mut packet = syn.build();
+        assert_eq!(packet.run(main), RewriterResult::Success);
+        assert_eq!(packet.data(), syn.bytes());
+    }
+
+    #[test]
+    fn drops_malformed_queries() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        let message = query("lmu.de", TYPE_A);
+        let mut pointer = message[..12].to_vec();
+        pointer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        let mut two_questions = message.clone();
+        two_questions[5] = 2;
+        let mut endless = message[..12].to_vec();
+        endless.extend_from_slice(&[63; 300]);
+
+        for payload in [
+            &message[..5],
+            &message[..20],
+            &pointer,
+            &two_questions,
+            &endless,
+        ] {
+            let mut packet = PacketBuilder::new()
+                .ipv4(CLIENT, SERVER)
+                .udp(40000, 53)
+                .payload(payload)
+                .build();
+            assert_eq!(packet.run(main), RewriterResult::Drop);
+        }
+    }
+
+    #[test]
+    fn passes_responses_and_other_ports() {
+        BLOCKLIST.insert("lmu.de", &BLOCK).unwrap();
+        let nxdomain = response("lmu.de", TYPE_A, dns::RCODE_NXDOMAIN, &[]);
+        let other_port = PacketBuilder::new()
+            .ipv4(CLIENT, SERVER)
+            .udp(40000, 5353)
+            .payload(&query("lmu.de", TYPE_A));
+        let from_server = PacketBuilder::new()
+            .ipv4(SERVER, CLIENT)
+            .udp(40000, 53)
+            .payload(&nxdomain);
+        for builder in [other_port, from_server] {
+            let mut packet = builder.build();
+            assert_eq!(packet.run(main), RewriterResult::Success);
+            assert_eq!(packet.data(), builder.bytes());
+        }
+    }
+}
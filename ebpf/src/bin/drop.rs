@@ -1,10 +1,11 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 use bpf_element::filter::FilterResult;
+use bpf_element::macros::bpf_filter;
+use bpf_element::{Error, Packet};
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main() -> FilterResult {
-    FilterResult::Drop
+#[bpf_filter(telemetry = false)]
+fn try_filter(_: &mut Packet) -> Result<FilterResult, Error> {
+    Ok(FilterResult::Drop)
 }
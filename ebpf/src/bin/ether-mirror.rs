@@ -1,29 +1,49 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 use core::mem;
 
-use bpf_element::BpfContext;
-use network_types::eth::EthHdr;
+use bpf_element::macros::bpf_rewriter;
 use bpf_element::rewriter::RewriterResult;
+use bpf_element::{Error, Packet};
+use network_types::eth::EthHdr;
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> RewriterResult {
-    let mut ctx = unsafe { *ctx };
+#[bpf_rewriter]
+fn try_rewrite(packet: &mut Packet) -> Result<RewriterResult, Error> {
+    let ethhdr: &mut EthHdr = packet.load_mut(0)?;
 
-    match try_rewrite(&mut ctx) {
-        Ok(_) => RewriterResult::Success,
-        Err(_) => RewriterResult::Abort,
-    }
+    // mirror ethernet source and destination addresses
+    mem::swap(&mut ethhdr.dst_addr, &mut ethhdr.src_addr);
+
+    Ok(RewriterResult::Success)
 }
 
-#[inline(always)]
-fn try_rewrite(ctx: &mut BpfContext) -> Result<(), ()> {
-    let ethhdr: &mut EthHdr = unsafe { &mut *ctx.get_ptr_mut(0)? };
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
 
-    // mirror ethernet source and destination addresses
-    mem::swap(&mut ethhdr.dst_addr, &mut ethhdr.src_addr);
+    use bpf_element::testing::{PacketBuilder, TestPacket};
+
+    use super::*;
+
+    #[test]
+    fn swaps_addresses() {
+        let packet = |src, dst| {
+            PacketBuilder::new()
+                .ethernet(src, dst)
+                .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+                .udp(1000, 2000)
+        };
+        let (a, b) = ([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]);
 
-    Ok(())
+        let mut mirrored = packet(a, b).build();
+        assert_eq!(mirrored.run(main), RewriterResult::Success);
+        assert_eq!(mirrored.data(), packet(b, a).bytes());
+    }
+
+    #[test]
+    fn aborts_on_runt_frames() {
+        let mut packet = TestPacket::new(&[0; 10]);
+        assert_eq!(packet.run(main), RewriterResult::Abort);
+    }
 }
@@ -1,62 +1,150 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use aya_ebpf::cty::{c_long, c_char};
-use core::mem;
 use aya_ebpf::macros::map;
-use aya_ebpf::helpers::bpf_printk;
-use aya_ebpf::helpers::gen::bpf_ktime_get_ns;
-use aya_ebpf::maps::{Array, HashMap};
-use bpf_element::{BpfContext, update_checksum_ip};
-use network_types::eth::{EthHdr, EtherType};
-use network_types::ip::{IpProto, Ipv4Hdr};
-use network_types::tcp::TcpHdr;
-use network_types::udp::UdpHdr;
 use bpf_element::filter::FilterResult;
+use bpf_element::firewall::{Fields, Rule};
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::{Array, Config};
+use bpf_element::parse::LinkLayer;
+use bpf_element::{Error, Packet};
 
-const PACKET_START: usize = 14; // 14 if ethernet has not been stripped
-//
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> FilterResult {
-    let mut ctx = unsafe { *ctx };
-    // unsafe { bpf_printk!(b"port %d\n", ctx.port) };
-    try_classify(&mut ctx).unwrap_or_else(|_| FilterResult::Drop)
+const MAX_RULES: u32 = 1024;
+
+/// Rules in the order they're tried, up to the first zeroed one. `morphos-firewall table` (see
+/// `runner`) writes them from a rule file.
+#[map(name = "RULE_TABLE")]
+static RULE_TABLE: Array<Rule> = Array::with_max_entries(MAX_RULES, 0);
+
+#[repr(C)]
+struct Settings {
+    /// Action for packets no rule matches, see [`FilterResult::from_action`]. 0 drops them.
+    default_action: u32,
 }
 
-#[inline(always)]
-fn try_classify(ctx: &mut BpfContext) -> Result<FilterResult, ()> {
-    let ethhdr: *const EthHdr = unsafe { ctx.get_ptr(0)? };
-    let ether_type  = unsafe { *ethhdr }.ether_type;
-    if ether_type != EtherType::Ipv4 {
-        // unsafe { bpf_printk!(b"err! #2\n") };
-        return Err(());
-    }
-    let ipv4hdr: *mut Ipv4Hdr = unsafe { ctx.get_ptr_mut(PACKET_START)? };
-    let proto = unsafe { *ipv4hdr }.proto;
-    let (src_port, dst_port) = match proto {
-        IpProto::Tcp => {
-            // unsafe { bpf_printk!(b"foo #2.1\n") };
-            let tcphdr: *const TcpHdr = unsafe { ctx.get_ptr(PACKET_START + Ipv4Hdr::LEN) }?;
-            (u16::from_be(unsafe { *tcphdr }.source), u16::from_be(unsafe { *tcphdr }.dest))
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
+
+#[bpf_filter(on_error = Drop, telemetry = false)]
+fn try_classify(packet: &mut Packet) -> Result<FilterResult, Error> {
+    let headers = packet.parse(LinkLayer::Ethernet)?;
+    // IPv4 or IPv6 only
+    let fields = Fields::of(&headers).ok_or(Error::Unsupported)?;
+
+    for index in 0..MAX_RULES {
+        let rule = RULE_TABLE.get(index).ok_or(Error::Map)?;
+        if rule.action == 0 {
+            break;
         }
-        IpProto::Udp => {
-            let udphdr: *const UdpHdr = unsafe { ctx.get_ptr(PACKET_START + Ipv4Hdr::LEN) }?;
-            (u16::from_be(unsafe { *udphdr}.source), u16::from_be(unsafe { *udphdr }.dest))
+        if rule.matches(&fields) {
+            return rule.result().ok_or(Error::Map);
+        }
+    }
+    match CONFIG.get()?.default_action {
+        0 => Ok(FilterResult::Drop),
+        action => FilterResult::from_action(action).ok_or(Error::Map),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use bpf_element::firewall::Prefix;
+    use bpf_element::parse::{IPPROTO_TCP, IPPROTO_UDP};
+    use bpf_element::testing::PacketBuilder;
+
+    use super::*;
+
+    fn ipv4() -> PacketBuilder {
+        PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+    }
+
+    fn rules(rules: &[Rule]) {
+        for (index, rule) in rules.iter().enumerate() {
+            let slot = RULE_TABLE.get_ptr_mut(index as u32).unwrap();
+            unsafe { *slot = *rule };
         }
-        _ => {
-            // unsafe { bpf_printk!(b"err! #1\n") };
-            return Err(())
-        },
-    };
-
-    // in vim, mark a block of numbers and increment them sequentially with g<C-a> (vim may hang
-    // for some time)
-    Ok(match dst_port {
-        // start
-1000 => FilterResult::Pass,
-1001 => FilterResult::Drop,
-        // end
-        _ => FilterResult::Drop,
-    })
+    }
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        rules(&[
+            Rule::new(FilterResult::Drop)
+                .protocol(IPPROTO_TCP)
+                .dst_ports(22, 22),
+            Rule::new(FilterResult::Pass).src(Prefix::v4([10, 0, 0, 0], 8)),
+        ]);
+        assert_eq!(ipv4().tcp(40000, 22).build().run(main), FilterResult::Drop);
+        assert_eq!(ipv4().udp(40000, 22).build().run(main), FilterResult::Pass);
+        assert_eq!(
+            ipv4().icmp_echo_request(1, 1).build().run(main),
+            FilterResult::Pass
+        );
+    }
+
+    #[test]
+    fn drops_unmatched_packets() {
+        rules(&[Rule::new(FilterResult::Pass)
+            .protocol(IPPROTO_UDP)
+            .dst_ports(1000, 1999)]);
+        assert_eq!(
+            ipv4().udp(40000, 1500).build().run(main),
+            FilterResult::Pass
+        );
+        assert_eq!(
+            ipv4().tcp(40000, 1500).build().run(main),
+            FilterResult::Drop
+        );
+        let ipv6 = PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST)
+            .udp(40000, 80);
+        assert_eq!(ipv6.build().run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn stops_at_the_first_empty_rule() {
+        rules(&[
+            Rule::new(FilterResult::Drop).dst_ports(22, 22),
+            Rule::new(FilterResult::Abort),
+            Rule::new(FilterResult::Pass),
+        ]);
+        assert_eq!(ipv4().tcp(40000, 80).build().run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn reads_default_action_from_config() {
+        CONFIG.set(Settings {
+            default_action: FilterResult::Pass as u32,
+        });
+        rules(&[Rule::new(FilterResult::Drop).dst_ports(22, 22)]);
+        assert_eq!(ipv4().tcp(40000, 80).build().run(main), FilterResult::Pass);
+        assert_eq!(ipv4().tcp(40000, 22).build().run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn drops_on_invalid_actions() {
+        let mut rule = Rule::new(FilterResult::Pass);
+        rule.action = 7;
+        rules(&[rule]);
+        assert_eq!(ipv4().tcp(40000, 80).build().run(main), FilterResult::Drop);
+
+        CONFIG.set(Settings { default_action: 7 });
+        rules(&[Rule::new(FilterResult::Abort)]);
+        assert_eq!(ipv4().tcp(40000, 80).build().run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn drops_non_ip_packets() {
+        CONFIG.set(Settings {
+            default_action: FilterResult::Pass as u32,
+        });
+        let other = PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .payload(&[0; 28]);
+        assert_eq!(other.build().run(main), FilterResult::Drop);
+    }
 }
@@ -1,54 +1,98 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use aya_ebpf::cty::{c_long, c_char};
-use core::mem;
+use core::net::Ipv4Addr;
 use aya_ebpf::macros::map;
-use aya_ebpf::helpers::bpf_printk;
-use aya_ebpf::helpers::gen::bpf_ktime_get_ns;
-use aya_ebpf::maps::{Array, HashMap};
-use bpf_element::{BpfContext, update_checksum_ip};
-use network_types::eth::{EthHdr, EtherType};
-use network_types::ip::{IpProto, Ipv4Hdr};
-use network_types::tcp::TcpHdr;
-use network_types::udp::UdpHdr;
+use bpf_element::classifier::Output;
+use bpf_element::helpers::{BPFClassifier, Helpers};
+use bpf_element::macros::bpf_classifier;
+use bpf_element::maps::{Array, Config, HashMap, LruHashMap, MapKey};
+use bpf_element::migration::Schema;
+use bpf_element::parse::{
+    Layout, LinkLayer, Quoted, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST, IPPROTO_ICMP, IPPROTO_TCP,
+    IPPROTO_UDP, L3, L4,
+};
+use bpf_element::{checksum, log, rewrite, Error, Packet};
+use network_types::icmp::IcmpHdr;
+
+const HELPERS: Helpers<BPFClassifier> = Helpers::new();
 
 const OUTPUTS: u32 = 2;
-pub type Output = u32;
+pub type Port = Output<OUTPUTS>;
 
-// NAT ports
+// NAT ports, for TCP and UDP as well as the identifiers of ICMP echos
 const PORT_START: u16 = 50000;
 const PORT_END: u16 = 65535;
-
-const FOUTPUT: u32 = 0; // packet towards the wild. Will have src_ip == DEV_EX.ip and dst_mac == GW_ADDR.mac.
-const ROUTPUT: u32 = 1; // reply flows are rewritten to look like the original flow -> routput (to the internal network)
-
-const PACKET_START: usize = 0; // 14 if ethernet has not been stripped
-
-const DEV_IN: InterfaceInfo = InterfaceInfo {
-    // mac: [0x00, 0x0d, 0x87, 0x9d, 0x1c, 0xe9],
-    ip: 0xac2c0002_u32.to_be(), // 172.44.0.2
-    subnet: 24,
-};
+// ports tried for a new flow before giving up on it
+const PORT_ATTEMPTS: u32 = 16;
+
+// How long a flow may idle before its mapping is dropped, after RFC 4787 (UDP) and RFC 5382 (TCP)
+const NS_PER_SEC: u64 = 1_000_000_000;
+const UDP_TIMEOUT_NS: u64 = 300 * NS_PER_SEC;
+// RFC 5508
+const ICMP_TIMEOUT_NS: u64 = 60 * NS_PER_SEC;
+const TCP_ESTABLISHED_TIMEOUT_NS: u64 = 7440 * NS_PER_SEC;
+// after a FIN, while the peers close the connection
+const TCP_CLOSING_TIMEOUT_NS: u64 = 240 * NS_PER_SEC;
+// after a RST, for retransmissions of it
+const TCP_RESET_TIMEOUT_NS: u64 = 10 * NS_PER_SEC;
+
+// Flow states, each one only ever followed by a later one
+const FLOW_OPEN: u32 = 0;
+const FLOW_CLOSING: u32 = 1;
+const FLOW_RESET: u32 = 2;
+
+const FOUTPUT: Port = Output::at::<0>(); // packet towards the wild. Will have src_ip == DEV_EX.ip.
+const ROUTPUT: Port = Output::at::<1>(); // reply flows are rewritten to look like the original flow -> routput (to the internal network)
+
+// Default for the external interface if CONFIG leaves it unset
 const DEV_EX: InterfaceInfo = InterfaceInfo {
     // mac: [0x00, 0x0d, 0x87, 0x9d, 0x1c, 0xe9],
     ip: 0xac2c0003_u32.to_be(), // 172.44.0.3
     subnet: 0,
 };
-const GW_ADDR: InterfaceInfo = InterfaceInfo {
-    // mac: [0x00, 0x20, 0x6f, 0x9d, 0x1c, 0xc2],
-    ip: 0xac2c0001_u32.to_be(), // 172.44.0.2
-    subnet: 0,
-};
 
+// Our verifier supports MAP_KEYS to be any generic numeric value, but can't comprehend that a
+// struct is also just a numeric value. MapKey packs it into one (104 bits, so a u128).
+//
+// ICMP echo requests have their identifier as source port and 0 as destination port, replies the
+// other way around, so the replies to a request have its connection reversed like TCP and UDP.
+#[derive(Copy, Clone, MapKey)]
 struct Connection {
     src_ip: u32,
     src_port: u16,
     dst_ip: u32,
     dst_port: u16,
-    protocol: IpProto,
+    protocol: u8,
+}
+
+impl Connection {
+    /// Key of the connection in `CONNECTIONS`, packed like the first nat did, which overlaps the
+    /// source port with the destination address. New tables use [`MapKey`].
+    #[inline(always)]
+    fn legacy_key(&self) -> u128 {
+        (self.src_ip as u128) << 96
+            | (self.src_port as u128) << 80
+            | (self.dst_ip as u128) << 64
+            | (self.dst_port as u128) << 48
+            | (self.protocol as u128) << 32
+    }
+
+    /// The connection of the packets going the other way.
+    #[inline(always)]
+    fn reversed(&self) -> Connection {
+        Connection {
+            src_ip: self.dst_ip,
+            src_port: self.dst_port,
+            dst_ip: self.src_ip,
+            dst_port: self.src_port,
+            protocol: self.protocol,
+        }
+    }
 }
 
+/// Addresses and ports of a flow's packets after translation.
+#[derive(Copy, Clone)]
 struct Rewrite {
     src_ip: u32,
     // src_mac: [u8; 6],
@@ -56,23 +100,93 @@ struct Rewrite {
     dst_ip: u32,
     // dst_mac: [u8; 6],
     dst_port: u16,
-    output: Output
+    // raw port, checked against OUTPUTS on every lookup
+    output: u32,
 }
 
+impl Rewrite {
+    #[inline(always)]
+    fn to(conn: &Connection, output: Port) -> Rewrite {
+        Rewrite {
+            src_ip: conn.src_ip,
+            src_port: conn.src_port,
+            dst_ip: conn.dst_ip,
+            dst_port: conn.dst_port,
+            output: output.port(),
+        }
+    }
+
+    /// The connection of the translated packets.
+    #[inline(always)]
+    fn connection(&self, protocol: u8) -> Connection {
+        Connection {
+            src_ip: self.src_ip,
+            src_port: self.src_port,
+            dst_ip: self.dst_ip,
+            dst_port: self.dst_port,
+            protocol,
+        }
+    }
+}
+
+type ConnectionKey = <Connection as MapKey>::Key;
+
+struct Flow {
+    rewrite: Rewrite,
+    /// `bpf_ktime_get_ns` of the last packet.
+    last_seen: u64,
+    /// One of the `FLOW_*` states.
+    state: u32,
+}
+
+impl Flow {
+    #[inline(always)]
+    fn new(rewrite: Rewrite, now: u64) -> Flow {
+        Flow {
+            rewrite,
+            last_seen: now,
+            state: FLOW_OPEN,
+        }
+    }
+
+    #[inline(always)]
+    fn expired(&self, protocol: u8, now: u64) -> bool {
+        let timeout = match (protocol, self.state) {
+            (IPPROTO_TCP, FLOW_RESET) => TCP_RESET_TIMEOUT_NS,
+            (IPPROTO_TCP, FLOW_CLOSING) => TCP_CLOSING_TIMEOUT_NS,
+            (IPPROTO_TCP, _) => TCP_ESTABLISHED_TIMEOUT_NS,
+            (IPPROTO_ICMP, _) => ICMP_TIMEOUT_NS,
+            _ => UDP_TIMEOUT_NS,
+        };
+        now.saturating_sub(self.last_seen) > timeout
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
 struct InterfaceInfo {
     // mac: [u8; 6],
     ip: u32,
     subnet: u8,
 }
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> Output {
-    let mut ctx = unsafe { *ctx };
-    // unsafe { bpf_printk!(b"port %d\n", ctx.port) };
-    try_classify(&mut ctx).unwrap_or_else(|_| core::u32::MAX)
+impl InterfaceInfo {
+    /// This interface, or `default` if its IP is unset (0).
+    #[inline(always)]
+    fn or(self, default: InterfaceInfo) -> InterfaceInfo {
+        if self.ip == 0 { default } else { self }
+    }
 }
 
+/// Addresses of the deployment, in network byte order.
+#[repr(C)]
+struct Interfaces {
+    dev_ex: InterfaceInfo,
+}
+
+#[map(name = "CONFIG")]
+static CONFIG: Config<Interfaces> = Config::new();
+
 #[map(name = "PKTCOUNTER")]
 static PKTCOUNTER: Array<u32> = Array::with_max_entries(1, 0);
 
@@ -80,185 +194,686 @@ static PKTCOUNTER: Array<u32> = Array::with_max_entries(1, 0);
 static NEXT_PORT: Array<u32> = Array::with_max_entries(1, 0);
 
 #[inline(always)]
-fn next_port() -> Result<u16, ()> {
-    let next_port = NEXT_PORT.get_ptr_mut(0).ok_or(())?;
+fn next_port() -> Result<u16, Error> {
+    let next_port = NEXT_PORT.get_ptr_mut(0).ok_or(Error::Map)?;
     let port = unsafe { *next_port };
-    // unsafe { bpf_printk!(b"next_port %d\n", port) };
     if port == 0 {
-        // unsafe { bpf_printk!(b"set 0\n") };
         unsafe { *next_port = PORT_START as u32 };
     }
     let port = unsafe { *next_port };
-    // unsafe { bpf_printk!(b"next_port %d\n", port) };
-    // unsafe { bpf_printk!(b"next_port %d\n", *next_port) };
-    unsafe { *next_port = (*next_port - PORT_START as u32 + 1) % (PORT_END - PORT_START) as u32 + PORT_START as u32 };
+    log::debug!("trying port {}", port);
+    unsafe { *next_port = (*next_port - PORT_START as u32 + 1) % (PORT_END - PORT_START + 1) as u32 + PORT_START as u32 };
     Ok(port as u16)
 }
 
+/// Ports of an ICMP echo, see `Connection`.
 #[inline(always)]
-fn apply_rewrite(ctx: &mut BpfContext, conn: &Connection, rewrite: *const Rewrite) -> Result<(), ()> {
-    let ipv4hdr: *mut Ipv4Hdr = unsafe { ctx.get_ptr_mut(PACKET_START)? };
-    unsafe { update_checksum_ip(&mut (*ipv4hdr).check, (*ipv4hdr).src_addr, (*rewrite).src_ip) };
-    unsafe { (*ipv4hdr).src_addr = (*rewrite).src_ip };
-    unsafe { update_checksum_ip(&mut (*ipv4hdr).check, (*ipv4hdr).dst_addr , (*rewrite).dst_ip) };
-    unsafe { (*ipv4hdr).dst_addr = (*rewrite).dst_ip };
-    match conn.protocol {
-        IpProto::Tcp => {
-            let tcphdr: *mut TcpHdr = unsafe { ctx.get_ptr_mut(PACKET_START + Ipv4Hdr::LEN) }?;
-            unsafe { (*tcphdr).source = (*rewrite).src_port.to_be() };
-            unsafe { (*tcphdr).dest = (*rewrite).dst_port.to_be() };
-        }
-        IpProto::Udp => {
-            let udphdr: *mut UdpHdr = unsafe { ctx.get_ptr_mut(PACKET_START + Ipv4Hdr::LEN) }?;
-            unsafe { (*udphdr).source = (*rewrite).src_port.to_be() };
-            unsafe { (*udphdr).dest = (*rewrite).dst_port.to_be() };
-        }
-        _ => {
-            // unsafe { bpf_printk!(b"err! #4\n") };
-            return Err(())
-        }
+fn echo_ports(hdr: &IcmpHdr) -> Option<(u16, u16)> {
+    let id = u16::from_be(unsafe { hdr.un.echo.id });
+    match hdr.type_ {
+        ICMP_ECHO_REQUEST => Some((id, 0)),
+        ICMP_ECHO_REPLY => Some((0, id)),
+        _ => None,
     }
+}
+
+/// Rewrites the packet at `layout` to belong to `conn`.
+#[inline(always)]
+fn apply_rewrite(packet: &mut Packet, conn: &Connection, layout: &Layout) -> Result<(), Error> {
+    // fails for anything but TCP, UDP and ICMP echos
+    if conn.protocol == IPPROTO_ICMP {
+        let hdr: &IcmpHdr = packet.load(layout.l4_offset)?;
+        let id = if hdr.type_ == ICMP_ECHO_REQUEST { conn.src_port } else { conn.dst_port };
+        rewrite::set_icmp_echo_id(packet, layout, id)?;
+    } else {
+        rewrite::set_l4_src_port(packet, layout, conn.src_port)?;
+        rewrite::set_l4_dst_port(packet, layout, conn.dst_port)?;
+    }
+    rewrite::set_ipv4_src(packet, layout, Ipv4Addr::from(u32::from_be(conn.src_ip)))?;
+    rewrite::set_ipv4_dst(packet, layout, Ipv4Addr::from(u32::from_be(conn.dst_ip)))?;
     Ok(())
 }
 
+/// Flows by the connection their packets arrive with, one entry for each direction. Least recently
+/// used flows make room for new ones when it's full, and idle flows expire (see `Flow::expired`).
+#[map(name = "CONNECTIONS_V2")]
+static FLOWS: LruHashMap<ConnectionKey, Flow> = LruHashMap::with_max_entries(32768, 0);
+
+/// The table of version 1, which neither expired nor evicted flows, keyed by
+/// [`Connection::legacy_key`]. Only read, see `lookup_flow`.
 #[map(name = "CONNECTIONS")]
 static CONNECTIONS: HashMap<u128, Rewrite> = HashMap::with_max_entries(1028, 0);
 
+/// Until when flows of `CONNECTIONS` are taken over, as `bpf_ktime_get_ns`.
+#[map(name = "CONNECTIONS_V1_UNTIL")]
+static CONNECTIONS_V1_UNTIL: Array<u64> = Array::with_max_entries(1, 0);
+
+/// Versions of the connection tables, see [`bpf_element::migration`].
+struct Maps;
+
+impl Schema for Maps {
+    const VERSION: u32 = 2;
+
+    fn migrate(from: u32) -> Result<u32, Error> {
+        match from {
+            // Builds without a schema, and version 1, keyed CONNECTIONS by `Connection::legacy_key`.
+            // It can't be iterated, so its flows move over with their next packet, as long as they
+            // could still be alive.
+            0 | 1 => {
+                let until = CONNECTIONS_V1_UNTIL.get_ptr_mut(0).ok_or(Error::Map)?;
+                unsafe { *until = HELPERS.ktime_get_ns() + TCP_ESTABLISHED_TIMEOUT_NS };
+                Ok(2)
+            }
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+/// The flow of `conn` unless it expired, taking it over from `CONNECTIONS` after a migration.
+#[inline(always)]
+fn lookup_flow(conn: &Connection, now: u64) -> Result<Option<*mut Flow>, Error> {
+    let key = &conn.to_key();
+    if let Some(flow) = FLOWS.get_ptr_mut(key) {
+        // expired flows stay until they are replaced or evicted, so CONNECTIONS can't revive them
+        let expired = unsafe { (*flow).expired(conn.protocol, now) };
+        return Ok(if expired { None } else { Some(flow) });
+    }
+
+    if now >= *CONNECTIONS_V1_UNTIL.get(0).ok_or(Error::Map)? {
+        return Ok(None);
+    }
+    let Some(rewrite) = CONNECTIONS.get_ptr(&conn.legacy_key()) else {
+        return Ok(None);
+    };
+    let flow = Flow::new(unsafe { *rewrite }, now);
+    FLOWS.insert(key, &flow, 0).map_err(|_| Error::Map)?;
+    Ok(FLOWS.get_ptr_mut(key))
+}
 
+/// Whether a flow of `key` is there and hasn't expired.
 #[inline(always)]
-fn connection_to_u128(connection: &Connection) -> u128 {
-    // Our verifier supports MAP_KEYS to be any generic numeric value, but can't comprehend that a struct is also just a numeric value.
-    // We convert it to a numeric value for it.
-    (connection.src_ip as u128) << 96 | (connection.src_port as u128) << 80 | (connection.dst_ip as u128) << 64 | (connection.dst_port as u128) << 48 | (connection.protocol as u128) << 32
+fn is_live(key: &ConnectionKey, protocol: u8, now: u64) -> bool {
+    match FLOWS.get_ptr(key) {
+        Some(flow) => unsafe { !(*flow).expired(protocol, now) },
+        None => false,
+    }
 }
 
+/// Marks the flow of `conn` and the one of its replies as used by a packet in `state`. Returns
+/// its rewrite unless the replies already belong to a newer flow, after the reply entry had been
+/// evicted.
 #[inline(always)]
-fn try_classify(ctx: &mut BpfContext) -> Result<Output, ()> {
-    let port = ctx.port as u32;
-    // let ethhdr: *const EthHdr = unsafe { ctx.get_ptr(0)? };
-    // let ether_type  = unsafe { *ethhdr }.ether_type;
-    // if ether_type != EtherType::Ipv4 {
-    //     unsafe { bpf_printk!(b"err! #2\n") };
-    //     return Err(());
-    // }
-    let ipv4hdr: *mut Ipv4Hdr = unsafe { ctx.get_ptr_mut(PACKET_START)? };
-    let a = 1337;
-    let b: *const u32 = &a;
-
-    let proto = unsafe { *ipv4hdr }.proto;
-    // unsafe { bpf_printk!(b"proto %d\n", proto as u8) };
-
-    let mut conn = match proto {
-        IpProto::Tcp => {
-            // unsafe { bpf_printk!(b"foo #2.1\n") };
-            let tcphdr: *const TcpHdr = unsafe { ctx.get_ptr(PACKET_START + Ipv4Hdr::LEN) }?;
-            Connection{
-                src_ip: unsafe { *ipv4hdr }.src_addr,
-                src_port: u16::from_be(unsafe { *tcphdr }.source),
-                dst_ip: unsafe { *ipv4hdr }.dst_addr,
-                dst_port: u16::from_be(unsafe { *tcphdr }.dest),
-                protocol: IpProto::Tcp,
+fn refresh(conn: &Connection, flow: *mut Flow, state: u32, now: u64) -> Result<Option<Rewrite>, Error> {
+    let flow = unsafe { &mut *flow };
+    flow.last_seen = now;
+    flow.state = flow.state.max(state);
+    let (rewrite, state) = (flow.rewrite, flow.state);
+
+    let reverse_key = rewrite.connection(conn.protocol).reversed().to_key();
+    match FLOWS.get_ptr_mut(&reverse_key) {
+        Some(reverse) => {
+            let reverse = unsafe { &mut *reverse };
+            if reverse.rewrite.dst_ip != conn.src_ip || reverse.rewrite.dst_port != conn.src_port {
+                return Ok(None);
             }
+            reverse.last_seen = now;
+            reverse.state = state;
         }
-        IpProto::Udp => {
-            let udphdr: *const UdpHdr = unsafe { ctx.get_ptr(PACKET_START + Ipv4Hdr::LEN) }?;
-            // unsafe { bpf_printk!(b"foo #3\n") };
-            Connection{
-                src_ip: unsafe { *ipv4hdr }.src_addr,
-                src_port: u16::from_be(unsafe { *udphdr }.source),
-                dst_ip: unsafe { *ipv4hdr }.dst_addr,
-                dst_port: u16::from_be(unsafe { *udphdr }.dest),
-                protocol: IpProto::Udp,
-            }
+        // evicted, or the flow predates reply entries (version 1, or 2 before replies worked)
+        None => {
+            let output = if rewrite.output == FOUTPUT.port() { ROUTPUT } else { FOUTPUT };
+            let mut reverse = Flow::new(Rewrite::to(&conn.reversed(), output), now);
+            reverse.state = state;
+            FLOWS.insert(&reverse_key, &reverse, 0).map_err(|_| Error::Map)?;
         }
+    }
+    Ok(Some(rewrite))
+}
+
+/// Opens a flow for `conn` from the internal network, on a port of the external address whose
+/// replies can't be mistaken for those of another live flow.
+#[inline(always)]
+fn open_flow(conn: &Connection, state: u32, now: u64) -> Result<Rewrite, Error> {
+    let dev_ex = CONFIG.get()?.dev_ex.or(DEV_EX);
+    for _ in 0..PORT_ATTEMPTS {
+        let translated = Connection {
+            src_ip: dev_ex.ip,
+            src_port: next_port()?,
+            ..*conn
+        };
+        let reverse_key = translated.reversed().to_key();
+        if is_live(&reverse_key, conn.protocol, now) {
+            continue;
+        }
+
+        // install outgoing rewrite rule (into the wild)
+        let mut flow = Flow::new(Rewrite::to(&translated, FOUTPUT), now);
+        flow.state = state;
+        FLOWS.insert(&conn.to_key(), &flow, 0).map_err(|_| Error::Map)?;
+        // install incoming rewrite rule (replies from the wild)
+        let mut reverse = Flow::new(Rewrite::to(&conn.reversed(), ROUTPUT), now);
+        reverse.state = state;
+        FLOWS.insert(&reverse_key, &reverse, 0).map_err(|_| Error::Map)?;
+        log::debug!(
+            "new flow from {:ipv4}:{} on port {}",
+            Ipv4Addr::from(u32::from_be(conn.src_ip)),
+            conn.src_port,
+            translated.src_port
+        );
+        return Ok(flow.rewrite);
+    }
+    // all ports tried are busy with flows to the same destination
+    log::warn!(
+        "no free port for a flow to {:ipv4}:{}",
+        Ipv4Addr::from(u32::from_be(conn.dst_ip)),
+        conn.dst_port
+    );
+    Err(Error::Map)
+}
+
+/// What happens to packets no flow matches: the ones from the wild are passed on (like
+/// `IPRewriter`'s `pass 1`), others are dropped.
+#[inline(always)]
+fn unmatched(port: u32) -> Result<Port, Error> {
+    match port {
+        1 => Ok(ROUTPUT),
+        _ => Err(Error::Unsupported),
+    }
+}
+
+/// Connection of the packet an ICMP error quotes.
+#[inline(always)]
+fn quoted_connection(packet: &Packet, quoted: &Quoted) -> Result<Connection, Error> {
+    let (src_port, dst_port) = match quoted.layout().protocol() {
+        Some(IPPROTO_ICMP) => echo_ports(packet.load(quoted.l4_offset)?).ok_or(Error::Unsupported)?,
+        Some(IPPROTO_TCP | IPPROTO_UDP) => {
+            let ports: &[u16; 2] = packet.load(quoted.l4_offset)?;
+            (u16::from_be(ports[0]), u16::from_be(ports[1]))
+        }
+        _ => return Err(Error::Unsupported),
+    };
+    Ok(Connection {
+        src_ip: quoted.ip.src_addr,
+        src_port,
+        dst_ip: quoted.ip.dst_addr,
+        dst_port,
+        protocol: quoted.protocol(),
+    })
+}
+
+/// Translates an ICMP error from `src` to `dst` about a packet of a flow, `quoted`, for the host
+/// that sent the packet.
+#[inline(always)]
+fn translate_error(
+    packet: &mut Packet,
+    (src, dst): (u32, u32),
+    layout: &Layout,
+    quoted: &Connection,
+    quoted_layout: &Layout,
+    now: u64,
+) -> Result<Port, Error> {
+    // the quoted packet went the other way than the packets of the flow that translated it
+    let Some(flow) = lookup_flow(&quoted.reversed(), now)? else {
+        return unmatched(packet.port());
+    };
+    let rewrite = unsafe { (*flow).rewrite };
+    let original = rewrite.connection(quoted.protocol).reversed();
+
+    if dst == quoted.src_ip {
+        rewrite::set_ipv4_dst(packet, layout, Ipv4Addr::from(u32::from_be(original.src_ip)))?;
+    }
+    if src == quoted.dst_ip {
+        rewrite::set_ipv4_src(packet, layout, Ipv4Addr::from(u32::from_be(original.dst_ip)))?;
+    }
+    apply_rewrite(packet, &original, quoted_layout)?;
+    // the ICMP checksum covers the quoted packet
+    checksum::set_l4_checksum(packet, layout)?;
+
+    Port::new(rewrite.output).ok_or(Error::Malformed)
+}
+
+#[bpf_classifier(outputs = 2, schema = Maps)]
+fn try_classify(packet: &mut Packet) -> Result<Port, Error> {
+    let port = packet.port();
+    let headers = packet.parse(LinkLayer::None)?;
+    let L3::Ipv4(ipv4hdr) = headers.l3 else {
+        log::debug!("dropping a non-IPv4 packet on input {}", port);
+        return Err(Error::Unsupported);
+    };
+    let layout = headers.layout();
+    let now = unsafe { HELPERS.ktime_get_ns() };
+
+    let (src_port, dst_port) = match headers.l4 {
+        L4::Tcp(_) | L4::Udp(_) => headers.ports().ok_or(Error::Malformed)?,
+        L4::Icmp(hdr) => match echo_ports(hdr) {
+            Some(ports) => ports,
+            None => {
+                let Some(quoted) = packet.parse_icmp_error(&headers)? else {
+                    return unmatched(port);
+                };
+                let quoted_layout = quoted.layout();
+                let quoted = quoted_connection(packet, &quoted)?;
+                let addrs = (ipv4hdr.src_addr, ipv4hdr.dst_addr);
+                return translate_error(packet, addrs, &layout, &quoted, &quoted_layout, now);
+            }
+        },
         _ => {
-            // unsafe { bpf_printk!(b"err! #1\n") };
-            return Err(())
+            log::debug!("no ports in protocol {} on input {}", ipv4hdr.proto as u8, port);
+            return unmatched(port);
         },
     };
+    let conn = Connection {
+        src_ip: ipv4hdr.src_addr,
+        src_port,
+        dst_ip: ipv4hdr.dst_addr,
+        dst_port,
+        protocol: ipv4hdr.proto as u8,
+    };
+    let state = match headers.l4 {
+        L4::Tcp(tcp) if tcp.rst() != 0 => FLOW_RESET,
+        L4::Tcp(tcp) if tcp.fin() != 0 => FLOW_CLOSING,
+        _ => FLOW_OPEN,
+    };
 
-    // handles packets from internal network for external network
-    let output = match CONNECTIONS.get_ptr(&connection_to_u128(&conn)) {
-        Some(rewrite) => {
-            // unsafe { bpf_printk!(b"rewrite port %d\n", (*rewrite).src_port) };
-            apply_rewrite(ctx, &conn, rewrite)?;
+    let rewrite = match lookup_flow(&conn, now)? {
+        Some(flow) => refresh(&conn, flow, state, now)?,
+        None => None,
+    };
+    let rewrite = match rewrite {
+        Some(rewrite) => rewrite,
+        // new connections from the internal network
+        None if port == 0 => open_flow(&conn, state, now)?,
+        None => return unmatched(port),
+    };
+    apply_rewrite(packet, &rewrite.connection(conn.protocol), &layout)?;
 
-            unsafe {(*rewrite).output % OUTPUTS}
-        },
+    Port::new(rewrite.output).ok_or(Error::Malformed)
+}
 
-        None if port == 1 => { FOUTPUT },
-
-        None if port == 0 => {
-            let local_nat_port = next_port()? as u32;
-
-            // install outgoing rewrite rule (into the wild)
-            let key_to = &conn;
-            let value_to = Rewrite {
-                src_ip: DEV_EX.ip,
-                src_port: local_nat_port as u16,
-                dst_ip: conn.dst_ip,
-                dst_port: conn.dst_port,
-                output: FOUTPUT,
-            };
-            // unsafe { bpf_printk!(b"local_nat_port %d\n", local_nat_port) };
-            CONNECTIONS.insert(&connection_to_u128(key_to), &value_to, 0).ok().ok_or(())?;
-
-            // install incoming rewrite rule (replies from the wild)
-            let key_from = Connection {
-                src_ip: conn.dst_ip,
-                src_port: conn.dst_port,
-                dst_ip: DEV_EX.ip,
-                dst_port: local_nat_port as u16,
-                protocol: conn.protocol,
-            };
-            let value_from = Rewrite {
-                src_ip: conn.dst_ip,
-                src_port: conn.dst_port,
-                dst_ip: conn.src_ip,
-                dst_port: conn.dst_port,
-                output: FOUTPUT,
-            };
-            CONNECTIONS.insert(&connection_to_u128(key_to), &value_to, 0).ok().ok_or(())?;
-            apply_rewrite(ctx, &conn, &value_to)?;
-
-            port
-        },
-        None => { // catch remaining None cases
-            // unsafe { bpf_printk!(b"err! #3\n") };
-            return Err(())
+#[cfg(test)]
+mod tests {
+    use bpf_element::migration::SCHEMA_VERSION;
+    use bpf_element::parse::{ICMP_DEST_UNREACH, ICMP_TIME_EXCEEDED};
+    use bpf_element::testing::{advance_time_ns, migrate, set_time_ns, PacketBuilder};
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(172, 44, 0, 10);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(172, 44, 0, 3);
+
+    #[test]
+    fn rewrites_outgoing_connections() {
+        let outgoing = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1234, 53).payload(b"query").port(0);
+        // the builder computes checksums from scratch, the program updates them incrementally
+        let translated = PacketBuilder::new().ipv4(EXTERNAL, SERVER).udp(PORT_START, 53).payload(b"query");
+
+        for _ in 0..2 {
+            let mut packet = outgoing.build();
+            assert_eq!(packet.run(main), FOUTPUT.port());
+            assert_eq!(packet.data(), translated.bytes());
         }
-    };
-    // unsafe { bpf_printk!(b"port %d #2\n", output) };
+    }
+
+    #[test]
+    fn allocates_a_port_per_connection() {
+        let first = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80);
+        let second = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1235, 80);
+
+        let mut packet = first.port(0).build();
+        packet.run(main);
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp(PORT_START, 80).bytes());
+
+        let mut packet = second.port(0).build();
+        packet.run(main);
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp(PORT_START + 1, 80).bytes());
+    }
+
+    #[test]
+    fn reads_external_address_from_config() {
+        let external = Ipv4Addr::new(198, 51, 100, 7);
+        CONFIG.set(Interfaces {
+            dev_ex: InterfaceInfo { ip: u32::from(external).to_be(), subnet: 0 },
+        });
+
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1234, 53).port(0).build();
+        assert_eq!(packet.run(main), FOUTPUT.port());
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(external, SERVER).udp(PORT_START, 53).bytes());
+    }
+
+    #[test]
+    fn keeps_connections_across_migration() {
+        let first = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80).port(0);
+        let second = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1235, 80).port(0);
+        first.build().run(main);
+
+        assert_eq!(migrate(main), FOUTPUT.port());
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&2));
+
+        let mut packet = first.build();
+        packet.run(main);
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp(PORT_START, 80).bytes());
+        let mut packet = second.build();
+        packet.run(main);
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp(PORT_START + 1, 80).bytes());
+    }
+
+    /// Sets up the maps as `version` left them, with one connection.
+    fn connection_of(version: u32, conn: &Connection) {
+        let rewrite = Rewrite {
+            src_ip: u32::from(EXTERNAL).to_be(),
+            src_port: PORT_START,
+            dst_ip: conn.dst_ip,
+            dst_port: conn.dst_port,
+            output: FOUTPUT.port(),
+        };
+        // as the first nat packed them
+        let key = (conn.src_ip as u128) << 96
+            | (conn.src_port as u128) << 80
+            | (conn.dst_ip as u128) << 64
+            | (conn.dst_port as u128) << 48
+            | (conn.protocol as u128) << 32;
+        CONNECTIONS.insert(&key, &rewrite, 0).unwrap();
+        unsafe { *NEXT_PORT.get_ptr_mut(0).unwrap() = PORT_START as u32 + 1 };
+        unsafe { *SCHEMA_VERSION.get_ptr_mut(0).unwrap() = version };
+    }
+
+    fn tcp_connection(src_port: u16, dst_port: u16) -> Connection {
+        Connection {
+            src_ip: u32::from(CLIENT).to_be(),
+            src_port,
+            dst_ip: u32::from(SERVER).to_be(),
+            dst_port,
+            protocol: IPPROTO_TCP,
+        }
+    }
+
+    #[test]
+    fn takes_over_connections_of_v1() {
+        connection_of(1, &tcp_connection(1234, 80));
+        set_time_ns(1000);
+        assert_eq!(migrate(main), FOUTPUT.port());
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&2));
+
+        advance_time_ns(600 * NS_PER_SEC);
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, ACK).port(0).build();
+        assert_eq!(packet.run(main), FOUTPUT.port());
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp_flags(PORT_START, 80, ACK).bytes());
+        // version 1 had no entries for replies
+        let mut packet = PacketBuilder::new().ipv4(SERVER, EXTERNAL).tcp_flags(80, PORT_START, ACK).port(1).build();
+        assert_eq!(packet.run(main), ROUTPUT.port());
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(SERVER, CLIENT).tcp_flags(80, 1234, ACK).bytes());
+        assert_eq!(FLOWS.len(), 2);
+    }
+
+    #[test]
+    fn forgets_connections_of_v1_after_they_could_have_expired() {
+        connection_of(1, &tcp_connection(1234, 80));
+        assert_eq!(migrate(main), FOUTPUT.port());
+
+        set_time_ns(TCP_ESTABLISHED_TIMEOUT_NS);
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, ACK).port(0).build();
+        packet.run(main);
+        let translated = PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp_flags(PORT_START + 1, 80, ACK);
+        assert_eq!(packet.data(), translated.bytes());
+    }
+
+    #[test]
+    fn takes_over_connections_without_schema() {
+        connection_of(0, &tcp_connection(1234, 80));
+        assert_eq!(migrate(main), FOUTPUT.port());
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&2));
+
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, ACK).port(0).build();
+        assert_eq!(packet.run(main), FOUTPUT.port());
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp_flags(PORT_START, 80, ACK).bytes());
+    }
+
+    const ACK: u8 = 0x10;
+    const FIN_ACK: u8 = 0x11;
+    const RST: u8 = 0x04;
+
+    /// The NAT port of the connection of `packet`, allocating one if it has none.
+    fn nat_port(packet: &PacketBuilder) -> u16 {
+        let mut packet = packet.build();
+        assert_eq!(packet.run(main), FOUTPUT.port());
+        // source port, after an IPv4 header without options
+        u16::from_be_bytes([packet.data()[20], packet.data()[21]])
+    }
+
+    #[test]
+    fn expires_idle_udp_flows() {
+        let query = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1234, 53).port(0);
+        assert_eq!(nat_port(&query), PORT_START);
+
+        advance_time_ns(UDP_TIMEOUT_NS);
+        assert_eq!(nat_port(&query), PORT_START);
+        advance_time_ns(UDP_TIMEOUT_NS + 1);
+        assert_eq!(nat_port(&query), PORT_START + 1);
+    }
 
-    Ok(output)
+    #[test]
+    fn keeps_idle_tcp_connections_longer() {
+        let syn = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80).port(0);
+        let ack = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, ACK).port(0);
+        assert_eq!(nat_port(&syn), PORT_START);
 
+        advance_time_ns(TCP_ESTABLISHED_TIMEOUT_NS);
+        assert_eq!(nat_port(&ack), PORT_START);
+        advance_time_ns(TCP_ESTABLISHED_TIMEOUT_NS + 1);
+        assert_eq!(nat_port(&syn), PORT_START + 1);
+    }
+
+    #[test]
+    fn expires_closed_tcp_connections_sooner() {
+        let syn = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80).port(0);
+        let ack = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, ACK).port(0);
+        let fin = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, FIN_ACK).port(0);
+        assert_eq!(nat_port(&syn), PORT_START);
+        assert_eq!(nat_port(&fin), PORT_START);
+
+        // the last ACK doesn't reopen the connection
+        advance_time_ns(TCP_CLOSING_TIMEOUT_NS);
+        assert_eq!(nat_port(&ack), PORT_START);
+        advance_time_ns(TCP_CLOSING_TIMEOUT_NS + 1);
+        assert_eq!(nat_port(&syn), PORT_START + 1);
+    }
 
-    // let z = unsafe { bpf_ktime_get_ns() };
-    // unsafe { bpf_printk!(b"foobar") };
-    // let printk: unsafe extern "C" fn(fmt: *const c_char, fmt_size: u32, ...) -> c_long =
-    //     unsafe { mem::transmute(6usize) };
-    // let fmt = b"hello world";
-    // let fmt_ptr = fmt.as_ptr() as *const c_char;
-    // let fmt_size = fmt.len() as u32;
-    // unsafe { printk(fmt_ptr, fmt_size) };
+    #[test]
+    fn expires_reset_tcp_connections_soonest() {
+        let syn = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80).port(0);
+        let rst = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp_flags(1234, 80, RST).port(0);
+        assert_eq!(nat_port(&syn), PORT_START);
+        assert_eq!(nat_port(&rst), PORT_START);
+
+        advance_time_ns(TCP_RESET_TIMEOUT_NS + 1);
+        assert_eq!(nat_port(&syn), PORT_START + 1);
+    }
 
-    // CONNECTIONS.insert(&conn, &1, 0).ok().ok_or(())?;
+    #[test]
+    fn evicts_least_recently_used_flows_when_full() {
+        let first = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1000, 53).port(0);
+        assert_eq!(nat_port(&first), PORT_START);
+        // fill the rest, as the old table refused all new flows after 1028
+        for n in 1..FLOWS.max_entries() - 1 {
+            let mut conn = tcp_connection(0, 80);
+            conn.src_ip = n.to_be();
+            let flow = Flow::new(Rewrite::to(&conn, FOUTPUT), 0);
+            FLOWS.insert(&conn.to_key(), &flow, 0).unwrap();
+        }
+        // keeps the first flow in use
+        assert_eq!(nat_port(&first), PORT_START);
+
+        let new = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1001, 53).port(0);
+        assert_eq!(nat_port(&new), PORT_START + 1);
+        assert_eq!(FLOWS.len(), FLOWS.max_entries() as usize);
+        assert_eq!(nat_port(&first), PORT_START);
+        let mut oldest = tcp_connection(0, 80);
+        oldest.src_ip = 1u32.to_be();
+        assert!(FLOWS.get_ptr(&oldest.to_key()).is_none());
+    }
 
-    // let a: *const u8 = unsafe { ctx.get_ptr_mut(0)? };
-    // let a: u8 = unsafe { *a };
-    // // *a = 0xff;
+    #[test]
+    fn rewrites_replies() {
+        let query = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1234, 53).payload(b"query").port(0);
+        query.build().run(main);
 
-    // let counter = PKTCOUNTER.get_ptr_mut(0).ok_or(())?;
+        let answer = PacketBuilder::new().ipv4(SERVER, EXTERNAL).udp(53, PORT_START).payload(b"answer");
+        let mut packet = answer.port(1).build();
+        assert_eq!(packet.run(main), ROUTPUT.port());
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(SERVER, CLIENT).udp(53, 1234).payload(b"answer").bytes());
+    }
 
-    // let output = unsafe { *counter } % OUTPUTS;
+    #[test]
+    fn passes_unknown_packets_from_the_wild() {
+        // for the host itself, or for an expired flow
+        for packet in [
+            PacketBuilder::new().ipv4(SERVER, EXTERNAL).tcp(4321, 22),
+            PacketBuilder::new().ipv4(SERVER, EXTERNAL).udp(53, PORT_START),
+            PacketBuilder::new().ipv4(SERVER, EXTERNAL).icmp_echo_request(1, 1),
+            PacketBuilder::new().ipv4(SERVER, EXTERNAL).icmp(ICMP_DEST_UNREACH, 3).payload(
+                &PacketBuilder::new().ipv4(EXTERNAL, SERVER).udp(PORT_START, 53).bytes(),
+            ),
+            PacketBuilder::new().ipv4(SERVER, EXTERNAL),
+        ] {
+            let mut test = packet.clone().port(1).build();
+            assert_eq!(test.run(main), ROUTPUT.port());
+            assert_eq!(test.data(), packet.bytes());
+        }
+        // but don't open flows
+        assert!(FLOWS.is_empty());
 
-    // unsafe {
-    //     *counter += 1;
-    // }
-    // // Ok(output)
-    // if conn.protocol == (IpProto::Tcp as u8) {
-    //     Ok(1)
-    // } else {
-    //     Ok(0)
-    // }
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).port(0).build();
+        assert_eq!(packet.run(main), bpf_element::classifier::ABORT);
+    }
+
+    #[test]
+    fn closes_both_directions() {
+        let syn = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80).port(0);
+        let fin = PacketBuilder::new().ipv4(SERVER, EXTERNAL).tcp_flags(80, PORT_START, FIN_ACK).port(1);
+        let ack = PacketBuilder::new().ipv4(SERVER, EXTERNAL).tcp_flags(80, PORT_START, ACK).port(1);
+        assert_eq!(nat_port(&syn), PORT_START);
+        assert_eq!(fin.build().run(main), ROUTPUT.port());
+
+        advance_time_ns(TCP_CLOSING_TIMEOUT_NS + 1);
+        let mut packet = ack.build();
+        assert_eq!(packet.run(main), ROUTPUT.port());
+        assert_eq!(packet.data(), ack.bytes());
+        assert_eq!(nat_port(&syn), PORT_START + 1);
+    }
+
+    #[test]
+    fn skips_ports_of_live_flows() {
+        let first = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80).port(0);
+        assert_eq!(nat_port(&first), PORT_START);
+        let last = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1235, 80).port(0);
+        unsafe { *NEXT_PORT.get_ptr_mut(0).unwrap() = PORT_END as u32 };
+        assert_eq!(nat_port(&last), PORT_END);
+
+        // the next port is taken towards SERVER:80
+        let second = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1236, 80).port(0);
+        assert_eq!(nat_port(&second), PORT_START + 1);
+        // but not towards other destinations
+        unsafe { *NEXT_PORT.get_ptr_mut(0).unwrap() = PORT_START as u32 };
+        let other = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1237, 443).port(0);
+        assert_eq!(nat_port(&other), PORT_START);
+
+        // replies still find their own flow
+        let reply = PacketBuilder::new().ipv4(SERVER, EXTERNAL).tcp_flags(80, PORT_START, ACK).port(1);
+        let mut packet = reply.build();
+        packet.run(main);
+        assert_eq!(packet.data(), PacketBuilder::new().ipv4(SERVER, CLIENT).tcp_flags(80, 1234, ACK).bytes());
+    }
+
+    #[test]
+    fn gives_up_on_busy_destinations() {
+        for port in 0..PORT_ATTEMPTS as u16 {
+            let packet = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1000 + port, 80).port(0);
+            assert_eq!(nat_port(&packet), PORT_START + port);
+        }
+        unsafe { *NEXT_PORT.get_ptr_mut(0).unwrap() = PORT_START as u32 };
+        bpf_element::testing::take_log();
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(2000, 80).port(0).build();
+        assert_eq!(packet.run(main), bpf_element::classifier::ABORT);
+        assert_eq!(
+            bpf_element::testing::take_log().last().unwrap(),
+            &format!("WARN no free port for a flow to {SERVER}:80")
+        );
+    }
+
+    #[test]
+    fn translates_pings() {
+        let request = PacketBuilder::new().ipv4(CLIENT, SERVER).icmp_echo_request(7, 1).payload(b"ping");
+        let mut packet = request.clone().port(0).build();
+        assert_eq!(packet.run(main), FOUTPUT.port());
+        let translated = PacketBuilder::new().ipv4(EXTERNAL, SERVER).icmp_echo_request(PORT_START, 1).payload(b"ping");
+        assert_eq!(packet.data(), translated.bytes());
+
+        let reply = PacketBuilder::new().ipv4(SERVER, EXTERNAL).icmp_echo_reply(PORT_START, 1).payload(b"ping");
+        let mut packet = reply.port(1).build();
+        assert_eq!(packet.run(main), ROUTPUT.port());
+        let translated = PacketBuilder::new().ipv4(SERVER, CLIENT).icmp_echo_reply(7, 1).payload(b"ping");
+        assert_eq!(packet.data(), translated.bytes());
+
+        advance_time_ns(ICMP_TIMEOUT_NS + 1);
+        let mut packet = request.port(0).build();
+        packet.run(main);
+        let translated = PacketBuilder::new().ipv4(EXTERNAL, SERVER).icmp_echo_request(PORT_START + 1, 1).payload(b"ping");
+        assert_eq!(packet.data(), translated.bytes());
+    }
+
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+    #[test]
+    fn translates_errors_from_the_wild() {
+        let originals = [
+            PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1234, 53).payload(b"query"),
+            PacketBuilder::new().ipv4(CLIENT, SERVER).tcp(1234, 80),
+            PacketBuilder::new().ipv4(CLIENT, SERVER).icmp_echo_request(7, 1),
+        ];
+        let translated = [
+            PacketBuilder::new().ipv4(EXTERNAL, SERVER).udp(PORT_START, 53).payload(b"query"),
+            PacketBuilder::new().ipv4(EXTERNAL, SERVER).tcp(PORT_START + 1, 80),
+            PacketBuilder::new().ipv4(EXTERNAL, SERVER).icmp_echo_request(PORT_START + 2, 1),
+        ];
+        for (original, translated) in originals.iter().zip(&translated) {
+            let mut packet = original.clone().port(0).build();
+            packet.run(main);
+            assert_eq!(packet.data(), translated.bytes());
+
+            // from a router on the way, and from the server itself
+            for (from, to) in [(ROUTER, CLIENT), (SERVER, CLIENT)] {
+                let error = PacketBuilder::new().ipv4(from, EXTERNAL).icmp(ICMP_TIME_EXCEEDED, 0);
+                let mut packet = error.payload(&translated.bytes()).port(1).build();
+                assert_eq!(packet.run(main), ROUTPUT.port());
+                let error = PacketBuilder::new().ipv4(from, to).icmp(ICMP_TIME_EXCEEDED, 0);
+                assert_eq!(packet.data(), error.payload(&original.bytes()).bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn translates_errors_from_the_internal_network() {
+        let query = PacketBuilder::new().ipv4(CLIENT, SERVER).udp(1234, 53).port(0);
+        query.build().run(main);
+
+        // the client is gone by the time the answer arrives
+        let answer = PacketBuilder::new().ipv4(SERVER, CLIENT).udp(53, 1234).payload(b"answer");
+        let error = PacketBuilder::new().ipv4(CLIENT, SERVER).icmp(ICMP_DEST_UNREACH, 3);
+        let mut packet = error.payload(&answer.bytes()).port(0).build();
+        assert_eq!(packet.run(main), FOUTPUT.port());
+
+        let answer = PacketBuilder::new().ipv4(SERVER, EXTERNAL).udp(53, PORT_START).payload(b"answer");
+        let error = PacketBuilder::new().ipv4(EXTERNAL, SERVER).icmp(ICMP_DEST_UNREACH, 3);
+        assert_eq!(packet.data(), error.payload(&answer.bytes()).bytes());
+    }
+
+    #[test]
+    fn aborts_on_ipv6() {
+        let mut packet = PacketBuilder::new()
+            .ipv6("2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap())
+            .udp(1234, 53)
+            .build();
+        assert_eq!(packet.run(main), bpf_element::classifier::ABORT);
+    }
 }
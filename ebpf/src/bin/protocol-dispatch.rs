@@ -0,0 +1,135 @@
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+
+use aya_ebpf::macros::map;
+use bpf_element::filter::FilterResult;
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::{Config, ProgArray};
+use bpf_element::parse::LinkLayer;
+use bpf_element::{Error, Packet};
+
+/// Filters by IP protocol number (IPv6: the protocol after the extension headers), loaded with
+/// `TAIL_CALL HANDLERS <protocol> <file>` and swapped with `helper reconfigure`.
+#[map(name = "HANDLERS")]
+static HANDLERS: ProgArray = ProgArray::with_max_entries(256, 0);
+
+#[repr(C)]
+struct Settings {
+    /// Action for IP packets without a handler, see [`FilterResult::from_action`]. 0 passes
+    /// them.
+    default_action: u32,
+}
+
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
+
+/// Hands IP packets to the handler for their protocol, and passes anything else.
+#[bpf_filter(on_error = Drop)]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+    let headers = packet.parse(LinkLayer::Ethernet)?;
+    let Some(proto) = headers.protocol() else {
+        return Ok(FilterResult::Pass);
+    };
+
+    // only returns if there is no handler, or the packet went through too many
+    let _ = HANDLERS.tail_call(packet, proto as u32);
+
+    match CONFIG.get()?.default_action {
+        0 => Ok(FilterResult::Pass),
+        action => FilterResult::from_action(action).ok_or(Error::Map),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use bpf_element::parse::{IPPROTO_TCP, IPPROTO_UDP};
+    use bpf_element::telemetry::telemetry;
+    use bpf_element::testing::{load_program, unload_program, PacketBuilder};
+    use bpf_element::BpfContext;
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 1, 2, 3);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    extern "C" fn drop_all(_ctx: *mut BpfContext) -> FilterResult {
+        FilterResult::Drop
+    }
+
+    extern "C" fn pass_all(_ctx: *mut BpfContext) -> FilterResult {
+        FilterResult::Pass
+    }
+
+    fn udp() -> FilterResult {
+        PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(CLIENT, SERVER)
+            .udp(40000, 53)
+            .build()
+            .run(main)
+    }
+
+    fn tcp() -> FilterResult {
+        PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(CLIENT, SERVER)
+            .tcp(40000, 443)
+            .build()
+            .run(main)
+    }
+
+    #[test]
+    fn dispatches_by_protocol() {
+        load_program(&HANDLERS, IPPROTO_UDP as u32, drop_all);
+        assert_eq!(udp(), FilterResult::Drop);
+        assert_eq!(tcp(), FilterResult::Pass);
+
+        let mut ipv6 = PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST)
+            .udp(40000, 53)
+            .build();
+        assert_eq!(ipv6.run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn swaps_handlers() {
+        load_program(&HANDLERS, IPPROTO_TCP as u32, drop_all);
+        assert_eq!(tcp(), FilterResult::Drop);
+        load_program(&HANDLERS, IPPROTO_TCP as u32, pass_all);
+        assert_eq!(tcp(), FilterResult::Pass);
+        unload_program(&HANDLERS, IPPROTO_TCP as u32);
+        CONFIG.set(Settings {
+            default_action: FilterResult::Drop as u32,
+        });
+        assert_eq!(tcp(), FilterResult::Drop);
+    }
+
+    #[test]
+    fn passes_non_ip_packets() {
+        load_program(&HANDLERS, 0, drop_all);
+        CONFIG.set(Settings {
+            default_action: FilterResult::Drop as u32,
+        });
+        let mut arp = PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .payload(&[0; 28])
+            .build();
+        assert_eq!(arp.run(main), FilterResult::Pass);
+    }
+
+    #[test]
+    fn stops_after_too_many_tail_calls() {
+        // the dispatcher as its own UDP handler jumps until the element refuses to
+        load_program(&HANDLERS, IPPROTO_UDP as u32, main);
+        CONFIG.set(Settings {
+            default_action: FilterResult::Drop as u32,
+        });
+        assert_eq!(udp(), FilterResult::Drop);
+        // and only the program that got the last word counts the packet
+        assert_eq!(telemetry().packets, 1);
+        assert_eq!(telemetry().drops, 1);
+    }
+}
@@ -1,91 +1,387 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use core::net::Ipv4Addr;
+use core::net::{IpAddr, Ipv6Addr};
 
-use aya_ebpf::helpers::bpf_ktime_get_ns;
 use aya_ebpf::macros::map;
-use aya_ebpf::maps::HashMap;
 use bpf_element::filter::FilterResult;
-use bpf_element::BpfContext;
-use network_types::ip::Ipv4Hdr;
+use bpf_element::helpers::{BPFilter, Helpers};
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::{Config, LruHashMap, MapKey};
+use bpf_element::migration::Schema;
+use bpf_element::parse::{Headers, LinkLayer};
+use bpf_element::{Error, Packet};
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> FilterResult {
-    let ctx = unsafe { *ctx };
+const HELPERS: Helpers<BPFilter> = Helpers::new();
 
-   try_filter(&ctx).unwrap_or_else(|_| FilterResult::Abort)
-}
+const NS_PER_SEC: u64 = 1_000_000_000;
 
-struct RateLimit {
-    tokens: u32,
-    last_token_grant: u32,
-}
+/// Packets per second, and the burst a new key may send before it's limited, until `CONFIG` sets
+/// them.
+const DEFAULT_RATE: u32 = 1;
+const DEFAULT_BURST: u32 = 3;
 
-impl Default for RateLimit {
-    #[inline(always)]
-    fn default() -> Self {
-        Self {
-            tokens: 1,
-            last_token_grant: (unsafe { bpf_ktime_get_ns() } / 1_000_000_000) as u32,
-        }
-    }
+/// What packets share a bucket, see [`Settings::key`].
+const KEY_SOURCE: u32 = 0;
+const KEY_DESTINATION: u32 = 1;
+const KEY_FLOW: u32 = 2;
+
+/// Prefix lengths of source and destination keys until `CONFIG` sets them.
+const DEFAULT_IPV4_PREFIX: u32 = 32;
+const DEFAULT_IPV6_PREFIX: u32 = 64;
+
+#[repr(C)]
+struct Settings {
+    /// Packets per second, 0 for [`DEFAULT_RATE`].
+    rate: u32,
+    /// Packets sent at once after idling, 0 for [`DEFAULT_BURST`].
+    burst: u32,
+    /// [`KEY_SOURCE`] (0), [`KEY_DESTINATION`] or [`KEY_FLOW`] (5-tuple).
+    key: u32,
+    /// Length of the source or destination prefixes that share a bucket, 0 for
+    /// [`DEFAULT_IPV4_PREFIX`] and [`DEFAULT_IPV6_PREFIX`].
+    ipv4_prefix: u32,
+    ipv6_prefix: u32,
 }
 
-impl RateLimit {
-    #[inline(always)]
-    pub fn grant_tokens_if_needed(&mut self) {
-        let now = (unsafe { bpf_ktime_get_ns() } / 1_000_000_000) as u32;
-        let elapsed = now - self.last_token_grant;
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
 
-        // grant 1 token per second
-        let new_tokens = elapsed;
+/// Packets sharing a bucket. IPv4 addresses are stored IPv4-mapped, fields the key mode doesn't use
+/// are zero.
+#[derive(Copy, Clone, MapKey)]
+struct Key {
+    /// 4 or 6.
+    family: u8,
+    protocol: u8,
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    src_port: u16,
+    dst_port: u16,
+}
 
-        if new_tokens > 0 {
-            self.tokens += new_tokens;
-            self.tokens = self.tokens.min(3);
-            self.last_token_grant = now;
-        }
-    }
+type BucketKey = <Key as MapKey>::Key;
+
+/// A token bucket, in billionths of a token so it refills by the nanosecond.
+#[repr(C)]
+struct Bucket {
+    tokens: u64,
+    /// `bpf_ktime_get_ns` of the last refill.
+    updated: u64,
+    /// Packets dropped for lack of tokens.
+    dropped: u64,
+}
 
+impl Bucket {
+    /// Refills the bucket for the time since the last packet, then takes a token if there is one.
     #[inline(always)]
-    pub fn spend_token(&mut self) -> bool {
-        if self.tokens >= 1 {
-            self.tokens -= 1;
+    fn take(&mut self, rate: u32, burst: u32, now: u64) -> bool {
+        let capacity = burst as u64 * NS_PER_SEC;
+        let elapsed = now.saturating_sub(self.updated);
+        // refilling from empty takes capacity / rate, so shorter times can't overflow
+        self.tokens = if elapsed >= capacity / rate as u64 {
+            capacity
+        } else {
+            (self.tokens + elapsed * rate as u64).min(capacity)
+        };
+        self.updated = now;
+
+        if self.tokens >= NS_PER_SEC {
+            self.tokens -= NS_PER_SEC;
             true
         } else {
+            self.dropped += 1;
             false
         }
     }
 }
 
-#[map(name = "PKTCOUNTHASHMAP")]
-static PKTCOUNTHASHMAP: HashMap<Ipv4Addr, RateLimit> = HashMap::with_max_entries(1024, 0);
+/// Buckets by key. Keys idle the longest make room for new ones, which start with a full bucket.
+#[map(name = "BUCKETS")]
+static BUCKETS: LruHashMap<BucketKey, Bucket> = LruHashMap::with_max_entries(65536, 0);
 
-#[inline(always)]
-fn try_filter(ctx: &BpfContext) -> Result<FilterResult, ()> {
-    let ip_hdr: *const Ipv4Hdr = unsafe { ctx.get_ptr(0)? };
-    let src_addr = unsafe { (*ip_hdr).src_addr() };
+/// Versions of the bucket layout, see [`bpf_element::migration`].
+struct Maps;
 
-    let rate_limit = match PKTCOUNTHASHMAP.get_ptr_mut(&src_addr) {
-        None => {
-            let rate_limit = RateLimit::default();
-            PKTCOUNTHASHMAP
-                .insert(&src_addr, &rate_limit, 0)
-                .map_err(|_| ())?;
-            unsafe { &mut *PKTCOUNTHASHMAP.get_ptr_mut(&src_addr).ok_or(())? }
+impl Schema for Maps {
+    const VERSION: u32 = 2;
+
+    fn migrate(from: u32) -> Result<u32, Error> {
+        match from {
+            // Builds without a schema used the layout of version 1, whole-second buckets of IPv4
+            // sources and IPv6 /64s. They can't be iterated, so every key starts over with a full
+            // bucket, as after being evicted.
+            0 | 1 => Ok(2),
+            _ => Err(Error::Unsupported),
         }
-        Some(rate_limit) => {
-            let rate_limit = unsafe { &mut *rate_limit };
-            rate_limit.grant_tokens_if_needed();
-            rate_limit
+    }
+}
+
+#[bpf_filter(schema = Maps)]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+    let headers = packet.parse(LinkLayer::None)?;
+    let settings = CONFIG.get()?;
+    // only IPv4 and IPv6 packets are limited
+    let Some(key) = key(&headers, settings)? else {
+        return Ok(FilterResult::Pass);
+    };
+    let rate = match settings.rate {
+        0 => DEFAULT_RATE,
+        rate => rate,
+    };
+    let burst = match settings.burst {
+        0 => DEFAULT_BURST,
+        burst => burst,
+    };
+
+    let now = unsafe { HELPERS.ktime_get_ns() };
+    let key = key.to_key();
+    let taken = match BUCKETS.get_ptr_mut(&key) {
+        Some(bucket) => unsafe { (*bucket).take(rate, burst, now) },
+        None => {
+            let mut bucket = Bucket {
+                tokens: burst as u64 * NS_PER_SEC,
+                updated: now,
+                dropped: 0,
+            };
+            let taken = bucket.take(rate, burst, now);
+            BUCKETS.insert(&key, &bucket, 0).map_err(|_| Error::Map)?;
+            taken
         }
     };
 
-    if rate_limit.spend_token() {
+    if taken {
         Ok(FilterResult::Pass)
     } else {
         Ok(FilterResult::Drop)
     }
 }
+
+/// The bucket key of a packet, `None` for packets other than IPv4 and IPv6.
+#[inline(always)]
+fn key(headers: &Headers, settings: &Settings) -> Result<Option<Key>, Error> {
+    let Some((src, dst)) = headers.addrs() else {
+        return Ok(None);
+    };
+    let (family, ipv4) = match src {
+        IpAddr::V4(_) => (4, true),
+        IpAddr::V6(_) => (6, false),
+    };
+    let prefix = match (ipv4, settings.ipv4_prefix, settings.ipv6_prefix) {
+        (true, 0, _) => DEFAULT_IPV4_PREFIX,
+        (true, len, _) => len,
+        (false, _, 0) => DEFAULT_IPV6_PREFIX,
+        (false, _, len) => len,
+    };
+
+    let mut key = Key {
+        family,
+        protocol: 0,
+        src_ip: Ipv6Addr::UNSPECIFIED,
+        dst_ip: Ipv6Addr::UNSPECIFIED,
+        src_port: 0,
+        dst_port: 0,
+    };
+    match settings.key {
+        KEY_SOURCE => key.src_ip = masked(src, prefix),
+        KEY_DESTINATION => key.dst_ip = masked(dst, prefix),
+        KEY_FLOW => {
+            key.protocol = headers.protocol().unwrap_or(0);
+            key.src_ip = masked(src, 128);
+            key.dst_ip = masked(dst, 128);
+            (key.src_port, key.dst_port) = headers.ports().unwrap_or((0, 0));
+        }
+        _ => return Err(Error::Map),
+    }
+    Ok(Some(key))
+}
+
+/// The first `len` bits of the address, IPv4-mapped.
+#[inline(always)]
+fn masked(addr: IpAddr, len: u32) -> Ipv6Addr {
+    let (mut octets, len) = match addr {
+        IpAddr::V4(addr) => (addr.to_ipv6_mapped().octets(), 96 + len.min(32)),
+        IpAddr::V6(addr) => (addr.octets(), len.min(128)),
+    };
+    // bytewise, BPF has no 128-bit shifts
+    for (i, byte) in octets.iter_mut().enumerate() {
+        let bits = len.saturating_sub(8 * i as u32).min(8);
+        *byte &= !0xffu8.checked_shr(bits).unwrap_or(0);
+    }
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use bpf_element::migration::SCHEMA_VERSION;
+    use bpf_element::testing::{advance_time_ns, migrate, PacketBuilder};
+
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn configure(rate: u32, burst: u32, key: u32, ipv4_prefix: u32) {
+        CONFIG.set(Settings {
+            rate,
+            burst,
+            key,
+            ipv4_prefix,
+            ipv6_prefix: 0,
+        });
+    }
+
+    fn udp(src: Ipv4Addr, dst: Ipv4Addr, src_port: u16) -> FilterResult {
+        PacketBuilder::new()
+            .ipv4(src, dst)
+            .udp(src_port, 2000)
+            .build()
+            .run(main)
+    }
+
+    #[test]
+    fn drops_fourth_packet_within_a_second() {
+        for _ in 0..DEFAULT_BURST {
+            assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        }
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+
+        advance_time_ns(NS_PER_SEC);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+    }
+
+    #[test]
+    fn refills_by_the_nanosecond() {
+        configure(10, 1, KEY_SOURCE, 0);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+
+        advance_time_ns(NS_PER_SEC / 10 - 1);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+        advance_time_ns(1);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+    }
+
+    #[test]
+    fn caps_tokens_at_the_burst() {
+        configure(1000, 2, KEY_SOURCE, 0);
+        advance_time_ns(3600 * NS_PER_SEC);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        advance_time_ns(3600 * NS_PER_SEC);
+        for _ in 0..2 {
+            assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        }
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+    }
+
+    #[test]
+    fn limits_sources_separately() {
+        for _ in 0..DEFAULT_BURST {
+            udp(SRC, DST, 1000);
+        }
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+        assert_eq!(
+            udp(Ipv4Addr::new(10, 0, 0, 3), DST, 1000),
+            FilterResult::Pass
+        );
+    }
+
+    #[test]
+    fn limits_ipv6_sources_per_prefix() {
+        let dst = "2001:db8:1::1".parse().unwrap();
+        let from = |src: &str| {
+            PacketBuilder::new()
+                .ipv6(src.parse::<Ipv6Addr>().unwrap(), dst)
+                .udp(1000, 2000)
+                .build()
+                .run(main)
+        };
+        for _ in 0..DEFAULT_BURST {
+            assert_eq!(from("2001:db8::1"), FilterResult::Pass);
+        }
+        assert_eq!(from("2001:db8::2"), FilterResult::Drop);
+        assert_eq!(from("2001:db8:0:1::1"), FilterResult::Pass);
+    }
+
+    #[test]
+    fn limits_configured_prefixes() {
+        configure(1, 1, KEY_SOURCE, 24);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        assert_eq!(
+            udp(Ipv4Addr::new(10, 0, 0, 200), DST, 1000),
+            FilterResult::Drop
+        );
+        assert_eq!(
+            udp(Ipv4Addr::new(10, 0, 1, 2), DST, 1000),
+            FilterResult::Pass
+        );
+    }
+
+    #[test]
+    fn limits_destinations() {
+        configure(1, 1, KEY_DESTINATION, 0);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        assert_eq!(
+            udp(Ipv4Addr::new(10, 0, 0, 3), DST, 1000),
+            FilterResult::Drop
+        );
+        assert_eq!(
+            udp(SRC, Ipv4Addr::new(10, 0, 0, 4), 1000),
+            FilterResult::Pass
+        );
+    }
+
+    #[test]
+    fn limits_flows() {
+        configure(1, 1, KEY_FLOW, 0);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Drop);
+        assert_eq!(udp(SRC, DST, 1001), FilterResult::Pass);
+        let tcp = PacketBuilder::new()
+            .ipv4(SRC, DST)
+            .tcp(1000, 2000)
+            .build()
+            .run(main);
+        assert_eq!(tcp, FilterResult::Pass);
+    }
+
+    #[test]
+    fn counts_drops_per_key() {
+        configure(1, 1, KEY_SOURCE, 0);
+        for _ in 0..4 {
+            udp(SRC, DST, 1000);
+        }
+        udp(Ipv4Addr::new(10, 0, 0, 3), DST, 1000);
+
+        let dropped = |src: Ipv4Addr| {
+            let key = Key {
+                family: 4,
+                protocol: 0,
+                src_ip: src.to_ipv6_mapped(),
+                dst_ip: Ipv6Addr::UNSPECIFIED,
+                src_port: 0,
+                dst_port: 0,
+            };
+            unsafe { (*BUCKETS.get_ptr(&key.to_key()).unwrap()).dropped }
+        };
+        assert_eq!(dropped(SRC), 3);
+        assert_eq!(dropped(Ipv4Addr::new(10, 0, 0, 3)), 0);
+    }
+
+    #[test]
+    fn aborts_on_unknown_key_modes() {
+        configure(1, 1, 7, 0);
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Abort);
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        unsafe { *SCHEMA_VERSION.get_ptr_mut(0).unwrap() = 1 };
+        assert_eq!(migrate(main), FilterResult::Pass);
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&2));
+        assert_eq!(udp(SRC, DST, 1000), FilterResult::Pass);
+    }
+}
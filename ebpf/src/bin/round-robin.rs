@@ -1,31 +1,36 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 use aya_ebpf::macros::map;
-use aya_ebpf::maps::Array;
-use bpf_element::BpfContext;
-
-const OUTPUTS: u32 = 2;
-pub type Output = u32;
-
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> Output {
-    let ctx = unsafe { *ctx };
-    try_classify(&ctx).unwrap_or_else(|_| 0)
-}
+use bpf_element::classifier::Output;
+use bpf_element::macros::bpf_classifier;
+use bpf_element::maps::Array;
+use bpf_element::{Error, Packet};
 
 #[map(name = "PKTCOUNTER")]
 static PKTCOUNTER: Array<u32> = Array::with_max_entries(1, 0);
 
-#[inline(always)]
-fn try_classify(_: &BpfContext) -> Result<Output, ()> {
-    let counter = PKTCOUNTER.get_ptr_mut(0).ok_or(())?;
+#[bpf_classifier(outputs = 2, on_error = 0)]
+fn try_classify(_: &mut Packet) -> Result<Output<2>, Error> {
+    let counter = PKTCOUNTER.get_ptr_mut(0).ok_or(Error::Map)?;
 
-    let output = unsafe { *counter } % OUTPUTS;
+    let output = Output::wrapping(unsafe { *counter });
 
     unsafe {
         *counter += 1;
     }
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use bpf_element::testing::TestPacket;
+
+    use super::*;
+
+    #[test]
+    fn alternates_outputs() {
+        let outputs: Vec<u32> = (0..4).map(|_| TestPacket::new(&[0; 64]).run(main)).collect();
+        assert_eq!(outputs, [0, 1, 0, 1]);
+    }
+}
@@ -1,25 +1,39 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 use aya_ebpf::macros::map;
-use aya_ebpf::maps::Array;
 use bpf_element::filter::FilterResult;
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::Array;
+use bpf_element::{Error, Packet};
 
 #[map(name = "PACKET_CTR_V1")]
 static PACKET_CTR: Array<u32> = Array::with_max_entries(1, 0);
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main() -> FilterResult {
-    let Some(counter) = PACKET_CTR.get_ptr_mut(0) else {
-        return FilterResult::Abort;
-    };
+#[bpf_filter]
+fn try_filter(_: &mut Packet) -> Result<FilterResult, Error> {
+    let counter = PACKET_CTR.get_ptr_mut(0).ok_or(Error::Map)?;
 
     unsafe { *counter += 1 };
 
     if unsafe { *counter } > 10 {
-        FilterResult::Drop
+        Ok(FilterResult::Drop)
     } else {
-        FilterResult::Pass
+        Ok(FilterResult::Pass)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bpf_element::testing::TestPacket;
+
+    use super::*;
+
+    #[test]
+    fn drops_after_ten_packets() {
+        for _ in 0..10 {
+            assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Pass);
+        }
+        assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Drop);
     }
 }
@@ -1,52 +1,87 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 use aya_ebpf::macros::map;
-use aya_ebpf::maps::Array;
 use bpf_element::filter::FilterResult;
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::Array;
+use bpf_element::migration::Schema;
+use bpf_element::{Error, Packet};
 
 #[map(name = "PACKET_CTR_V2")]
 static PACKET_CTR_V2: Array<u64> = Array::with_max_entries(1, 0);
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main() -> FilterResult {
-    if let Err(_) = handle_migration() {
-        return FilterResult::Abort;
-    }
-
-    let Some(counter) = PACKET_CTR_V2.get_ptr_mut(0) else {
-        return FilterResult::Abort;
-    };
+#[bpf_filter(schema = Counter)]
+fn try_filter(_: &mut Packet) -> Result<FilterResult, Error> {
+    let counter = PACKET_CTR_V2.get_ptr_mut(0).ok_or(Error::Map)?;
 
     unsafe { *counter += 1 };
 
     let current_count = unsafe { *counter };
     if current_count > 10 {
-        FilterResult::Drop
+        Ok(FilterResult::Drop)
     } else {
-        FilterResult::Pass
+        Ok(FilterResult::Pass)
     }
 }
 
+/// The counter of state-migration-v1, left in place so it can take over again.
 #[map(name = "PACKET_CTR_V1")]
 static PACKET_CTR_V1: Array<u32> = Array::with_max_entries(1, 0);
 
-#[map(name = "VERSION")]
-static VERSION: Array<u64> = Array::with_max_entries(1, 0);
+struct Counter;
+
+impl Schema for Counter {
+    const VERSION: u32 = 2;
+
+    fn migrate(from: u32) -> Result<u32, Error> {
+        match from {
+            // state-migration-v1 has no schema
+            0 => {
+                let old_counter = PACKET_CTR_V1.get(0).ok_or(Error::Map)?;
+                let new_counter = PACKET_CTR_V2.get_ptr_mut(0).ok_or(Error::Map)?;
+                unsafe { *new_counter = *old_counter as u64 };
+                Ok(2)
+            }
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bpf_element::migration::SCHEMA_VERSION;
+    use bpf_element::testing::{migrate, TestPacket};
 
-fn handle_migration() -> Result<(), ()> {
-    let current_version = VERSION.get_ptr_mut(0).ok_or(())?;
+    use super::*;
 
-    if unsafe { *current_version } != 2 {
-        let old_counter = PACKET_CTR_V1.get(0).ok_or(())?;
-        let new_counter = PACKET_CTR_V2.get_ptr_mut(0).ok_or(())?;
+    #[test]
+    fn continues_count_of_v1() {
+        // state left behind by state-migration-v1
+        unsafe { *PACKET_CTR_V1.get_ptr_mut(0).unwrap() = 9 };
 
-        unsafe {
-            *new_counter = *old_counter as u64;
-            *current_version = 2;
-        };
+        assert_eq!(migrate(main), FilterResult::Pass);
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&2));
+        assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Pass);
+        assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Drop);
+        // v1 finds its counter as it left it
+        assert_eq!(PACKET_CTR_V1.get(0), Some(&9));
     }
 
-    Ok(())
+    #[test]
+    fn migrates_only_once() {
+        assert_eq!(migrate(main), FilterResult::Pass);
+        assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Pass);
+        unsafe { *PACKET_CTR_V1.get_ptr_mut(0).unwrap() = 100 };
+        assert_eq!(migrate(main), FilterResult::Pass);
+        assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Pass);
+        assert_eq!(PACKET_CTR_V2.get(0), Some(&2));
+    }
+
+    #[test]
+    fn packets_dont_migrate() {
+        unsafe { *PACKET_CTR_V1.get_ptr_mut(0).unwrap() = 9 };
+        assert_eq!(TestPacket::new(&[0; 64]).run(main), FilterResult::Pass);
+        assert_eq!(PACKET_CTR_V2.get(0), Some(&1));
+    }
 }
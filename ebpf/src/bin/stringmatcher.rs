@@ -1,58 +1,55 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
+use bpf_element::aho_corasick::{self, Automaton};
 use bpf_element::filter::FilterResult;
-use bpf_element::BpfContext;
-use network_types::ip::{IpProto, Ipv4Hdr};
-use network_types::tcp::TcpHdr;
-use network_types::udp::UdpHdr;
+use bpf_element::macros::bpf_filter;
+use bpf_element::{Error, Packet};
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> FilterResult {
-    let mut ctx = unsafe { *ctx };
+const SIGNATURES: &[u8] = include_bytes!("stringmatcher.txt");
+const PATTERNS: [&[u8]; aho_corasick::line_count(SIGNATURES)] = aho_corasick::lines(SIGNATURES);
+static MATCHER: Automaton<{ aho_corasick::states(&PATTERNS) }> = Automaton::new(&PATTERNS);
 
-    try_filter(&mut ctx).unwrap_or_else(|_| FilterResult::Abort)
+/// Drops packets that contain any of the signatures in `stringmatcher.txt`.
+#[bpf_filter]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+    match MATCHER.find_in(packet, 0)? {
+        Some(_) => Ok(FilterResult::Drop),
+        None => Ok(FilterResult::Pass),
+    }
 }
 
-/***
-* A very simple string matching filter that drops packets that contains the bytes 'leetcodew
-*/
-#[inline(always)]
-fn try_filter(ctx: &mut BpfContext) -> Result<FilterResult, ()> {
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ctx.get_ptr(0)? };
-
-    let match_word: [u8; 8] = [
-        'l' as u8,
-        'e' as u8,
-        'e' as u8,
-        't' as u8,
-        'c' as u8,
-        'o' as u8,
-        'd' as u8,
-        'e' as u8
-    ];
-    let b0: u8 = 0x22;
-    let b1: u8 = 0x8d;
-    let mut state: u16 = 0;
-
-    for i in 0..2000 {
-        // we consider packets of size 2k at max
-        let inspectable: *const u8 = match unsafe { ctx.get_ptr(i) } {
-            Ok(a) => a,
-            Err(_) => break, // end of packet. Stop loop
-        };
-        let writable: &mut u8 = unsafe { &mut *ctx.get_ptr_mut(0)? };
-        let inspectable: u8 = unsafe { *inspectable };
-        if state >= 8 {
-            return Ok(FilterResult::Drop); // we tested and found all states (match_word bytes)
-        }
-        if inspectable == match_word[state as usize] {
-            state += 1;
-        } else {
-            state = 0;
-        }
+#[cfg(test)]
+mod tests {
+    use bpf_element::testing::TestPacket;
+
+    use super::*;
+
+    #[test]
+    fn drops_packets_containing_word() {
+        let mut packet = TestPacket::new(b"GET /leetcode/problems HTTP/1.1");
+        assert_eq!(packet.run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn drops_overlapping_and_trailing_matches() {
+        let mut packet = TestPacket::new(b"leeleetcode");
+        assert_eq!(packet.run(main), FilterResult::Drop);
+        let mut packet = TestPacket::new(b"xx teststringtomatch");
+        assert_eq!(packet.run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn searches_the_whole_packet() {
+        let mut data = vec![b'x'; 3000];
+        data.extend_from_slice(b"leetcode");
+        let mut packet = TestPacket::new(&data);
+        assert_eq!(packet.run(main), FilterResult::Drop);
     }
 
-    Ok(FilterResult::Pass)
+    #[test]
+    fn passes_other_packets() {
+        let mut packet = TestPacket::new(b"GET /leetcod/problems HTTP/1.1");
+        assert_eq!(packet.run(main), FilterResult::Pass);
+    }
 }
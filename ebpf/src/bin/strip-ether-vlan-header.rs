@@ -1,35 +1,54 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use bpf_element::rewriter::{bpf_packet_add_space, RewriterResult};
-use bpf_element::BpfContext;
+use bpf_element::macros::bpf_rewriter;
+use bpf_element::rewriter::RewriterResult;
+use bpf_element::{Error, Packet};
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> RewriterResult {
-    let mut ctx = unsafe { *ctx };
+#[bpf_rewriter]
+fn try_rewrite(packet: &mut Packet) -> Result<RewriterResult, Error> {
+    let ether_type: &u16 = packet.load(12)?;
+    let ether_type = u16::from_be(*ether_type);
 
-    match try_rewrite(&mut ctx) {
-        Ok(_) => RewriterResult::Success,
-        Err(_) => RewriterResult::Abort,
+    const ETHERTYPE_8021Q: u16 = 0x8100;
+    if ether_type == ETHERTYPE_8021Q {
+        packet.adjust_head(-18)?;
+    } else {
+        packet.adjust_head(-14)?;
     }
+
+    Ok(RewriterResult::Success)
 }
 
-#[inline(always)]
-fn try_rewrite(ctx: &mut BpfContext) -> Result<(), ()> {
-    let ether_type_ptr: *const u16 = unsafe { ctx.get_ptr(12)? };
-    let ether_type = u16::from_be(unsafe { *ether_type_ptr });
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
 
-    const ETHERTYPE_8021Q: u16 = 0x8100;
-    if ether_type == ETHERTYPE_8021Q {
-        unsafe {
-            bpf_packet_add_space(ctx, -18, 0);
-        }
-    } else {
-        unsafe {
-            bpf_packet_add_space(ctx, -14, 0);
-        }
+    use bpf_element::testing::PacketBuilder;
+
+    use super::*;
+
+    const SRC_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const DST_MAC: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+    fn ip_packet() -> PacketBuilder {
+        PacketBuilder::new()
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+            .udp(1000, 2000)
+            .payload(b"hello")
     }
 
-    Ok(())
+    #[test]
+    fn strips_ethernet_header() {
+        let mut packet = ip_packet().ethernet(SRC_MAC, DST_MAC).build();
+        assert_eq!(packet.run(main), RewriterResult::Success);
+        assert_eq!(packet.data(), ip_packet().bytes());
+    }
+
+    #[test]
+    fn strips_vlan_tag() {
+        let mut packet = ip_packet().ethernet(SRC_MAC, DST_MAC).vlan(42).build();
+        assert_eq!(packet.run(main), RewriterResult::Success);
+        assert_eq!(packet.data(), ip_packet().bytes());
+    }
 }
@@ -1,39 +1,85 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
+use aya_ebpf::macros::map;
 use bpf_element::filter::FilterResult;
-use bpf_element::BpfContext;
-use network_types::ip::{IpProto, Ipv4Hdr};
-use network_types::tcp::TcpHdr;
-use network_types::udp::UdpHdr;
+use bpf_element::macros::bpf_filter;
+use bpf_element::maps::Config;
+use bpf_element::parse::LinkLayer;
+use bpf_element::{Error, Packet};
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> FilterResult {
-    let ctx = unsafe { *ctx };
+/// Target port until `CONFIG` sets one.
+const DEFAULT_PORT: u16 = 12345;
 
-    try_filter(&ctx).unwrap_or_else(|_| FilterResult::Abort)
+#[repr(C)]
+struct Settings {
+    /// Network byte order, 0 for [`DEFAULT_PORT`].
+    port: u16,
 }
 
-#[inline(always)]
-fn try_filter(ctx: &BpfContext) -> Result<FilterResult, ()> {
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ctx.get_ptr(0)? };
-
-    let target_port = match unsafe { *ipv4hdr }.proto {
-        IpProto::Tcp => {
-            let tcphdr: *const TcpHdr = unsafe { ctx.get_ptr(Ipv4Hdr::LEN) }?;
-            u16::from_be(unsafe { *tcphdr }.dest)
-        }
-        IpProto::Udp => {
-            let udphdr: *const UdpHdr = unsafe { ctx.get_ptr(Ipv4Hdr::LEN) }?;
-            u16::from_be(unsafe { *udphdr }.dest)
-        }
-        _ => return Err(()),
-    };
+#[map(name = "CONFIG")]
+static CONFIG: Config<Settings> = Config::new();
+
+#[bpf_filter]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {
+    // IPv4 or IPv6 carrying TCP or UDP
+    let headers = packet.parse(LinkLayer::None)?;
+    let (_, dst_port) = headers.ports().ok_or(Error::Unsupported)?;
 
-    if target_port == 12345 {
+    let target_port = match CONFIG.get()?.port {
+        0 => DEFAULT_PORT,
+        port => u16::from_be(port),
+    };
+    if dst_port == target_port {
         Ok(FilterResult::Drop)
     } else {
         Ok(FilterResult::Pass)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use bpf_element::testing::PacketBuilder;
+
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    #[test]
+    fn drops_target_port() {
+        let udp = PacketBuilder::new().ipv4(SRC, DST).udp(1000, 12345);
+        assert_eq!(udp.build().run(main), FilterResult::Drop);
+
+        let tcp = PacketBuilder::new()
+            .ipv6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST)
+            .tcp(1000, 12345);
+        assert_eq!(tcp.build().run(main), FilterResult::Drop);
+    }
+
+    #[test]
+    fn passes_other_ports() {
+        let packet = PacketBuilder::new().ipv4(SRC, DST).tcp(12345, 80);
+        assert_eq!(packet.build().run(main), FilterResult::Pass);
+    }
+
+    #[test]
+    fn reads_target_port_from_config() {
+        CONFIG.set(Settings {
+            port: 8080u16.to_be(),
+        });
+        let packet = PacketBuilder::new().ipv4(SRC, DST).tcp(1000, 8080);
+        assert_eq!(packet.build().run(main), FilterResult::Drop);
+
+        let packet = PacketBuilder::new().ipv4(SRC, DST).udp(1000, 12345);
+        assert_eq!(packet.build().run(main), FilterResult::Pass);
+    }
+
+    #[test]
+    fn aborts_without_ports() {
+        let packet = PacketBuilder::new().ipv4(SRC, DST).icmp_echo_request(1, 1);
+        assert_eq!(packet.build().run(main), FilterResult::Abort);
+    }
+}
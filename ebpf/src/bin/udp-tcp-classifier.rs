@@ -1,45 +1,70 @@
-#![no_std]
-#![no_main]
-
-use bpf_element::BpfContext;
-use network_types::eth::{EthHdr, EtherType};
-use network_types::ip::{IpProto, Ipv4Hdr};
-
-#[derive(Copy, Clone)]
-#[repr(u32)]
-pub enum ClassifyResult {
-    Udp = 0,
-    Tcp = 1,
-    Rest = 2,
-}
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+
+use bpf_element::classifier::Output;
+use bpf_element::macros::bpf_classifier;
+use bpf_element::parse::{LinkLayer, L4};
+use bpf_element::{Error, Packet};
+
+const UDP: Output<3> = Output::at::<0>();
+const TCP: Output<3> = Output::at::<1>();
+const REST: Output<3> = Output::at::<2>();
 
-#[no_mangle]
-#[link_section = "bpffilter"]
-pub extern "C" fn main(ctx: *mut BpfContext) -> ClassifyResult {
-    let ctx = unsafe { *ctx };
-    try_classify(&ctx).unwrap_or_else(|_| {
-        ClassifyResult::Rest
+#[bpf_classifier(outputs = 3, on_error = 2)]
+fn try_classify(packet: &mut Packet) -> Result<Output<3>, Error> {
+    // non-IP packets come back with `L4::None`
+    let headers = packet.parse(LinkLayer::Ethernet)?;
+
+    Ok(match headers.l4 {
+        L4::Udp(_) => UDP,
+        L4::Tcp(_) => TCP,
+        _ => REST,
     })
 }
 
-#[inline(always)]
-fn try_classify(ctx: &BpfContext) -> Result<ClassifyResult, ()> {
-    let ethhdr: *const EthHdr = unsafe { ctx.get_ptr(0)? };
-    let ether_type = unsafe { *ethhdr }.ether_type;
-    if ether_type != EtherType::Ipv4 {
-        return Ok(ClassifyResult::Rest);
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
+
+    use bpf_element::telemetry::{telemetry, PROGRAM_CLASSIFIER};
+    use bpf_element::testing::{PacketBuilder, TestPacket};
+
+    use super::*;
+
+    fn ipv4() -> PacketBuilder {
+        PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
     }
 
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ctx.get_ptr(EthHdr::LEN)? };
-    let proto = unsafe { *ipv4hdr }.proto;
-    let ipv4hdr_len = unsafe { *ipv4hdr }.ihl() as usize * 4;
-    if ipv4hdr_len < Ipv4Hdr::LEN {
-        return Ok(ClassifyResult::Rest);
+    #[test]
+    fn classifies_by_transport() {
+        assert_eq!(ipv4().udp(1000, 53).build().run(main), UDP.port());
+        assert_eq!(ipv4().tcp(1000, 80).build().run(main), TCP.port());
+        assert_eq!(ipv4().icmp_echo_request(1, 1).build().run(main), REST.port());
     }
 
-    match proto {
-        IpProto::Udp => Ok(ClassifyResult::Udp),
-        IpProto::Tcp => Ok(ClassifyResult::Tcp),
-        _ => Ok(ClassifyResult::Rest),
+    #[test]
+    fn sends_truncated_packets_to_rest() {
+        let bytes = ipv4().udp(1000, 53).bytes();
+        let mut packet = TestPacket::new(&bytes[..20]);
+        assert_eq!(packet.run(main), REST.port());
+    }
+
+    #[test]
+    fn counts_packets_by_port_and_error() {
+        let (udp, tcp) = (ipv4().udp(1000, 53).bytes(), ipv4().tcp(1000, 80).bytes());
+        TestPacket::new(&udp).run(main);
+        TestPacket::new(&udp).run(main);
+        TestPacket::new(&tcp).run(main);
+        TestPacket::new(&udp[..20]).run(main);
+
+        let telemetry = telemetry();
+        assert_eq!(telemetry.program, PROGRAM_CLASSIFIER);
+        assert_eq!(telemetry.packets, 4);
+        assert_eq!(telemetry.bytes, (2 * udp.len() + tcp.len() + 20) as u64);
+        assert_eq!(telemetry.verdicts[..3], [2, 1, 1]);
+        assert_eq!(telemetry.errors[Error::OutOfBounds as usize], 1);
+        assert_eq!((telemetry.drops, telemetry.aborts), (0, 0));
     }
 }
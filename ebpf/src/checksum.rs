@@ -0,0 +1,288 @@
+//! Internet checksums (RFC 1071) computed from scratch.
+//!
+//! [`crate::update_checksum`] and [`crate::rewrite`] patch a checksum after changing a few fields.
+//! When a rewriter builds or resizes headers, the checksum has to be recomputed over the whole
+//! header or segment instead. The loops here are bounded by [`MAX_SLICE_LEN`] so the verifier can
+//! follow them for any packet [`BpfContext::get_slice`](crate::BpfContext::get_slice) hands out.
+//!
+//! Sums are kept as unfolded `u32`s in host byte order so they can be chained, e.g. a pseudo
+//! header followed by the segment, and turned into the 16-bit checksum with [`finish`].
+
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use network_types::ip::{Ipv4Hdr, Ipv6Hdr};
+
+use crate::parse::{
+    Layout, ETH_P_IPV4, ETH_P_IPV6, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP,
+};
+use crate::{Error, Packet, MAX_SLICE_LEN};
+
+/// Offset of the checksum field in the TCP header.
+const TCP_CHECK: usize = 16;
+/// Offset of the checksum field in the UDP header.
+const UDP_CHECK: usize = 6;
+/// Offset of the checksum field in the ICMP and ICMPv6 header.
+const ICMP_CHECK: usize = 2;
+
+/// Folds a sum into 16 bits, keeping the carries.
+#[inline(always)]
+pub fn fold(mut sum: u32) -> u16 {
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum as u16
+}
+
+/// The checksum of everything summed into `sum`, in host byte order.
+#[inline(always)]
+pub fn finish(sum: u32) -> u16 {
+    !fold(sum)
+}
+
+/// Adds `data` to `sum` as big-endian 16-bit words. An odd trailing byte is padded with zero.
+///
+/// Data beyond [`MAX_SLICE_LEN`] is ignored.
+#[inline(always)]
+pub fn sum(data: &[u8], sum: u32) -> u32 {
+    let len = data.len().min(MAX_SLICE_LEN);
+    let words = len / 2;
+
+    // 25000 words of at most 0xffff can't overflow once `sum` has been folded
+    let mut acc = fold(sum) as u32;
+    for i in 0..MAX_SLICE_LEN / 2 {
+        if i >= words {
+            break;
+        }
+        acc += u16::from_be_bytes([data[2 * i], data[2 * i + 1]]) as u32;
+    }
+    if len % 2 == 1 {
+        acc += (data[len - 1] as u32) << 8;
+    }
+    acc
+}
+
+/// Sum of the IPv4 pseudo header for a transport segment of `len` bytes.
+#[inline(always)]
+pub fn pseudo_header_v4(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: u16) -> u32 {
+    let acc = sum(&src.octets(), 0);
+    let acc = sum(&dst.octets(), acc);
+    acc + proto as u32 + len as u32
+}
+
+/// Sum of the IPv6 pseudo header for a transport segment of `len` bytes.
+#[inline(always)]
+pub fn pseudo_header_v6(src: Ipv6Addr, dst: Ipv6Addr, proto: u8, len: u32) -> u32 {
+    let acc = sum(&src.octets(), 0);
+    let acc = sum(&dst.octets(), acc);
+    acc + proto as u32 + (len >> 16) + (len & 0xffff)
+}
+
+/// Recomputes the IPv4 header checksum, options included.
+#[inline(always)]
+pub fn set_ipv4_checksum(packet: &mut Packet, layout: &Layout) -> Result<(), Error> {
+    if layout.ip != ETH_P_IPV4 {
+        return Err(Error::Unsupported);
+    }
+
+    let hdr: &mut Ipv4Hdr = packet.load_mut(layout.l3_offset)?;
+    let hdr_len = hdr.ihl() as usize * 4;
+    hdr.check = 0;
+
+    let check = finish(sum(packet.slice(layout.l3_offset, hdr_len)?, 0));
+    let hdr: &mut Ipv4Hdr = packet.load_mut(layout.l3_offset)?;
+    hdr.check = check.to_be();
+    Ok(())
+}
+
+/// Recomputes the TCP, UDP, ICMP or ICMPv6 checksum over the whole segment.
+///
+/// The segment length is taken from the IP header, so set it before calling this after resizing
+/// the packet. UDP checksums that come out as 0 are sent as `0xffff`.
+#[inline(always)]
+pub fn set_l4_checksum(packet: &mut Packet, layout: &Layout) -> Result<(), Error> {
+    let (proto, check_offset) = match layout.l4 {
+        Some(IPPROTO_TCP) => (IPPROTO_TCP, TCP_CHECK),
+        Some(IPPROTO_UDP) => (IPPROTO_UDP, UDP_CHECK),
+        Some(IPPROTO_ICMP) => (IPPROTO_ICMP, ICMP_CHECK),
+        Some(IPPROTO_ICMPV6) => (IPPROTO_ICMPV6, ICMP_CHECK),
+        _ => return Err(Error::Unsupported),
+    };
+
+    let (len, pseudo) = match layout.ip {
+        ETH_P_IPV4 => {
+            let hdr: &Ipv4Hdr = packet.load(layout.l3_offset)?;
+            let len = u16::from_be(hdr.tot_len) as usize;
+            let len = len
+                .checked_sub(layout.l4_offset - layout.l3_offset)
+                .ok_or(Error::Malformed)?;
+            // ICMP doesn't checksum a pseudo header
+            let pseudo = match proto {
+                IPPROTO_ICMP => 0,
+                _ => pseudo_header_v4(hdr.src_addr(), hdr.dst_addr(), proto, len as u16),
+            };
+            (len, pseudo)
+        }
+        ETH_P_IPV6 => {
+            let hdr: &Ipv6Hdr = packet.load(layout.l3_offset)?;
+            let len = u16::from_be(hdr.payload_len) as usize;
+            // extension headers count towards the payload length, but not the segment
+            let ext_len = layout.l4_offset - layout.l3_offset - Ipv6Hdr::LEN;
+            let len = len.checked_sub(ext_len).ok_or(Error::Malformed)?;
+            let pseudo = pseudo_header_v6(hdr.src_addr(), hdr.dst_addr(), proto, len as u32);
+            (len, pseudo)
+        }
+        _ => return Err(Error::Unsupported),
+    };
+
+    let check: &mut u16 = packet.load_mut(layout.l4_offset + check_offset)?;
+    *check = 0;
+
+    let mut check = finish(sum(packet.slice(layout.l4_offset, len)?, pseudo));
+    if check == 0 && proto == IPPROTO_UDP {
+        check = 0xffff;
+    }
+    let field: &mut u16 = packet.load_mut(layout.l4_offset + check_offset)?;
+    *field = check.to_be();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::LinkLayer;
+    use crate::{update_checksum, BpfContext};
+
+    fn with_packet<R>(data: &mut [u8], f: impl FnOnce(&mut Packet) -> R) -> R {
+        let range = data.as_mut_ptr_range();
+        let mut packet = unsafe {
+            Packet::new(BpfContext {
+                data: range.start,
+                data_end: range.end,
+                port: 0,
+            })
+        };
+        f(&mut packet)
+    }
+
+    // RFC 1071, section 3
+    #[test]
+    fn rfc1071_example() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(fold(sum(&data, 0)), 0xddf2);
+        assert_eq!(finish(sum(&data, 0)), 0x220d);
+    }
+
+    #[test]
+    fn odd_length() {
+        assert_eq!(fold(sum(&[0x12, 0x34, 0x56], 0)), 0x1234 + 0x5600);
+        // summing in pieces gives the same result as long as the pieces have even lengths
+        let data = [0xff; 9];
+        assert_eq!(
+            fold(sum(&data[4..], sum(&data[..4], 0))),
+            fold(sum(&data, 0))
+        );
+    }
+
+    // 192.168.0.1 -> 192.168.0.199, UDP, from the Wikipedia article on the IPv4 header checksum
+    const IPV4_HEADER: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+
+    #[test]
+    fn ipv4_header() {
+        let mut hdr = IPV4_HEADER;
+        hdr[10] = 0;
+        hdr[11] = 0;
+        assert_eq!(finish(sum(&hdr, 0)), 0xb861);
+        // a correct header sums to 0xffff
+        assert_eq!(finish(sum(&IPV4_HEADER, 0)), 0);
+    }
+
+    // The checksums of the following packets were computed independently with a plain RFC 1071
+    // sum over the pseudo header and segment.
+
+    // 10.0.0.1:12345 -> 10.0.0.2:53, UDP payload "hello"
+    const IPV4_UDP: [u8; 33] = [
+        0x45, 0x00, 0x00, 0x21, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0x66, 0xc9, 0x0a, 0x00, 0x00,
+        0x01, 0x0a, 0x00, 0x00, 0x02, 0x30, 0x39, 0x00, 0x35, 0x00, 0x0d, 0x77, 0x91, 0x68, 0x65,
+        0x6c, 0x6c, 0x6f,
+    ];
+
+    #[test]
+    fn ipv4_udp() {
+        let mut packet = IPV4_UDP;
+        packet[10..12].fill(0);
+        packet[26..28].fill(0);
+        with_packet(&mut packet, |packet| {
+            let layout = packet.parse(LinkLayer::None).unwrap().layout();
+            set_ipv4_checksum(packet, &layout).unwrap();
+            set_l4_checksum(packet, &layout).unwrap();
+        });
+        assert_eq!(packet, IPV4_UDP);
+    }
+
+    // ::1 -> ::1, TCP SYN from port 40000 to 80
+    const IPV6_TCP: [u8; 60] = [
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x14, 0x06, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x9c, 0x40, 0x00, 0x50, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x50, 0x02, 0x20, 0x00, 0xf3, 0x50, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn ipv6_tcp() {
+        let mut packet = IPV6_TCP;
+        packet[56..58].fill(0);
+        with_packet(&mut packet, |packet| {
+            let layout = packet.parse(LinkLayer::None).unwrap().layout();
+            assert_eq!(set_ipv4_checksum(packet, &layout), Err(Error::Unsupported));
+            set_l4_checksum(packet, &layout).unwrap();
+        });
+        assert_eq!(packet, IPV6_TCP);
+    }
+
+    #[test]
+    fn incremental_update_matches_full() {
+        let mut packet = IPV4_UDP;
+        // 10.0.0.1 -> 172.44.0.3
+        let old = u32::from_ne_bytes(packet[12..16].try_into().unwrap());
+        let new = u32::from(Ipv4Addr::new(172, 44, 0, 3)).to_be();
+        let mut check = u16::from_ne_bytes([packet[10], packet[11]]);
+        crate::update_checksum_ip(&mut check, old, new);
+        packet[12..16].copy_from_slice(&new.to_ne_bytes());
+        packet[10..12].copy_from_slice(&check.to_ne_bytes());
+        assert_eq!(finish(sum(&packet[..20], 0)), 0);
+
+        // source port 12345 -> 50000
+        let mut check = u16::from_ne_bytes([packet[26], packet[27]]);
+        let old = u16::from_ne_bytes([packet[20], packet[21]]);
+        let new = 50000u16.to_be();
+        update_checksum(&mut check, old, new);
+        crate::update_checksum_ip(
+            &mut check,
+            u32::from(Ipv4Addr::new(10, 0, 0, 1)).to_be(),
+            u32::from(Ipv4Addr::new(172, 44, 0, 3)).to_be(),
+        );
+        packet[20..22].copy_from_slice(&new.to_ne_bytes());
+        packet[26..28].copy_from_slice(&check.to_ne_bytes());
+
+        let mut full = packet;
+        full[26..28].fill(0);
+        with_packet(&mut full, |packet| {
+            let layout = packet.parse(LinkLayer::None).unwrap().layout();
+            set_l4_checksum(packet, &layout).unwrap();
+        });
+        assert_eq!(full, packet);
+    }
+
+    #[test]
+    fn truncated_segment() {
+        let mut packet = IPV4_UDP;
+        // claim more payload than the packet has
+        packet[3] = 0x40;
+        with_packet(&mut packet, |packet| {
+            let layout = packet.parse(LinkLayer::None).unwrap().layout();
+            assert_eq!(set_l4_checksum(packet, &layout), Err(Error::OutOfBounds));
+        });
+    }
+}
@@ -0,0 +1,569 @@
+//! DNS queries: parsing their question, matching names against domain suffixes and answering them
+//! in place.
+//!
+//! [`Query::parse`] reads the question of a standard query to port 53 over UDP or TCP. It never
+//! panics: truncated messages, names with compression pointers (which queries don't need), and
+//! messages with other than one question are [`Error::Malformed`]. Over TCP only the first
+//! message of a segment is read, and segments that continue a message can't be told apart from
+//! malformed ones.
+//!
+//! A [`SuffixMap`] holds values by domain, and a lookup finds the value of the longest domain the
+//! queried name is in, so `lmu.de` also covers `www.lmu.de`. Names are matched case-insensitively.
+//!
+//! ```ignore
+//! #[map(name = "BLOCKLIST")]
+//! static BLOCKLIST: SuffixMap<u32> = SuffixMap::with_max_entries(65536, 0);
+//!
+//! let Some(query) = Query::parse(packet, &headers)? else { ... };
+//! if let Some(action) = BLOCKLIST.get(&query) { ... }
+//! ```
+//!
+//! [`respond`] turns a query into the answer to its client, e.g. NXDOMAIN, which needs
+//! `BPFRewriter`'s `bpf_packet_add_space`.
+
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use network_types::eth::EthHdr;
+use network_types::ip::{Ipv4Hdr, Ipv6Hdr};
+use network_types::tcp::TcpHdr;
+use network_types::udp::UdpHdr;
+
+use crate::checksum;
+use crate::maps::{LpmKey, LpmTrie};
+use crate::parse::{Headers, Layout, LinkLayer, ETH_P_IPV4, ETH_P_IPV6, L3, L4};
+use crate::{Error, Packet};
+
+pub const DNS_PORT: u16 = 53;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+/// Length of the message header.
+pub const HEADER_LEN: usize = 12;
+/// Longest name in wire format, root label included (RFC 1035).
+pub const MAX_NAME_LEN: usize = 255;
+/// Longest domain a [`SuffixMap`] holds, in wire format without the root label.
+pub const MAX_SUFFIX_LEN: usize = 128;
+
+const MAX_LABEL_LEN: u8 = 63;
+
+const FLAG_QR: u16 = 0x8000;
+const OPCODE: u16 = 0x7800;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+/// A domain's labels in reverse order and lower-cased, e.g. `\x02de\x03lmu` for `LMU.de`, cut
+/// after [`MAX_SUFFIX_LEN`] bytes. The prefix length is in bits. A name is in a domain if the
+/// domain's key is a prefix of the name's.
+pub type SuffixKey = LpmKey<[u8; MAX_SUFFIX_LEN]>;
+
+/// The question of a standard query.
+pub struct Query {
+    /// Offset of the message in the packet, behind the length TCP prefixes messages with.
+    pub offset: usize,
+    /// Length of the message up to the end of the question.
+    pub question_len: usize,
+    pub qtype: u16,
+    pub qclass: u16,
+    pub tcp: bool,
+    name: SuffixKey,
+}
+
+impl Query {
+    /// The query a UDP datagram or TCP segment to [`DNS_PORT`] starts with. `None` for other
+    /// packets, TCP segments without payload, and messages other than standard queries.
+    #[inline(always)]
+    pub fn parse(packet: &Packet, headers: &Headers) -> Result<Option<Query>, Error> {
+        let (tcp, offset, len) = match headers.l4 {
+            L4::Udp(hdr) if u16::from_be(hdr.dest) == DNS_PORT => {
+                let len = (u16::from_be(hdr.len) as usize)
+                    .checked_sub(UdpHdr::LEN)
+                    .ok_or(Error::Malformed)?;
+                (false, headers.payload_offset, len)
+            }
+            L4::Tcp(hdr) if u16::from_be(hdr.dest) == DNS_PORT => {
+                let payload_len = payload_end(headers)?
+                    .checked_sub(headers.payload_offset)
+                    .ok_or(Error::Malformed)?;
+                if payload_len == 0 {
+                    return Ok(None);
+                }
+                // the rest of a longer message follows in later segments
+                let prefix: &[u8; 2] = packet.load(headers.payload_offset)?;
+                let available = payload_len.checked_sub(2).ok_or(Error::Malformed)?;
+                let len = (u16::from_be_bytes(*prefix) as usize).min(available);
+                (true, headers.payload_offset + 2, len)
+            }
+            _ => return Ok(None),
+        };
+
+        // the question is all that's read
+        let message = packet.slice(offset, len.min(HEADER_LEN + MAX_NAME_LEN + 4))?;
+        let (Some(flags), Some(qdcount)) = (be16(message, 2), be16(message, 4)) else {
+            return Err(Error::Malformed);
+        };
+        if flags & (FLAG_QR | OPCODE) != 0 {
+            return Ok(None);
+        }
+        if qdcount != 1 {
+            return Err(Error::Malformed);
+        }
+
+        let (name, name_len) = read_name(message.get(HEADER_LEN..).ok_or(Error::Malformed)?)?;
+        let end = HEADER_LEN + name_len;
+        let (Some(qtype), Some(qclass)) = (be16(message, end), be16(message, end + 2)) else {
+            return Err(Error::Malformed);
+        };
+        Ok(Some(Query {
+            offset,
+            question_len: end + 4,
+            qtype,
+            qclass,
+            tcp,
+            name,
+        }))
+    }
+
+    /// Whether the queried name is `domain` or in it.
+    #[inline(always)]
+    pub fn is_in(&self, domain: &SuffixKey) -> bool {
+        let len = domain.prefix_len as usize / 8;
+        if domain.prefix_len > self.name.prefix_len {
+            return false;
+        }
+        // byte arrays aren't misaligned in the packed keys
+        let (name, domain) = (&self.name.data, &domain.data);
+        for i in 0..MAX_SUFFIX_LEN {
+            if i >= len {
+                break;
+            }
+            if name[i] != domain[i] {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[inline(always)]
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// End of the IP payload, which may be followed by link-layer padding.
+#[inline(always)]
+fn payload_end(headers: &Headers) -> Result<usize, Error> {
+    match headers.l3 {
+        L3::Ipv4(hdr) => Ok(headers.l3_offset + u16::from_be(hdr.tot_len) as usize),
+        L3::Ipv6(hdr) => {
+            Ok(headers.l3_offset + Ipv6Hdr::LEN + u16::from_be(hdr.payload_len) as usize)
+        }
+        L3::Other(_) => Err(Error::Unsupported),
+    }
+}
+
+/// Reads the uncompressed name `data` starts with. Returns its [`SuffixKey`] and its length in
+/// wire format, root label included.
+#[inline(always)]
+pub fn read_name(data: &[u8]) -> Result<(SuffixKey, usize), Error> {
+    // find the root label
+    let mut label = 0;
+    let mut len = None;
+    for i in 0..MAX_NAME_LEN {
+        if i != label {
+            continue;
+        }
+        let size = *data.get(i).ok_or(Error::Malformed)?;
+        if size == 0 {
+            len = Some(i);
+            break;
+        }
+        // compression pointers and extended label types
+        if size > MAX_LABEL_LEN {
+            return Err(Error::Malformed);
+        }
+        label = i + 1 + size as usize;
+    }
+    let len = len.ok_or(Error::Malformed)?;
+
+    // byte i of the label at `start..end` moves to `len - end + (i - start)`; length bytes are
+    // below 'A', so lower-casing leaves them alone
+    let mut key = [0; MAX_SUFFIX_LEN];
+    let (mut start, mut end) = (0, 0);
+    for i in 0..MAX_NAME_LEN {
+        let Some(&byte) = data.get(i).filter(|_| i < len) else {
+            break;
+        };
+        if i == end {
+            start = i;
+            end = i + 1 + byte as usize;
+        }
+        if let Some(slot) = key.get_mut(len.saturating_sub(end) + (i - start)) {
+            *slot = byte.to_ascii_lowercase();
+        }
+    }
+    Ok((
+        LpmKey::new(8 * len.min(MAX_SUFFIX_LEN) as u32, key),
+        len + 1,
+    ))
+}
+
+/// The [`SuffixKey`] of a domain written with dots, e.g. `lmu.de`. The root domain, which covers
+/// every name, is `""` or `"."`. Fails with [`Error::Unsupported`] for domains longer than
+/// [`MAX_SUFFIX_LEN`].
+pub fn suffix_key(domain: &str) -> Result<SuffixKey, Error> {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    let mut key = [0; MAX_SUFFIX_LEN];
+    let mut len = 0;
+    if !domain.is_empty() {
+        for label in domain.rsplit('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN as usize {
+                return Err(Error::Malformed);
+            }
+            let slot = key
+                .get_mut(len..len + 1 + label.len())
+                .ok_or(Error::Unsupported)?;
+            slot[0] = label.len() as u8;
+            for (to, from) in slot[1..].iter_mut().zip(label.bytes()) {
+                *to = from.to_ascii_lowercase();
+            }
+            len += 1 + label.len();
+        }
+    }
+    Ok(LpmKey::new(8 * len as u32, key))
+}
+
+/// A domain written with dots, e.g. `lmu.de`, in DNS wire format, e.g. `\x03lmu\x02de\x00`, for
+/// building queries on the host. The root domain is `""` or `"."`.
+#[cfg(any(test, feature = "std"))]
+pub fn wire_name(domain: &str) -> Vec<u8> {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    let mut wire = Vec::new();
+    if !domain.is_empty() {
+        for label in domain.split('.') {
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label.as_bytes());
+        }
+    }
+    wire.push(0);
+    wire
+}
+
+/// Values by domain, looked up by the longest domain a queried name is in.
+///
+/// It's an [`LpmTrie`] of [`SuffixKey`]s, so the element and tools see 132-byte keys: the length
+/// of the domain in bits, then its labels in reverse order.
+#[repr(transparent)]
+pub struct SuffixMap<V> {
+    trie: LpmTrie<[u8; MAX_SUFFIX_LEN], V>,
+}
+
+impl<V> SuffixMap<V> {
+    pub const fn with_max_entries(max_entries: u32, flags: u32) -> SuffixMap<V> {
+        SuffixMap {
+            trie: LpmTrie::with_max_entries(max_entries, flags),
+        }
+    }
+
+    /// Value of the longest domain the queried name is in.
+    #[inline(always)]
+    pub fn get(&self, query: &Query) -> Option<&V> {
+        self.trie.get(&query.name)
+    }
+
+    /// Inserts or overwrites `domain`, see [`suffix_key`].
+    pub fn insert(&self, domain: &str, value: &V) -> Result<(), Error> {
+        self.trie
+            .insert(&suffix_key(domain)?, value, 0)
+            .map_err(|_| Error::Map)
+    }
+
+    /// Removes exactly `domain`.
+    pub fn remove(&self, domain: &str) -> Result<(), Error> {
+        self.trie
+            .remove(&suffix_key(domain)?)
+            .map_err(|_| Error::Map)
+    }
+}
+
+/// A record answering the question, with its TTL in seconds.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Answer {
+    None,
+    A(Ipv4Addr, u32),
+    Aaaa(Ipv6Addr, u32),
+}
+
+/// Turns the query into a response with `rcode` and `answer`, sent back to the client: addresses,
+/// ports and, with [`LinkLayer::Ethernet`], MAC addresses are swapped, everything behind the
+/// question is cut off and lengths and checksums are fixed.
+///
+/// The server never sees a query answered over TCP, so the connection can't go on; the segment
+/// becomes a reset to the client instead. Packets with IPv6 extension headers aren't turned around
+/// ([`Error::Unsupported`]). Other than [`Error::Resize`], errors leave the packet as it was.
+#[inline(always)]
+pub fn respond(
+    packet: &mut Packet,
+    link: LinkLayer,
+    layout: &Layout,
+    query: &Query,
+    rcode: u8,
+    answer: Answer,
+) -> Result<(), Error> {
+    if layout.ip == ETH_P_IPV6 && layout.l4_offset != layout.l3_offset + Ipv6Hdr::LEN {
+        return Err(Error::Unsupported);
+    }
+    if query.tcp {
+        return reset(packet, link, layout);
+    }
+
+    let answer_len = match answer {
+        Answer::None => 0,
+        Answer::A(..) => 16,
+        Answer::Aaaa(..) => 28,
+    };
+    let end = query.offset + query.question_len + answer_len;
+    let old_len = packet.len();
+    packet.adjust_tail(end as i32 - old_len as i32)?;
+
+    let header: &mut [u8; HEADER_LEN] = packet.load_mut(query.offset)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let flags = FLAG_QR | (flags & (OPCODE | FLAG_RD)) | FLAG_RA | (rcode & 0x0f) as u16;
+    header[2..4].copy_from_slice(&flags.to_be_bytes());
+    // one question, and the answer if there is one
+    header[4..12].copy_from_slice(&[0, 1, 0, (answer_len != 0) as u8, 0, 0, 0, 0]);
+
+    let record = query.offset + query.question_len;
+    match answer {
+        Answer::None => {}
+        Answer::A(addr, ttl) => {
+            let rr: &mut [u8; 16] = packet.load_mut(record)?;
+            *rr = resource_record(TYPE_A, ttl, 4, &addr.octets());
+        }
+        Answer::Aaaa(addr, ttl) => {
+            let rr: &mut [u8; 28] = packet.load_mut(record)?;
+            *rr = resource_record(TYPE_AAAA, ttl, 16, &addr.octets());
+        }
+    }
+
+    turn_around(packet, link, layout, end)?;
+    let udp: &mut UdpHdr = packet.load_mut(layout.l4_offset)?;
+    udp.len = ((end - layout.l4_offset) as u16).to_be();
+    checksum::set_l4_checksum(packet, layout)
+}
+
+/// A record of the queried name, pointed to at the start of the question.
+#[inline(always)]
+fn resource_record<const N: usize>(rtype: u16, ttl: u32, rdlength: u16, rdata: &[u8]) -> [u8; N] {
+    let mut rr = [0; N];
+    rr[0..2].copy_from_slice(&(0xc000 | HEADER_LEN as u16).to_be_bytes());
+    rr[2..4].copy_from_slice(&rtype.to_be_bytes());
+    rr[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+    rr[6..10].copy_from_slice(&ttl.to_be_bytes());
+    rr[10..12].copy_from_slice(&rdlength.to_be_bytes());
+    rr[12..].copy_from_slice(rdata);
+    rr
+}
+
+/// Turns a TCP segment into a reset of its connection, sent back to the client.
+#[inline(always)]
+fn reset(packet: &mut Packet, link: LinkLayer, layout: &Layout) -> Result<(), Error> {
+    let end = layout.l4_offset + TcpHdr::LEN;
+    let payload_len = match layout.ip {
+        ETH_P_IPV4 => {
+            let hdr: &Ipv4Hdr = packet.load(layout.l3_offset)?;
+            u16::from_be(hdr.tot_len) as usize
+        }
+        _ => {
+            let hdr: &Ipv6Hdr = packet.load(layout.l3_offset)?;
+            Ipv6Hdr::LEN + u16::from_be(hdr.payload_len) as usize
+        }
+    };
+    let segment: &[u8; TcpHdr::LEN] = packet.load(layout.l4_offset)?;
+    let doff = (segment[12] >> 4) as usize * 4;
+    let payload_len = (layout.l3_offset + payload_len)
+        .checked_sub(layout.l4_offset + doff)
+        .ok_or(Error::Malformed)?;
+
+    // SYN and FIN take a sequence number each
+    let seq = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    let ack_seq = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    let flags = segment[13];
+    let taken = payload_len as u32
+        + (flags & TCP_FLAG_SYN != 0) as u32
+        + (flags & TCP_FLAG_FIN != 0) as u32;
+
+    let old_len = packet.len();
+    packet.adjust_tail(end as i32 - old_len as i32)?;
+    let segment: &mut [u8; TcpHdr::LEN] = packet.load_mut(layout.l4_offset)?;
+    segment[4..8].copy_from_slice(&ack_seq.to_be_bytes());
+    segment[8..12].copy_from_slice(&seq.wrapping_add(taken).to_be_bytes());
+    segment[12] = 5 << 4;
+    segment[13] = TCP_FLAG_RST | TCP_FLAG_ACK;
+    // window and urgent pointer
+    segment[14..16].copy_from_slice(&[0, 0]);
+    segment[18..20].copy_from_slice(&[0, 0]);
+
+    turn_around(packet, link, layout, end)?;
+    checksum::set_l4_checksum(packet, layout)
+}
+
+/// Swaps the addresses and ports of a packet ending at `end`, and sets its IP length.
+#[inline(always)]
+fn turn_around(
+    packet: &mut Packet,
+    link: LinkLayer,
+    layout: &Layout,
+    end: usize,
+) -> Result<(), Error> {
+    if link == LinkLayer::Ethernet {
+        let eth: &mut EthHdr = packet.load_mut(0)?;
+        (eth.src_addr, eth.dst_addr) = (eth.dst_addr, eth.src_addr);
+    }
+
+    match layout.ip {
+        ETH_P_IPV4 => {
+            let hdr: &mut Ipv4Hdr = packet.load_mut(layout.l3_offset)?;
+            (hdr.src_addr, hdr.dst_addr) = (hdr.dst_addr, hdr.src_addr);
+            hdr.tot_len = ((end - layout.l3_offset) as u16).to_be();
+            checksum::set_ipv4_checksum(packet, layout)?;
+        }
+        ETH_P_IPV6 => {
+            let hdr: &mut Ipv6Hdr = packet.load_mut(layout.l3_offset)?;
+            (hdr.src_addr, hdr.dst_addr) = (hdr.dst_addr, hdr.src_addr);
+            hdr.payload_len = ((end - layout.l3_offset - Ipv6Hdr::LEN) as u16).to_be();
+        }
+        _ => return Err(Error::Unsupported),
+    }
+
+    let ports: &mut [u8; 4] = packet.load_mut(layout.l4_offset)?;
+    *ports = [ports[2], ports[3], ports[0], ports[1]];
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::PacketBuilder;
+
+    /// The bytes of a key within its prefix length.
+    fn bytes(key: SuffixKey) -> Vec<u8> {
+        let (len, data) = (key.prefix_len as usize, key.data);
+        assert_eq!(len % 8, 0);
+        data[..len / 8].to_vec()
+    }
+
+    fn read(name: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+        read_name(name).map(|(key, len)| (bytes(key), len))
+    }
+
+    fn suffix(domain: &str) -> Result<Vec<u8>, Error> {
+        suffix_key(domain).map(bytes)
+    }
+
+    fn query(name: &[u8]) -> Query {
+        let (name, _) = read_name(name).unwrap();
+        Query {
+            offset: 0,
+            question_len: 0,
+            qtype: TYPE_A,
+            qclass: CLASS_IN,
+            tcp: false,
+            name,
+        }
+    }
+
+    #[test]
+    fn reads_names_reversed_and_lower_cased() {
+        let name = read(b"\x03WwW\x03lmu\x02De\x00\x00\x01").unwrap();
+        assert_eq!(name, (b"\x02de\x03lmu\x03www".to_vec(), 12));
+        assert_eq!(read(b"\x00").unwrap(), (vec![], 1));
+    }
+
+    #[test]
+    fn rejects_pointers_and_unterminated_names() {
+        assert_eq!(read(b"\x03www\xc0\x0c"), Err(Error::Malformed));
+        assert_eq!(read(b"\x03www\x03lmu"), Err(Error::Malformed));
+        assert_eq!(read(b""), Err(Error::Malformed));
+        assert_eq!(read(&[1; 300]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn keeps_the_last_labels_of_long_names() {
+        let mut wire = Vec::new();
+        for _ in 0..3 {
+            wire.push(63);
+            wire.extend_from_slice(&[b'a'; 63]);
+        }
+        wire.extend_from_slice(b"\x02de\x00");
+        let (name, len) = read(&wire).unwrap();
+        assert_eq!(len, wire.len());
+        assert_eq!(name.len(), MAX_SUFFIX_LEN);
+        assert_eq!(name[..4], *b"\x02de\x3f");
+    }
+
+    #[test]
+    fn builds_suffix_keys() {
+        assert_eq!(suffix("www.LMU.de").unwrap(), b"\x02de\x03lmu\x03www");
+        assert_eq!(suffix("lmu.de.").unwrap(), b"\x02de\x03lmu");
+        assert_eq!(suffix("").unwrap(), b"");
+        assert_eq!(suffix(".").unwrap(), b"");
+        assert_eq!(suffix("lmu..de"), Err(Error::Malformed));
+        assert_eq!(suffix(&"a".repeat(64)), Err(Error::Malformed));
+        let long = ["a".repeat(63), "b".repeat(63), "de".into()].join(".");
+        assert_eq!(suffix(&long), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn encodes_wire_names() {
+        assert_eq!(wire_name("lmu.de"), b"\x03lmu\x02de\x00");
+        assert_eq!(wire_name("lmu.de."), b"\x03lmu\x02de\x00");
+        assert_eq!(wire_name("."), b"\x00");
+        let (name, len) = read(&wire_name("www.LMU.de")).unwrap();
+        assert_eq!(name, suffix("www.lmu.de").unwrap());
+        assert_eq!(len, 12);
+    }
+
+    #[test]
+    fn matches_whole_labels() {
+        let lmu = suffix_key("lmu.de").unwrap();
+        assert!(query(b"\x03lmu\x02de\x00").is_in(&lmu));
+        assert!(query(b"\x03www\x03LMU\x02de\x00").is_in(&lmu));
+        assert!(!query(b"\x06notlmu\x02de\x00").is_in(&lmu));
+        assert!(!query(b"\x02de\x00").is_in(&lmu));
+        assert!(query(b"\x02de\x00").is_in(&suffix_key("").unwrap()));
+    }
+
+    #[test]
+    fn caps_tcp_messages_at_the_segment() {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(b"\x03lmu\x02de\x00\x00\x01\x00\x01");
+        // the length prefix claims more than the segment holds
+        let mut payload = 512u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(&message);
+        let mut packet = PacketBuilder::new()
+            .ipv4([10, 0, 0, 2].into(), [10, 0, 0, 53].into())
+            .tcp_flags(40000, DNS_PORT, 0x18)
+            .payload(&payload)
+            .build();
+        packet.with_packet(|packet| {
+            let headers = packet.parse(LinkLayer::None).unwrap();
+            let query = Query::parse(packet, &headers).unwrap().unwrap();
+            assert!(query.tcp);
+            assert_eq!(query.offset, 42);
+            assert_eq!(query.question_len, message.len());
+            assert!(query.is_in(&suffix_key("lmu.de").unwrap()));
+        });
+    }
+}
@@ -0,0 +1,797 @@
+//! Encapsulation and decapsulation for `BPFRewriter` programs.
+//!
+//! Encapsulating makes room at the head of the packet with [`Packet::adjust_head`], moves the
+//! link-layer header (if any) to the new front and fills in the new headers, including their
+//! lengths and checksums. Decapsulating does the reverse and fixes the ether type of the link-layer
+//! header. IP-in-IP, GRE and VXLAN work on packets with and without an Ethernet header, see
+//! [`LinkLayer`]; VLAN and MPLS tags need one.
+//!
+//! ```ignore
+//! #[bpf_rewriter]
+//! fn try_rewrite(packet: &mut Packet) -> Result<RewriterResult, Error> {
+//!     let outer = OuterIp::V4 {
+//!         src: Ipv4Addr::new(192, 0, 2, 1),
+//!         dst: Ipv4Addr::new(192, 0, 2, 2),
+//!     };
+//!     encap::gre_encap(packet, LinkLayer::Ethernet, outer, Some(42))?;
+//!     Ok(RewriterResult::Success)
+//! }
+//! ```
+//!
+//! Only `BPFRewriter` registers `bpf_packet_add_space`, so only rewriters can use this module.
+//! Errors other than [`Error::Resize`] are returned before the packet is changed. Outer packets that are fragmented or carry
+//! IPv6 extension headers aren't decapsulated ([`Error::Unsupported`]).
+
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use network_types::eth::EthHdr;
+use network_types::ip::{Ipv4Hdr, Ipv6Hdr};
+use network_types::udp::UdpHdr;
+
+use crate::checksum::{self, finish, sum};
+use crate::parse::{
+    Headers, Layout, LinkLayer, VlanHdr, ETH_P_8021AD, ETH_P_8021Q, ETH_P_IPV4, ETH_P_IPV6,
+    ETH_P_MPLS_UC, IPPROTO_GRE, IPPROTO_IPIP, IPPROTO_IPV6, IPPROTO_UDP, L3, L4,
+};
+use crate::{Error, Packet};
+
+/// IANA-assigned VXLAN port (RFC 7348).
+pub const VXLAN_PORT: u16 = 4789;
+
+/// Length of the VXLAN header.
+const VXLAN_LEN: usize = 8;
+/// The I flag: the VNI is valid.
+const VXLAN_FLAG_VNI: u8 = 0x08;
+
+const GRE_LEN: usize = 4;
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_ROUTING: u16 = 0x4000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+/// Length of an MPLS label stack entry.
+const MPLS_LEN: usize = 4;
+/// Bottom of stack bit of a label stack entry.
+const MPLS_BOS: u32 = 0x100;
+
+/// Offset of the ether type, or of the first VLAN tag, in the Ethernet header.
+const ETH_TYPE_OFFSET: usize = 12;
+
+/// TTL / hop limit of outer IP headers.
+const OUTER_TTL: u8 = 64;
+
+/// Longest link-layer header moved when inserting or removing headers behind it: Ethernet with
+/// two VLAN tags. Keeps the copy a bounded loop.
+const MAX_LINK_LEN: usize = EthHdr::LEN + 2 * VlanHdr::LEN;
+
+/// Outer IP header of a tunnel.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OuterIp {
+    V4 { src: Ipv4Addr, dst: Ipv4Addr },
+    V6 { src: Ipv6Addr, dst: Ipv6Addr },
+}
+
+impl OuterIp {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        match self {
+            OuterIp::V4 { .. } => Ipv4Hdr::LEN,
+            OuterIp::V6 { .. } => Ipv6Hdr::LEN,
+        }
+    }
+
+    #[inline(always)]
+    fn ether_type(&self) -> u16 {
+        match self {
+            OuterIp::V4 { .. } => ETH_P_IPV4,
+            OuterIp::V6 { .. } => ETH_P_IPV6,
+        }
+    }
+
+    /// Fails if `payload_len` bytes don't fit into one packet.
+    #[inline(always)]
+    fn check_payload_len(&self, payload_len: usize) -> Result<(), Error> {
+        let max = match self {
+            OuterIp::V4 { .. } => u16::MAX as usize - Ipv4Hdr::LEN,
+            OuterIp::V6 { .. } => u16::MAX as usize,
+        };
+        if payload_len > max {
+            return Err(Error::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Writes the header at `offset`, followed by `payload_len` bytes of protocol `proto`.
+    #[inline(always)]
+    fn write(
+        &self,
+        packet: &mut Packet,
+        offset: usize,
+        proto: u8,
+        payload_len: usize,
+    ) -> Result<(), Error> {
+        match *self {
+            OuterIp::V4 { src, dst } => {
+                let mut hdr = [0u8; Ipv4Hdr::LEN];
+                hdr[0] = 0x45;
+                hdr[2..4].copy_from_slice(&((Ipv4Hdr::LEN + payload_len) as u16).to_be_bytes());
+                hdr[8] = OUTER_TTL;
+                hdr[9] = proto;
+                hdr[12..16].copy_from_slice(&src.octets());
+                hdr[16..20].copy_from_slice(&dst.octets());
+                let check = finish(sum(&hdr, 0));
+                hdr[10..12].copy_from_slice(&check.to_be_bytes());
+                *packet.load_mut::<[u8; Ipv4Hdr::LEN]>(offset)? = hdr;
+            }
+            OuterIp::V6 { src, dst } => {
+                let mut hdr = [0u8; Ipv6Hdr::LEN];
+                hdr[0] = 0x60;
+                hdr[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+                hdr[6] = proto;
+                hdr[7] = OUTER_TTL;
+                hdr[8..24].copy_from_slice(&src.octets());
+                hdr[24..40].copy_from_slice(&dst.octets());
+                *packet.load_mut::<[u8; Ipv6Hdr::LEN]>(offset)? = hdr;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pushes an 802.1Q ([`ETH_P_8021Q`]) or 802.1ad ([`ETH_P_8021AD`]) tag in front of any tags the
+/// frame already has. `tci` holds the priority, DEI and VLAN ID.
+#[inline(always)]
+pub fn vlan_push(packet: &mut Packet, tpid: u16, tci: u16) -> Result<(), Error> {
+    if tpid != ETH_P_8021Q && tpid != ETH_P_8021AD {
+        return Err(Error::Unsupported);
+    }
+    if packet.len() < EthHdr::LEN {
+        return Err(Error::OutOfBounds);
+    }
+
+    insert(packet, ETH_TYPE_OFFSET, VlanHdr::LEN)?;
+    // the tag's ether type is the one the frame had
+    *packet.load_mut::<u16>(ETH_TYPE_OFFSET)? = tpid.to_be();
+    *packet.load_mut::<u16>(ETH_TYPE_OFFSET + 2)? = tci.to_be();
+    Ok(())
+}
+
+/// Removes the outermost VLAN tag and returns its TCI.
+#[inline(always)]
+pub fn vlan_pop(packet: &mut Packet) -> Result<u16, Error> {
+    let tpid = u16::from_be(*packet.load::<u16>(ETH_TYPE_OFFSET)?);
+    if tpid != ETH_P_8021Q && tpid != ETH_P_8021AD {
+        return Err(Error::Unsupported);
+    }
+    let tci = u16::from_be(*packet.load::<u16>(ETH_TYPE_OFFSET + 2)?);
+
+    remove(packet, ETH_TYPE_OFFSET, VlanHdr::LEN)?;
+    Ok(tci)
+}
+
+/// Pushes an MPLS label stack entry behind the Ethernet header and its VLAN tags.
+///
+/// The bottom of stack bit is set if the packet isn't MPLS yet. `label` is 20 bits, `tc` 3 bits.
+#[inline(always)]
+pub fn mpls_push(packet: &mut Packet, label: u32, tc: u8, ttl: u8) -> Result<(), Error> {
+    let (link_len, ether_type) = link(packet, LinkLayer::Ethernet)?;
+    let bos = if ether_type == ETH_P_MPLS_UC {
+        0
+    } else {
+        MPLS_BOS
+    };
+    let entry = (label & 0xfffff) << 12 | ((tc & 0x7) as u32) << 9 | bos | ttl as u32;
+
+    insert(packet, link_len, MPLS_LEN)?;
+    *packet.load_mut::<u32>(link_len)? = entry.to_be();
+    set_ether_type(packet, link_len, ETH_P_MPLS_UC)
+}
+
+/// Removes the outermost MPLS label stack entry and returns its label.
+///
+/// Popping the bottom of the stack only works for IPv4 and IPv6 payloads, whose ether type is then
+/// taken from the IP version.
+#[inline(always)]
+pub fn mpls_pop(packet: &mut Packet) -> Result<u32, Error> {
+    let (link_len, ether_type) = link(packet, LinkLayer::Ethernet)?;
+    if ether_type != ETH_P_MPLS_UC {
+        return Err(Error::Unsupported);
+    }
+    let entry = u32::from_be(*packet.load::<u32>(link_len)?);
+    let next = if entry & MPLS_BOS != 0 {
+        ip_version(packet, link_len + MPLS_LEN)?
+    } else {
+        ETH_P_MPLS_UC
+    };
+
+    remove(packet, link_len, MPLS_LEN)?;
+    set_ether_type(packet, link_len, next)?;
+    Ok(entry >> 12)
+}
+
+/// Wraps the IPv4 or IPv6 packet in an outer IP header (IP-in-IP, RFC 2003, or IPv6 / IPv4 over
+/// IP, RFC 4213).
+#[inline(always)]
+pub fn ipip_encap(packet: &mut Packet, link_layer: LinkLayer, outer: OuterIp) -> Result<(), Error> {
+    let (link_len, inner) = inner_ip(packet, link_layer)?;
+    let proto = if inner == ETH_P_IPV4 {
+        IPPROTO_IPIP
+    } else {
+        IPPROTO_IPV6
+    };
+    let payload_len = packet.len() - link_len;
+    outer.check_payload_len(payload_len)?;
+
+    insert(packet, link_len, outer.len())?;
+    outer.write(packet, link_len, proto, payload_len)?;
+    set_ether_type(packet, link_len, outer.ether_type())
+}
+
+/// Removes the outer IP header of an IP-in-IP packet.
+#[inline(always)]
+pub fn ipip_decap(packet: &mut Packet, link_layer: LinkLayer) -> Result<(), Error> {
+    let (link_len, outer_end, proto) = outer_ip(packet, link_layer)?;
+    let inner = match proto {
+        IPPROTO_IPIP => ETH_P_IPV4,
+        IPPROTO_IPV6 => ETH_P_IPV6,
+        _ => return Err(Error::Unsupported),
+    };
+
+    remove(packet, link_len, outer_end - link_len)?;
+    set_ether_type(packet, link_len, inner)
+}
+
+/// Wraps the IPv4 or IPv6 packet in an outer IP and GRE header (RFC 2784), with a key (RFC 2890)
+/// if given.
+#[inline(always)]
+pub fn gre_encap(
+    packet: &mut Packet,
+    link_layer: LinkLayer,
+    outer: OuterIp,
+    key: Option<u32>,
+) -> Result<(), Error> {
+    let (link_len, inner) = inner_ip(packet, link_layer)?;
+    let gre_len = if key.is_some() { GRE_LEN + 4 } else { GRE_LEN };
+    let payload_len = packet.len() - link_len + gre_len;
+    outer.check_payload_len(payload_len)?;
+
+    insert(packet, link_len, outer.len() + gre_len)?;
+    outer.write(packet, link_len, IPPROTO_GRE, payload_len)?;
+
+    let gre = link_len + outer.len();
+    let flags: u16 = if key.is_some() { GRE_KEY } else { 0 };
+    let mut hdr = [0u8; GRE_LEN];
+    hdr[0..2].copy_from_slice(&flags.to_be_bytes());
+    hdr[2..4].copy_from_slice(&inner.to_be_bytes());
+    *packet.load_mut::<[u8; GRE_LEN]>(gre)? = hdr;
+    if let Some(key) = key {
+        *packet.load_mut::<u32>(gre + GRE_LEN)? = key.to_be();
+    }
+
+    set_ether_type(packet, link_len, outer.ether_type())
+}
+
+/// Removes the outer IP and GRE header of a GRE packet carrying IPv4 or IPv6 and returns the GRE
+/// key, if any. The GRE checksum isn't verified.
+#[inline(always)]
+pub fn gre_decap(packet: &mut Packet, link_layer: LinkLayer) -> Result<Option<u32>, Error> {
+    let (link_len, gre, proto) = outer_ip(packet, link_layer)?;
+    if proto != IPPROTO_GRE {
+        return Err(Error::Unsupported);
+    }
+
+    let flags = u16::from_be(*packet.load::<u16>(gre)?);
+    let inner = u16::from_be(*packet.load::<u16>(gre + 2)?);
+    if flags & (GRE_ROUTING | GRE_VERSION) != 0 || (inner != ETH_P_IPV4 && inner != ETH_P_IPV6) {
+        return Err(Error::Unsupported);
+    }
+
+    let mut gre_len = GRE_LEN;
+    if flags & GRE_CHECKSUM != 0 {
+        gre_len += 4;
+    }
+    let key = if flags & GRE_KEY != 0 {
+        let key = u32::from_be(*packet.load::<u32>(gre + gre_len)?);
+        gre_len += 4;
+        Some(key)
+    } else {
+        None
+    };
+    if flags & GRE_SEQUENCE != 0 {
+        gre_len += 4;
+    }
+    if packet.len() < gre + gre_len {
+        return Err(Error::OutOfBounds);
+    }
+
+    remove(packet, link_len, gre + gre_len - link_len)?;
+    set_ether_type(packet, link_len, inner)?;
+    Ok(key)
+}
+
+/// Wraps the Ethernet frame in VXLAN (RFC 7348): an outer Ethernet header if `outer_eth` gives its
+/// `(src, dst)` addresses, an outer IP header, UDP to [`VXLAN_PORT`] and the VXLAN header with the
+/// low 24 bits of `vni`.
+///
+/// `src_port` should be derived from the inner flow so that ECMP spreads tunnels over paths. The
+/// UDP checksum is left 0 over IPv4 and computed over IPv6.
+#[inline(always)]
+pub fn vxlan_encap(
+    packet: &mut Packet,
+    outer_eth: Option<([u8; 6], [u8; 6])>,
+    outer: OuterIp,
+    src_port: u16,
+    vni: u32,
+) -> Result<(), Error> {
+    if packet.len() < EthHdr::LEN {
+        return Err(Error::OutOfBounds);
+    }
+    let udp_len = UdpHdr::LEN + VXLAN_LEN + packet.len();
+    outer.check_payload_len(udp_len)?;
+
+    let l3_offset = if outer_eth.is_some() { EthHdr::LEN } else { 0 };
+    let l4_offset = l3_offset + outer.len();
+    insert(packet, 0, l4_offset + UdpHdr::LEN + VXLAN_LEN)?;
+
+    if let Some((src, dst)) = outer_eth {
+        let mut hdr = [0u8; EthHdr::LEN];
+        hdr[0..6].copy_from_slice(&dst);
+        hdr[6..12].copy_from_slice(&src);
+        hdr[12..14].copy_from_slice(&outer.ether_type().to_be_bytes());
+        *packet.load_mut::<[u8; EthHdr::LEN]>(0)? = hdr;
+    }
+    outer.write(packet, l3_offset, IPPROTO_UDP, udp_len)?;
+
+    let mut hdr = [0u8; UdpHdr::LEN + VXLAN_LEN];
+    hdr[0..2].copy_from_slice(&src_port.to_be_bytes());
+    hdr[2..4].copy_from_slice(&VXLAN_PORT.to_be_bytes());
+    hdr[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    hdr[8] = VXLAN_FLAG_VNI;
+    hdr[12..16].copy_from_slice(&((vni & 0xffffff) << 8).to_be_bytes());
+    *packet.load_mut::<[u8; UdpHdr::LEN + VXLAN_LEN]>(l4_offset)? = hdr;
+
+    if let OuterIp::V6 { .. } = outer {
+        // a zero UDP checksum isn't allowed over IPv6
+        let layout = Layout {
+            l3_offset,
+            l4_offset,
+            ip: ETH_P_IPV6,
+            l4: Some(IPPROTO_UDP),
+        };
+        checksum::set_l4_checksum(packet, &layout)?;
+    }
+    Ok(())
+}
+
+/// Removes everything up to and including the VXLAN header, leaving the inner Ethernet frame, and
+/// returns the VNI.
+#[inline(always)]
+pub fn vxlan_decap(packet: &mut Packet, link_layer: LinkLayer) -> Result<u32, Error> {
+    let vxlan = {
+        let headers = packet.parse(link_layer)?;
+        check_not_fragmented(&headers)?;
+        match headers.l4 {
+            L4::Udp(udp) if u16::from_be(udp.dest) == VXLAN_PORT => headers.payload_offset,
+            _ => return Err(Error::Unsupported),
+        }
+    };
+
+    let flags: &u8 = packet.load(vxlan)?;
+    if *flags & VXLAN_FLAG_VNI == 0 {
+        return Err(Error::Malformed);
+    }
+    let vni = u32::from_be(*packet.load::<u32>(vxlan + 4)?) >> 8;
+    if packet.len() < vxlan + VXLAN_LEN + EthHdr::LEN {
+        return Err(Error::OutOfBounds);
+    }
+
+    remove(packet, 0, vxlan + VXLAN_LEN)?;
+    Ok(vni)
+}
+
+/// Length of the link-layer header and the ether type it announces, 0 for both without one.
+#[inline(always)]
+fn link(packet: &Packet, link_layer: LinkLayer) -> Result<(usize, u16), Error> {
+    match link_layer {
+        LinkLayer::Ethernet => {
+            let mut cursor = packet.cursor();
+            let eth = cursor.ethernet()?;
+            Ok((cursor.offset(), eth.ether_type))
+        }
+        LinkLayer::None => Ok((0, 0)),
+    }
+}
+
+/// Sets the ether type in the last two bytes of the link-layer header, if there is one.
+#[inline(always)]
+fn set_ether_type(packet: &mut Packet, link_len: usize, ether_type: u16) -> Result<(), Error> {
+    if link_len > 0 {
+        *packet.load_mut::<u16>(link_len - 2)? = ether_type.to_be();
+    }
+    Ok(())
+}
+
+/// [`ETH_P_IPV4`] or [`ETH_P_IPV6`] after the IP version of the header at `offset`.
+#[inline(always)]
+fn ip_version(packet: &Packet, offset: usize) -> Result<u16, Error> {
+    let version: &u8 = packet.load(offset)?;
+    match *version >> 4 {
+        4 => Ok(ETH_P_IPV4),
+        6 => Ok(ETH_P_IPV6),
+        _ => Err(Error::Unsupported),
+    }
+}
+
+/// Length of the link-layer header and ether type of the IP packet following it.
+#[inline(always)]
+fn inner_ip(packet: &Packet, link_layer: LinkLayer) -> Result<(usize, u16), Error> {
+    let (link_len, ether_type) = link(packet, link_layer)?;
+    let version = ip_version(packet, link_len)?;
+    if link_layer == LinkLayer::Ethernet && ether_type != version {
+        return Err(Error::Unsupported);
+    }
+    Ok((link_len, version))
+}
+
+/// Length of the link-layer header, end of the outer IP header and the protocol following it.
+#[inline(always)]
+fn outer_ip(packet: &Packet, link_layer: LinkLayer) -> Result<(usize, usize, u8), Error> {
+    let headers = packet.parse(link_layer)?;
+    check_not_fragmented(&headers)?;
+    let proto = headers.protocol().ok_or(Error::Unsupported)?;
+    Ok((headers.l3_offset, headers.l4_offset, proto))
+}
+
+/// Tunnels carry whole inner packets, which a fragment doesn't.
+#[inline(always)]
+fn check_not_fragmented(headers: &Headers) -> Result<(), Error> {
+    match headers.l3 {
+        // more fragments or a fragment offset
+        L3::Ipv4(hdr) if u16::from_be(hdr.frag_off) & 0x3fff != 0 => Err(Error::Unsupported),
+        L3::Ipv4(_) => Ok(()),
+        // rejects fragment headers along with the other extension headers
+        L3::Ipv6(_) if headers.l4_offset - headers.l3_offset != Ipv6Hdr::LEN => {
+            Err(Error::Unsupported)
+        }
+        L3::Ipv6(_) => Ok(()),
+        L3::Other(_) => Err(Error::Unsupported),
+    }
+}
+
+/// Inserts `len` bytes after the first `at` bytes of the packet.
+#[inline(always)]
+fn insert(packet: &mut Packet, at: usize, len: usize) -> Result<(), Error> {
+    if at > MAX_LINK_LEN {
+        return Err(Error::Unsupported);
+    }
+    if packet.len() < at {
+        return Err(Error::OutOfBounds);
+    }
+
+    packet.adjust_head(len as i32)?;
+    for i in 0..MAX_LINK_LEN {
+        if i >= at {
+            break;
+        }
+        let byte: u8 = *packet.load(len + i)?;
+        *packet.load_mut::<u8>(i)? = byte;
+    }
+    Ok(())
+}
+
+/// Removes `len` bytes after the first `at` bytes of the packet.
+#[inline(always)]
+fn remove(packet: &mut Packet, at: usize, len: usize) -> Result<(), Error> {
+    if at > MAX_LINK_LEN {
+        return Err(Error::Unsupported);
+    }
+    if packet.len() < at + len {
+        return Err(Error::OutOfBounds);
+    }
+
+    // back to front, the ranges overlap if `len < at`
+    for i in (0..MAX_LINK_LEN).rev() {
+        if i >= at {
+            continue;
+        }
+        let byte: u8 = *packet.load(i)?;
+        *packet.load_mut::<u8>(len + i)? = byte;
+    }
+    packet.adjust_head(-(len as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::pseudo_header_v6;
+    use crate::testing::{PacketBuilder, TestPacket};
+
+    use super::*;
+
+    const SRC_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const DST_MAC: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+    const OUTER_V4: OuterIp = OuterIp::V4 {
+        src: Ipv4Addr::new(192, 0, 2, 1),
+        dst: Ipv4Addr::new(192, 0, 2, 2),
+    };
+    const OUTER_V6: OuterIp = OuterIp::V6 {
+        src: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+        dst: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2),
+    };
+
+    fn udp_v4() -> PacketBuilder {
+        PacketBuilder::new()
+            .ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+            .udp(1000, 2000)
+            .payload(b"hello")
+    }
+
+    fn tcp_v6() -> PacketBuilder {
+        PacketBuilder::new()
+            .ipv6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST)
+            .tcp(40000, 80)
+    }
+
+    fn ether_type(packet: &TestPacket) -> u16 {
+        u16::from_be_bytes([packet.data()[12], packet.data()[13]])
+    }
+
+    /// Checks the outer IPv4 header at `offset` and returns the protocol it carries.
+    fn check_outer_v4(data: &[u8], offset: usize) -> u8 {
+        let hdr = &data[offset..offset + Ipv4Hdr::LEN];
+        assert_eq!(hdr[0], 0x45);
+        assert_eq!(
+            u16::from_be_bytes([hdr[2], hdr[3]]) as usize,
+            data.len() - offset
+        );
+        assert_eq!(&hdr[12..16], &[192, 0, 2, 1]);
+        assert_eq!(&hdr[16..20], &[192, 0, 2, 2]);
+        assert_eq!(finish(sum(hdr, 0)), 0, "outer IPv4 checksum");
+        hdr[9]
+    }
+
+    #[test]
+    fn vlan_push_pop() {
+        let untagged = udp_v4().ethernet(SRC_MAC, DST_MAC);
+        let mut packet = untagged.build();
+
+        packet
+            .with_packet(|packet| vlan_push(packet, ETH_P_8021Q, 42))
+            .unwrap();
+        assert_eq!(packet.data(), untagged.clone().vlan(42).bytes());
+
+        // QinQ: the new tag goes in front
+        packet
+            .with_packet(|packet| vlan_push(packet, ETH_P_8021AD, 7))
+            .unwrap();
+        assert_eq!(ether_type(&packet), ETH_P_8021AD);
+        assert_eq!(packet.with_packet(vlan_pop), Ok(7));
+        assert_eq!(packet.with_packet(vlan_pop), Ok(42));
+        assert_eq!(packet.data(), untagged.bytes());
+
+        assert_eq!(packet.with_packet(vlan_pop), Err(Error::Unsupported));
+        assert_eq!(packet.data(), untagged.bytes());
+    }
+
+    #[test]
+    fn mpls_push_pop() {
+        let plain = tcp_v6().ethernet(SRC_MAC, DST_MAC).vlan(5);
+        let mut packet = plain.build();
+
+        packet
+            .with_packet(|packet| mpls_push(packet, 100, 0, 64))
+            .unwrap();
+        packet
+            .with_packet(|packet| mpls_push(packet, 0xfffff, 7, 1))
+            .unwrap();
+        let data = packet.data();
+        // behind the VLAN tag, which now announces MPLS
+        assert_eq!(&data[16..18], &ETH_P_MPLS_UC.to_be_bytes());
+        assert_eq!(
+            &data[18..22],
+            &(0xfffff << 12 | 7 << 9 | 1u32).to_be_bytes()
+        );
+        assert_eq!(&data[22..26], &(100 << 12 | MPLS_BOS | 64).to_be_bytes());
+
+        assert_eq!(packet.with_packet(mpls_pop), Ok(0xfffff));
+        assert_eq!(packet.data()[16..18], ETH_P_MPLS_UC.to_be_bytes());
+        assert_eq!(packet.with_packet(mpls_pop), Ok(100));
+        assert_eq!(packet.data(), plain.bytes());
+        assert_eq!(packet.with_packet(mpls_pop), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn ipip_without_link_layer() {
+        let inner = udp_v4().bytes();
+        let mut packet = TestPacket::new(&inner);
+
+        packet
+            .with_packet(|packet| ipip_encap(packet, LinkLayer::None, OUTER_V4))
+            .unwrap();
+        assert_eq!(check_outer_v4(packet.data(), 0), IPPROTO_IPIP);
+        assert_eq!(&packet.data()[Ipv4Hdr::LEN..], inner);
+
+        packet
+            .with_packet(|packet| ipip_decap(packet, LinkLayer::None))
+            .unwrap();
+        assert_eq!(packet.data(), inner);
+    }
+
+    #[test]
+    fn ipv6_over_ipv4_with_ethernet() {
+        let frame = tcp_v6().ethernet(SRC_MAC, DST_MAC);
+        let mut packet = frame.build();
+
+        packet
+            .with_packet(|packet| ipip_encap(packet, LinkLayer::Ethernet, OUTER_V4))
+            .unwrap();
+        assert_eq!(&packet.data()[..12], &frame.bytes()[..12]);
+        assert_eq!(ether_type(&packet), ETH_P_IPV4);
+        assert_eq!(check_outer_v4(packet.data(), EthHdr::LEN), IPPROTO_IPV6);
+
+        packet
+            .with_packet(|packet| ipip_decap(packet, LinkLayer::Ethernet))
+            .unwrap();
+        assert_eq!(packet.data(), frame.bytes());
+    }
+
+    #[test]
+    fn ipip_over_ipv6() {
+        let inner = udp_v4().bytes();
+        let mut packet = TestPacket::new(&inner);
+
+        packet
+            .with_packet(|packet| ipip_encap(packet, LinkLayer::None, OUTER_V6))
+            .unwrap();
+        let data = packet.data();
+        assert_eq!(data[0] >> 4, 6);
+        assert_eq!(u16::from_be_bytes([data[4], data[5]]) as usize, inner.len());
+        assert_eq!(data[6], IPPROTO_IPIP);
+
+        packet
+            .with_packet(|packet| ipip_decap(packet, LinkLayer::None))
+            .unwrap();
+        assert_eq!(packet.data(), inner);
+    }
+
+    #[test]
+    fn gre_with_key() {
+        let frame = udp_v4().ethernet(SRC_MAC, DST_MAC);
+        let mut packet = frame.build();
+
+        packet
+            .with_packet(|packet| gre_encap(packet, LinkLayer::Ethernet, OUTER_V4, Some(0xdead)))
+            .unwrap();
+        let gre = EthHdr::LEN + Ipv4Hdr::LEN;
+        assert_eq!(check_outer_v4(packet.data(), EthHdr::LEN), IPPROTO_GRE);
+        assert_eq!(
+            &packet.data()[gre..gre + 8],
+            &[0x20, 0, 0x08, 0x00, 0, 0, 0xde, 0xad]
+        );
+        assert_eq!(&packet.data()[gre + 8..], &frame.bytes()[EthHdr::LEN..]);
+
+        assert_eq!(
+            packet.with_packet(|packet| gre_decap(packet, LinkLayer::Ethernet)),
+            Ok(Some(0xdead))
+        );
+        assert_eq!(packet.data(), frame.bytes());
+    }
+
+    #[test]
+    fn gre_without_key() {
+        let inner = tcp_v6().bytes();
+        let mut packet = TestPacket::new(&inner);
+
+        packet
+            .with_packet(|packet| gre_encap(packet, LinkLayer::None, OUTER_V4, None))
+            .unwrap();
+        assert_eq!(&packet.data()[20..24], &[0, 0, 0x86, 0xdd]);
+        assert_eq!(
+            packet.with_packet(|packet| gre_decap(packet, LinkLayer::None)),
+            Ok(None)
+        );
+        assert_eq!(packet.data(), inner);
+    }
+
+    #[test]
+    fn vxlan_over_ipv4() {
+        let frame = udp_v4().ethernet(SRC_MAC, DST_MAC).bytes();
+        let mut packet = TestPacket::new(&frame);
+        let outer_eth = Some(([2, 0, 0, 0, 0, 0xa], [2, 0, 0, 0, 0, 0xb]));
+
+        packet
+            .with_packet(|packet| vxlan_encap(packet, outer_eth, OUTER_V4, 50000, 0x123456))
+            .unwrap();
+        let data = packet.data();
+        assert_eq!(&data[0..12], &[2, 0, 0, 0, 0, 0xb, 2, 0, 0, 0, 0, 0xa]);
+        assert_eq!(ether_type(&packet), ETH_P_IPV4);
+        assert_eq!(check_outer_v4(data, EthHdr::LEN), IPPROTO_UDP);
+        let udp = &data[34..50];
+        assert_eq!(udp[0..4], [0xc3, 0x50, 0x12, 0xb5]);
+        assert_eq!(
+            u16::from_be_bytes([udp[4], udp[5]]) as usize,
+            16 + frame.len()
+        );
+        // no UDP checksum over IPv4
+        assert_eq!(udp[6..8], [0, 0]);
+        assert_eq!(udp[8..16], [0x08, 0, 0, 0, 0x12, 0x34, 0x56, 0]);
+        assert_eq!(&data[50..], frame);
+
+        assert_eq!(
+            packet.with_packet(|packet| vxlan_decap(packet, LinkLayer::Ethernet)),
+            Ok(0x123456)
+        );
+        assert_eq!(packet.data(), frame);
+    }
+
+    #[test]
+    fn vxlan_over_ipv6() {
+        let frame = tcp_v6().ethernet(SRC_MAC, DST_MAC).bytes();
+        let mut packet = TestPacket::new(&frame);
+
+        // more than the test packet's headroom, so the buffer gets moved as well
+        packet
+            .with_packet(|packet| vxlan_encap(packet, Some((SRC_MAC, DST_MAC)), OUTER_V6, 50000, 7))
+            .unwrap();
+        let data = packet.data();
+        let segment = &data[EthHdr::LEN + Ipv6Hdr::LEN..];
+        let pseudo = pseudo_header_v6(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2),
+            IPPROTO_UDP,
+            segment.len() as u32,
+        );
+        assert_ne!(&segment[6..8], &[0, 0]);
+        assert_eq!(finish(sum(segment, pseudo)), 0, "UDP checksum");
+
+        assert_eq!(
+            packet.with_packet(|packet| vxlan_decap(packet, LinkLayer::Ethernet)),
+            Ok(7)
+        );
+        assert_eq!(packet.data(), frame);
+    }
+
+    #[test]
+    fn rejects_what_it_cant_handle() {
+        // not a tunnel
+        let plain = udp_v4().bytes();
+        let mut packet = TestPacket::new(&plain);
+        assert_eq!(
+            packet.with_packet(|packet| gre_decap(packet, LinkLayer::None)),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            packet.with_packet(|packet| ipip_decap(packet, LinkLayer::None)),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            packet.with_packet(|packet| vxlan_decap(packet, LinkLayer::None)),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(packet.data(), plain);
+
+        // not IP
+        let frame = PacketBuilder::new()
+            .ethernet(SRC_MAC, DST_MAC)
+            .payload(b"raw")
+            .bytes();
+        let mut packet = TestPacket::new(&frame);
+        assert_eq!(
+            packet.with_packet(|packet| ipip_encap(packet, LinkLayer::Ethernet, OUTER_V4)),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(packet.data(), frame);
+
+        // fragmented outer packet
+        let mut packet = TestPacket::new(&plain);
+        packet
+            .with_packet(|packet| ipip_encap(packet, LinkLayer::None, OUTER_V4))
+            .unwrap();
+        packet.data_mut()[6] = 0x20;
+        assert_eq!(
+            packet.with_packet(|packet| ipip_decap(packet, LinkLayer::None)),
+            Err(Error::Unsupported)
+        );
+    }
+}
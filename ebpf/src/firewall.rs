@@ -0,0 +1,356 @@
+//! Firewall rules over the 5-tuple: source and destination prefix, port ranges and protocol.
+//!
+//! Rules are plain data, built with `const fn`s so that programs generated from a rule file by
+//! `morphos-firewall` (see `runner`) spell them out as constants and the compiler drops the checks
+//! of the fields a rule doesn't restrict:
+//!
+//! ```ignore
+//! const SSH: Rule = Rule::new(FilterResult::Pass)
+//!     .protocol(IPPROTO_TCP)
+//!     .src(Prefix::v4([10, 0, 0, 0], 8))
+//!     .dst_ports(22, 22);
+//!
+//! let fields = Fields::of(&packet.parse(LinkLayer::Ethernet)?).ok_or(Error::Unsupported)?;
+//! if SSH.matches(&fields) {
+//!     return Ok(FilterResult::Pass);
+//! }
+//! ```
+//!
+//! The `firewall` program instead reads them from a map in the same layout, which `morphos-firewall`
+//! writes as bytes: keep [`Rule`] and the tool in sync.
+//!
+//! Addresses are IPv6; IPv4 addresses are compared as IPv4-mapped addresses (`::ffff:a.b.c.d`), so
+//! IPv4 prefixes never match IPv6 packets. IPv6 prefixes overlapping `::ffff:0:0/96`, like `::/0` or
+//! `::ffff:10.0.0.0/104`, match IPv4 packets, which is why `morphos-firewall` refuses them.
+
+use crate::filter::FilterResult;
+use crate::parse::{Headers, L3};
+
+/// [`Rule::protocol`] of rules for any protocol. 0 is IPv6 hop-by-hop options, which the parser
+/// never reports as the transport protocol.
+pub const ANY_PROTOCOL: u8 = 0;
+
+/// What a packet is matched on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Fields {
+    /// Source and destination address as two big-endian words, see [`Prefix`].
+    pub src: [u64; 2],
+    pub dst: [u64; 2],
+    pub protocol: u8,
+    /// Source and destination port of TCP and UDP packets.
+    pub ports: Option<(u16, u16)>,
+}
+
+impl Fields {
+    /// The fields of an IPv4 or IPv6 packet, `None` for anything else.
+    #[inline(always)]
+    pub fn of(headers: &Headers<'_>) -> Option<Fields> {
+        let (src, dst) = match headers.l3 {
+            L3::Ipv4(hdr) => (
+                mapped(u32::from_be(hdr.src_addr)),
+                mapped(u32::from_be(hdr.dst_addr)),
+            ),
+            L3::Ipv6(hdr) => (
+                words(hdr.src_addr().to_bits()),
+                words(hdr.dst_addr().to_bits()),
+            ),
+            L3::Other(_) => return None,
+        };
+        Some(Fields {
+            src,
+            dst,
+            protocol: headers.protocol()?,
+            ports: headers.ports(),
+        })
+    }
+}
+
+#[inline(always)]
+const fn words(addr: u128) -> [u64; 2] {
+    [(addr >> 64) as u64, addr as u64]
+}
+
+/// `::ffff:addr`.
+#[inline(always)]
+const fn mapped(addr: u32) -> [u64; 2] {
+    [0, 0xffff_0000_0000 | addr as u64]
+}
+
+/// An address prefix. IPv4 prefixes are stored as IPv4-mapped IPv6 prefixes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Prefix {
+    /// Address, with the bits outside of the mask cleared.
+    pub addr: [u64; 2],
+    pub mask: [u64; 2],
+}
+
+impl Prefix {
+    pub const ANY: Prefix = Prefix {
+        addr: [0; 2],
+        mask: [0; 2],
+    };
+
+    /// `addr/len`. Lengths above 128 count as 128.
+    pub const fn v6(addr: [u8; 16], len: u8) -> Prefix {
+        let len = if len > 128 { 128 } else { len as u32 };
+        let mask = match len {
+            0 => 0,
+            len => u128::MAX << (128 - len),
+        };
+        Prefix {
+            addr: words(u128::from_be_bytes(addr) & mask),
+            mask: words(mask),
+        }
+    }
+
+    /// `addr/len`. Lengths above 32 count as 32.
+    pub const fn v4(addr: [u8; 4], len: u8) -> Prefix {
+        let len = if len > 32 { 32 } else { len };
+        let [a, b, c, d] = addr;
+        Prefix::v6(
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d],
+            96 + len,
+        )
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, addr: &[u64; 2]) -> bool {
+        addr[0] & self.mask[0] == self.addr[0] && addr[1] & self.mask[1] == self.addr[1]
+    }
+}
+
+/// Ports `first..=last`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub const ANY: PortRange = PortRange {
+        first: 0,
+        last: u16::MAX,
+    };
+
+    #[inline(always)]
+    pub const fn is_any(&self) -> bool {
+        self.first == 0 && self.last == u16::MAX
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+}
+
+/// A rule and the [`FilterResult`] of the packets it matches.
+///
+/// 80 bytes in this order, little-endian, with 6 bytes of padding at the end.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Rule {
+    pub src: Prefix,
+    pub dst: Prefix,
+    pub src_ports: PortRange,
+    pub dst_ports: PortRange,
+    /// IP protocol number, or [`ANY_PROTOCOL`].
+    pub protocol: u8,
+    /// A [`FilterResult`]. Zeroed rules (`Abort`) mark the end of a rule table.
+    pub action: u8,
+}
+
+impl Rule {
+    /// Matches every IP packet.
+    pub const fn new(action: FilterResult) -> Rule {
+        Rule {
+            src: Prefix::ANY,
+            dst: Prefix::ANY,
+            src_ports: PortRange::ANY,
+            dst_ports: PortRange::ANY,
+            protocol: ANY_PROTOCOL,
+            action: action as u8,
+        }
+    }
+
+    pub const fn protocol(mut self, protocol: u8) -> Rule {
+        self.protocol = protocol;
+        self
+    }
+
+    pub const fn src(mut self, prefix: Prefix) -> Rule {
+        self.src = prefix;
+        self
+    }
+
+    pub const fn dst(mut self, prefix: Prefix) -> Rule {
+        self.dst = prefix;
+        self
+    }
+
+    pub const fn src_ports(mut self, first: u16, last: u16) -> Rule {
+        self.src_ports = PortRange { first, last };
+        self
+    }
+
+    pub const fn dst_ports(mut self, first: u16, last: u16) -> Rule {
+        self.dst_ports = PortRange { first, last };
+        self
+    }
+
+    /// The rule's [`FilterResult`], `None` for the end of a rule table and invalid actions.
+    #[inline(always)]
+    pub fn result(&self) -> Option<FilterResult> {
+        FilterResult::from_action(self.action as u32)
+    }
+
+    /// Rules that restrict ports only match TCP and UDP packets.
+    #[inline(always)]
+    pub fn matches(&self, fields: &Fields) -> bool {
+        if self.protocol != ANY_PROTOCOL && self.protocol != fields.protocol {
+            return false;
+        }
+        if !self.src.contains(&fields.src) || !self.dst.contains(&fields.dst) {
+            return false;
+        }
+        if self.src_ports.is_any() && self.dst_ports.is_any() {
+            return true;
+        }
+        match fields.ports {
+            Some((src, dst)) => self.src_ports.contains(src) && self.dst_ports.contains(dst),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::parse::{LinkLayer, IPPROTO_TCP as TCP, IPPROTO_UDP as UDP};
+    use crate::testing::PacketBuilder;
+
+    use super::*;
+
+    fn fields(packet: PacketBuilder) -> Option<Fields> {
+        packet
+            .build()
+            .with_packet(|packet| Fields::of(&packet.parse(LinkLayer::None).unwrap()))
+    }
+
+    fn tcp_v4(src: Ipv4Addr, dst: Ipv4Addr, dst_port: u16) -> Fields {
+        fields(PacketBuilder::new().ipv4(src, dst).tcp(40000, dst_port)).unwrap()
+    }
+
+    #[test]
+    fn reads_fields_of_ipv4_and_ipv6() {
+        let v4 = tcp_v4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(192, 0, 2, 1), 80);
+        assert_eq!(v4.src, [0, 0xffff_0a00_0001]);
+        assert_eq!(v4.dst, [0, 0xffff_c000_0201]);
+        assert_eq!(v4.protocol, TCP);
+        assert_eq!(v4.ports, Some((40000, 80)));
+
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let v6 = fields(
+            PacketBuilder::new()
+                .ipv6(src, Ipv6Addr::LOCALHOST)
+                .udp(53, 5353),
+        )
+        .unwrap();
+        assert_eq!(v6.src, [0x2001_0db8_0000_0000, 1]);
+        assert_eq!(v6.dst, [0, 1]);
+        assert_eq!(v6.protocol, UDP);
+        assert_eq!(v6.ports, Some((53, 5353)));
+
+        let ping = PacketBuilder::new()
+            .ipv4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)
+            .icmp_echo_request(1, 1);
+        assert_eq!(fields(ping).unwrap().ports, None);
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        let net = Prefix::v4([10, 1, 2, 3], 8);
+        assert_eq!(net, Prefix::v4([10, 0, 0, 0], 8));
+        let rule = Rule::new(FilterResult::Pass).src(net);
+        let inside = tcp_v4(Ipv4Addr::new(10, 255, 0, 1), Ipv4Addr::LOCALHOST, 80);
+        let outside = tcp_v4(Ipv4Addr::new(11, 0, 0, 1), Ipv4Addr::LOCALHOST, 80);
+        assert!(rule.matches(&inside));
+        assert!(!rule.matches(&outside));
+
+        let host = Rule::new(FilterResult::Pass).dst(Prefix::v4([127, 0, 0, 1], 32));
+        assert!(host.matches(&inside));
+        assert!(!host.matches(&tcp_v4(
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(127, 0, 0, 2),
+            80
+        )));
+
+        let v6 = Prefix::v6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0).octets(), 32);
+        assert!(v6.contains(&[0x2001_0db8_ffff_0000, 7]));
+        assert!(!v6.contains(&[0x2001_0db9_0000_0000, 0]));
+        assert!(Prefix::v6([0xff; 16], 128).contains(&[u64::MAX, u64::MAX]));
+    }
+
+    #[test]
+    fn keeps_address_families_apart() {
+        let v6 = fields(
+            PacketBuilder::new()
+                .ipv6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST)
+                .tcp(1, 2),
+        )
+        .unwrap();
+        let any_v4 = Rule::new(FilterResult::Drop).src(Prefix::v4([0, 0, 0, 0], 0));
+        assert!(!any_v4.matches(&v6));
+        assert!(any_v4.matches(&tcp_v4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, 1)));
+        assert!(Rule::new(FilterResult::Drop).matches(&v6));
+    }
+
+    #[test]
+    fn matches_protocols_and_port_ranges() {
+        let rule = Rule::new(FilterResult::Pass)
+            .protocol(TCP)
+            .dst_ports(1000, 1999);
+        assert!(rule.matches(&tcp_v4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, 1000)));
+        assert!(rule.matches(&tcp_v4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, 1999)));
+        assert!(!rule.matches(&tcp_v4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, 2000)));
+
+        let udp = fields(
+            PacketBuilder::new()
+                .ipv4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)
+                .udp(40000, 1000),
+        )
+        .unwrap();
+        assert!(!rule.matches(&udp));
+        assert!(!rule.src_ports(0, 39999).protocol(UDP).matches(&udp));
+    }
+
+    #[test]
+    fn port_rules_only_match_packets_with_ports() {
+        let ping = fields(
+            PacketBuilder::new()
+                .ipv4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)
+                .icmp_echo_request(1, 1),
+        )
+        .unwrap();
+        assert!(!Rule::new(FilterResult::Pass)
+            .dst_ports(0, 1000)
+            .matches(&ping));
+        assert!(Rule::new(FilterResult::Pass).matches(&ping));
+    }
+
+    #[test]
+    fn has_the_layout_of_morphos_firewall() {
+        assert_eq!(mem::size_of::<Rule>(), 80);
+        assert_eq!(mem::offset_of!(Rule, src_ports), 64);
+        assert_eq!(mem::offset_of!(Rule, protocol), 72);
+        assert_eq!(mem::offset_of!(Rule, action), 73);
+        assert_eq!(
+            Rule::new(FilterResult::Pass).result(),
+            Some(FilterResult::Pass)
+        );
+        assert_eq!(Rule::new(FilterResult::Abort).result(), None);
+    }
+}
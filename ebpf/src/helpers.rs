@@ -0,0 +1,186 @@
+//! The helper table MorphOS registers with the uBPF VM.
+//!
+//! `BPFElement::init_ubpf_vm` registers the helpers every element has, `BPFRewriter` adds
+//! `bpf_packet_add_space` on top. The verifier (`verifier/platform/gpl/spec_prototypes.cpp`) has to
+//! agree with both, so keep all three in sync with the table below.
+//!
+//! Programs call helpers through [`Helpers`], which is tied to the element kind the program is
+//! written for and only exposes the helpers that kind registers. Calling a helper the element
+//! doesn't have fails to compile instead of failing to load:
+//!
+//! ```compile_fail
+//! use bpf_element::helpers::{BPFilter, Helpers};
+//!
+//! const HELPERS: Helpers<BPFilter> = Helpers::new();
+//! unsafe { HELPERS.packet_add_space(-14, 0) };
+//! ```
+//!
+//! With the `std` feature, helpers are served by the mocks in [`crate::testing`] instead.
+
+use core::marker::PhantomData;
+
+use aya_ebpf::cty::{c_char, c_long, c_void};
+
+/// The Click element a program is loaded into.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ElementKind {
+    BPFilter,
+    BPFClassifier,
+    BPFRewriter,
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Type-level [`ElementKind`].
+pub trait Kind: sealed::Sealed {
+    const KIND: ElementKind;
+}
+
+/// Implemented by the element kinds that register helper `ID`.
+pub trait Has<const ID: u32>: Kind {}
+
+pub struct BPFilter;
+pub struct BPFClassifier;
+pub struct BPFRewriter;
+
+impl sealed::Sealed for BPFilter {}
+impl sealed::Sealed for BPFClassifier {}
+impl sealed::Sealed for BPFRewriter {}
+
+impl Kind for BPFilter {
+    const KIND: ElementKind = ElementKind::BPFilter;
+}
+
+impl Kind for BPFClassifier {
+    const KIND: ElementKind = ElementKind::BPFClassifier;
+}
+
+impl Kind for BPFRewriter {
+    const KIND: ElementKind = ElementKind::BPFRewriter;
+}
+
+/// One entry of [`TABLE`].
+pub struct HelperDef {
+    pub id: u32,
+    pub name: &'static str,
+    pub kinds: &'static [ElementKind],
+}
+
+impl HelperDef {
+    #[inline(always)]
+    pub const fn available_in(&self, kind: ElementKind) -> bool {
+        let mut i = 0;
+        while i < self.kinds.len() {
+            if self.kinds[i] as u8 == kind as u8 {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+}
+
+/// Helpers available to a program loaded into an element of kind `K`.
+#[derive(Copy, Clone)]
+pub struct Helpers<K: Kind> {
+    _kind: PhantomData<K>,
+}
+
+impl<K: Kind> Helpers<K> {
+    pub const fn new() -> Self {
+        Self { _kind: PhantomData }
+    }
+}
+
+impl<K: Kind> Default for Helpers<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+macro_rules! helpers {
+    ($(
+        $(#[$meta:meta])*
+        $id:literal => $name:ident / $const_name:ident ($($arg:ident: $ty:ty),*) -> $ret:ty
+            [$($kind:ident),+];
+    )*) => {
+        /// Helper IDs as registered with `ubpf_register`.
+        pub mod id {
+            $(pub const $const_name: u32 = $id;)*
+        }
+
+        /// Every helper MorphOS registers, with the element kinds that register it.
+        pub const TABLE: &[HelperDef] = &[
+            $(HelperDef {
+                id: $id,
+                name: concat!("bpf_", stringify!($name)),
+                kinds: &[$(ElementKind::$kind),+],
+            },)*
+        ];
+
+        $($(impl Has<$id> for $kind {})+)*
+
+        impl<K: Kind> Helpers<K> {
+            $(
+                $(#[$meta])*
+                #[inline(always)]
+                #[allow(clippy::missing_safety_doc)]
+                pub unsafe fn $name(&self, $($arg: $ty),*) -> $ret
+                where
+                    K: Has<$id>,
+                {
+                    #[cfg(not(any(test, feature = "std")))]
+                    let fun: unsafe extern "C" fn($($ty),*) -> $ret = core::mem::transmute($id as usize);
+                    // host tests call the mocks in `crate::testing` instead
+                    #[cfg(any(test, feature = "std"))]
+                    let fun: unsafe fn($($ty),*) -> $ret = crate::testing::mock::$name;
+                    fun($($arg),*)
+                }
+            )*
+        }
+    };
+}
+
+helpers! {
+    1 => map_lookup_elem / MAP_LOOKUP_ELEM (map: *mut c_void, key: *const c_void) -> *mut c_void
+        [BPFilter, BPFClassifier, BPFRewriter];
+    2 => map_update_elem / MAP_UPDATE_ELEM (map: *mut c_void, key: *const c_void, value: *const c_void, flags: u64) -> c_long
+        [BPFilter, BPFClassifier, BPFRewriter];
+    3 => map_delete_elem / MAP_DELETE_ELEM (map: *mut c_void, key: *const c_void) -> c_long
+        [BPFilter, BPFClassifier, BPFRewriter];
+    /// Monotonic clock in nanoseconds.
+    5 => ktime_get_ns / KTIME_GET_NS () -> u64
+        [BPFilter, BPFClassifier, BPFRewriter];
+    /// Prints `fmt` with up to three `%d` arguments. MorphOS reads the arguments through the
+    /// pointers, not by value.
+    6 => trace_printk / TRACE_PRINTK (fmt: *const c_char, fmt_size: u32, arg1: *const i32, arg2: *const i32, arg3: *const i32) -> c_long
+        [BPFilter, BPFClassifier, BPFRewriter];
+    7 => get_prandom_u32 / GET_PRANDOM_U32 () -> u32
+        [BPFilter, BPFClassifier, BPFRewriter];
+    /// Makes the element run program `index` of the `BPF_MAP_TYPE_PROG_ARRAY` `map` on the packet
+    /// once the calling program stopped, see [`crate::maps::ProgArray`]. Returns 0, or a negative
+    /// error if the slot is empty or the packet went through too many tail calls.
+    12 => tail_call / TAIL_CALL (ctx: *mut c_void, map: *mut c_void, index: u32) -> c_long
+        [BPFilter, BPFClassifier, BPFRewriter];
+    /// Returns its argument. uBPF stops the program when it returns 0.
+    20 => unwind / UNWIND (value: u64) -> u64
+        [BPFilter, BPFClassifier, BPFRewriter];
+    /// Grows (positive) or shrinks (negative) the packet at its head and tail and returns the new
+    /// start of the packet.
+    60 => packet_add_space / PACKET_ADD_SPACE (head_len: i32, tail_len: i32) -> *mut u8
+        [BPFRewriter];
+}
+
+const _: () = {
+    let mut i = 0;
+    while i < TABLE.len() {
+        let mut j = i + 1;
+        while j < TABLE.len() {
+            assert!(TABLE[i].id != TABLE[j].id, "helper IDs must be unique");
+            j += 1;
+        }
+        i += 1;
+    }
+};
@@ -1,12 +1,56 @@
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![allow(dead_code)]
 
+pub mod aho_corasick;
+pub mod checksum;
+pub mod dns;
+pub mod encap;
+pub mod firewall;
+pub mod helpers;
+pub mod log;
+pub mod maps;
+pub mod migration;
+mod packet;
+pub mod parse;
 mod programs;
+pub mod rewrite;
+pub mod telemetry;
+#[cfg(any(test, feature = "std"))]
+pub mod testing;
 
 use core::mem;
 
+pub use bpf_element_macros as macros;
+pub use packet::Packet;
 pub use programs::*;
 
+/// Why a program gave up on a packet.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum Error {
+    /// An access went past the end of the packet.
+    OutOfBounds = 1,
+    /// A header contradicts itself, e.g. an IPv4 IHL below 5.
+    Malformed = 2,
+    /// The packet is fine, but the program doesn't handle its protocol.
+    Unsupported = 3,
+    /// A map lookup or update failed.
+    Map = 4,
+    /// The element couldn't grow the packet and dropped it.
+    Resize = 5,
+}
+
+/// [`BpfContext`]'s accessors only fail on out-of-bounds accesses.
+impl From<()> for Error {
+    #[inline(always)]
+    fn from(_: ()) -> Self {
+        Error::OutOfBounds
+    }
+}
+
+/// Longest slice [`BpfContext::get_slice`] hands out. Loops over packet data are bounded by it.
+pub const MAX_SLICE_LEN: usize = 50000;
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct BpfContext {
@@ -48,7 +92,7 @@ impl BpfContext {
         let end = self.data_end as usize;
 
         // Limit the size of the slice to 50KB so the verifier doesn't complain
-        if len > 50000 {
+        if len > MAX_SLICE_LEN {
             return Err(());
         }
 
@@ -61,6 +105,7 @@ impl BpfContext {
     }
 }
 
+#[cfg(not(any(test, feature = "std")))]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
@@ -0,0 +1,376 @@
+//! Leveled event logging into a ring buffer the control plane reads, instead of
+//! `bpf_trace_printk`.
+//!
+//! ```ignore
+//! use bpf_element::log;
+//!
+//! log::warn!("no free port for {:ipv4}:{}", src_ip, src_port);
+//! log::debug!("next port {}", port);
+//! ```
+//!
+//! An event is not formatted in the program. The macros store a 32-bit ID of the format string,
+//! the level, a timestamp and up to [`MAX_ARGS`] arguments as `u64`s in the next [`Record`] of
+//! [`LOG`], an array of [`LOG_ENTRIES`] records used as a ring buffer: [`LOG_HEAD`] counts the
+//! events written so far, and the oldest records are overwritten. The format strings go into the
+//! `log_formats` section of the ELF file, which the element doesn't load, so `helper log` reads
+//! them from the program to render the records. Formats take `{}` (decimal), `{:x}` (hex) and
+//! `{:ipv4}` (an [`Ipv4Addr`] or a `u32` in host byte order), one per argument, checked at compile
+//! time.
+//!
+//! [`LOG_LEVEL`] sets the most verbose level recorded, [`Level::Info`] until the control plane
+//! writes it. `debug!` is compiled out entirely, arguments included, unless bpf-element is built
+//! with the `debug-log` feature (or for the host), so debug events cost nothing in production
+//! builds.
+
+use core::net::Ipv4Addr;
+
+use aya_ebpf::macros::map;
+
+use crate::helpers::{BPFilter, Helpers};
+use crate::maps::{Array, Config};
+
+const HELPERS: Helpers<BPFilter> = Helpers::new();
+
+/// Records [`LOG`] holds before overwriting the oldest. A power of two.
+pub const LOG_ENTRIES: u32 = 256;
+
+/// Arguments an event can have.
+pub const MAX_ARGS: usize = 4;
+
+/// How severe an event is. [`LOG_LEVEL`] holds the most verbose one recorded.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl Level {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// An event in [`LOG`]. The element and tools see 56 bytes: `seq`, `time_ns`, `id` and `level` in
+/// little-endian, a byte for `len`, two bytes padding and the arguments.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Record {
+    /// Number of the event plus one, so 0 marks an unused record.
+    pub seq: u64,
+    /// `bpf_ktime_get_ns` when it was logged.
+    pub time_ns: u64,
+    /// [`event_id`] of the format string.
+    pub id: u32,
+    /// A [`Level`].
+    pub level: u8,
+    /// Arguments used.
+    pub len: u8,
+    _padding: [u8; 2],
+    pub args: [u64; MAX_ARGS],
+}
+
+/// The ring buffer, record `seq - 1` at index `(seq - 1) % LOG_ENTRIES`.
+#[map(name = "LOG")]
+pub static LOG: Array<Record> = Array::with_max_entries(LOG_ENTRIES, 0);
+
+/// Events written so far.
+#[map(name = "LOG_HEAD")]
+pub static LOG_HEAD: Array<u64> = Array::with_max_entries(1, 0);
+
+/// The most verbose [`Level`] recorded, 0 for [`Level::Info`].
+#[map(name = "LOG_LEVEL")]
+pub static LOG_LEVEL: Config<u32> = Config::new();
+
+/// A value an event can carry.
+pub trait Arg {
+    fn to_arg(self) -> u64;
+}
+
+macro_rules! impl_arg {
+    ($($ty:ty),*) => {
+        $(impl Arg for $ty {
+            #[inline(always)]
+            fn to_arg(self) -> u64 {
+                self as u64
+            }
+        })*
+    };
+}
+
+impl_arg!(u8, u16, u32, u64, usize, bool);
+
+impl Arg for Ipv4Addr {
+    #[inline(always)]
+    fn to_arg(self) -> u64 {
+        u32::from(self) as u64
+    }
+}
+
+impl Arg for crate::Error {
+    #[inline(always)]
+    fn to_arg(self) -> u64 {
+        self as u64
+    }
+}
+
+/// FNV-1a hash of `format`, which identifies it in [`Record::id`].
+pub const fn event_id(format: &str) -> u32 {
+    let bytes = format.as_bytes();
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+/// Number of `{...}` placeholders in `format`.
+pub const fn placeholders(format: &str) -> usize {
+    let bytes = format.as_bytes();
+    let mut count = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'{' {
+            count += 1;
+        }
+        i += 1;
+    }
+    count
+}
+
+/// The `log_formats` entry of a format string: its length (2 bytes) and ID (4 bytes) in
+/// little-endian, then the string. `N` is the string's length plus 6.
+pub const fn format_entry<const N: usize>(id: u32, format: &str) -> [u8; N] {
+    let bytes = format.as_bytes();
+    assert!(bytes.len() + 6 == N && bytes.len() <= u16::MAX as usize);
+    let mut entry = [0; N];
+    let len = (bytes.len() as u16).to_le_bytes();
+    let id = id.to_le_bytes();
+    entry[0] = len[0];
+    entry[1] = len[1];
+    entry[2] = id[0];
+    entry[3] = id[1];
+    entry[4] = id[2];
+    entry[5] = id[3];
+    let mut i = 0;
+    while i < bytes.len() {
+        entry[6 + i] = bytes[i];
+        i += 1;
+    }
+    entry
+}
+
+/// Appends an event to [`LOG`] if [`LOG_LEVEL`] records `level`. Called by the macros.
+#[inline(always)]
+pub fn write(level: Level, id: u32, args: &[u64]) {
+    let max = match LOG_LEVEL.get() {
+        Ok(0) | Err(_) => Level::Info as u32,
+        Ok(&max) => max,
+    };
+    if level as u32 > max {
+        return;
+    }
+    let Some(head) = LOG_HEAD.get_ptr_mut(0) else {
+        return;
+    };
+    let seq = unsafe { *head };
+    let Some(record) = LOG.get_ptr_mut((seq % LOG_ENTRIES as u64) as u32) else {
+        return;
+    };
+    unsafe { *head = seq + 1 };
+
+    let record = unsafe { &mut *record };
+    record.seq = seq + 1;
+    record.time_ns = unsafe { HELPERS.ktime_get_ns() };
+    record.id = id;
+    record.level = level as u8;
+    record.len = args.len().min(MAX_ARGS) as u8;
+    record.args = [0; MAX_ARGS];
+    for (slot, arg) in record.args.iter_mut().zip(args) {
+        *slot = *arg;
+    }
+}
+
+/// Remembers the format behind an ID for [`crate::testing::take_log`]. Does nothing in programs.
+#[inline(always)]
+pub fn register(id: u32, format: &'static str) {
+    #[cfg(any(test, feature = "std"))]
+    crate::testing::register_format(id, format);
+    #[cfg(not(any(test, feature = "std")))]
+    let _ = (id, format);
+}
+
+/// Renders a format string with its arguments, as `helper log` does.
+#[cfg(any(test, feature = "std"))]
+pub fn render(format: &str, args: &[u64]) -> String {
+    let mut line = String::new();
+    let mut args = args.iter();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        line.push_str(&rest[..start]);
+        let arg = args.next().copied().unwrap_or_default();
+        match &rest[start + 1..start + end] {
+            ":x" => line.push_str(&format!("{arg:#x}")),
+            ":ipv4" => line.push_str(&Ipv4Addr::from(arg as u32).to_string()),
+            _ => line.push_str(&arg.to_string()),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    line.push_str(rest);
+    line
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_event {
+    ($level:expr, $format:literal $(, $arg:expr)* $(,)?) => {{
+        const FORMAT: &str = $format;
+        const ID: u32 = $crate::log::event_id(FORMAT);
+        const ARGS: usize = <[&str]>::len(&[$(stringify!($arg)),*]);
+        const _: () = assert!(ARGS <= $crate::log::MAX_ARGS, "too many log arguments");
+        const _: () = assert!(
+            $crate::log::placeholders(FORMAT) == ARGS,
+            "the format needs one placeholder per argument"
+        );
+        #[link_section = "log_formats"]
+        #[used]
+        static FORMAT_ENTRY: [u8; FORMAT.len() + 6] = $crate::log::format_entry(ID, FORMAT);
+        $crate::log::register(ID, FORMAT);
+        $crate::log::write($level, ID, &[$($crate::log::Arg::to_arg($arg)),*]);
+    }};
+}
+
+/// Logs an event at [`Level::Error`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_error {
+    ($($args:tt)*) => {
+        $crate::__log_event!($crate::log::Level::Error, $($args)*)
+    };
+}
+
+/// Logs an event at [`Level::Warn`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warn {
+    ($($args:tt)*) => {
+        $crate::__log_event!($crate::log::Level::Warn, $($args)*)
+    };
+}
+
+/// Logs an event at [`Level::Info`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_info {
+    ($($args:tt)*) => {
+        $crate::__log_event!($crate::log::Level::Info, $($args)*)
+    };
+}
+
+/// Logs an event at [`Level::Debug`].
+#[cfg(any(feature = "debug-log", feature = "std", test))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_debug {
+    ($($args:tt)*) => {
+        $crate::__log_event!($crate::log::Level::Debug, $($args)*)
+    };
+}
+
+/// Compiled out without the `debug-log` feature; the arguments are neither evaluated nor stored.
+#[cfg(not(any(feature = "debug-log", feature = "std", test)))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_debug {
+    ($format:literal $(, $arg:expr)* $(,)?) => {
+        if false {
+            $(let _ = $arg;)*
+        }
+    };
+}
+
+pub use __log_debug as debug;
+pub use __log_error as error;
+pub use __log_info as info;
+pub use __log_warn as warn;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{set_time_ns, take_log};
+
+    #[test]
+    fn records_events_in_order() {
+        set_time_ns(1_500_000);
+        error!(
+            "port {} of {:ipv4} is taken",
+            8080u16,
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        warn!("no arguments");
+        info!("flags {:x}", 0x12u8);
+
+        assert_eq!(
+            take_log(),
+            [
+                "ERROR port 8080 of 10.0.0.1 is taken",
+                "WARN no arguments",
+                "INFO flags 0x12",
+            ]
+        );
+        let record = LOG.get(0).unwrap();
+        assert_eq!((record.seq, record.time_ns, record.len), (1, 1_500_000, 2));
+        assert_eq!(record.id, event_id("port {} of {:ipv4} is taken"));
+        assert_eq!(LOG_HEAD.get(0), Some(&3));
+    }
+
+    #[test]
+    fn filters_by_level() {
+        debug!("hidden {}", 1u32);
+        LOG_LEVEL.set(Level::Debug as u32);
+        debug!("shown {}", 2u32);
+        LOG_LEVEL.set(Level::Error as u32);
+        warn!("hidden");
+        error!("failed with {}", crate::Error::Map);
+
+        assert_eq!(take_log(), ["DEBUG shown 2", "ERROR failed with 4"]);
+    }
+
+    #[test]
+    fn overwrites_the_oldest_records() {
+        for i in 0..LOG_ENTRIES as u64 + 3 {
+            info!("event {}", i);
+        }
+        assert_eq!(LOG.get(0).unwrap().seq, LOG_ENTRIES as u64 + 1);
+        let lines = take_log();
+        assert_eq!(lines.len(), LOG_ENTRIES as usize);
+        assert_eq!(lines[0], "INFO event 3");
+    }
+
+    #[test]
+    fn encodes_format_entries() {
+        let entry: [u8; 9] = format_entry(0x0403_0201, "a{}");
+        assert_eq!(entry, [3, 0, 1, 2, 3, 4, b'a', b'{', b'}']);
+        assert_eq!(placeholders("{} and {:x}"), 2);
+        // FNV-1a test vector
+        assert_eq!(event_id("a"), 0xe40c_292c);
+    }
+
+    #[test]
+    fn keeps_unclosed_placeholders() {
+        assert_eq!(render("{:x} of {", &[255, 1]), "0xff of {");
+    }
+}
@@ -0,0 +1,334 @@
+//! Map helpers on top of `aya_ebpf::maps`.
+//!
+//! Programs take [`HashMap`], [`LruHashMap`], [`LpmTrie`] and [`Array`] from here rather than from
+//! `aya_ebpf`.
+//! They are aya's maps when building for the element, and in-memory maps with the same API when
+//! building with the `std` feature for host tests (see [`crate::testing`]).
+//!
+//! An [`LruHashMap`] never runs full: inserting a new key into a map with `max_entries` entries
+//! evicts the one looked up or updated longest ago. Tables of flows or clients use it, so traffic
+//! can't lock out new flows by filling the map. Evicting an entry invalidates pointers to its
+//! value, so don't hold them across inserts.
+//!
+//! An [`LpmTrie`] looks keys up by longest prefix match: an [`LpmKey`] is a prefix length and the
+//! data, and a lookup finds the value of the longest stored prefix of the key's first
+//! `prefix_len` bits. Inserting a new prefix into a trie holding `max_entries` fails with
+//! `-ENOSPC`. [`IpPrefixMap`] wraps one for IPv4 and IPv6 prefixes, e.g. for ACLs:
+//!
+//! ```ignore
+//! #[map(name = "PREFIXES")]
+//! static PREFIXES: IpPrefixMap<u32> = IpPrefixMap::with_max_entries(65536, 0);
+//!
+//! let (src, _) = headers.addrs().ok_or(Error::Unsupported)?;
+//! if let Some(action) = PREFIXES.get(src) { ... }
+//! ```
+//!
+//! The verifier only understands map keys that are plain numbers or byte arrays, not structs.
+//! [`MapKey`] packs a struct into such a key and back:
+//!
+//! ```ignore
+//! #[derive(Copy, Clone, MapKey)]
+//! struct Flow {
+//!     src_ip: Ipv4Addr,
+//!     dst_ip: Ipv4Addr,
+//!     src_port: u16,
+//!     dst_port: u16,
+//!     protocol: u8,
+//! }
+//!
+//! #[map(name = "FLOWS")]
+//! static FLOWS: HashMap<<Flow as MapKey>::Key, u64> = HashMap::with_max_entries(1024, 0);
+//!
+//! FLOWS.get(&flow.to_key());
+//! ```
+//!
+//! Fields are packed in declaration order, the first field in the most significant bits. If they
+//! fit into 128 bits, the key is the smallest of `u8` to `u128` that holds them. Otherwise it's a
+//! `[u8; N]` with every field rounded up to whole bytes, big-endian. `#[key(bits = N)]` narrows a
+//! field to `N` bits; bits above that are cut off when packing.
+//!
+//! Parameters that differ between deployments, like addresses and ports, belong in a [`Config`]
+//! rather than in constants, so one signed binary serves them all. By convention the map is named
+//! `CONFIG` and holds a `#[repr(C)]` struct the control plane writes:
+//!
+//! ```ignore
+//! #[repr(C)]
+//! struct Settings {
+//!     /// Network byte order, 0 for the default.
+//!     port: u16,
+//! }
+//!
+//! #[map(name = "CONFIG")]
+//! static CONFIG: Config<Settings> = Config::new();
+//!
+//! let port = match CONFIG.get()?.port {
+//!     0 => DEFAULT_PORT,
+//!     port => u16::from_be(port),
+//! };
+//! ```
+//!
+//! The map is zeroed until it is written, so a zero field must mean the program's default.
+//!
+//! A [`ProgArray`] composes programs within one element: a parser can hand the packet to the
+//! handler for its protocol, and the element swaps handlers without reloading the parser. The
+//! element fills the slots with the programs it was configured with (`TAIL_CALL` in the Click
+//! configuration), all of which share the maps of the main program by name:
+//!
+//! ```ignore
+//! #[map(name = "HANDLERS")]
+//! static HANDLERS: ProgArray = ProgArray::with_max_entries(256, 0);
+//!
+//! // doesn't return if a handler is loaded for the protocol
+//! let _ = HANDLERS.tail_call(packet, proto as u32);
+//! Ok(FilterResult::Pass)
+//! ```
+
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use aya_ebpf::cty::c_void;
+
+use crate::helpers::{BPFilter, Helpers};
+use crate::{Error, Packet};
+
+#[cfg(not(any(test, feature = "std")))]
+pub use aya_ebpf::maps::{
+    lpm_trie::Key as LpmKey, Array, HashMap, LpmTrie, LruHashMap, ProgramArray,
+};
+pub use bpf_element_macros::MapKey;
+
+#[cfg(any(test, feature = "std"))]
+mod host;
+#[cfg(any(test, feature = "std"))]
+pub use host::{Array, HashMap, LpmKey, LpmTrie, LruHashMap, ProgramArray};
+
+/// Every element registers the helpers [`ProgArray`] needs, whatever the program is written for.
+const HELPERS: Helpers<BPFilter> = Helpers::new();
+
+/// A read-only value set from outside the program, backed by an [`Array`] with one entry.
+///
+/// It has the layout of the array, so the element and tools see an ordinary array map with
+/// 4-byte key `0`.
+#[repr(transparent)]
+pub struct Config<T> {
+    array: Array<T>,
+}
+
+impl<T> Config<T> {
+    pub const fn new() -> Config<T> {
+        Config {
+            array: Array::with_max_entries(1, 0),
+        }
+    }
+
+    /// The current value, all zeroes if it was never written.
+    #[inline(always)]
+    pub fn get(&self) -> Result<&T, Error> {
+        self.array.get(0).ok_or(Error::Map)
+    }
+
+    /// Writes the value, as the control plane does. Only exists on the host.
+    #[cfg(any(test, feature = "std"))]
+    pub fn set(&self, value: T) {
+        let slot = self.array.get_ptr_mut(0).unwrap();
+        unsafe { slot.write(value) };
+    }
+}
+
+impl<T> Default for Config<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Values by IPv4 and IPv6 prefix, looked up by address with longest prefix match.
+///
+/// It's an [`LpmTrie`] whose data is the address family (4 or 6) followed by the address, padded
+/// to 16 bytes, so the families don't overlap: even `::/0` only matches IPv6 addresses. The element
+/// and tools see 21-byte keys: the prefix length plus 8, the family and the address.
+#[repr(transparent)]
+pub struct IpPrefixMap<V> {
+    trie: LpmTrie<[u8; 17], V>,
+}
+
+impl<V> IpPrefixMap<V> {
+    pub const fn with_max_entries(max_entries: u32, flags: u32) -> IpPrefixMap<V> {
+        IpPrefixMap {
+            trie: LpmTrie::with_max_entries(max_entries, flags),
+        }
+    }
+
+    /// Value of the longest prefix containing `addr`.
+    #[inline(always)]
+    pub fn get(&self, addr: IpAddr) -> Option<&V> {
+        self.trie.get(&prefix_key(addr, 128))
+    }
+
+    /// Inserts or overwrites `addr/len`. Lengths past the address count as the whole address.
+    #[inline(always)]
+    pub fn insert(&self, addr: IpAddr, len: u8, value: &V) -> Result<(), Error> {
+        self.trie
+            .insert(&prefix_key(addr, len), value, 0)
+            .map_err(|_| Error::Map)
+    }
+
+    /// Removes exactly `addr/len`.
+    #[inline(always)]
+    pub fn remove(&self, addr: IpAddr, len: u8) -> Result<(), Error> {
+        self.trie
+            .remove(&prefix_key(addr, len))
+            .map_err(|_| Error::Map)
+    }
+}
+
+/// Tail calls a packet can go through, as in the kernel. Further ones fail.
+pub const MAX_TAIL_CALLS: u32 = 33;
+
+/// Programs to hand the packet to, a `BPF_MAP_TYPE_PROG_ARRAY`.
+///
+/// Programs can't write it: the element puts the programs it loads with `TAIL_CALL` into their
+/// slots, as 4-byte numbers the control plane sees, and the control plane can only empty slots.
+/// Programs jumped to must be written for the same kind of element.
+#[repr(transparent)]
+pub struct ProgArray {
+    programs: ProgramArray,
+}
+
+impl ProgArray {
+    pub const fn with_max_entries(max_entries: u32, flags: u32) -> ProgArray {
+        ProgArray {
+            programs: ProgramArray::with_max_entries(max_entries, flags),
+        }
+    }
+
+    /// Slots of the array. Only exists on the host.
+    #[cfg(any(test, feature = "std"))]
+    pub fn max_entries(&self) -> u32 {
+        self.programs.max_entries()
+    }
+
+    /// Hands the packet to the program in slot `index`, whose verdict is the element's. Doesn't
+    /// return if there is one: the element stops this program and runs that one on the packet,
+    /// as it is now. Fails with [`Error::Map`] if the slot is empty or the packet went through
+    /// [`MAX_TAIL_CALLS`] already, and the program carries on.
+    ///
+    /// On the host, it returns `Ok(())` after a jump, and [`crate::testing::TestPacket::run`] runs
+    /// the program in the slot once the entry point returned, discarding its verdict.
+    #[inline(always)]
+    pub fn tail_call(&self, packet: &Packet, index: u32) -> Result<(), Error> {
+        let ctx = packet.ctx() as *const _ as *mut c_void;
+        let map = &self.programs as *const ProgramArray as *mut c_void;
+        if unsafe { HELPERS.tail_call(ctx, map, index) } != 0 {
+            return Err(Error::Map);
+        }
+        // uBPF ends the program when unwind returns 0, the element takes it from there
+        unsafe { HELPERS.unwind(0) };
+        Ok(())
+    }
+}
+
+/// Address family byte of [`IpPrefixMap`] keys.
+const FAMILY_IPV4: u8 = 4;
+const FAMILY_IPV6: u8 = 6;
+
+#[inline(always)]
+fn prefix_key(addr: IpAddr, len: u8) -> LpmKey<[u8; 17]> {
+    let mut data = [0; 17];
+    let len = match addr {
+        IpAddr::V4(addr) => {
+            data[0] = FAMILY_IPV4;
+            data[1..5].copy_from_slice(&addr.octets());
+            len.min(32)
+        }
+        IpAddr::V6(addr) => {
+            data[0] = FAMILY_IPV6;
+            data[1..].copy_from_slice(&addr.octets());
+            len.min(128)
+        }
+    };
+    LpmKey::new(8 + len as u32, data)
+}
+
+/// A struct that can be used as a map key through its packed [`MapKey::Key`].
+///
+/// Derive it with `#[derive(MapKey)]` rather than implementing it by hand.
+pub trait MapKey: Sized {
+    /// The packed key, a `u8` to `u128` or a byte array.
+    type Key: Copy;
+
+    fn to_key(&self) -> Self::Key;
+
+    fn from_key(key: Self::Key) -> Self;
+}
+
+/// A fixed-width value that can be a field of a [`MapKey`].
+pub trait KeyField: Copy {
+    /// Width of the value. `#[key(bits = N)]` may narrow it, but not widen it.
+    const BITS: u32;
+
+    fn to_bits(self) -> u128;
+
+    /// Builds the value from its lowest [`KeyField::BITS`] bits. Higher bits are zero.
+    fn from_bits(bits: u128) -> Self;
+}
+
+macro_rules! key_field_int {
+    ($($ty:ty),*) => {
+        $(impl KeyField for $ty {
+            const BITS: u32 = <$ty>::BITS;
+
+            #[inline(always)]
+            fn to_bits(self) -> u128 {
+                self as u128
+            }
+
+            #[inline(always)]
+            fn from_bits(bits: u128) -> Self {
+                bits as $ty
+            }
+        })*
+    };
+}
+
+key_field_int!(u8, u16, u32, u64, u128);
+
+impl KeyField for bool {
+    const BITS: u32 = 1;
+
+    #[inline(always)]
+    fn to_bits(self) -> u128 {
+        self as u128
+    }
+
+    #[inline(always)]
+    fn from_bits(bits: u128) -> Self {
+        bits != 0
+    }
+}
+
+impl KeyField for Ipv4Addr {
+    const BITS: u32 = 32;
+
+    #[inline(always)]
+    fn to_bits(self) -> u128 {
+        u32::from(self) as u128
+    }
+
+    #[inline(always)]
+    fn from_bits(bits: u128) -> Self {
+        Ipv4Addr::from(bits as u32)
+    }
+}
+
+impl KeyField for Ipv6Addr {
+    const BITS: u32 = 128;
+
+    #[inline(always)]
+    fn to_bits(self) -> u128 {
+        u128::from(self)
+    }
+
+    #[inline(always)]
+    fn from_bits(bits: u128) -> Self {
+        Ipv6Addr::from(bits)
+    }
+}
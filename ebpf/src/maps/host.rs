@@ -0,0 +1,427 @@
+//! In-memory maps for host tests, with the API of their `aya_ebpf::maps` counterparts.
+//!
+//! Every test thread sees its own, initially empty contents of each map, so tests of one program
+//! don't leak state into each other even though the maps are `static`s. Like MorphOS' maps (see
+//! `libs/ubpf/helper/bpf_helpers.cc`), hash maps don't enforce `max_entries`, LPM tries refuse
+//! new prefixes when they are full, LRU hash maps evict the least recently used entry instead, and
+//! array entries start out zeroed.
+
+use core::alloc::Layout;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::{mem, ptr, slice};
+use std::alloc;
+use std::collections::{BTreeMap, HashMap as StdHashMap};
+
+use aya_ebpf::cty::c_long;
+
+enum Storage {
+    /// Raw key bytes to a heap allocation holding one value. LPM tries store their keys with the
+    /// bits past the prefix length cleared.
+    Hash(BTreeMap<Vec<u8>, *mut u8>),
+    /// Like `Hash`, with the tick of each entry's last use, and the current tick.
+    Lru(BTreeMap<Vec<u8>, (*mut u8, u64)>, u64),
+    /// `max_entries` zeroed values.
+    Array(*mut u8),
+}
+
+thread_local! {
+    /// Contents of each map on this thread, by address of the map.
+    static MAPS: RefCell<StdHashMap<usize, Storage>> = RefCell::new(StdHashMap::new());
+}
+
+#[inline]
+fn bytes_of<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Allocation that holds at least one byte, so zero-sized values still get a unique address.
+#[inline]
+fn layout_of<T>(count: usize) -> Layout {
+    let layout = Layout::array::<T>(count).expect("map too large");
+    Layout::from_size_align(layout.size().max(1), layout.align()).unwrap()
+}
+
+pub struct HashMap<K, V> {
+    max_entries: u32,
+    _types: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Sync, V: Sync> Sync for HashMap<K, V> {}
+
+impl<K, V> HashMap<K, V> {
+    pub const fn with_max_entries(max_entries: u32, _flags: u32) -> HashMap<K, V> {
+        HashMap {
+            max_entries,
+            _types: PhantomData,
+        }
+    }
+
+    pub const fn pinned(max_entries: u32, flags: u32) -> HashMap<K, V> {
+        Self::with_max_entries(max_entries, flags)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut BTreeMap<Vec<u8>, *mut u8>) -> R) -> R {
+        MAPS.with(|maps| {
+            let mut maps = maps.borrow_mut();
+            let storage = maps
+                .entry(self as *const Self as usize)
+                .or_insert_with(|| Storage::Hash(BTreeMap::new()));
+            match storage {
+                Storage::Hash(entries) => f(entries),
+                _ => unreachable!("map address reused"),
+            }
+        })
+    }
+
+    /// # Safety
+    ///
+    /// The reference must not outlive a [`HashMap::remove`] of the key.
+    #[inline]
+    pub unsafe fn get(&self, key: &K) -> Option<&V> {
+        self.get_ptr(key).map(|value| &*value)
+    }
+
+    #[inline]
+    pub fn get_ptr(&self, key: &K) -> Option<*const V> {
+        self.get_ptr_mut(key).map(|value| value as *const V)
+    }
+
+    #[inline]
+    pub fn get_ptr_mut(&self, key: &K) -> Option<*mut V> {
+        self.with(|entries| entries.get(bytes_of(key)).map(|value| *value as *mut V))
+    }
+
+    /// Inserts or overwrites `key`. Overwriting keeps the value's address, as in MorphOS.
+    #[inline]
+    pub fn insert(&self, key: &K, value: &V, _flags: u64) -> Result<(), c_long> {
+        self.with(|entries| {
+            let slot = *entries
+                .entry(bytes_of(key).to_vec())
+                .or_insert_with(|| unsafe { alloc::alloc(layout_of::<V>(1)) });
+            unsafe {
+                ptr::copy_nonoverlapping(value as *const V, slot as *mut V, 1);
+            }
+        });
+        Ok(())
+    }
+
+    #[inline]
+    pub fn remove(&self, key: &K) -> Result<(), c_long> {
+        if let Some(value) = self.with(|entries| entries.remove(bytes_of(key))) {
+            unsafe { alloc::dealloc(value, layout_of::<V>(1)) };
+        }
+        Ok(())
+    }
+
+    /// Number of entries on this thread. Only exists on the host.
+    pub fn len(&self) -> usize {
+        self.with(|entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn max_entries(&self) -> u32 {
+        self.max_entries
+    }
+}
+
+pub struct LruHashMap<K, V> {
+    max_entries: u32,
+    _types: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Sync, V: Sync> Sync for LruHashMap<K, V> {}
+
+impl<K, V> LruHashMap<K, V> {
+    pub const fn with_max_entries(max_entries: u32, _flags: u32) -> LruHashMap<K, V> {
+        LruHashMap {
+            max_entries,
+            _types: PhantomData,
+        }
+    }
+
+    pub const fn pinned(max_entries: u32, flags: u32) -> LruHashMap<K, V> {
+        Self::with_max_entries(max_entries, flags)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut BTreeMap<Vec<u8>, (*mut u8, u64)>, &mut u64) -> R) -> R {
+        MAPS.with(|maps| {
+            let mut maps = maps.borrow_mut();
+            let storage = maps
+                .entry(self as *const Self as usize)
+                .or_insert_with(|| Storage::Lru(BTreeMap::new(), 0));
+            match storage {
+                Storage::Lru(entries, tick) => f(entries, tick),
+                _ => unreachable!("map address reused"),
+            }
+        })
+    }
+
+    /// # Safety
+    ///
+    /// The reference must not outlive a [`LruHashMap::remove`] of the key, or its eviction.
+    #[inline]
+    pub unsafe fn get(&self, key: &K) -> Option<&V> {
+        self.get_ptr(key).map(|value| &*value)
+    }
+
+    #[inline]
+    pub fn get_ptr(&self, key: &K) -> Option<*const V> {
+        self.get_ptr_mut(key).map(|value| value as *const V)
+    }
+
+    /// Counts as a use of the entry.
+    #[inline]
+    pub fn get_ptr_mut(&self, key: &K) -> Option<*mut V> {
+        self.with(|entries, tick| {
+            let (value, used) = entries.get_mut(bytes_of(key))?;
+            *tick += 1;
+            *used = *tick;
+            Some(*value as *mut V)
+        })
+    }
+
+    /// Inserts or overwrites `key`, evicting the least recently used entry if the map is full.
+    #[inline]
+    pub fn insert(&self, key: &K, value: &V, _flags: u64) -> Result<(), c_long> {
+        self.with(|entries, tick| {
+            let key = bytes_of(key);
+            if !entries.contains_key(key) && entries.len() >= self.max_entries as usize {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(key, _)| key.clone());
+                if let Some((evicted, _)) = oldest.and_then(|oldest| entries.remove(&oldest)) {
+                    unsafe { alloc::dealloc(evicted, layout_of::<V>(1)) };
+                }
+            }
+            *tick += 1;
+            let (slot, used) = entries
+                .entry(key.to_vec())
+                .or_insert_with(|| (unsafe { alloc::alloc(layout_of::<V>(1)) }, 0));
+            *used = *tick;
+            unsafe {
+                ptr::copy_nonoverlapping(value as *const V, *slot as *mut V, 1);
+            }
+        });
+        Ok(())
+    }
+
+    #[inline]
+    pub fn remove(&self, key: &K) -> Result<(), c_long> {
+        if let Some((value, _)) = self.with(|entries, _| entries.remove(bytes_of(key))) {
+            unsafe { alloc::dealloc(value, layout_of::<V>(1)) };
+        }
+        Ok(())
+    }
+
+    /// Number of entries on this thread. Only exists on the host.
+    pub fn len(&self) -> usize {
+        self.with(|entries, _| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn max_entries(&self) -> u32 {
+        self.max_entries
+    }
+}
+
+/// Key of an [`LpmTrie`], like `aya_ebpf::maps::lpm_trie::Key`.
+#[repr(C, packed)]
+pub struct LpmKey<K> {
+    /// Number of leading bits of `data` that count.
+    pub prefix_len: u32,
+    pub data: K,
+}
+
+impl<K> LpmKey<K> {
+    pub fn new(prefix_len: u32, data: K) -> Self {
+        Self { prefix_len, data }
+    }
+}
+
+/// `data` with the bits past the first `len` cleared, after `len`, as MorphOS stores LPM keys.
+fn prefix_key(len: u32, data: &[u8]) -> Vec<u8> {
+    let mut key = len.to_le_bytes().to_vec();
+    key.extend(data.iter().enumerate().map(|(i, &byte)| {
+        let bits = len.saturating_sub(8 * i as u32).min(8);
+        byte & !0xffu8.checked_shr(bits).unwrap_or(0)
+    }));
+    key
+}
+
+pub struct LpmTrie<K, V> {
+    max_entries: u32,
+    _types: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Sync, V: Sync> Sync for LpmTrie<K, V> {}
+
+impl<K, V> LpmTrie<K, V> {
+    pub const fn with_max_entries(max_entries: u32, _flags: u32) -> LpmTrie<K, V> {
+        LpmTrie {
+            max_entries,
+            _types: PhantomData,
+        }
+    }
+
+    pub const fn pinned(max_entries: u32, flags: u32) -> LpmTrie<K, V> {
+        Self::with_max_entries(max_entries, flags)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut BTreeMap<Vec<u8>, *mut u8>) -> R) -> R {
+        MAPS.with(|maps| {
+            let mut maps = maps.borrow_mut();
+            let storage = maps
+                .entry(self as *const Self as usize)
+                .or_insert_with(|| Storage::Hash(BTreeMap::new()));
+            match storage {
+                Storage::Hash(entries) => f(entries),
+                _ => unreachable!("map address reused"),
+            }
+        })
+    }
+
+    /// The key's prefix length, and its data.
+    fn split(key: &LpmKey<K>) -> (u32, &[u8]) {
+        let data = &bytes_of(key)[mem::size_of::<u32>()..];
+        (key.prefix_len, data)
+    }
+
+    /// Value of the longest stored prefix of the key's first `prefix_len` bits.
+    #[inline]
+    pub fn get(&self, key: &LpmKey<K>) -> Option<&V> {
+        let (len, data) = Self::split(key);
+        let len = len.min(8 * data.len() as u32);
+        self.with(|entries| {
+            (0..=len)
+                .rev()
+                .find_map(|len| entries.get(&prefix_key(len, data)))
+                .map(|value| unsafe { &*(*value as *const V) })
+        })
+    }
+
+    /// Inserts or overwrites the prefix. Fails with `-EINVAL` for prefixes longer than the key's
+    /// data and `-ENOSPC` for new prefixes once `max_entries` are stored.
+    #[inline]
+    pub fn insert(&self, key: &LpmKey<K>, value: &V, _flags: u64) -> Result<(), c_long> {
+        let (len, data) = Self::split(key);
+        if len > 8 * data.len() as u32 {
+            return Err(-22);
+        }
+        self.with(|entries| {
+            let key = prefix_key(len, data);
+            if !entries.contains_key(&key) && entries.len() >= self.max_entries as usize {
+                return Err(-28);
+            }
+            let slot = *entries
+                .entry(key)
+                .or_insert_with(|| unsafe { alloc::alloc(layout_of::<V>(1)) });
+            unsafe {
+                ptr::copy_nonoverlapping(value as *const V, slot as *mut V, 1);
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes exactly this prefix.
+    #[inline]
+    pub fn remove(&self, key: &LpmKey<K>) -> Result<(), c_long> {
+        let (len, data) = Self::split(key);
+        if let Some(value) = self.with(|entries| entries.remove(&prefix_key(len, data))) {
+            unsafe { alloc::dealloc(value, layout_of::<V>(1)) };
+        }
+        Ok(())
+    }
+
+    /// Number of entries on this thread. Only exists on the host.
+    pub fn len(&self) -> usize {
+        self.with(|entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn max_entries(&self) -> u32 {
+        self.max_entries
+    }
+}
+
+pub struct Array<T> {
+    max_entries: u32,
+    _type: PhantomData<T>,
+}
+
+unsafe impl<T: Sync> Sync for Array<T> {}
+
+impl<T> Array<T> {
+    pub const fn with_max_entries(max_entries: u32, _flags: u32) -> Array<T> {
+        Array {
+            max_entries,
+            _type: PhantomData,
+        }
+    }
+
+    pub const fn pinned(max_entries: u32, flags: u32) -> Array<T> {
+        Self::with_max_entries(max_entries, flags)
+    }
+
+    fn data(&self) -> *mut T {
+        MAPS.with(|maps| {
+            let mut maps = maps.borrow_mut();
+            let storage = maps.entry(self as *const Self as usize).or_insert_with(|| {
+                let layout = layout_of::<T>(self.max_entries as usize);
+                Storage::Array(unsafe { alloc::alloc_zeroed(layout) })
+            });
+            match storage {
+                Storage::Array(data) => *data as *mut T,
+                _ => unreachable!("map address reused"),
+            }
+        })
+    }
+
+    #[inline]
+    pub fn get(&self, index: u32) -> Option<&T> {
+        self.get_ptr(index).map(|value| unsafe { &*value })
+    }
+
+    #[inline]
+    pub fn get_ptr(&self, index: u32) -> Option<*const T> {
+        self.get_ptr_mut(index).map(|value| value as *const T)
+    }
+
+    #[inline]
+    pub fn get_ptr_mut(&self, index: u32) -> Option<*mut T> {
+        if index >= self.max_entries {
+            return None;
+        }
+        Some(unsafe { self.data().add(index as usize) })
+    }
+}
+
+/// Its slots hold no programs until a test puts them there with
+/// [`crate::testing::load_program`], which also keeps them per test thread.
+pub struct ProgramArray {
+    max_entries: u32,
+}
+
+impl ProgramArray {
+    pub const fn with_max_entries(max_entries: u32, _flags: u32) -> ProgramArray {
+        ProgramArray { max_entries }
+    }
+
+    pub const fn pinned(max_entries: u32, flags: u32) -> ProgramArray {
+        Self::with_max_entries(max_entries, flags)
+    }
+
+    pub fn max_entries(&self) -> u32 {
+        self.max_entries
+    }
+}
@@ -0,0 +1,161 @@
+//! Migrating map contents when the element swaps in a new version of a program.
+//!
+//! The element keeps maps across reconfigurations by name, so a new program finds the state of the
+//! old one as long as the definitions match. When the layout changes, e.g. a value grows a field,
+//! the new program declares the version of its layout and how to get there from older ones:
+//!
+//! ```ignore
+//! struct Maps;
+//!
+//! impl Schema for Maps {
+//!     const VERSION: u32 = 2;
+//!
+//!     fn migrate(from: u32) -> Result<u32, Error> {
+//!         match from {
+//!             // maps of a program without a schema, or none at all
+//!             0 | 1 => {
+//!                 copy_counters_v1_to_v2()?;
+//!                 Ok(2)
+//!             }
+//!             _ => Err(Error::Unsupported),
+//!         }
+//!     }
+//! }
+//!
+//! #[bpf_filter(schema = Maps)]
+//! fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> { ... }
+//! ```
+//!
+//! Maps survive reloading the program, so a program that changes their layout needs a new version
+//! and a migration into maps with new names, rather than reinterpreting the old ones in place.
+//! Programs test their own migration steps; refusing versions they don't know is common to all.
+//!
+//! The version the maps are at lives in the [`SCHEMA_VERSION`] map. Programs that reference it,
+//! which `schema = ...` does, are called once after loading with [`MIGRATE_PORT`] as the input port
+//! and an empty packet, before they see the first packet and with packet processing stopped. The
+//! entry point runs [`migrate`] then instead of the program, so packets only pay for comparing the
+//! port.
+//!
+//! If migrating fails, the element goes back to the old program, which finds its maps untouched as
+//! long as migrations only write to maps of the new layout, under new names. [`SCHEMA_VERSION`]
+//! is only bumped after all steps succeeded, so the next attempt starts over.
+
+use aya_ebpf::macros::map;
+
+use crate::maps::Array;
+use crate::Error;
+
+/// Input port of the call that migrates the maps, one no element has.
+pub const MIGRATE_PORT: u32 = u32::MAX;
+
+/// Most migration steps from any version to the current one.
+pub const MAX_STEPS: u32 = 16;
+
+/// Version of the layout the maps are at, 0 if no program with a [`Schema`] ran yet.
+#[map(name = "SCHEMA_VERSION")]
+pub static SCHEMA_VERSION: Array<u32> = Array::with_max_entries(1, 0);
+
+/// Layout versions of a program's maps.
+pub trait Schema {
+    /// Version of the layout the program uses, at least 1.
+    const VERSION: u32;
+
+    /// Migrates the maps from version `from` to a later (or, for downgrades, earlier) one and
+    /// returns it. Fails with [`Error::Unsupported`] for versions it can't migrate from.
+    fn migrate(from: u32) -> Result<u32, Error>;
+}
+
+/// Brings the maps to `S::VERSION`, one [`Schema::migrate`] step at a time.
+#[inline(always)]
+pub fn migrate<S: Schema>() -> Result<(), Error> {
+    let version = SCHEMA_VERSION.get_ptr_mut(0).ok_or(Error::Map)?;
+    let mut current = unsafe { *version };
+    for _ in 0..MAX_STEPS {
+        if current == S::VERSION {
+            unsafe { *version = current };
+            return Ok(());
+        }
+        current = S::migrate(current)?;
+    }
+    Err(Error::Unsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static V1: Array<u32> = Array::with_max_entries(1, 0);
+    static V2: Array<u64> = Array::with_max_entries(1, 0);
+
+    struct Counter;
+
+    impl Schema for Counter {
+        const VERSION: u32 = 3;
+
+        fn migrate(from: u32) -> Result<u32, Error> {
+            match from {
+                0 => {
+                    let old = V1.get(0).ok_or(Error::Map)?;
+                    let new = V2.get_ptr_mut(0).ok_or(Error::Map)?;
+                    unsafe { *new = *old as u64 };
+                    Ok(2)
+                }
+                // 2 to 3 changed nothing in the maps
+                2 => Ok(3),
+                _ => Err(Error::Unsupported),
+            }
+        }
+    }
+
+    /// Never gets anywhere.
+    struct Loop;
+
+    impl Schema for Loop {
+        const VERSION: u32 = 2;
+
+        fn migrate(from: u32) -> Result<u32, Error> {
+            Ok(from)
+        }
+    }
+
+    fn set_version(version: u32) {
+        unsafe { *SCHEMA_VERSION.get_ptr_mut(0).unwrap() = version };
+    }
+
+    #[test]
+    fn migrates_step_by_step() {
+        unsafe { *V1.get_ptr_mut(0).unwrap() = 7 };
+        assert_eq!(migrate::<Counter>(), Ok(()));
+        assert_eq!(V2.get(0), Some(&7));
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&3));
+    }
+
+    #[test]
+    fn current_maps_stay_untouched() {
+        set_version(3);
+        unsafe { *V1.get_ptr_mut(0).unwrap() = 7 };
+        assert_eq!(migrate::<Counter>(), Ok(()));
+        assert_eq!(V2.get(0), Some(&0));
+    }
+
+    #[test]
+    fn keeps_version_on_failure() {
+        set_version(1);
+        assert_eq!(migrate::<Counter>(), Err(Error::Unsupported));
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&1));
+
+        set_version(0);
+        assert_eq!(migrate::<Loop>(), Err(Error::Unsupported));
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&0));
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        // left behind by a program that was rolled back
+        set_version(4);
+        unsafe { *V1.get_ptr_mut(0).unwrap() = 7 };
+        assert_eq!(migrate::<Counter>(), Err(Error::Unsupported));
+        assert_eq!(SCHEMA_VERSION.get(0), Some(&4));
+        assert_eq!(V2.get(0), Some(&0));
+    }
+}
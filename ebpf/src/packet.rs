@@ -0,0 +1,85 @@
+use crate::parse::{self, Cursor, Headers, LinkLayer, Quoted};
+use crate::{BpfContext, Error};
+
+/// The packet a program runs on.
+///
+/// Entry points generated by [`crate::macros`] hand a `Packet` to the program function. All
+/// accessors are bounds-checked through [`BpfContext`], so programs don't need `unsafe` to read or
+/// rewrite headers.
+#[repr(transparent)]
+pub struct Packet {
+    ctx: BpfContext,
+}
+
+impl Packet {
+    /// # Safety
+    ///
+    /// `ctx.data..ctx.data_end` must be the packet buffer the element passed to the program.
+    #[inline(always)]
+    pub unsafe fn new(ctx: BpfContext) -> Self {
+        Self { ctx }
+    }
+
+    /// Input port of the element the packet arrived on.
+    #[inline(always)]
+    pub fn port(&self) -> u32 {
+        self.ctx.port
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.ctx.data_end as usize - self.ctx.data as usize
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline(always)]
+    pub fn load<T>(&self, offset: usize) -> Result<&T, Error> {
+        let ptr: *const T = unsafe { self.ctx.get_ptr(offset)? };
+        Ok(unsafe { &*ptr })
+    }
+
+    #[inline(always)]
+    pub fn load_mut<T>(&mut self, offset: usize) -> Result<&mut T, Error> {
+        let ptr: *mut T = unsafe { self.ctx.get_ptr_mut(offset)? };
+        Ok(unsafe { &mut *ptr })
+    }
+
+    #[inline(always)]
+    pub fn slice(&self, offset: usize, len: usize) -> Result<&[u8], Error> {
+        Ok(unsafe { self.ctx.get_slice(len, offset)? })
+    }
+
+    #[inline(always)]
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor::new(&self.ctx)
+    }
+
+    #[inline(always)]
+    pub fn parse(&self, link: LinkLayer) -> Result<Headers<'_>, Error> {
+        parse::parse(&self.ctx, link)
+    }
+
+    /// The packet quoted by an ICMP error, see [`parse::parse_icmp_error`].
+    #[inline(always)]
+    pub fn parse_icmp_error(&self, headers: &Headers<'_>) -> Result<Option<Quoted<'_>>, Error> {
+        parse::parse_icmp_error(&self.ctx, headers)
+    }
+
+    #[inline(always)]
+    pub fn ctx(&self) -> &BpfContext {
+        &self.ctx
+    }
+
+    /// # Safety
+    ///
+    /// `data` and `data_end` must keep pointing at the packet buffer, e.g. as updated by
+    /// [`crate::rewriter::bpf_packet_add_space`].
+    #[inline(always)]
+    pub unsafe fn ctx_mut(&mut self) -> &mut BpfContext {
+        &mut self.ctx
+    }
+}
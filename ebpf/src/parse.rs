@@ -0,0 +1,612 @@
+//! Header parsing on top of [`BpfContext::get_ptr`].
+//!
+//! [`Cursor`] walks a packet front to back and hands out typed references to its headers. Every
+//! access goes through `get_ptr`, so it is bounds-checked against `data_end` right before the
+//! header is touched, which is the pattern the verifier can follow. [`parse`] uses the cursor to
+//! walk Ethernet, up to two 802.1Q/802.1ad tags, IPv4 (honouring IHL) or IPv6 (skipping extension
+//! headers) and TCP/UDP/ICMP.
+
+use core::mem;
+use core::net::IpAddr;
+
+use network_types::eth::EthHdr;
+use network_types::icmp::IcmpHdr;
+use network_types::ip::{Ipv4Hdr, Ipv6Hdr};
+use network_types::tcp::TcpHdr;
+use network_types::udp::UdpHdr;
+
+use crate::{BpfContext, Error};
+
+pub const ETH_P_IPV4: u16 = 0x0800;
+pub const ETH_P_IPV6: u16 = 0x86DD;
+pub const ETH_P_8021Q: u16 = 0x8100;
+pub const ETH_P_8021AD: u16 = 0x88A8;
+pub const ETH_P_MPLS_UC: u16 = 0x8847;
+
+pub const IPPROTO_HOPOPTS: u8 = 0;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_IPIP: u8 = 4;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_IPV6: u8 = 41;
+pub const IPPROTO_ROUTING: u8 = 43;
+pub const IPPROTO_FRAGMENT: u8 = 44;
+pub const IPPROTO_GRE: u8 = 47;
+pub const IPPROTO_AH: u8 = 51;
+pub const IPPROTO_ICMPV6: u8 = 58;
+pub const IPPROTO_DSTOPTS: u8 = 60;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACH: u8 = 3;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_PARAMETERPROB: u8 = 12;
+
+/// Number of IPv6 extension headers [`Cursor::ipv6_ext`] follows before giving up. Keeps the walk
+/// a bounded loop for the verifier.
+pub const IPV6_MAX_EXT_HEADERS: usize = 6;
+
+/// 802.1Q / 802.1ad tag, i.e. the four bytes following the addresses of a tagged frame.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct VlanHdr {
+    pub tci: u16,
+    pub ether_type: u16,
+}
+
+impl VlanHdr {
+    pub const LEN: usize = mem::size_of::<VlanHdr>();
+
+    #[inline(always)]
+    pub fn vid(&self) -> u16 {
+        u16::from_be(self.tci) & 0x0fff
+    }
+}
+
+/// Common prefix of the hop-by-hop, routing, destination options and authentication headers.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Ipv6ExtHdr {
+    pub next_hdr: u8,
+    pub hdr_len: u8,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Ipv6FragHdr {
+    pub next_hdr: u8,
+    pub reserved: u8,
+    pub frag_off: u16,
+    pub id: u32,
+}
+
+impl Ipv6FragHdr {
+    pub const LEN: usize = mem::size_of::<Ipv6FragHdr>();
+
+    /// Whether this is the first fragment, i.e. whether it carries the transport header.
+    #[inline(always)]
+    pub fn is_first(&self) -> bool {
+        u16::from_be(self.frag_off) & 0xfff8 == 0
+    }
+}
+
+/// What the first byte of the packet is.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LinkLayer {
+    /// The packet starts with an Ethernet header.
+    Ethernet = 1,
+    /// The Ethernet header has been stripped (e.g. by `StripEtherVLANHeader`) and the packet
+    /// starts with the IP header.
+    None = 0,
+}
+
+impl LinkLayer {
+    /// The link layer a program's `Config` sets by value, so a zeroed one means
+    /// [`LinkLayer::None`].
+    #[inline(always)]
+    pub fn from_config(value: u32) -> Option<LinkLayer> {
+        match value {
+            0 => Some(LinkLayer::None),
+            1 => Some(LinkLayer::Ethernet),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Ethernet<'a> {
+    pub hdr: &'a EthHdr,
+    /// Outer and inner VLAN tag, if present.
+    pub vlans: [Option<&'a VlanHdr>; 2],
+    /// Ether type of the payload following the VLAN tags, in host byte order.
+    pub ether_type: u16,
+}
+
+#[derive(Copy, Clone)]
+pub enum L3<'a> {
+    Ipv4(&'a Ipv4Hdr),
+    Ipv6(&'a Ipv6Hdr),
+    /// Anything that isn't IP. Carries the ether type (host byte order), or 0 without Ethernet.
+    Other(u16),
+}
+
+#[derive(Copy, Clone)]
+pub enum L4<'a> {
+    Tcp(&'a TcpHdr),
+    Udp(&'a UdpHdr),
+    Icmp(&'a IcmpHdr),
+    /// No transport header was parsed: either an unknown protocol or a non-first fragment.
+    /// Carries the IP protocol number.
+    Other(u8),
+    /// The packet isn't IP.
+    None,
+}
+
+/// Walks a packet one header at a time.
+#[derive(Copy, Clone)]
+pub struct Cursor<'a> {
+    ctx: &'a BpfContext,
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    #[inline(always)]
+    pub fn new(ctx: &'a BpfContext) -> Self {
+        Self { ctx, offset: 0 }
+    }
+
+    #[inline(always)]
+    pub fn at(ctx: &'a BpfContext, offset: usize) -> Self {
+        Self { ctx, offset }
+    }
+
+    /// Offset of the next header from the start of the packet.
+    #[inline(always)]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the header at the current offset without advancing.
+    #[inline(always)]
+    pub fn peek<T>(&self) -> Result<&'a T, Error> {
+        let ptr: *const T = unsafe { self.ctx.get_ptr(self.offset)? };
+        Ok(unsafe { &*ptr })
+    }
+
+    /// Returns the header at the current offset and advances past it.
+    #[inline(always)]
+    pub fn read<T>(&mut self) -> Result<&'a T, Error> {
+        let hdr = self.peek::<T>()?;
+        self.offset += mem::size_of::<T>();
+        Ok(hdr)
+    }
+
+    #[inline(always)]
+    pub fn skip(&mut self, len: usize) {
+        self.offset += len;
+    }
+
+    /// Parses an Ethernet header and up to two VLAN tags.
+    #[inline(always)]
+    pub fn ethernet(&mut self) -> Result<Ethernet<'a>, Error> {
+        // Read the ether type as a plain integer: tagged frames carry values `EtherType` can't
+        // represent.
+        let ether_type: &u16 = Cursor::at(self.ctx, self.offset + 12).peek()?;
+        let mut ether_type = u16::from_be(*ether_type);
+        let hdr = self.read::<EthHdr>()?;
+
+        let mut vlans = [None, None];
+        for vlan in vlans.iter_mut() {
+            if ether_type != ETH_P_8021Q && ether_type != ETH_P_8021AD {
+                break;
+            }
+            let tag = self.read::<VlanHdr>()?;
+            ether_type = u16::from_be(tag.ether_type);
+            *vlan = Some(tag);
+        }
+
+        Ok(Ethernet {
+            hdr,
+            vlans,
+            ether_type,
+        })
+    }
+
+    /// Parses an IPv4 header and advances past its options.
+    #[inline(always)]
+    pub fn ipv4(&mut self) -> Result<&'a Ipv4Hdr, Error> {
+        let hdr = self.peek::<Ipv4Hdr>()?;
+        let len = hdr.ihl() as usize * 4;
+        if hdr.version() != 4 || len < Ipv4Hdr::LEN {
+            return Err(Error::Malformed);
+        }
+        self.offset += len;
+        Ok(hdr)
+    }
+
+    #[inline(always)]
+    pub fn ipv6(&mut self) -> Result<&'a Ipv6Hdr, Error> {
+        let hdr = self.peek::<Ipv6Hdr>()?;
+        if hdr.version() != 6 {
+            return Err(Error::Malformed);
+        }
+        self.offset += Ipv6Hdr::LEN;
+        Ok(hdr)
+    }
+
+    /// Skips the IPv6 extension headers following a header whose next header field is `next_hdr`.
+    ///
+    /// Returns the upper-layer protocol and whether its header follows, which is not the case for
+    /// non-first fragments.
+    #[inline(always)]
+    pub fn ipv6_ext(&mut self, next_hdr: u8) -> Result<(u8, bool), Error> {
+        let mut next_hdr = next_hdr;
+        for _ in 0..IPV6_MAX_EXT_HEADERS {
+            match next_hdr {
+                IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS => {
+                    let ext = self.peek::<Ipv6ExtHdr>()?;
+                    next_hdr = ext.next_hdr;
+                    self.offset += (ext.hdr_len as usize + 1) * 8;
+                }
+                IPPROTO_AH => {
+                    let ext = self.peek::<Ipv6ExtHdr>()?;
+                    next_hdr = ext.next_hdr;
+                    self.offset += (ext.hdr_len as usize + 2) * 4;
+                }
+                IPPROTO_FRAGMENT => {
+                    let frag = self.read::<Ipv6FragHdr>()?;
+                    next_hdr = frag.next_hdr;
+                    if !frag.is_first() {
+                        return Ok((next_hdr, false));
+                    }
+                }
+                _ => return Ok((next_hdr, true)),
+            }
+        }
+
+        match next_hdr {
+            // more extension headers than we are willing to walk
+            IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS | IPPROTO_AH | IPPROTO_FRAGMENT => {
+                Err(Error::Unsupported)
+            }
+            _ => Ok((next_hdr, true)),
+        }
+    }
+
+    /// Parses a TCP header and advances past its options.
+    #[inline(always)]
+    pub fn tcp(&mut self) -> Result<&'a TcpHdr, Error> {
+        let hdr = self.peek::<TcpHdr>()?;
+        let len = hdr.doff() as usize * 4;
+        if len < TcpHdr::LEN {
+            return Err(Error::Malformed);
+        }
+        self.offset += len;
+        Ok(hdr)
+    }
+
+    #[inline(always)]
+    pub fn udp(&mut self) -> Result<&'a UdpHdr, Error> {
+        self.read()
+    }
+
+    #[inline(always)]
+    pub fn icmp(&mut self) -> Result<&'a IcmpHdr, Error> {
+        self.read()
+    }
+
+    /// Parses the transport header for IP protocol `proto`.
+    #[inline(always)]
+    pub fn l4(&mut self, proto: u8) -> Result<L4<'a>, Error> {
+        Ok(match proto {
+            IPPROTO_TCP => L4::Tcp(self.tcp()?),
+            IPPROTO_UDP => L4::Udp(self.udp()?),
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => L4::Icmp(self.icmp()?),
+            _ => L4::Other(proto),
+        })
+    }
+}
+
+/// All headers of a packet as found by [`parse`].
+///
+/// The offsets are relative to the start of the packet and can be passed to
+/// [`BpfContext::get_ptr_mut`] to rewrite a header in place.
+#[derive(Copy, Clone)]
+pub struct Headers<'a> {
+    pub eth: Option<Ethernet<'a>>,
+    pub l3: L3<'a>,
+    pub l3_offset: usize,
+    pub l4: L4<'a>,
+    pub l4_offset: usize,
+    pub payload_offset: usize,
+    proto: u8,
+}
+
+impl<'a> Headers<'a> {
+    /// IP protocol number of the transport header, if the packet is IP. For IPv6 this is the
+    /// protocol following the extension headers.
+    #[inline(always)]
+    pub fn protocol(&self) -> Option<u8> {
+        match self.l3 {
+            L3::Other(_) => None,
+            _ => Some(self.proto),
+        }
+    }
+
+    /// Source and destination address of IPv4 and IPv6 packets.
+    #[inline(always)]
+    pub fn addrs(&self) -> Option<(IpAddr, IpAddr)> {
+        match self.l3 {
+            L3::Ipv4(hdr) => Some((hdr.src_addr().into(), hdr.dst_addr().into())),
+            L3::Ipv6(hdr) => Some((hdr.src_addr().into(), hdr.dst_addr().into())),
+            L3::Other(_) => None,
+        }
+    }
+
+    /// Source and destination port in host byte order for TCP and UDP packets.
+    #[inline(always)]
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match self.l4 {
+            L4::Tcp(hdr) => Some((u16::from_be(hdr.source), u16::from_be(hdr.dest))),
+            L4::Udp(hdr) => Some((u16::from_be(hdr.source), u16::from_be(hdr.dest))),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn layout(&self) -> Layout {
+        let ip = match self.l3 {
+            L3::Ipv4(_) => ETH_P_IPV4,
+            L3::Ipv6(_) => ETH_P_IPV6,
+            L3::Other(_) => 0,
+        };
+        let l4 = match self.l4 {
+            L4::Tcp(_) | L4::Udp(_) | L4::Icmp(_) => Some(self.proto),
+            L4::Other(_) | L4::None => None,
+        };
+        Layout {
+            l3_offset: self.l3_offset,
+            l4_offset: self.l4_offset,
+            ip,
+            l4,
+        }
+    }
+}
+
+/// The IPv4 packet an ICMP error quotes after its header.
+///
+/// Errors only have to quote the IP header and the first 8 bytes of the transport header (RFC 792),
+/// so the transport header isn't parsed. [`Quoted::layout`] lets [`crate::rewrite`] rewrite the
+/// quoted packet like any other, which for TCP needs the whole header quoted, as RFC 1812 routers
+/// do.
+#[derive(Copy, Clone)]
+pub struct Quoted<'a> {
+    pub ip: &'a Ipv4Hdr,
+    pub l3_offset: usize,
+    pub l4_offset: usize,
+}
+
+impl Quoted<'_> {
+    /// IP protocol number of the quoted packet.
+    #[inline(always)]
+    pub fn protocol(&self) -> u8 {
+        self.ip.proto as u8
+    }
+
+    #[inline(always)]
+    pub fn layout(&self) -> Layout {
+        let first = u16::from_be(self.ip.frag_off) & 0x1fff == 0;
+        let l4 = match self.protocol() {
+            IPPROTO_TCP | IPPROTO_UDP | IPPROTO_ICMP if first => Some(self.protocol()),
+            _ => None,
+        };
+        Layout {
+            l3_offset: self.l3_offset,
+            l4_offset: self.l4_offset,
+            ip: ETH_P_IPV4,
+            l4,
+        }
+    }
+}
+
+/// Positions and kinds of a packet's IP and transport headers, without borrowing the packet.
+///
+/// Taken from [`Headers::layout`] before rewriting the packet with [`crate::rewrite`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Layout {
+    pub l3_offset: usize,
+    pub l4_offset: usize,
+    /// [`ETH_P_IPV4`], [`ETH_P_IPV6`] or 0 for anything else.
+    pub(crate) ip: u16,
+    /// IP protocol number if the transport header was parsed.
+    pub(crate) l4: Option<u8>,
+}
+
+impl Layout {
+    /// IP protocol number of the transport header, if it was parsed.
+    #[inline(always)]
+    pub fn protocol(&self) -> Option<u8> {
+        self.l4
+    }
+}
+
+/// Parses the packet up to the transport header.
+///
+/// Non-IP packets are returned with [`L3::Other`] and [`L4::None`] rather than as an error, so
+/// callers can decide what to do with them.
+#[inline(always)]
+pub fn parse(ctx: &BpfContext, link: LinkLayer) -> Result<Headers<'_>, Error> {
+    let mut cursor = Cursor::new(ctx);
+
+    let (eth, ether_type) = match link {
+        LinkLayer::Ethernet => {
+            let eth = cursor.ethernet()?;
+            (Some(eth), eth.ether_type)
+        }
+        LinkLayer::None => {
+            // without a link layer the IP version nibble tells us what follows
+            let version: &u8 = cursor.peek()?;
+            match *version >> 4 {
+                4 => (None, ETH_P_IPV4),
+                6 => (None, ETH_P_IPV6),
+                _ => (None, 0),
+            }
+        }
+    };
+
+    let l3_offset = cursor.offset();
+    // `has_l4` is false for non-first fragments, which only carry payload
+    let (l3, proto, has_l4) = match ether_type {
+        ETH_P_IPV4 => {
+            let hdr = cursor.ipv4()?;
+            let has_l4 = u16::from_be(hdr.frag_off) & 0x1fff == 0;
+            (L3::Ipv4(hdr), hdr.proto as u8, has_l4)
+        }
+        ETH_P_IPV6 => {
+            let hdr = cursor.ipv6()?;
+            let (proto, has_l4) = cursor.ipv6_ext(hdr.next_hdr as u8)?;
+            (L3::Ipv6(hdr), proto, has_l4)
+        }
+        _ => (L3::Other(ether_type), 0, false),
+    };
+
+    let l4_offset = cursor.offset();
+    let l4 = match l3 {
+        L3::Other(_) => L4::None,
+        _ if !has_l4 => L4::Other(proto),
+        _ => cursor.l4(proto)?,
+    };
+
+    Ok(Headers {
+        eth,
+        l3,
+        l3_offset,
+        l4,
+        l4_offset,
+        payload_offset: cursor.offset(),
+        proto,
+    })
+}
+
+/// Parses the packet quoted by an ICMP destination unreachable, time exceeded or parameter problem
+/// message. `None` for any other packet, including ICMPv6.
+#[inline(always)]
+pub fn parse_icmp_error<'a>(
+    ctx: &'a BpfContext,
+    headers: &Headers<'_>,
+) -> Result<Option<Quoted<'a>>, Error> {
+    let (L3::Ipv4(_), L4::Icmp(icmp)) = (headers.l3, headers.l4) else {
+        return Ok(None);
+    };
+    if !matches!(
+        icmp.type_,
+        ICMP_DEST_UNREACH | ICMP_TIME_EXCEEDED | ICMP_PARAMETERPROB
+    ) {
+        return Ok(None);
+    }
+
+    let mut cursor = Cursor::at(ctx, headers.payload_offset);
+    let ip = cursor.ipv4()?;
+    Ok(Some(Quoted {
+        ip,
+        l3_offset: headers.payload_offset,
+        l4_offset: cursor.offset(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestPacket;
+
+    use super::*;
+
+    /// Walks the extension headers at the start of `data`, which follow a header with `next_hdr`.
+    fn ipv6_ext(next_hdr: u8, data: &[u8]) -> (Result<(u8, bool), Error>, usize) {
+        let mut packet = TestPacket::new(data);
+        let ctx = packet.context();
+        let mut cursor = Cursor::new(&ctx);
+        let result = cursor.ipv6_ext(next_hdr);
+        (result, cursor.offset())
+    }
+
+    /// `n` destination options headers of 8 bytes each, followed by UDP.
+    fn dstopts(n: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..n {
+            let next = if i + 1 == n {
+                IPPROTO_UDP
+            } else {
+                IPPROTO_DSTOPTS
+            };
+            data.extend_from_slice(&[next, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        data.extend_from_slice(&[0; UdpHdr::LEN]);
+        data
+    }
+
+    #[test]
+    fn walks_up_to_the_limit_of_extension_headers() {
+        let data = dstopts(IPV6_MAX_EXT_HEADERS);
+        let end = IPV6_MAX_EXT_HEADERS * 8;
+        assert_eq!(
+            ipv6_ext(IPPROTO_DSTOPTS, &data),
+            (Ok((IPPROTO_UDP, true)), end)
+        );
+    }
+
+    #[test]
+    fn refuses_more_extension_headers() {
+        let data = dstopts(IPV6_MAX_EXT_HEADERS + 1);
+        assert_eq!(ipv6_ext(IPPROTO_DSTOPTS, &data).0, Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn stops_at_non_first_fragments() {
+        // offset 185 * 8, more fragments follow
+        let frag_off = (185u16 << 3 | 1).to_be_bytes();
+        let data = [
+            IPPROTO_TCP,
+            0,
+            frag_off[0],
+            frag_off[1],
+            0,
+            0,
+            0,
+            42,
+            0,
+            0,
+            0,
+            0,
+        ];
+        assert_eq!(
+            ipv6_ext(IPPROTO_FRAGMENT, &data),
+            (Ok((IPPROTO_TCP, false)), Ipv6FragHdr::LEN)
+        );
+
+        // the first fragment carries the transport header
+        let data = [IPPROTO_TCP, 0, 0, 1, 0, 0, 0, 42, 0, 0, 0, 0];
+        assert_eq!(
+            ipv6_ext(IPPROTO_FRAGMENT, &data),
+            (Ok((IPPROTO_TCP, true)), Ipv6FragHdr::LEN)
+        );
+    }
+
+    #[test]
+    fn counts_authentication_header_length_in_words() {
+        // AH with a 96-bit ICV: 12 bytes of fixed fields plus the ICV, payload length 4
+        let mut data = vec![IPPROTO_UDP, 4];
+        data.resize(24, 0);
+        data.extend_from_slice(&[0; UdpHdr::LEN]);
+        assert_eq!(ipv6_ext(IPPROTO_AH, &data), (Ok((IPPROTO_UDP, true)), 24));
+    }
+
+    #[test]
+    fn reads_the_link_layer_from_config() {
+        for link in [LinkLayer::None, LinkLayer::Ethernet] {
+            assert!(LinkLayer::from_config(link as u32) == Some(link));
+        }
+        assert!(LinkLayer::from_config(0) == Some(LinkLayer::None));
+        assert!(LinkLayer::from_config(2).is_none());
+    }
+}
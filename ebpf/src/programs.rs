@@ -1,37 +1,280 @@
 pub mod filter {
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
     #[repr(u32)]
     pub enum FilterResult {
         Abort = 0,
         Drop = 1,
         Pass = 2,
     }
+
+    impl FilterResult {
+        /// The result an action in a map or `Config` stands for, the action being the result as a
+        /// `u32`. `None` for anything but [`FilterResult::Drop`] and [`FilterResult::Pass`].
+        #[inline(always)]
+        pub const fn from_action(action: u32) -> Option<FilterResult> {
+            match action {
+                1 => Some(FilterResult::Drop),
+                2 => Some(FilterResult::Pass),
+                _ => None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn maps_actions() {
+            for result in [FilterResult::Drop, FilterResult::Pass] {
+                assert_eq!(FilterResult::from_action(result as u32), Some(result));
+            }
+            assert_eq!(FilterResult::from_action(FilterResult::Abort as u32), None);
+            assert_eq!(FilterResult::from_action(3), None);
+        }
+    }
+}
+
+pub mod classifier {
+    //! Results of `BPFClassifier` programs.
+    //!
+    //! `BPFClassifier` pushes the packet to the output port the program returns. [`Output<N>`] can
+    //! only hold ports below `N`, and [`ClassifierResult`] keeps dropping and aborting apart from
+    //! port 0.
+
+    /// Raw return value that makes `BPFClassifier` drop the packet and report an error (`-1`).
+    pub const ABORT: u32 = u32::MAX;
+    /// Raw return value that makes `BPFClassifier` drop the packet silently (`-2`).
+    pub const DROP: u32 = u32::MAX - 1;
+
+    /// Output port of a classifier with `N` outputs.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    #[repr(transparent)]
+    pub struct Output<const N: u32>(u32);
+
+    impl<const N: u32> Output<N> {
+        const VALID: () = assert!(N > 0 && N < DROP, "a classifier needs 1 to 2^32-3 outputs");
+
+        /// Output `P`, checked at compile time.
+        #[inline(always)]
+        pub const fn at<const P: u32>() -> Self {
+            const { assert!(P < N, "output port out of range") };
+            Self(P)
+        }
+
+        /// Output `port`, if the classifier has it.
+        #[inline(always)]
+        pub const fn new(port: u32) -> Option<Self> {
+            let () = Self::VALID;
+            if port < N {
+                Some(Self(port))
+            } else {
+                None
+            }
+        }
+
+        /// Output `value % N`, e.g. to spread packets over all outputs.
+        #[inline(always)]
+        pub const fn wrapping(value: u32) -> Self {
+            let () = Self::VALID;
+            Self(value % N)
+        }
+
+        #[inline(always)]
+        pub const fn port(self) -> u32 {
+            self.0
+        }
+    }
+
+    /// What `BPFClassifier` does with a packet.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum ClassifierResult<const N: u32> {
+        /// Push the packet to an output port.
+        Output(Output<N>),
+        /// Drop the packet.
+        Drop,
+        /// Drop the packet and report an error.
+        Abort,
+    }
+
+    impl<const N: u32> ClassifierResult<N> {
+        /// The value `BPFClassifier` expects the program to return.
+        #[inline(always)]
+        pub const fn to_raw(self) -> u32 {
+            match self {
+                ClassifierResult::Output(output) => output.port(),
+                ClassifierResult::Drop => DROP,
+                ClassifierResult::Abort => ABORT,
+            }
+        }
+    }
+
+    impl<const N: u32> From<Output<N>> for ClassifierResult<N> {
+        #[inline(always)]
+        fn from(output: Output<N>) -> Self {
+            ClassifierResult::Output(output)
+        }
+    }
 }
 
 pub mod rewriter {
-    use crate::BpfContext;
-    use core::mem;
+    //! Results of `BPFRewriter` programs, and resizing the packet they rewrite.
+    //!
+    //! `BPFRewriter` registers `bpf_packet_add_space`, which grows or shrinks the packet at its head
+    //! (Click's `push`/`pull`) and tail (`put`/`take`). [`Packet::adjust_head`] and
+    //! [`Packet::adjust_tail`] wrap it:
+    //!
+    //! ```ignore
+    //! packet.adjust_head(-(EthHdr::LEN as i32))?; // strip the Ethernet header
+    //! packet.adjust_tail(4)?; // room for a trailer
+    //! ```
+    //!
+    //! Pointers and references into the packet are invalid after resizing it, since Click may have
+    //! moved the packet to a new buffer.
 
-    #[derive(Copy, Clone)]
+    use crate::helpers::{BPFRewriter, Helpers};
+    use crate::{BpfContext, Error, Packet};
+
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
     #[repr(u32)]
     pub enum RewriterResult {
+        /// Drop the packet and report an error.
         Abort = 0,
+        /// Push the rewritten packet out.
         Success = 1,
+        /// Drop the packet silently.
+        Drop = 2,
     }
 
-    unsafe fn bpf_packet_add_space_impl(head_len: i32, tail_len: i32) -> *mut u8 {
-        let fun: unsafe extern "C" fn(head: i32, tail: i32) -> *mut u8 = mem::transmute(60usize);
-        unsafe { fun(head_len, tail_len) }
-    }
+    const HELPERS: Helpers<BPFRewriter> = Helpers::new();
 
-    pub unsafe fn bpf_packet_add_space<'a>(ctx: &mut BpfContext, head_len: i32, tail_len: i32) {
-        let old_len = ctx.data_end.offset_from(ctx.data) as usize;
-        let new_len = old_len as isize + head_len as isize + tail_len as isize;
+    /// Grows (positive) or shrinks (negative) the packet at its head and tail and points `ctx` at
+    /// the resized packet.
+    ///
+    /// Fails with [`Error::OutOfBounds`] without touching the packet if it would shrink below zero
+    /// bytes, and with [`Error::Resize`] if Click couldn't grow it. Click drops the packet then, and
+    /// `ctx` is left empty.
+    ///
+    /// # Safety
+    ///
+    /// `ctx` must be the context of the packet the rewriter runs on.
+    #[inline(always)]
+    pub unsafe fn bpf_packet_add_space(
+        ctx: &mut BpfContext,
+        head_len: i32,
+        tail_len: i32,
+    ) -> Result<(), Error> {
+        let old_len = ctx.data_end as usize - ctx.data as usize;
+        // the head is adjusted first, so it alone mustn't shrink the packet below zero either
+        let head = old_len as i64 + head_len as i64;
+        let new_len = head + tail_len as i64;
+        if head < 0 || new_len < 0 {
+            return Err(Error::OutOfBounds);
+        }
 
-        let new_ptr = unsafe { bpf_packet_add_space_impl(head_len, tail_len) };
-        let new_tail = new_ptr.add(new_len as usize);
+        let new_ptr = unsafe { HELPERS.packet_add_space(head_len, tail_len) };
+        if new_ptr.is_null() {
+            ctx.data = new_ptr;
+            ctx.data_end = new_ptr;
+            return Err(Error::Resize);
+        }
 
         ctx.data = new_ptr;
-        ctx.data_end = new_tail;
+        ctx.data_end = unsafe { new_ptr.add(new_len as usize) };
+        Ok(())
+    }
+
+    impl Packet {
+        /// Grows the packet by `len` bytes at its head (`len > 0`) or removes `-len` bytes from it.
+        /// The new bytes are uninitialized.
+        #[inline(always)]
+        pub fn adjust_head(&mut self, len: i32) -> Result<(), Error> {
+            unsafe { bpf_packet_add_space(self.ctx_mut(), len, 0) }
+        }
+
+        /// Grows the packet by `len` bytes at its tail (`len > 0`) or removes `-len` bytes from it.
+        /// The new bytes are uninitialized.
+        #[inline(always)]
+        pub fn adjust_tail(&mut self, len: i32) -> Result<(), Error> {
+            unsafe { bpf_packet_add_space(self.ctx_mut(), 0, len) }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::testing::{fail_next_add_space, TestPacket};
+
+        const DATA: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        #[test]
+        fn adjust_head() {
+            let mut packet = TestPacket::new(&DATA);
+            packet.with_packet(|packet| {
+                packet.adjust_head(4).unwrap();
+                assert_eq!(packet.len(), 12);
+                *packet.load_mut::<[u8; 4]>(0).unwrap() = [9; 4];
+                packet.adjust_head(-6).unwrap();
+                assert_eq!(packet.len(), 6);
+            });
+            assert_eq!(packet.data(), &DATA[2..]);
+        }
+
+        #[test]
+        fn adjust_tail() {
+            let mut packet = TestPacket::new(&DATA);
+            packet.with_packet(|packet| {
+                packet.adjust_tail(2).unwrap();
+                *packet.load_mut::<[u8; 2]>(8).unwrap() = [9, 10];
+            });
+            assert_eq!(packet.data(), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+            packet.with_packet(|packet| packet.adjust_tail(-7)).unwrap();
+            assert_eq!(packet.data(), &DATA[..3]);
+        }
+
+        #[test]
+        fn grows_beyond_headroom() {
+            let mut packet = TestPacket::new(&DATA);
+            packet.with_packet(|packet| {
+                packet.adjust_head(1000).unwrap();
+                packet.adjust_tail(1000).unwrap();
+                assert_eq!(packet.len(), 2008);
+                assert_eq!(packet.load::<[u8; 8]>(1000).unwrap(), &DATA);
+            });
+            assert_eq!(&packet.data()[1000..1008], &DATA);
+        }
+
+        #[test]
+        fn rejects_shrinking_below_zero() {
+            let mut packet = TestPacket::new(&DATA);
+            packet.with_packet(|packet| {
+                // the mock helper panics if it is called with these
+                assert_eq!(packet.adjust_head(-9), Err(Error::OutOfBounds));
+                assert_eq!(packet.adjust_tail(-9), Err(Error::OutOfBounds));
+                assert_eq!(packet.adjust_head(i32::MIN), Err(Error::OutOfBounds));
+                // the head goes first, growing the tail afterwards doesn't help
+                let ctx = unsafe { packet.ctx_mut() };
+                assert_eq!(
+                    unsafe { bpf_packet_add_space(ctx, -10, 4) },
+                    Err(Error::OutOfBounds)
+                );
+                assert_eq!(packet.len(), 8);
+
+                packet.adjust_head(-8).unwrap();
+                assert!(packet.is_empty());
+            });
+        }
+
+        #[test]
+        fn helper_failure() {
+            let mut packet = TestPacket::new(&DATA);
+            packet.with_packet(|packet| {
+                fail_next_add_space();
+                assert_eq!(packet.adjust_head(4), Err(Error::Resize));
+                assert!(packet.is_empty());
+                assert!(packet.load::<u8>(0).is_err());
+            });
+        }
     }
 }
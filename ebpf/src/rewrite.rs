@@ -0,0 +1,410 @@
+//! Address and port rewrites that keep the packet's checksums valid.
+//!
+//! Every setter updates the IPv4 header checksum and the transport checksum incrementally
+//! (RFC 1624), including the pseudo-header fields TCP, UDP and ICMPv6 checksum over. UDP over IPv4
+//! without a checksum (0) stays without one. The setters work in every element, not only in
+//! `BPFRewriter`, and take the [`Layout`] of the packet from [`crate::parse`]:
+//!
+//! ```ignore
+//! let layout = packet.parse(LinkLayer::None)?.layout();
+//! rewrite::set_ipv4_src(packet, &layout, Ipv4Addr::new(172, 44, 0, 3))?;
+//! rewrite::set_l4_src_port(packet, &layout, 50000)?;
+//! ```
+
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use network_types::icmp::IcmpHdr;
+use network_types::ip::{Ipv4Hdr, Ipv6Hdr};
+use network_types::tcp::TcpHdr;
+use network_types::udp::UdpHdr;
+
+use crate::parse::{
+    Layout, ETH_P_IPV4, ETH_P_IPV6, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST, IPPROTO_ICMP,
+    IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP,
+};
+use crate::{update_checksum, update_checksum_ip, Error, Packet};
+
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+#[inline(always)]
+pub fn set_ipv4_src(packet: &mut Packet, layout: &Layout, addr: Ipv4Addr) -> Result<(), Error> {
+    set_ipv4_addr(packet, layout, addr, true)
+}
+
+#[inline(always)]
+pub fn set_ipv4_dst(packet: &mut Packet, layout: &Layout, addr: Ipv4Addr) -> Result<(), Error> {
+    set_ipv4_addr(packet, layout, addr, false)
+}
+
+#[inline(always)]
+pub fn set_ipv6_src(packet: &mut Packet, layout: &Layout, addr: Ipv6Addr) -> Result<(), Error> {
+    set_ipv6_addr(packet, layout, addr, true)
+}
+
+#[inline(always)]
+pub fn set_ipv6_dst(packet: &mut Packet, layout: &Layout, addr: Ipv6Addr) -> Result<(), Error> {
+    set_ipv6_addr(packet, layout, addr, false)
+}
+
+/// Sets the TCP or UDP source port (host byte order).
+#[inline(always)]
+pub fn set_l4_src_port(packet: &mut Packet, layout: &Layout, port: u16) -> Result<(), Error> {
+    set_l4_port(packet, layout, port, true)
+}
+
+/// Sets the TCP or UDP destination port (host byte order).
+#[inline(always)]
+pub fn set_l4_dst_port(packet: &mut Packet, layout: &Layout, port: u16) -> Result<(), Error> {
+    set_l4_port(packet, layout, port, false)
+}
+
+/// Sets the identifier of an ICMP or ICMPv6 echo request or reply, which takes the place of the
+/// port when translating pings.
+#[inline(always)]
+pub fn set_icmp_echo_id(packet: &mut Packet, layout: &Layout, id: u16) -> Result<(), Error> {
+    let (request, reply) = match layout.l4 {
+        Some(IPPROTO_ICMP) => (ICMP_ECHO_REQUEST, ICMP_ECHO_REPLY),
+        Some(IPPROTO_ICMPV6) => (ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY),
+        _ => return Err(Error::Unsupported),
+    };
+    let hdr: &mut IcmpHdr = packet.load_mut(layout.l4_offset)?;
+    if hdr.type_ != request && hdr.type_ != reply {
+        return Err(Error::Unsupported);
+    }
+
+    let new = id.to_be();
+    let old = unsafe { hdr.un.echo.id };
+    hdr.un.echo.id = new;
+    update_checksum(&mut hdr.checksum, old, new);
+    Ok(())
+}
+
+#[inline(always)]
+fn set_ipv4_addr(
+    packet: &mut Packet,
+    layout: &Layout,
+    addr: Ipv4Addr,
+    src: bool,
+) -> Result<(), Error> {
+    if layout.ip != ETH_P_IPV4 {
+        return Err(Error::Unsupported);
+    }
+
+    let new = u32::from(addr).to_be();
+    let hdr: &mut Ipv4Hdr = packet.load_mut(layout.l3_offset)?;
+    let field = if src {
+        &mut hdr.src_addr
+    } else {
+        &mut hdr.dst_addr
+    };
+    let old = *field;
+    *field = new;
+    update_checksum_ip(&mut hdr.check, old, new);
+
+    update_pseudo_header(packet, layout, |check| update_checksum_ip(check, old, new))
+}
+
+#[inline(always)]
+fn set_ipv6_addr(
+    packet: &mut Packet,
+    layout: &Layout,
+    addr: Ipv6Addr,
+    src: bool,
+) -> Result<(), Error> {
+    if layout.ip != ETH_P_IPV6 {
+        return Err(Error::Unsupported);
+    }
+
+    let new = addr.octets();
+    let hdr: &mut Ipv6Hdr = packet.load_mut(layout.l3_offset)?;
+    let field = if src {
+        &mut hdr.src_addr
+    } else {
+        &mut hdr.dst_addr
+    };
+    let old = unsafe { field.in6_u.u6_addr8 };
+    field.in6_u.u6_addr8 = new;
+
+    update_pseudo_header(packet, layout, |check| {
+        for i in 0..8 {
+            let old = u16::from_ne_bytes([old[2 * i], old[2 * i + 1]]);
+            let new = u16::from_ne_bytes([new[2 * i], new[2 * i + 1]]);
+            update_checksum(check, old, new);
+        }
+    })
+}
+
+#[inline(always)]
+fn set_l4_port(packet: &mut Packet, layout: &Layout, port: u16, src: bool) -> Result<(), Error> {
+    let new = port.to_be();
+    match layout.l4 {
+        Some(IPPROTO_TCP) => {
+            let hdr: &mut TcpHdr = packet.load_mut(layout.l4_offset)?;
+            let field = if src { &mut hdr.source } else { &mut hdr.dest };
+            let old = *field;
+            *field = new;
+            update_checksum(&mut hdr.check, old, new);
+        }
+        Some(IPPROTO_UDP) => {
+            let hdr: &mut UdpHdr = packet.load_mut(layout.l4_offset)?;
+            let field = if src { &mut hdr.source } else { &mut hdr.dest };
+            let old = *field;
+            *field = new;
+            update_udp_checksum(hdr, |check| update_checksum(check, old, new));
+        }
+        _ => return Err(Error::Unsupported),
+    }
+    Ok(())
+}
+
+/// Applies `update` to the transport checksum if it covers the IP addresses.
+#[inline(always)]
+fn update_pseudo_header(
+    packet: &mut Packet,
+    layout: &Layout,
+    update: impl FnOnce(&mut u16),
+) -> Result<(), Error> {
+    match layout.l4 {
+        Some(IPPROTO_TCP) => {
+            let hdr: &mut TcpHdr = packet.load_mut(layout.l4_offset)?;
+            update(&mut hdr.check);
+        }
+        Some(IPPROTO_UDP) => {
+            let hdr: &mut UdpHdr = packet.load_mut(layout.l4_offset)?;
+            update_udp_checksum(hdr, update);
+        }
+        Some(IPPROTO_ICMPV6) => {
+            let hdr: &mut IcmpHdr = packet.load_mut(layout.l4_offset)?;
+            update(&mut hdr.checksum);
+        }
+        // ICMP doesn't checksum the IP header, and fragments without a transport header have
+        // their checksum in the first fragment
+        _ => {}
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn update_udp_checksum(hdr: &mut UdpHdr, update: impl FnOnce(&mut u16)) {
+    // 0 means the sender didn't compute a checksum
+    if hdr.check == 0 {
+        return;
+    }
+    update(&mut hdr.check);
+    // a computed checksum of 0 is sent as all ones (RFC 768)
+    if hdr.check == 0 {
+        hdr.check = 0xffff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::{finish, fold, pseudo_header_v4, pseudo_header_v6, sum};
+    use crate::parse::LinkLayer;
+    use crate::testing::PacketBuilder;
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const NAT: Ipv4Addr = Ipv4Addr::new(172, 44, 0, 3);
+    const CLIENT_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    const SERVER_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const NAT_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0xffff, 0, 0, 0, 0x1234, 0xabcd);
+
+    /// Applies `rewrite` to the built packet, which starts with the IP header.
+    fn rewrite(
+        builder: PacketBuilder,
+        rewrite: impl FnOnce(&mut Packet, &Layout) -> Result<(), Error>,
+    ) -> Vec<u8> {
+        let mut packet = builder.build();
+        packet
+            .with_packet(|packet| {
+                let layout = packet.parse(LinkLayer::None)?.layout();
+                rewrite(packet, &layout)
+            })
+            .unwrap();
+        packet.data().to_vec()
+    }
+
+    /// The transport checksum of a packet as it is, and as computed from scratch.
+    fn l4_checksum(data: &[u8]) -> (u16, u16) {
+        let (proto, l4_offset, pseudo) = match data[0] >> 4 {
+            4 => {
+                let src = Ipv4Addr::from(<[u8; 4]>::try_from(&data[12..16]).unwrap());
+                let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&data[16..20]).unwrap());
+                let pseudo = match data[9] {
+                    IPPROTO_ICMP => 0,
+                    proto => pseudo_header_v4(src, dst, proto, data.len() as u16 - 20),
+                };
+                (data[9], 20, pseudo)
+            }
+            _ => {
+                let src = Ipv6Addr::from(<[u8; 16]>::try_from(&data[8..24]).unwrap());
+                let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&data[24..40]).unwrap());
+                (
+                    data[6],
+                    40,
+                    pseudo_header_v6(src, dst, data[6], data.len() as u32 - 40),
+                )
+            }
+        };
+        let check_offset = l4_offset
+            + match proto {
+                IPPROTO_TCP => 16,
+                IPPROTO_UDP => 6,
+                _ => 2,
+            };
+        let mut data = data.to_vec();
+        let check = u16::from_be_bytes([data[check_offset], data[check_offset + 1]]);
+        data[check_offset..check_offset + 2].fill(0);
+        let mut full = finish(sum(&data[l4_offset..], pseudo));
+        if full == 0 && proto == IPPROTO_UDP {
+            full = 0xffff;
+        }
+        (check, full)
+    }
+
+    fn assert_checksums(data: &[u8]) {
+        if data[0] >> 4 == 4 {
+            assert_eq!(finish(sum(&data[..20], 0)), 0, "IPv4 header checksum");
+        }
+        let (check, full) = l4_checksum(data);
+        assert_eq!(check, full, "transport checksum");
+    }
+
+    #[test]
+    fn ipv4_tcp() {
+        let data = rewrite(
+            PacketBuilder::new()
+                .ipv4(CLIENT, SERVER)
+                .tcp(40000, 443)
+                .payload(b"hello"),
+            |packet, layout| {
+                set_ipv4_src(packet, layout, NAT)?;
+                set_ipv4_dst(packet, layout, CLIENT)?;
+                set_l4_src_port(packet, layout, 50000)?;
+                set_l4_dst_port(packet, layout, 8443)
+            },
+        );
+        assert_eq!(data[12..20], [172, 44, 0, 3, 10, 0, 0, 1]);
+        assert_eq!(data[20..24], [0xc3, 0x50, 0x20, 0xfb]);
+        assert_checksums(&data);
+    }
+
+    #[test]
+    fn ipv6_udp() {
+        let data = rewrite(
+            PacketBuilder::new()
+                .ipv6(CLIENT_V6, SERVER_V6)
+                .udp(40000, 53)
+                .payload(b"query"),
+            |packet, layout| {
+                set_ipv6_src(packet, layout, NAT_V6)?;
+                set_ipv6_dst(packet, layout, CLIENT_V6)?;
+                set_l4_src_port(packet, layout, 50000)
+            },
+        );
+        assert_eq!(data[8..24], NAT_V6.octets());
+        assert_eq!(data[24..40], CLIENT_V6.octets());
+        assert_checksums(&data);
+    }
+
+    #[test]
+    fn icmpv6_echo() {
+        let data = rewrite(
+            PacketBuilder::new()
+                .ipv6(CLIENT_V6, SERVER_V6)
+                .icmp_echo_request(7, 1),
+            |packet, layout| {
+                // ICMPv6 checksums the pseudo header, unlike ICMP
+                set_ipv6_src(packet, layout, NAT_V6)?;
+                set_ipv6_dst(packet, layout, CLIENT_V6)?;
+                set_icmp_echo_id(packet, layout, 4242)
+            },
+        );
+        assert_eq!(data[8..24], NAT_V6.octets());
+        assert_eq!(data[44..46], 4242u16.to_be_bytes());
+        assert_checksums(&data);
+    }
+
+    #[test]
+    fn ipv4_icmp_echo() {
+        let data = rewrite(
+            PacketBuilder::new()
+                .ipv4(CLIENT, SERVER)
+                .icmp_echo_reply(7, 1),
+            |packet, layout| {
+                set_ipv4_dst(packet, layout, NAT)?;
+                set_icmp_echo_id(packet, layout, 4242)
+            },
+        );
+        assert_eq!(data[24..26], 4242u16.to_be_bytes());
+        assert_checksums(&data);
+    }
+
+    #[test]
+    fn refuses_other_headers() {
+        let mut packet = PacketBuilder::new().ipv4(CLIENT, SERVER).icmp(3, 1).build();
+        let before = packet.data().to_vec();
+        packet.with_packet(|packet| {
+            let layout = packet.parse(LinkLayer::None).unwrap().layout();
+            assert_eq!(
+                set_ipv6_src(packet, &layout, NAT_V6),
+                Err(Error::Unsupported)
+            );
+            assert_eq!(set_l4_src_port(packet, &layout, 1), Err(Error::Unsupported));
+            assert_eq!(
+                set_icmp_echo_id(packet, &layout, 1),
+                Err(Error::Unsupported)
+            );
+        });
+        assert_eq!(packet.data(), before);
+
+        let mut packet = PacketBuilder::new()
+            .ipv6(CLIENT_V6, SERVER_V6)
+            .tcp(1, 2)
+            .build();
+        packet.with_packet(|packet| {
+            let layout = packet.parse(LinkLayer::None).unwrap().layout();
+            assert_eq!(set_ipv4_dst(packet, &layout, NAT), Err(Error::Unsupported));
+        });
+    }
+
+    #[test]
+    fn keeps_udp_without_checksum() {
+        let data = rewrite(
+            PacketBuilder::new()
+                .ipv4(CLIENT, SERVER)
+                .udp_without_checksum(40000, 53)
+                .payload(b"query"),
+            |packet, layout| {
+                set_ipv4_src(packet, layout, NAT)?;
+                set_l4_src_port(packet, layout, 50000)
+            },
+        );
+        assert_eq!(data[12..16], NAT.octets());
+        assert_eq!(data[26..28], [0, 0]);
+        assert_eq!(finish(sum(&data[..20], 0)), 0);
+    }
+
+    #[test]
+    fn sends_a_udp_checksum_of_zero_as_ones() {
+        let builder = PacketBuilder::new()
+            .ipv4(CLIENT, SERVER)
+            .udp(0, 53)
+            .payload(b"query");
+        // the source port that makes the sum of the datagram all ones, its checksum 0
+        let mut data = builder.bytes();
+        data[26..28].fill(0);
+        let pseudo = pseudo_header_v4(CLIENT, SERVER, IPPROTO_UDP, data.len() as u16 - 20);
+        let port = 0xffff - fold(sum(&data[20..], pseudo));
+
+        let data = rewrite(builder, |packet, layout| {
+            set_l4_src_port(packet, layout, port)
+        });
+        assert_eq!(data[26..28], [0xff, 0xff]);
+        assert_checksums(&data);
+    }
+}
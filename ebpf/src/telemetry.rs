@@ -0,0 +1,216 @@
+//! Counters every program keeps about the packets it sees, for the control plane.
+//!
+//! The entry attributes count each packet in [`TELEMETRY`] after the program ran: its length, the
+//! verdict or output port, whether it was dropped or aborted, and why the program failed if it
+//! returned an [`Error`]. `helper telemetry` reads and decodes the map, so no program needs
+//! `bpf_printk` or `Print` elements to be observed. `#[bpf_filter(telemetry = false)]` leaves the
+//! counting out, e.g. for benchmarks.
+//!
+//! Like every map, [`TELEMETRY`] outlives the program: a reconfigured element keeps counting where
+//! the old program left off. [`Telemetry::program`] tells which kind of program counted last, so
+//! tools know how to name the verdicts.
+
+use aya_ebpf::macros::map;
+
+use crate::classifier::{ABORT, DROP};
+use crate::filter::FilterResult;
+use crate::maps::Array;
+use crate::rewriter::RewriterResult;
+use crate::Error;
+
+/// Kinds of programs, see [`Telemetry::program`].
+pub const PROGRAM_FILTER: u64 = 1;
+pub const PROGRAM_CLASSIFIER: u64 = 2;
+pub const PROGRAM_REWRITER: u64 = 3;
+
+/// Verdicts [`Telemetry::verdicts`] tells apart.
+pub const VERDICTS: usize = 16;
+/// Errors [`Telemetry::errors`] tells apart, by [`Error`] value.
+pub const ERRORS: usize = 8;
+
+/// The value of [`TELEMETRY`]. The element and tools see 232 bytes of little-endian `u64`s in
+/// field order.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Telemetry {
+    /// [`PROGRAM_FILTER`], [`PROGRAM_CLASSIFIER`] or [`PROGRAM_REWRITER`].
+    pub program: u64,
+    /// Packets the program ran on, and their length before it did.
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets by verdict: the value of the [`FilterResult`] or [`RewriterResult`], or the output
+    /// port of a classifier. Ports from `VERDICTS - 1` on share the last slot.
+    pub verdicts: [u64; VERDICTS],
+    /// Packets dropped silently, by any kind of program.
+    pub drops: u64,
+    /// Packets the element dropped and reported an error for, by any kind of program.
+    pub aborts: u64,
+    /// Packets the program returned an [`Error`] for, by its value, whatever verdict `on_error`
+    /// made of it. Slot 0 is unused.
+    pub errors: [u64; ERRORS],
+}
+
+#[map(name = "TELEMETRY")]
+pub static TELEMETRY: Array<Telemetry> = Array::with_max_entries(1, 0);
+
+/// What an entry point returns, as counted in [`Telemetry`].
+pub trait Verdict: Copy {
+    /// Kind of program returning it.
+    const PROGRAM: u64;
+
+    /// Slot in [`Telemetry::verdicts`], if it has one.
+    fn slot(self) -> Option<usize>;
+
+    fn is_drop(self) -> bool;
+
+    fn is_abort(self) -> bool;
+}
+
+impl Verdict for FilterResult {
+    const PROGRAM: u64 = PROGRAM_FILTER;
+
+    #[inline(always)]
+    fn slot(self) -> Option<usize> {
+        Some(self as usize)
+    }
+
+    #[inline(always)]
+    fn is_drop(self) -> bool {
+        self == FilterResult::Drop
+    }
+
+    #[inline(always)]
+    fn is_abort(self) -> bool {
+        self == FilterResult::Abort
+    }
+}
+
+impl Verdict for RewriterResult {
+    const PROGRAM: u64 = PROGRAM_REWRITER;
+
+    #[inline(always)]
+    fn slot(self) -> Option<usize> {
+        Some(self as usize)
+    }
+
+    #[inline(always)]
+    fn is_drop(self) -> bool {
+        self == RewriterResult::Drop
+    }
+
+    #[inline(always)]
+    fn is_abort(self) -> bool {
+        self == RewriterResult::Abort
+    }
+}
+
+/// The raw value `#[bpf_classifier]` returns, see [`crate::classifier::ClassifierResult::to_raw`].
+impl Verdict for u32 {
+    const PROGRAM: u64 = PROGRAM_CLASSIFIER;
+
+    #[inline(always)]
+    fn slot(self) -> Option<usize> {
+        match self {
+            ABORT | DROP => None,
+            port => Some((port as usize).min(VERDICTS - 1)),
+        }
+    }
+
+    #[inline(always)]
+    fn is_drop(self) -> bool {
+        self == DROP
+    }
+
+    #[inline(always)]
+    fn is_abort(self) -> bool {
+        self == ABORT
+    }
+}
+
+/// Counts a packet of `len` bytes that got `verdict`, after the program failed with `error` if it
+/// did. Called by the entry attributes.
+#[inline(always)]
+pub fn record<V: Verdict>(len: usize, verdict: V, error: Option<Error>) {
+    // the element never gets here after a tail call, the program jumped to counts the packet
+    #[cfg(any(test, feature = "std"))]
+    if crate::testing::tail_call_pending() {
+        return;
+    }
+    let Some(telemetry) = TELEMETRY.get_ptr_mut(0) else {
+        return;
+    };
+    let telemetry = unsafe { &mut *telemetry };
+    telemetry.program = V::PROGRAM;
+    telemetry.packets += 1;
+    telemetry.bytes += len as u64;
+    if let Some(count) = verdict
+        .slot()
+        .and_then(|slot| telemetry.verdicts.get_mut(slot))
+    {
+        *count += 1;
+    }
+    if verdict.is_drop() {
+        telemetry.drops += 1;
+    }
+    if verdict.is_abort() {
+        telemetry.aborts += 1;
+    }
+    if let Some(count) = error.and_then(|error| telemetry.errors.get_mut(error as usize)) {
+        *count += 1;
+    }
+}
+
+/// The counters so far, all zero if nothing was counted. Only exists on the host.
+#[cfg(any(test, feature = "std"))]
+pub fn telemetry() -> Telemetry {
+    *TELEMETRY.get(0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_filter_verdicts() {
+        record(60, FilterResult::Pass, None);
+        record(40, FilterResult::Drop, None);
+        record(100, FilterResult::Abort, Some(Error::Malformed));
+        record(20, FilterResult::Drop, Some(Error::OutOfBounds));
+
+        let telemetry = telemetry();
+        assert_eq!(telemetry.program, PROGRAM_FILTER);
+        assert_eq!((telemetry.packets, telemetry.bytes), (4, 220));
+        assert_eq!(telemetry.verdicts[..4], [1, 2, 1, 0]);
+        assert_eq!((telemetry.drops, telemetry.aborts), (2, 1));
+        assert_eq!(telemetry.errors[..4], [0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn counts_rewriter_verdicts() {
+        record(60, RewriterResult::Success, None);
+        record(60, RewriterResult::Drop, None);
+
+        let telemetry = telemetry();
+        assert_eq!(telemetry.program, PROGRAM_REWRITER);
+        assert_eq!(telemetry.verdicts[..3], [0, 1, 1]);
+        assert_eq!((telemetry.drops, telemetry.aborts), (1, 0));
+    }
+
+    #[test]
+    fn counts_classifier_ports() {
+        for port in [0, 1, 1, 15, 100] {
+            record(60, port, None);
+        }
+        record(60, DROP, None);
+        record(60, ABORT, Some(Error::Unsupported));
+
+        let telemetry = telemetry();
+        assert_eq!(telemetry.program, PROGRAM_CLASSIFIER);
+        assert_eq!(telemetry.packets, 7);
+        assert_eq!(telemetry.verdicts[..2], [1, 2]);
+        assert_eq!(telemetry.verdicts[VERDICTS - 1], 2);
+        assert_eq!(telemetry.verdicts.iter().sum::<u64>(), 5);
+        assert_eq!((telemetry.drops, telemetry.aborts), (1, 1));
+        assert_eq!(telemetry.errors[Error::Unsupported as usize], 1);
+    }
+}
@@ -0,0 +1,637 @@
+//! Host-side test harness, available with the `std` feature.
+//!
+//! Programs in `src/bin` can be tested with plain `cargo test` (`make test`): [`PacketBuilder`]
+//! crafts packets, [`TestPacket::run`] calls the program's `main` on them, the maps in
+//! [`crate::maps`] are kept in memory per test thread, and the helpers are served by the mocks
+//! below, with a clock tests can set. Programs put into a [`ProgArray`] with [`load_program`] are
+//! run after tail calls into them, like the element does.
+//!
+//! ```ignore
+//! #[test]
+//! fn drops_port_12345() {
+//!     let mut packet = PacketBuilder::new()
+//!         .ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))
+//!         .udp(1000, 12345)
+//!         .build();
+//!     assert_eq!(packet.run(main), FilterResult::Drop);
+//! }
+//! ```
+
+use std::any::Any;
+use std::boxed::Box;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ptr;
+use std::string::String;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use crate::checksum::{finish, pseudo_header_v4, pseudo_header_v6, sum};
+use crate::log::{Level, LOG, LOG_ENTRIES, LOG_HEAD};
+use crate::maps::{ProgArray, MAX_TAIL_CALLS};
+use crate::migration::MIGRATE_PORT;
+use crate::parse::{
+    ETH_P_8021Q, ETH_P_IPV4, ETH_P_IPV6, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP,
+};
+use crate::{BpfContext, Packet};
+
+/// Room in front of and behind a test packet for `bpf_packet_add_space` to grow into before the
+/// buffer has to be reallocated.
+const HEADROOM: usize = 64;
+
+/// Local experimental ether type, used for Ethernet frames without an IP header.
+const ETH_P_EXPERIMENTAL: u16 = 0x88B5;
+
+thread_local! {
+    static TIME_NS: Cell<u64> = const { Cell::new(0) };
+    static PRANDOM: Cell<u32> = const { Cell::new(0x2545_f491) };
+    static TRACE: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static FAIL_ADD_SPACE: Cell<bool> = const { Cell::new(false) };
+    /// Events of [`crate::log`] [`take_log`] returned already.
+    static LOG_TAKEN: Cell<u64> = const { Cell::new(0) };
+    /// The packet [`TestPacket::run`] is running a program on.
+    static CURRENT: Cell<*mut TestPacket> = const { Cell::new(ptr::null_mut()) };
+    /// Entry points [`load_program`] put into the slots of program arrays, by map address and slot.
+    static PROGRAMS: RefCell<BTreeMap<(usize, u32), Box<dyn Any>>> =
+        const { RefCell::new(BTreeMap::new()) };
+    /// The slot the running program tail called into, run once it returns.
+    static TAIL_CALL: Cell<Option<(usize, u32)>> = const { Cell::new(None) };
+    /// Tail calls the packet [`TestPacket::run`] is running on went through.
+    static TAIL_CALLS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Sets what `bpf_ktime_get_ns` returns on this thread. Starts at 0.
+pub fn set_time_ns(ns: u64) {
+    TIME_NS.with(|time| time.set(ns));
+}
+
+pub fn advance_time_ns(ns: u64) {
+    TIME_NS.with(|time| time.set(time.get() + ns));
+}
+
+/// Seeds the xorshift generator behind `bpf_get_prandom_u32` on this thread.
+pub fn set_prandom_seed(seed: u32) {
+    PRANDOM.with(|state| state.set(seed.max(1)));
+}
+
+/// Takes the lines printed with `bpf_trace_printk` on this thread so far.
+pub fn take_trace() -> Vec<String> {
+    TRACE.with(|trace| trace.take())
+}
+
+/// Format strings of [`crate::log`] events by ID, as `helper log` reads them from the program.
+static LOG_FORMATS: Mutex<BTreeMap<u32, &'static str>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn register_format(id: u32, format: &'static str) {
+    LOG_FORMATS.lock().unwrap().insert(id, format);
+}
+
+/// Takes the events logged with [`crate::log`] on this thread so far and still in the ring buffer,
+/// rendered like `helper log` does without timestamps, e.g. `WARN no port left`.
+pub fn take_log() -> Vec<String> {
+    let head = *LOG_HEAD.get(0).unwrap();
+    let taken = LOG_TAKEN.with(|taken| taken.replace(head));
+    let formats = LOG_FORMATS.lock().unwrap();
+    (taken.max(head.saturating_sub(LOG_ENTRIES as u64))..head)
+        .map(|seq| LOG.get((seq % LOG_ENTRIES as u64) as u32).unwrap())
+        .map(|record| {
+            let level = [Level::Error, Level::Warn, Level::Info, Level::Debug]
+                .into_iter()
+                .find(|level| *level as u8 == record.level)
+                .map_or("?", Level::name);
+            let format = formats.get(&record.id).copied().unwrap_or("unknown event");
+            let args = &record.args[..record.len as usize];
+            format!("{level} {}", crate::log::render(format, args))
+        })
+        .collect()
+}
+
+/// Puts a program's `main` into slot `index` of `array` on this thread, as `TAIL_CALL` does in the
+/// element's configuration. The program must be written for the same kind of element as the one
+/// tail calling into it.
+pub fn load_program<R: 'static>(
+    array: &ProgArray,
+    index: u32,
+    main: extern "C" fn(*mut BpfContext) -> R,
+) {
+    assert!(
+        index < array.max_entries(),
+        "slot {index} out of the program array"
+    );
+    let key = (array as *const ProgArray as usize, index);
+    PROGRAMS.with(|programs| programs.borrow_mut().insert(key, Box::new(main)));
+}
+
+/// Empties slot `index` of `array` on this thread.
+pub fn unload_program(array: &ProgArray, index: u32) {
+    let key = (array as *const ProgArray as usize, index);
+    PROGRAMS.with(|programs| programs.borrow_mut().remove(&key));
+}
+
+/// Whether the running program tail called and the element is going to run another one instead.
+pub(crate) fn tail_call_pending() -> bool {
+    TAIL_CALL.with(|pending| pending.get().is_some())
+}
+
+/// Makes the next `bpf_packet_add_space` on this thread fail, as when Click can't grow a packet.
+pub fn fail_next_add_space() {
+    FAIL_ADD_SPACE.with(|fail| fail.set(true));
+}
+
+/// Calls a program's `main` the way the element does after loading it, to migrate its maps (see
+/// [`crate::migration`]).
+pub fn migrate<R: 'static>(main: extern "C" fn(*mut BpfContext) -> R) -> R {
+    TestPacket::new(&[]).port(MIGRATE_PORT).run(main)
+}
+
+/// A packet buffer a program can run on, with room to grow at both ends.
+pub struct TestPacket {
+    buf: Vec<u8>,
+    head: usize,
+    len: usize,
+    port: u32,
+}
+
+impl TestPacket {
+    pub fn new(data: &[u8]) -> Self {
+        let mut buf = vec![0; HEADROOM + data.len() + HEADROOM];
+        buf[HEADROOM..HEADROOM + data.len()].copy_from_slice(data);
+        Self {
+            buf,
+            head: HEADROOM,
+            len: data.len(),
+            port: 0,
+        }
+    }
+
+    /// Sets the input port the packet arrives on.
+    pub fn port(mut self, port: u32) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf[self.head..self.head + self.len]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.head..self.head + self.len]
+    }
+
+    /// The context an element would pass to the program for this packet.
+    pub fn context(&mut self) -> BpfContext {
+        let range = self.data_mut().as_mut_ptr_range();
+        BpfContext {
+            data: range.start,
+            data_end: range.end,
+            port: self.port,
+        }
+    }
+
+    /// Runs a program's `main` on the packet and returns its result. Rewrites, including those
+    /// through `bpf_packet_add_space`, show up in [`TestPacket::data`] afterwards.
+    ///
+    /// If the program tail called, its result is dropped and the result is that of the program it
+    /// jumped to, as in the element.
+    pub fn run<R: 'static>(&mut self, main: extern "C" fn(*mut BpfContext) -> R) -> R {
+        TAIL_CALL.with(|pending| pending.set(None));
+        TAIL_CALLS.with(|count| count.set(0));
+        let mut result = self.run_once(main);
+        while let Some(key) = TAIL_CALL.with(|pending| pending.take()) {
+            let main = PROGRAMS.with(|programs| {
+                let programs = programs.borrow();
+                let program = programs.get(&key).expect("tail call into an emptied slot");
+                *program
+                    .downcast_ref::<extern "C" fn(*mut BpfContext) -> R>()
+                    .expect("tail call into a program of another element kind")
+            });
+            result = self.run_once(main);
+        }
+        result
+    }
+
+    fn run_once<R>(&mut self, main: extern "C" fn(*mut BpfContext) -> R) -> R {
+        let mut ctx = self.context();
+        CURRENT.with(|current| current.set(self));
+        let result = main(&mut ctx);
+        CURRENT.with(|current| current.set(ptr::null_mut()));
+        result
+    }
+
+    /// Runs library code on the packet the way a program would, e.g. to test code that calls
+    /// `bpf_packet_add_space` without writing an entry point for it.
+    pub fn with_packet<R>(&mut self, f: impl FnOnce(&mut Packet) -> R) -> R {
+        let mut packet = unsafe { Packet::new(self.context()) };
+        CURRENT.with(|current| current.set(self));
+        let result = f(&mut packet);
+        CURRENT.with(|current| current.set(ptr::null_mut()));
+        result
+    }
+
+    /// Click's `push`/`pull` (`head`) and `put`/`take` (`tail`).
+    fn add_space(&mut self, head: i32, tail: i32) -> *mut u8 {
+        if head > 0 {
+            let head = head as usize;
+            if head > self.head {
+                // out of headroom, move the packet back like Click's `expensive_push`
+                let grow = head - self.head + HEADROOM;
+                self.buf.splice(0..0, std::iter::repeat_n(0, grow));
+                self.head += grow;
+            }
+            self.head -= head;
+            self.len += head;
+        } else {
+            let pull = head.unsigned_abs() as usize;
+            assert!(
+                pull <= self.len,
+                "bpf_packet_add_space: pulled beyond the packet"
+            );
+            self.head += pull;
+            self.len -= pull;
+        }
+
+        if tail > 0 {
+            let tail = tail as usize;
+            let end = self.head + self.len + tail;
+            if end > self.buf.len() {
+                self.buf.resize(end + HEADROOM, 0);
+            }
+            self.len += tail;
+        } else {
+            let take = tail.unsigned_abs() as usize;
+            assert!(
+                take <= self.len,
+                "bpf_packet_add_space: took beyond the packet"
+            );
+            self.len -= take;
+        }
+
+        self.data_mut().as_mut_ptr()
+    }
+}
+
+#[derive(Copy, Clone)]
+enum L3Spec {
+    Ipv4(Ipv4Addr, Ipv4Addr),
+    Ipv6(Ipv6Addr, Ipv6Addr),
+}
+
+#[derive(Copy, Clone)]
+enum L4Spec {
+    /// Ports, and whether the datagram has a checksum.
+    Udp(u16, u16, bool),
+    Tcp(u16, u16, u8),
+    IcmpEcho {
+        reply: bool,
+        id: u16,
+        seq: u16,
+    },
+    Icmp {
+        type_: u8,
+        code: u8,
+    },
+}
+
+/// Builds packets with valid lengths and checksums, layer by layer.
+///
+/// Leave out [`PacketBuilder::ethernet`] for programs that run behind `StripEtherVLANHeader`.
+#[derive(Clone, Default)]
+pub struct PacketBuilder {
+    eth: Option<([u8; 6], [u8; 6])>,
+    vlan: Option<u16>,
+    l3: Option<L3Spec>,
+    l4: Option<L4Spec>,
+    payload: Vec<u8>,
+    port: u32,
+}
+
+impl PacketBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ethernet(mut self, src: [u8; 6], dst: [u8; 6]) -> Self {
+        self.eth = Some((src, dst));
+        self
+    }
+
+    /// Adds an 802.1Q tag. Needs [`PacketBuilder::ethernet`].
+    pub fn vlan(mut self, vid: u16) -> Self {
+        self.vlan = Some(vid);
+        self
+    }
+
+    pub fn ipv4(mut self, src: Ipv4Addr, dst: Ipv4Addr) -> Self {
+        self.l3 = Some(L3Spec::Ipv4(src, dst));
+        self
+    }
+
+    pub fn ipv6(mut self, src: Ipv6Addr, dst: Ipv6Addr) -> Self {
+        self.l3 = Some(L3Spec::Ipv6(src, dst));
+        self
+    }
+
+    pub fn udp(mut self, src_port: u16, dst_port: u16) -> Self {
+        self.l4 = Some(L4Spec::Udp(src_port, dst_port, true));
+        self
+    }
+
+    /// A UDP datagram without a checksum (0), which only IPv4 allows.
+    pub fn udp_without_checksum(mut self, src_port: u16, dst_port: u16) -> Self {
+        self.l4 = Some(L4Spec::Udp(src_port, dst_port, false));
+        self
+    }
+
+    /// A TCP segment with only the SYN flag set.
+    pub fn tcp(self, src_port: u16, dst_port: u16) -> Self {
+        self.tcp_flags(src_port, dst_port, 0x02)
+    }
+
+    pub fn tcp_flags(mut self, src_port: u16, dst_port: u16, flags: u8) -> Self {
+        self.l4 = Some(L4Spec::Tcp(src_port, dst_port, flags));
+        self
+    }
+
+    /// An ICMP or, over IPv6, ICMPv6 echo request.
+    pub fn icmp_echo_request(mut self, id: u16, seq: u16) -> Self {
+        self.l4 = Some(L4Spec::IcmpEcho {
+            reply: false,
+            id,
+            seq,
+        });
+        self
+    }
+
+    pub fn icmp_echo_reply(mut self, id: u16, seq: u16) -> Self {
+        self.l4 = Some(L4Spec::IcmpEcho {
+            reply: true,
+            id,
+            seq,
+        });
+        self
+    }
+
+    /// Any other ICMP or ICMPv6 message, with the rest of the header zeroed. Errors quote the
+    /// packet passed to [`PacketBuilder::payload`].
+    pub fn icmp(mut self, type_: u8, code: u8) -> Self {
+        self.l4 = Some(L4Spec::Icmp { type_, code });
+        self
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Sets the input port of the built [`TestPacket`].
+    pub fn port(mut self, port: u32) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn build(&self) -> TestPacket {
+        TestPacket::new(&self.bytes()).port(self.port)
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let segment = self.segment();
+
+        let mut packet = Vec::new();
+        if let Some((src, dst)) = self.eth {
+            packet.extend_from_slice(&dst);
+            packet.extend_from_slice(&src);
+            if let Some(vid) = self.vlan {
+                packet.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+                packet.extend_from_slice(&(vid & 0x0fff).to_be_bytes());
+            }
+            let ether_type = match self.l3 {
+                Some(L3Spec::Ipv4(..)) => ETH_P_IPV4,
+                Some(L3Spec::Ipv6(..)) => ETH_P_IPV6,
+                None => ETH_P_EXPERIMENTAL,
+            };
+            packet.extend_from_slice(&ether_type.to_be_bytes());
+        } else {
+            assert!(self.vlan.is_none(), "a VLAN tag needs an Ethernet header");
+        }
+
+        let proto = self.proto();
+        match self.l3 {
+            Some(L3Spec::Ipv4(src, dst)) => {
+                let mut hdr = [0u8; 20];
+                hdr[0] = 0x45;
+                hdr[2..4].copy_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+                hdr[8] = 64;
+                hdr[9] = proto;
+                hdr[12..16].copy_from_slice(&src.octets());
+                hdr[16..20].copy_from_slice(&dst.octets());
+                let check = finish(sum(&hdr, 0));
+                hdr[10..12].copy_from_slice(&check.to_be_bytes());
+                packet.extend_from_slice(&hdr);
+            }
+            Some(L3Spec::Ipv6(src, dst)) => {
+                let mut hdr = [0u8; 40];
+                hdr[0] = 0x60;
+                hdr[4..6].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+                hdr[6] = proto;
+                hdr[7] = 64;
+                hdr[8..24].copy_from_slice(&src.octets());
+                hdr[24..40].copy_from_slice(&dst.octets());
+                packet.extend_from_slice(&hdr);
+            }
+            None => assert!(self.l4.is_none(), "a transport header needs an IP header"),
+        }
+
+        packet.extend_from_slice(&segment);
+        packet
+    }
+
+    fn proto(&self) -> u8 {
+        match (self.l3, self.l4) {
+            (_, Some(L4Spec::Udp(..))) => IPPROTO_UDP,
+            (_, Some(L4Spec::Tcp(..))) => IPPROTO_TCP,
+            (Some(L3Spec::Ipv6(..)), Some(L4Spec::IcmpEcho { .. } | L4Spec::Icmp { .. })) => {
+                IPPROTO_ICMPV6
+            }
+            (_, Some(L4Spec::IcmpEcho { .. } | L4Spec::Icmp { .. })) => IPPROTO_ICMP,
+            // no next header
+            (Some(L3Spec::Ipv6(..)), None) => 59,
+            // IP-in-IP reserved for experimentation
+            (_, None) => 253,
+        }
+    }
+
+    /// The transport header with its checksum, followed by the payload.
+    fn segment(&self) -> Vec<u8> {
+        let (mut segment, check_offset) = match self.l4 {
+            Some(L4Spec::Udp(src, dst, _)) => {
+                let mut hdr = vec![0u8; 8];
+                hdr[0..2].copy_from_slice(&src.to_be_bytes());
+                hdr[2..4].copy_from_slice(&dst.to_be_bytes());
+                hdr[4..6].copy_from_slice(&((8 + self.payload.len()) as u16).to_be_bytes());
+                (hdr, 6)
+            }
+            Some(L4Spec::Tcp(src, dst, flags)) => {
+                let mut hdr = vec![0u8; 20];
+                hdr[0..2].copy_from_slice(&src.to_be_bytes());
+                hdr[2..4].copy_from_slice(&dst.to_be_bytes());
+                hdr[12] = 5 << 4;
+                hdr[13] = flags;
+                hdr[14..16].copy_from_slice(&0xffffu16.to_be_bytes());
+                (hdr, 16)
+            }
+            Some(L4Spec::IcmpEcho { reply, id, seq }) => {
+                let v6 = matches!(self.l3, Some(L3Spec::Ipv6(..)));
+                let mut hdr = vec![0u8; 8];
+                hdr[0] = match (v6, reply) {
+                    (false, false) => 8,
+                    (false, true) => 0,
+                    (true, false) => 128,
+                    (true, true) => 129,
+                };
+                hdr[4..6].copy_from_slice(&id.to_be_bytes());
+                hdr[6..8].copy_from_slice(&seq.to_be_bytes());
+                (hdr, 2)
+            }
+            Some(L4Spec::Icmp { type_, code }) => {
+                let mut hdr = vec![0u8; 8];
+                hdr[0] = type_;
+                hdr[1] = code;
+                (hdr, 2)
+            }
+            None => return self.payload.clone(),
+        };
+        segment.extend_from_slice(&self.payload);
+
+        let proto = self.proto();
+        let pseudo = match self.l3 {
+            Some(_) if proto == IPPROTO_ICMP => 0,
+            Some(L3Spec::Ipv4(src, dst)) => pseudo_header_v4(src, dst, proto, segment.len() as u16),
+            Some(L3Spec::Ipv6(src, dst)) => pseudo_header_v6(src, dst, proto, segment.len() as u32),
+            None => 0,
+        };
+        let mut check = finish(sum(&segment, pseudo));
+        if check == 0 && proto == IPPROTO_UDP {
+            check = 0xffff;
+        }
+        if let Some(L4Spec::Udp(_, _, false)) = self.l4 {
+            check = 0;
+        }
+        segment[check_offset..check_offset + 2].copy_from_slice(&check.to_be_bytes());
+        segment
+    }
+}
+
+/// Stand-ins for the helpers MorphOS registers, called by [`crate::helpers::Helpers`].
+pub(crate) mod mock {
+    use aya_ebpf::cty::{c_char, c_long, c_void};
+
+    use super::*;
+
+    // Raw map handles don't exist on the host, the maps in `crate::maps` keep their entries
+    // themselves. Anything that still goes through the helpers finds nothing and can't store.
+
+    pub unsafe fn map_lookup_elem(_map: *mut c_void, _key: *const c_void) -> *mut c_void {
+        core::ptr::null_mut()
+    }
+
+    pub unsafe fn map_update_elem(
+        _map: *mut c_void,
+        _key: *const c_void,
+        _value: *const c_void,
+        _flags: u64,
+    ) -> c_long {
+        -22 // -EINVAL
+    }
+
+    pub unsafe fn map_delete_elem(_map: *mut c_void, _key: *const c_void) -> c_long {
+        -22 // -EINVAL
+    }
+
+    pub unsafe fn ktime_get_ns() -> u64 {
+        TIME_NS.with(|time| time.get())
+    }
+
+    /// Supports `%d`, `%i`, `%u`, `%x` and `%%`, like the programs use it.
+    pub unsafe fn trace_printk(
+        fmt: *const c_char,
+        fmt_size: u32,
+        arg1: *const i32,
+        arg2: *const i32,
+        arg3: *const i32,
+    ) -> c_long {
+        let fmt = std::slice::from_raw_parts(fmt as *const u8, fmt_size as usize);
+        let fmt = fmt.split(|b| *b == 0).next().unwrap_or_default();
+        let fmt = String::from_utf8_lossy(fmt);
+
+        let mut args = [arg1, arg2, arg3].into_iter();
+        let mut line = String::new();
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                line.push(c);
+                continue;
+            }
+            let spec = chars.next();
+            if spec == Some('%') {
+                line.push('%');
+                continue;
+            }
+            let arg = *args
+                .next()
+                .expect("bpf_trace_printk: more than three arguments");
+            match spec {
+                Some('d' | 'i') => line.push_str(&arg.to_string()),
+                Some('u') => line.push_str(&(arg as u32).to_string()),
+                Some('x') => line.push_str(&format!("{:x}", arg)),
+                _ => panic!("bpf_trace_printk: unsupported format {fmt:?}"),
+            }
+        }
+
+        let len = line.len() as c_long;
+        TRACE.with(|trace| trace.borrow_mut().push(line));
+        len
+    }
+
+    pub unsafe fn get_prandom_u32() -> u32 {
+        PRANDOM.with(|state| {
+            let mut x = state.get();
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            state.set(x);
+            x
+        })
+    }
+
+    /// Fails with `-ENOENT` for an empty slot and `-E2BIG` past [`MAX_TAIL_CALLS`], otherwise
+    /// leaves the jump to [`TestPacket::run`].
+    pub unsafe fn tail_call(_ctx: *mut c_void, map: *mut c_void, index: u32) -> c_long {
+        let key = (map as usize, index);
+        if !PROGRAMS.with(|programs| programs.borrow().contains_key(&key)) {
+            return -2;
+        }
+        if TAIL_CALLS.with(|count| count.get()) >= MAX_TAIL_CALLS {
+            return -7;
+        }
+        TAIL_CALLS.with(|count| count.set(count.get() + 1));
+        TAIL_CALL.with(|pending| pending.set(Some(key)));
+        0
+    }
+
+    pub unsafe fn unwind(value: u64) -> u64 {
+        value
+    }
+
+    pub unsafe fn packet_add_space(head_len: i32, tail_len: i32) -> *mut u8 {
+        let packet = CURRENT.with(|current| current.get());
+        assert!(
+            !packet.is_null(),
+            "bpf_packet_add_space outside of TestPacket::run or TestPacket::with_packet"
+        );
+        if FAIL_ADD_SPACE.with(|fail| fail.replace(false)) {
+            return ptr::null_mut();
+        }
+        (*packet).add_space(head_len, tail_len)
+    }
+}
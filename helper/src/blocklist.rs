@@ -0,0 +1,115 @@
+//! `helper blocklist ...`: loads a list of IPv4 and IPv6 prefixes, or domains, into an LPM trie map
+//! of a BPF element in the running VM, e.g. the SOURCES map of ebpf/src/bin/cidr-firewall.rs or
+//! the BLOCKLIST map of ebpf/src/bin/dns-policy.rs.
+
+use std::collections::BTreeSet;
+use std::fs;
+
+use anyhow::{bail, Context};
+
+use crate::map::{parse_prefix, Client, BPF_MAP_TYPE_LPM_TRIE};
+
+/// Value of blocked prefixes, `FilterResult::Drop`.
+const DROP: u32 = 1;
+
+const USAGE: &str = "\
+usage: helper blocklist [--id <element id>] <map> <file>
+
+Sets the prefixes in <file> to DROP in <map>, an LPM trie with 4-byte values, and deletes the
+DROP prefixes that are no longer in the file. Prefixes with other values are left alone. The file
+has one prefix (e.g. 203.0.113.0/24 or 2001:db8::/32) or address per line, or one domain (e.g.
+lmu.de, which covers its subdomains) for maps of domains; # starts a comment.
+";
+
+/// The keys of the prefixes or domains in the blocklist file `path`, for a map with
+/// `key_size`-byte keys.
+fn parse_blocklist(path: &str, text: &str, key_size: u32) -> anyhow::Result<BTreeSet<Vec<u8>>> {
+    let mut blocked = BTreeSet::new();
+    for (number, line) in text.lines().enumerate() {
+        let prefix = line.split('#').next().unwrap().trim();
+        if prefix.is_empty() {
+            continue;
+        }
+        let key =
+            parse_prefix(prefix, key_size).with_context(|| format!("{path}:{}", number + 1))?;
+        blocked.insert(key);
+    }
+    Ok(blocked)
+}
+
+pub fn blocklist(args: &[String]) -> anyhow::Result<()> {
+    let (element_id, args) = match args {
+        [flag, id, rest @ ..] if flag == "--id" => {
+            (id.parse().context("invalid element ID")?, rest)
+        }
+        _ => (1, args),
+    };
+    let [name, path] = args else {
+        bail!("invalid blocklist command\n\n{USAGE}");
+    };
+
+    let mut client = Client::new(element_id)?;
+    let def = client.map_def(name)?;
+    if def.map_type != BPF_MAP_TYPE_LPM_TRIE || def.value_size != 4 {
+        bail!("{name} isn't an LPM trie with 4-byte values");
+    }
+
+    let text = fs::read_to_string(path).with_context(|| format!("couldn't read {path}"))?;
+    let blocked = parse_blocklist(path, &text, def.key_size)?;
+    if blocked.len() > def.max_entries as usize {
+        bail!(
+            "{path} has {} prefixes, {name} holds {}",
+            blocked.len(),
+            def.max_entries
+        );
+    }
+
+    let drop = DROP.to_le_bytes();
+    let mut removed = 0;
+    for (key, value) in client.dump(&def)? {
+        if value == drop && !blocked.contains(&key) {
+            client.delete(name, &key)?;
+            removed += 1;
+        }
+    }
+    for key in &blocked {
+        client.update(name, key, &drop)?;
+    }
+    println!("{} entries blocked, {removed} removed", blocked.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_blocklists() {
+        // the second /24 is the first one again
+        let text = "# blocked networks\n\n203.0.113.0/24\n  198.51.100.7 # a single host\n\
+                    203.0.113.9/24\n";
+        let blocked = parse_blocklist("list", text, 8).unwrap();
+        assert_eq!(
+            blocked.into_iter().collect::<Vec<_>>(),
+            [
+                [24, 0, 0, 0, 203, 0, 113, 0],
+                [32, 0, 0, 0, 198, 51, 100, 7]
+            ]
+        );
+
+        let blocked = parse_blocklist("list", "2001:db8::/32\n10.0.0.0/8\n", 21).unwrap();
+        assert_eq!(blocked.len(), 2);
+
+        let blocked = parse_blocklist("list", "lmu.de\nLMU.de.\nexample.com\n", 132).unwrap();
+        assert_eq!(blocked.len(), 2);
+    }
+
+    #[test]
+    fn names_the_line_of_invalid_prefixes() {
+        let error = parse_blocklist("list", "10.0.0.0/8\n\n10.0.0.0/33\n", 8).unwrap_err();
+        assert_eq!(error.to_string(), "list:3");
+        let error = parse_blocklist("list", "2001:db8::/32\n", 8).unwrap_err();
+        assert_eq!(error.to_string(), "list:1");
+    }
+}
@@ -0,0 +1,242 @@
+//! `helper log ...`: reads the events programs logged with bpf_element::log from the LOG ring
+//! buffer and renders them with the format strings in the program's ELF file, see
+//! ebpf/src/log.rs.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Context};
+use object::{Object, ObjectSection};
+
+use crate::map::Client;
+
+/// Size of bpf_element::log::Record.
+const RECORD_SIZE: usize = 56;
+const MAX_ARGS: usize = 4;
+
+/// ELF section the format strings are in.
+const FORMATS_SECTION: &str = "log_formats";
+
+/// bpf_element::log::Level by value.
+const LEVEL_NAMES: [&str; 5] = ["", "ERROR", "WARN", "INFO", "DEBUG"];
+
+const USAGE: &str = "\
+usage: helper log [--id <element id>] [<program.elf>]
+
+Prints the events in the LOG ring buffer of the element's program, oldest first, with their time
+in seconds since boot. The format strings are read from the program's ELF file; without it, events
+show their format ID and arguments. `helper map update LOG_LEVEL 0 4` records debug events too,
+if the program was built with bpf-element's debug-log feature.
+";
+
+/// A bpf_element::log::Record.
+struct Record {
+    seq: u64,
+    time_ns: u64,
+    id: u32,
+    level: u8,
+    args: Vec<u64>,
+}
+
+impl Record {
+    /// Decodes the little-endian value of the map.
+    fn decode(value: &[u8]) -> anyhow::Result<Record> {
+        if value.len() != RECORD_SIZE {
+            bail!(
+                "LOG has {}-byte values, expected {RECORD_SIZE}",
+                value.len()
+            );
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(value[offset..offset + 8].try_into().unwrap());
+        let len = (value[21] as usize).min(MAX_ARGS);
+        Ok(Record {
+            seq: u64_at(0),
+            time_ns: u64_at(8),
+            id: u32::from_le_bytes(value[16..20].try_into().unwrap()),
+            level: value[20],
+            args: (0..len).map(|i| u64_at(24 + 8 * i)).collect(),
+        })
+    }
+
+    fn level_name(&self) -> String {
+        LEVEL_NAMES
+            .get(self.level as usize)
+            .filter(|name| !name.is_empty())
+            .map_or_else(|| format!("LEVEL{}", self.level), |name| name.to_string())
+    }
+
+    fn message(&self, formats: &HashMap<u32, String>) -> String {
+        match formats.get(&self.id) {
+            Some(format) => render(format, &self.args),
+            None => {
+                let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+                format!("event {:#010x} ({})", self.id, args.join(", "))
+            }
+        }
+    }
+}
+
+/// Format strings by ID, from the `log_formats` section of a program.
+fn read_formats(path: &str) -> anyhow::Result<HashMap<u32, String>> {
+    let elf = fs::read(path).with_context(|| format!("couldn't read {path}"))?;
+    let file = object::File::parse(&*elf).context("not an ELF file")?;
+    let Some(section) = file.section_by_name(FORMATS_SECTION) else {
+        // the program logs nothing
+        return Ok(HashMap::new());
+    };
+    let data = section.data().context("couldn't read the log formats")?;
+    parse_formats(path, data)
+}
+
+/// Decodes the `log_formats` section of the program `path`. Each entry is its length (2 bytes)
+/// and ID (4 bytes) in little-endian, then the string.
+fn parse_formats(path: &str, mut data: &[u8]) -> anyhow::Result<HashMap<u32, String>> {
+    let mut formats = HashMap::new();
+    while !data.is_empty() {
+        if data.len() < 6 {
+            bail!("truncated log format in {path}");
+        }
+        let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+        let id = u32::from_le_bytes(data[2..6].try_into().unwrap());
+        let format = data
+            .get(6..6 + len)
+            .with_context(|| format!("truncated log format in {path}"))?;
+        formats.insert(id, String::from_utf8_lossy(format).into_owned());
+        data = &data[6 + len..];
+    }
+    Ok(formats)
+}
+
+/// Fills in the placeholders of a format string like bpf_element::log::render.
+fn render(format: &str, args: &[u64]) -> String {
+    let mut line = String::new();
+    let mut args = args.iter();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        line.push_str(&rest[..start]);
+        let arg = args.next().copied().unwrap_or_default();
+        match &rest[start + 1..start + end] {
+            ":x" => line.push_str(&format!("{arg:#x}")),
+            ":ipv4" => line.push_str(&Ipv4Addr::from(arg as u32).to_string()),
+            _ => line.push_str(&arg.to_string()),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    line.push_str(rest);
+    line
+}
+
+pub fn log(args: &[String]) -> anyhow::Result<()> {
+    let (element_id, program) = match args {
+        [flag, id, rest @ ..] if flag == "--id" => {
+            (id.parse().context("invalid element ID")?, rest)
+        }
+        rest => (1, rest),
+    };
+    let formats = match program {
+        [] => HashMap::new(),
+        [path] => read_formats(path)?,
+        _ => bail!("invalid log command\n\n{USAGE}"),
+    };
+
+    let mut client = Client::new(element_id)?;
+    let def = client.map_def("LOG")?;
+    let mut records = client
+        .dump(&def)?
+        .iter()
+        .map(|(_, value)| Record::decode(value))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    records.retain(|record| record.seq != 0);
+    records.sort_by_key(|record| record.seq);
+
+    if let Some(first) = records.first() {
+        if first.seq > 1 {
+            println!("({} earlier events were overwritten)", first.seq - 1);
+        }
+    }
+    for record in &records {
+        println!(
+            "[{:6}.{:06}] {:5} {}",
+            record.time_ns / 1_000_000_000,
+            record.time_ns % 1_000_000_000 / 1000,
+            record.level_name(),
+            record.message(&formats)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(seq: u64, level: u8, args: &[u64]) -> Vec<u8> {
+        let mut value = seq.to_le_bytes().to_vec();
+        value.extend(1_500_000_000u64.to_le_bytes());
+        value.extend(0x1234u32.to_le_bytes());
+        value.extend([level, args.len() as u8, 0, 0]);
+        for i in 0..MAX_ARGS {
+            value.extend(args.get(i).copied().unwrap_or_default().to_le_bytes());
+        }
+        value
+    }
+
+    fn format(id: u32, format: &str) -> Vec<u8> {
+        let mut entry = (format.len() as u16).to_le_bytes().to_vec();
+        entry.extend(id.to_le_bytes());
+        entry.extend(format.as_bytes());
+        entry
+    }
+
+    #[test]
+    fn decodes_records() {
+        let record = Record::decode(&encode(7, 2, &[1, 2])).unwrap();
+        assert_eq!(
+            (record.seq, record.time_ns, record.id),
+            (7, 1_500_000_000, 0x1234)
+        );
+        assert_eq!(record.level_name(), "WARN");
+        assert_eq!(record.args, [1, 2]);
+        assert_eq!(record.message(&HashMap::new()), "event 0x00001234 (1, 2)");
+
+        // more arguments than a record holds
+        let mut value = encode(1, 9, &[1, 2, 3, 4]);
+        value[21] = 5;
+        let record = Record::decode(&value).unwrap();
+        assert_eq!(record.args.len(), MAX_ARGS);
+        assert_eq!(record.level_name(), "LEVEL9");
+
+        assert!(Record::decode(&value[..RECORD_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn parses_formats() {
+        let data = [format(0x1234, "dropped {:ipv4}"), format(7, "")].concat();
+        let formats = parse_formats("prog.elf", &data).unwrap();
+        assert_eq!(formats.len(), 2);
+        assert_eq!(formats[&7], "");
+        let record = Record::decode(&encode(1, 3, &[0x0a000001])).unwrap();
+        assert_eq!(record.message(&formats), "dropped 10.0.0.1");
+
+        assert!(parse_formats("prog.elf", &data[..data.len() - 1]).is_err());
+        assert!(parse_formats("prog.elf", &[1, 0, 0]).is_err());
+        assert!(parse_formats("prog.elf", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(render("{} of {}", &[1, 2]), "1 of 2");
+        assert_eq!(render("flags {:x}", &[255]), "flags 0xff");
+        assert_eq!(render("from {:ipv4}", &[0xc0000201]), "from 192.0.2.1");
+        // missing arguments are 0, an unclosed placeholder is left as it is
+        assert_eq!(render("{} {}", &[1]), "1 0");
+        assert_eq!(render("a {b", &[1]), "a {b");
+        assert_eq!(render("no placeholders", &[1]), "no placeholders");
+    }
+}
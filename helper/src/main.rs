@@ -2,6 +2,11 @@ use std::env::args;
 use std::net::{TcpStream, UdpSocket};
 use anyhow::{bail, Context};
 
+mod blocklist;
+mod log;
+mod map;
+mod telemetry;
+
 fn main() -> anyhow::Result<()> {
     let arg = args().nth(1);
     match arg.as_deref() {
@@ -14,6 +19,18 @@ fn main() -> anyhow::Result<()> {
         Some("send-tcp-packet") => {
             send_tcp_packet()?;
         }
+        Some("map") => {
+            map::map(&args().skip(2).collect::<Vec<_>>())?;
+        }
+        Some("blocklist") => {
+            blocklist::blocklist(&args().skip(2).collect::<Vec<_>>())?;
+        }
+        Some("telemetry") => {
+            telemetry::telemetry(&args().skip(2).collect::<Vec<_>>())?;
+        }
+        Some("log") => {
+            log::log(&args().skip(2).collect::<Vec<_>>())?;
+        }
         _ => bail!("Invalid argument")
     }
 
@@ -35,6 +52,17 @@ fn reconfigure() -> anyhow::Result<()> {
     data.extend_from_slice(&(signature.len() as u64).to_le_bytes());
     data.extend_from_slice(signature.as_bytes());
 
+    // `MAP INDEX` loads the program into a slot of a program array instead
+    if let Some(map) = args().nth(4) {
+        let index: u32 = args()
+            .nth(5)
+            .context("slot index needs to be passed")?
+            .parse()?;
+        let slot = format!("{map} {index}");
+        data.extend_from_slice(&(slot.len() as u64).to_le_bytes());
+        data.extend_from_slice(slot.as_bytes());
+    }
+
     socket()?.send_to(&data, CONTROL_ADDR).context("couldn't send packet")?;
 
     Ok(())
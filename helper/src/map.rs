@@ -0,0 +1,650 @@
+//! `helper map ...`: reads and writes the maps of a BPF element in the running VM, with the
+//! "ctrlmap" requests the Control element answers (see libs/click/unikraft/control.cc).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::telemetry::Telemetry;
+use crate::{socket, CONTROL_ADDR};
+
+const MAGIC: &[u8; 7] = b"ctrlmap";
+
+const OP_LIST: u8 = 1;
+const OP_GET: u8 = 2;
+const OP_UPDATE: u8 = 3;
+const OP_DELETE: u8 = 4;
+const OP_DUMP: u8 = 5;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_PROG_ARRAY: u32 = 3;
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+pub(crate) const BPF_MAP_TYPE_LPM_TRIE: u32 = 11;
+
+/// Key size of LPM tries holding domains, like bpf_element::dns::SuffixMap.
+const DOMAIN_KEY_SIZE: u32 = 132;
+
+const USAGE: &str = "\
+usage: helper map [--id <element id>] <command>
+
+commands:
+  list                          maps of the element's program
+  get <map> <key>               value of a key
+  update <map> <key> <value>    insert or overwrite a key
+  delete <map> <key>            delete a key of a hash map, or empty a slot of a program array
+  dump <map>                    all entries; arrays leave out all-zero entries, LRU hash maps
+                                list the most recently used first, LPM tries the longest prefixes
+
+Keys and values are decimal integers (little-endian, for maps with 1, 2, 4, 8 or 16 byte keys or
+values), IPv4 addresses (network byte order) or 0x-prefixed hex bytes, e.g. 0x0a000001. They must
+have the size of the map's keys and values. Keys of LPM tries with IPv4 or IPv6 data, like
+bpf_element::maps::IpPrefixMap, can be prefixes, e.g. 10.0.0.0/8; get finds the longest prefix
+containing an address. Keys of LPM tries with 128-byte data, like bpf_element::dns::SuffixMap, are
+domains, e.g. lmu.de, which also cover their subdomains.
+
+Values print as hex bytes, with the little-endian number for 1, 2, 4 and 8-byte values. The map
+definitions only give their sizes, so only the values of TELEMETRY (bpf_element::telemetry) and of
+rate-limiter's BUCKETS are decoded into their fields.
+";
+
+/// Nanoseconds per token of rate-limiter's buckets, which count billionths of a token.
+const NS_PER_TOKEN: u64 = 1_000_000_000;
+
+/// A map as the element's program defines it.
+pub(crate) struct MapDef {
+    pub(crate) name: String,
+    pub(crate) map_type: u32,
+    pub(crate) key_size: u32,
+    pub(crate) value_size: u32,
+    pub(crate) max_entries: u32,
+}
+
+pub(crate) struct Client {
+    socket: UdpSocket,
+    element_id: u64,
+    seq: u32,
+}
+
+impl Client {
+    pub(crate) fn new(element_id: u64) -> anyhow::Result<Client> {
+        let socket = socket()?;
+        socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+        Ok(Client {
+            socket,
+            element_id,
+            seq: 0,
+        })
+    }
+
+    /// Sends a request and returns the body of the reply.
+    fn request(&mut self, op: u8, map: &str, args: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.push(op);
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data.extend_from_slice(&self.element_id.to_le_bytes());
+        data.extend_from_slice(&(map.len() as u64).to_le_bytes());
+        data.extend_from_slice(map.as_bytes());
+        data.extend_from_slice(args);
+        self.socket
+            .send_to(&data, CONTROL_ADDR)
+            .context("couldn't send packet")?;
+
+        let mut buf = [0; 2048];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(_) => bail!(
+                    "no reply from {CONTROL_ADDR} (is the Control element's output connected?)"
+                ),
+            };
+            let mut reply = Reader(&buf[..len]);
+            if reply.bytes(7)? != MAGIC || reply.u8()? != op || reply.u32()? != self.seq {
+                // a late reply to an earlier request
+                continue;
+            }
+            let status = reply.u32()? as i32;
+            if status != 0 {
+                bail!("{}", error_message(-status));
+            }
+            return Ok(reply.0.to_vec());
+        }
+    }
+
+    fn list(&mut self) -> anyhow::Result<Vec<MapDef>> {
+        let body = self.request(OP_LIST, "", &[])?;
+        let mut reader = Reader(&body);
+        let count = reader.u32()?;
+        (0..count)
+            .map(|_| {
+                let name = reader.blob()?;
+                Ok(MapDef {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    map_type: reader.u32()?,
+                    key_size: reader.u32()?,
+                    value_size: reader.u32()?,
+                    max_entries: reader.u32()?,
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn map_def(&mut self, name: &str) -> anyhow::Result<MapDef> {
+        self.list()?
+            .into_iter()
+            .find(|map| map.name == name)
+            .with_context(|| {
+                format!(
+                    "the program of element {} has no map {name}",
+                    self.element_id
+                )
+            })
+    }
+
+    pub(crate) fn get(&mut self, name: &str, key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let body = self.request(OP_GET, name, &blob(key))?;
+        Ok(Reader(&body).blob()?.to_vec())
+    }
+
+    pub(crate) fn update(&mut self, name: &str, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        let mut args = blob(key);
+        args.extend(blob(value));
+        self.request(OP_UPDATE, name, &args)?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, name: &str, key: &[u8]) -> anyhow::Result<()> {
+        self.request(OP_DELETE, name, &blob(key))?;
+        Ok(())
+    }
+
+    /// All keys and values of the map.
+    pub(crate) fn dump(&mut self, def: &MapDef) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let mut offset = 0u64;
+        loop {
+            let body = self.request(OP_DUMP, &def.name, &offset.to_le_bytes())?;
+            let mut reply = Reader(&body);
+            offset = reply.u64()?;
+            let more = reply.u8()? != 0;
+            for _ in 0..reply.u32()? {
+                let key = reply.bytes(def.key_size as usize)?;
+                let value = reply.bytes(def.value_size as usize)?;
+                entries.push((key.to_vec(), value.to_vec()));
+            }
+            if !more {
+                return Ok(entries);
+            }
+        }
+    }
+}
+
+/// Little-endian fields of a reply.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if len > self.0.len() {
+            bail!("truncated reply");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn blob(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.bytes(len as usize)
+    }
+}
+
+fn error_message(errno: i32) -> String {
+    match errno {
+        2 => "no such map or key".to_string(),
+        7 => "reply too large".to_string(),
+        19 => "no BPF element with this ID".to_string(),
+        22 => "key or value doesn't match the map, or can't be deleted".to_string(),
+        95 => "request not supported".to_string(),
+        errno => format!("error {errno}"),
+    }
+}
+
+fn blob(bytes: &[u8]) -> Vec<u8> {
+    let mut data = (bytes.len() as u64).to_le_bytes().to_vec();
+    data.extend_from_slice(bytes);
+    data
+}
+
+/// Encodes a key or value given on the command line into `size` bytes.
+fn parse_value(value: &str, size: u32) -> anyhow::Result<Vec<u8>> {
+    let size = size as usize;
+    let bytes = if let Some(hex) = value.strip_prefix("0x") {
+        if hex.len() % 2 != 0 {
+            bail!("odd number of hex digits in {value}");
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid hex bytes {value}"))?
+    } else if let Ok(ip) = value.parse::<Ipv4Addr>() {
+        ip.octets().to_vec()
+    } else {
+        let number: u128 = value
+            .parse()
+            .with_context(|| format!("invalid value {value}"))?;
+        if ![1, 2, 4, 8, 16].contains(&size) || (size < 16 && number >> (size * 8) != 0) {
+            bail!("{value} doesn't fit into {size} bytes");
+        }
+        number.to_le_bytes()[..size].to_vec()
+    };
+    if bytes.len() != size {
+        bail!("{value} has {} bytes, the map needs {size}", bytes.len());
+    }
+    Ok(bytes)
+}
+
+/// Encodes a key, as a prefix if the map is an LPM trie with IPv4 or IPv6 data.
+fn parse_key(key: &str, def: &MapDef) -> anyhow::Result<Vec<u8>> {
+    if def.map_type == BPF_MAP_TYPE_LPM_TRIE && !key.starts_with("0x") {
+        return parse_prefix(key, def.key_size);
+    }
+    parse_value(key, def.key_size)
+}
+
+/// Encodes `addr/len`, or an address as a host prefix, into an LPM trie key of `size` bytes: the
+/// prefix length, then the data. Tries with 4-byte data hold IPv4 prefixes, with 16-byte data IPv6
+/// prefixes, and with 17-byte data both, as bpf_element::maps::IpPrefixMap: the address family
+/// (4 or 6) and the address, with a prefix length 8 bits longer. Tries with 128-byte data hold
+/// domains, see [`parse_domain`].
+pub(crate) fn parse_prefix(prefix: &str, size: u32) -> anyhow::Result<Vec<u8>> {
+    if size == DOMAIN_KEY_SIZE {
+        return parse_domain(prefix);
+    }
+    let (addr, len) = match prefix.split_once('/') {
+        Some((addr, len)) => (addr, Some(len)),
+        None => (prefix, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .with_context(|| format!("invalid address in {prefix}"))?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let len: u32 = match len {
+        None => bits,
+        Some(len) => match len.parse() {
+            Ok(len) if len <= bits => len,
+            _ => bail!("invalid prefix length in {prefix}"),
+        },
+    };
+    // stored with the bits past the prefix cleared
+    let addr = match addr {
+        IpAddr::V4(addr) => {
+            Ipv4Addr::from(u32::from(addr) & u32::MAX.checked_shl(32 - len).unwrap_or(0)).into()
+        }
+        IpAddr::V6(addr) => {
+            Ipv6Addr::from(u128::from(addr) & u128::MAX.checked_shl(128 - len).unwrap_or(0)).into()
+        }
+    };
+    let mut data = match (size, addr) {
+        (8, IpAddr::V4(addr)) => addr.octets().to_vec(),
+        (20, IpAddr::V6(addr)) => addr.octets().to_vec(),
+        (21, IpAddr::V4(addr)) => [&[4][..], &addr.octets(), &[0; 12]].concat(),
+        (21, IpAddr::V6(addr)) => [&[6][..], &addr.octets()].concat(),
+        _ => bail!("{prefix} doesn't fit into the map's {size}-byte keys"),
+    };
+    let len = if size == 21 { len + 8 } else { len };
+    let mut key = len.to_le_bytes().to_vec();
+    key.append(&mut data);
+    Ok(key)
+}
+
+/// Encodes a domain, e.g. lmu.de, as the key of a bpf_element::dns::SuffixMap: its labels in reverse
+/// order and lower-cased, e.g. \x02de\x03lmu. Matches the domain and its subdomains; "." matches
+/// every name.
+fn parse_domain(domain: &str) -> anyhow::Result<Vec<u8>> {
+    let trimmed = domain.strip_suffix('.').unwrap_or(domain);
+    let mut data = Vec::new();
+    if !trimmed.is_empty() {
+        for label in trimmed.rsplit('.') {
+            if label.is_empty() || label.len() > 63 {
+                bail!("invalid label in {domain}");
+            }
+            data.push(label.len() as u8);
+            data.extend(label.bytes().map(|byte| byte.to_ascii_lowercase()));
+        }
+    }
+    if data.len() > DOMAIN_KEY_SIZE as usize - 4 {
+        bail!("{domain} is longer than {} bytes", DOMAIN_KEY_SIZE - 4);
+    }
+    let mut key = (8 * data.len() as u32).to_le_bytes().to_vec();
+    key.append(&mut data);
+    key.resize(DOMAIN_KEY_SIZE as usize, 0);
+    Ok(key)
+}
+
+/// A domain key written with dots, see [`parse_domain`].
+fn format_domain(len: u32, data: &[u8]) -> Option<String> {
+    let mut data = data.get(..len as usize / 8)?;
+    let mut labels = Vec::new();
+    while let Some((&size, rest)) = data.split_first() {
+        let (label, rest) = rest.split_at_checked(size as usize)?;
+        labels.push(String::from_utf8_lossy(label));
+        data = rest;
+    }
+    labels.reverse();
+    Some(labels.join(".") + ".")
+}
+
+/// An LPM trie key as a prefix, if it has IPv4 or IPv6 data, or as a domain.
+fn format_prefix(key: &[u8]) -> Option<String> {
+    let (len, data) = key.split_at_checked(4)?;
+    let len = u32::from_le_bytes(len.try_into().unwrap());
+    if key.len() == DOMAIN_KEY_SIZE as usize {
+        return format_domain(len, data);
+    }
+    let (addr, len) = match (data.len(), data.first()) {
+        (4, _) => (IpAddr::from(<[u8; 4]>::try_from(data).ok()?), len),
+        (16, _) => (IpAddr::from(<[u8; 16]>::try_from(data).ok()?), len),
+        (17, Some(4)) => (
+            IpAddr::from(<[u8; 4]>::try_from(&data[1..5]).ok()?),
+            len.checked_sub(8)?,
+        ),
+        (17, Some(6)) => (
+            IpAddr::from(<[u8; 16]>::try_from(&data[1..]).ok()?),
+            len.checked_sub(8)?,
+        ),
+        _ => return None,
+    };
+    Some(format!("{addr}/{len}"))
+}
+
+/// Keys of LPM tries as prefixes, everything else like [`format_value`].
+fn format_key(key: &[u8], def: &MapDef) -> String {
+    match def.map_type {
+        BPF_MAP_TYPE_LPM_TRIE => format_prefix(key).unwrap_or_else(|| format_value(key)),
+        _ => format_value(key),
+    }
+}
+
+/// Hex bytes, with the little-endian number for sizes numbers come in.
+fn format_value(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    if matches!(bytes.len(), 1 | 2 | 4 | 8) {
+        let mut number = [0; 8];
+        number[..bytes.len()].copy_from_slice(bytes);
+        format!("0x{hex} ({})", u64::from_le_bytes(number))
+    } else {
+        format!("0x{hex}")
+    }
+}
+
+/// A value of one of the maps whose layout is known by name, see USAGE, as its fields, else like
+/// [`format_value`].
+fn format_map_value(name: &str, value: &[u8]) -> String {
+    if name == "TELEMETRY" {
+        if let Ok(telemetry) = Telemetry::decode(value) {
+            return telemetry.lines().join("; ");
+        }
+    }
+    // rate-limiter's Bucket: tokens, last refill, packets dropped
+    if name == "BUCKETS" && value.len() == 24 {
+        let field = |i: usize| u64::from_le_bytes(value[i * 8..i * 8 + 8].try_into().unwrap());
+        return format!(
+            "{}.{:09} tokens, refilled at {} ns, {} dropped",
+            field(0) / NS_PER_TOKEN,
+            field(0) % NS_PER_TOKEN,
+            field(1),
+            field(2)
+        );
+    }
+    format_value(value)
+}
+
+fn map_type(map_type: u32) -> String {
+    match map_type {
+        BPF_MAP_TYPE_HASH => "hash".to_string(),
+        BPF_MAP_TYPE_ARRAY => "array".to_string(),
+        BPF_MAP_TYPE_PROG_ARRAY => "program array".to_string(),
+        BPF_MAP_TYPE_LRU_HASH => "LRU hash".to_string(),
+        BPF_MAP_TYPE_LPM_TRIE => "LPM trie".to_string(),
+        other => format!("type {other}"),
+    }
+}
+
+pub fn map(args: &[String]) -> anyhow::Result<()> {
+    let (element_id, args) = match args {
+        [flag, id, rest @ ..] if flag == "--id" => {
+            (id.parse().context("invalid element ID")?, rest)
+        }
+        _ => (1, args),
+    };
+
+    let mut client = Client::new(element_id)?;
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["list"] => {
+            for map in client.list()? {
+                println!(
+                    "{}: {}, {}-byte keys, {}-byte values, {} entries",
+                    map.name,
+                    map_type(map.map_type),
+                    map.key_size,
+                    map.value_size,
+                    map.max_entries
+                );
+            }
+        }
+        ["get", name, key] => {
+            let def = client.map_def(name)?;
+            let key = parse_key(key, &def)?;
+            println!("{}", format_map_value(name, &client.get(name, &key)?));
+        }
+        ["update", name, key, value] => {
+            let def = client.map_def(name)?;
+            let key = parse_key(key, &def)?;
+            client.update(name, &key, &parse_value(value, def.value_size)?)?;
+        }
+        ["delete", name, key] => {
+            let def = client.map_def(name)?;
+            let key = parse_key(key, &def)?;
+            client.delete(name, &key)?;
+        }
+        ["dump", name] => {
+            let def = client.map_def(name)?;
+            let entries = client.dump(&def)?;
+            for (key, value) in &entries {
+                println!(
+                    "{} => {}",
+                    format_key(key, &def),
+                    format_map_value(name, value)
+                );
+            }
+            println!("{} entries", entries.len());
+        }
+        _ => bail!("invalid map command\n\n{USAGE}"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(parse_value("258", 2).unwrap(), [2, 1]);
+        assert_eq!(parse_value("10.0.0.1", 4).unwrap(), [10, 0, 0, 1]);
+        assert_eq!(parse_value("0x0a0b", 2).unwrap(), [10, 11]);
+        assert_eq!(parse_value("1", 16).unwrap(), [&[1][..], &[0; 15]].concat());
+        assert!(parse_value("256", 1).is_err());
+        assert!(parse_value("1", 3).is_err());
+        assert!(parse_value("0x0a0", 2).is_err());
+        assert!(parse_value("0xzz", 1).is_err());
+        assert!(parse_value("0x0a", 2).is_err());
+        assert!(parse_value("10.0.0.1", 8).is_err());
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        assert_eq!(
+            parse_prefix("10.1.2.3/8", 8).unwrap(),
+            [8, 0, 0, 0, 10, 0, 0, 0]
+        );
+        assert_eq!(
+            parse_prefix("10.1.2.3", 8).unwrap(),
+            [32, 0, 0, 0, 10, 1, 2, 3]
+        );
+        assert_eq!(parse_prefix("0.0.0.0/0", 8).unwrap(), [0; 8]);
+        let v6 = parse_prefix("2001:db8::1/32", 20).unwrap();
+        assert_eq!(v6[..8], [32, 0, 0, 0, 0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(v6[8..], [0; 12]);
+
+        // both families, with the family byte counted into the prefix length
+        let v4 = parse_prefix("192.0.2.0/24", 21).unwrap();
+        assert_eq!(v4, [&[32, 0, 0, 0, 4, 192, 0, 2, 0][..], &[0; 12]].concat());
+        let v6 = parse_prefix("::1", 21).unwrap();
+        assert_eq!(v6[..5], [136, 0, 0, 0, 6]);
+        assert_eq!(v6[20], 1);
+
+        assert!(parse_prefix("10.0.0.0/33", 8).is_err());
+        assert!(parse_prefix("10.0.0.0/x", 8).is_err());
+        assert!(parse_prefix("::1", 8).is_err());
+        assert!(parse_prefix("10.0.0.0/8", 20).is_err());
+        assert!(parse_prefix("lmu.de", 8).is_err());
+    }
+
+    #[test]
+    fn formats_prefixes() {
+        for (prefix, size) in [
+            ("10.0.0.0/8", 8),
+            ("2001:db8::/32", 20),
+            ("192.0.2.0/24", 21),
+            ("::1/128", 21),
+        ] {
+            assert_eq!(
+                format_prefix(&parse_prefix(prefix, size).unwrap()).unwrap(),
+                prefix
+            );
+        }
+        // unknown family, and a prefix too short to hold one
+        assert_eq!(
+            format_prefix(&[&[8, 0, 0, 0, 5][..], &[0; 16]].concat()),
+            None
+        );
+        assert_eq!(
+            format_prefix(&[&[4, 0, 0, 0, 4][..], &[0; 16]].concat()),
+            None
+        );
+        assert_eq!(format_prefix(&[1, 0, 0]), None);
+    }
+
+    #[test]
+    fn encodes_domains() {
+        let key = parse_prefix("LMU.de.", DOMAIN_KEY_SIZE).unwrap();
+        assert_eq!(key.len(), DOMAIN_KEY_SIZE as usize);
+        assert_eq!(key[..11], *b"\x38\0\0\0\x02de\x03lmu");
+        assert!(key[11..].iter().all(|&byte| byte == 0));
+        assert_eq!(format_prefix(&key).unwrap(), "lmu.de.");
+
+        let root = parse_prefix(".", DOMAIN_KEY_SIZE).unwrap();
+        assert_eq!(root, [0; DOMAIN_KEY_SIZE as usize]);
+        assert_eq!(format_prefix(&root).unwrap(), ".");
+
+        assert!(parse_domain("lmu..de").is_err());
+        assert!(parse_domain(&"a".repeat(64)).is_err());
+        assert!(parse_domain(&vec!["a".repeat(63); 2].join(".")).is_ok());
+        assert!(parse_domain(&vec!["a".repeat(63); 3].join(".")).is_err());
+        // a label running past the prefix
+        assert_eq!(format_domain(16, b"\x05de"), None);
+    }
+
+    #[test]
+    fn decodes_known_values() {
+        let bucket: Vec<u8> = [2_500_000_000u64, 7, 3]
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect();
+        assert_eq!(
+            format_map_value("BUCKETS", &bucket),
+            "2.500000000 tokens, refilled at 7 ns, 3 dropped"
+        );
+        assert!(format_map_value("TELEMETRY", &[0; 232]).starts_with("no program: 0 packets"));
+        // other layouts, or known names with other sizes
+        assert_eq!(
+            format_map_value("BUCKETS", &bucket[..8]),
+            "0x00f9029500000000 (2500000000)"
+        );
+        assert_eq!(format_map_value("TELEMETRY", &[1, 0]), "0x0100 (1)");
+        assert_eq!(format_map_value("FLOWS", &[1, 2, 3]), "0x010203");
+    }
+
+    #[test]
+    fn formats_keys_and_values() {
+        assert_eq!(format_value(&[1, 1]), "0x0101 (257)");
+        assert_eq!(format_value(&[1, 2, 3]), "0x010203");
+        let trie = MapDef {
+            name: "TRIE".to_string(),
+            map_type: BPF_MAP_TYPE_LPM_TRIE,
+            key_size: 8,
+            value_size: 4,
+            max_entries: 16,
+        };
+        assert_eq!(format_key(&[8, 0, 0, 0, 10, 0, 0, 0], &trie), "10.0.0.0/8");
+        assert_eq!(
+            parse_key(
+                "0x0800000a",
+                &MapDef {
+                    key_size: 4,
+                    ..trie
+                }
+            )
+            .unwrap(),
+            [8, 0, 0, 10]
+        );
+        let hash = MapDef {
+            name: "HASH".to_string(),
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 4,
+            max_entries: 16,
+        };
+        assert_eq!(format_key(&[8, 0, 0, 10], &hash), "0x0800000a (167772168)");
+        assert_eq!(parse_key("10.0.0.8", &hash).unwrap(), [10, 0, 0, 8]);
+    }
+
+    #[test]
+    fn reads_replies() {
+        let mut data = vec![7];
+        data.extend(3u32.to_le_bytes());
+        data.extend(blob(b"key"));
+        data.extend(9u64.to_le_bytes());
+        let mut reply = Reader(&data);
+        assert_eq!(reply.u8().unwrap(), 7);
+        assert_eq!(reply.u32().unwrap(), 3);
+        assert_eq!(reply.blob().unwrap(), b"key");
+        assert_eq!(reply.u64().unwrap(), 9);
+        assert!(reply.u8().is_err());
+
+        // a blob longer than the reply
+        let mut reply = Reader(&[4, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(reply.blob().is_err());
+    }
+}
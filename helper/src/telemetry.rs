@@ -0,0 +1,218 @@
+//! `helper telemetry ...`: reads and decodes the TELEMETRY map every program built with
+//! bpf_element's entry attributes keeps, see ebpf/src/telemetry.rs.
+
+use anyhow::{bail, Context};
+
+use crate::map::Client;
+
+/// Slots of the verdict and error histograms.
+const VERDICTS: usize = 16;
+const ERRORS: usize = 8;
+
+/// Size of bpf_element::telemetry::Telemetry.
+const SIZE: usize = 8 * (5 + VERDICTS + ERRORS);
+
+const PROGRAM_FILTER: u64 = 1;
+const PROGRAM_CLASSIFIER: u64 = 2;
+const PROGRAM_REWRITER: u64 = 3;
+
+/// bpf_element::Error by value.
+const ERROR_NAMES: [&str; 6] = [
+    "",
+    "OutOfBounds",
+    "Malformed",
+    "Unsupported",
+    "Map",
+    "Resize",
+];
+
+const USAGE: &str = "\
+usage: helper telemetry [--id <element id>]
+
+Prints the packets, bytes, verdicts and errors the program of the element counted, including the
+programs it replaced.
+";
+
+/// The counters of bpf_element::telemetry::Telemetry.
+pub(crate) struct Telemetry {
+    program: u64,
+    packets: u64,
+    bytes: u64,
+    verdicts: [u64; VERDICTS],
+    drops: u64,
+    aborts: u64,
+    errors: [u64; ERRORS],
+}
+
+impl Telemetry {
+    /// Decodes the little-endian value of the map.
+    pub(crate) fn decode(value: &[u8]) -> anyhow::Result<Telemetry> {
+        if value.len() != SIZE {
+            bail!("TELEMETRY has {}-byte values, expected {SIZE}", value.len());
+        }
+        let mut fields = value
+            .chunks_exact(8)
+            .map(|field| u64::from_le_bytes(field.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+        Ok(Telemetry {
+            program: next(),
+            packets: next(),
+            bytes: next(),
+            verdicts: std::array::from_fn(|_| next()),
+            drops: next(),
+            aborts: next(),
+            errors: std::array::from_fn(|_| next()),
+        })
+    }
+
+    /// Names of the verdict slots of the kind of program that counted.
+    fn verdict_name(&self, slot: usize) -> String {
+        let names: &[&str] = match self.program {
+            PROGRAM_FILTER => &["Abort", "Drop", "Pass"],
+            PROGRAM_REWRITER => &["Abort", "Success", "Drop"],
+            PROGRAM_CLASSIFIER if slot == VERDICTS - 1 => return format!("ports {slot}+"),
+            PROGRAM_CLASSIFIER => return format!("port {slot}"),
+            _ => &[],
+        };
+        names
+            .get(slot)
+            .map_or_else(|| format!("verdict {slot}"), |name| name.to_string())
+    }
+
+    fn program_name(&self) -> &'static str {
+        match self.program {
+            PROGRAM_FILTER => "filter",
+            PROGRAM_CLASSIFIER => "classifier",
+            PROGRAM_REWRITER => "rewriter",
+            _ => "no program",
+        }
+    }
+
+    /// The counters as `helper telemetry` prints them.
+    pub(crate) fn lines(&self) -> [String; 4] {
+        let verdicts = self.verdicts.iter().enumerate();
+        let errors = self.errors.iter().enumerate().map(|(value, count)| {
+            let name = ERROR_NAMES.get(value).filter(|name| !name.is_empty());
+            (
+                name.map_or_else(|| format!("error {value}"), |name| name.to_string()),
+                *count,
+            )
+        });
+        [
+            format!(
+                "{}: {} packets, {} bytes",
+                self.program_name(),
+                self.packets,
+                self.bytes
+            ),
+            format!(
+                "verdicts: {}",
+                histogram(verdicts.map(|(slot, count)| (self.verdict_name(slot), *count)))
+            ),
+            format!("dropped: {}, aborted: {}", self.drops, self.aborts),
+            format!("errors: {}", histogram(errors)),
+        ]
+    }
+}
+
+/// `name count` for every nonzero count, or "none".
+fn histogram(counts: impl Iterator<Item = (String, u64)>) -> String {
+    let counts: Vec<String> = counts
+        .filter(|(_, count)| *count != 0)
+        .map(|(name, count)| format!("{name} {count}"))
+        .collect();
+    if counts.is_empty() {
+        "none".to_string()
+    } else {
+        counts.join(", ")
+    }
+}
+
+pub fn telemetry(args: &[String]) -> anyhow::Result<()> {
+    let element_id = match args {
+        [] => 1,
+        [flag, id] if flag == "--id" => id.parse().context("invalid element ID")?,
+        _ => bail!("invalid telemetry command\n\n{USAGE}"),
+    };
+
+    let mut client = Client::new(element_id)?;
+    let telemetry = Telemetry::decode(&client.get("TELEMETRY", &0u32.to_le_bytes())?)?;
+    for line in telemetry.lines() {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A value with the fields numbered from 1, the program of the given kind.
+    fn value(program: u64) -> Vec<u8> {
+        let mut fields: Vec<u64> = (1..=(SIZE / 8) as u64).collect();
+        fields[0] = program;
+        fields
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_counters() {
+        let telemetry = Telemetry::decode(&value(PROGRAM_FILTER)).unwrap();
+        assert_eq!(telemetry.program_name(), "filter");
+        assert_eq!((telemetry.packets, telemetry.bytes), (2, 3));
+        assert_eq!(telemetry.verdicts[0], 4);
+        assert_eq!(telemetry.verdicts[VERDICTS - 1], 19);
+        assert_eq!((telemetry.drops, telemetry.aborts), (20, 21));
+        assert_eq!(telemetry.errors, [22, 23, 24, 25, 26, 27, 28, 29]);
+
+        let error = Telemetry::decode(&value(PROGRAM_FILTER)[8..])
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("TELEMETRY has {}-byte values, expected {SIZE}", SIZE - 8)
+        );
+    }
+
+    #[test]
+    fn names_verdicts() {
+        let filter = Telemetry::decode(&value(PROGRAM_FILTER)).unwrap();
+        assert_eq!(filter.verdict_name(1), "Drop");
+        assert_eq!(filter.verdict_name(3), "verdict 3");
+        let rewriter = Telemetry::decode(&value(PROGRAM_REWRITER)).unwrap();
+        assert_eq!(rewriter.verdict_name(1), "Success");
+        let classifier = Telemetry::decode(&value(PROGRAM_CLASSIFIER)).unwrap();
+        assert_eq!(classifier.program_name(), "classifier");
+        assert_eq!(classifier.verdict_name(2), "port 2");
+        assert_eq!(classifier.verdict_name(VERDICTS - 1), "ports 15+");
+        let empty = Telemetry::decode(&[0; SIZE]).unwrap();
+        assert_eq!(empty.program_name(), "no program");
+        assert_eq!(empty.verdict_name(0), "verdict 0");
+    }
+
+    #[test]
+    fn prints_counters() {
+        let telemetry = Telemetry::decode(&value(PROGRAM_REWRITER)).unwrap();
+        let lines = telemetry.lines();
+        assert_eq!(lines[0], "rewriter: 2 packets, 3 bytes");
+        assert!(lines[1].starts_with("verdicts: Abort 4, Success 5, Drop 6, verdict 3 7, "));
+        assert_eq!(lines[2], "dropped: 20, aborted: 21");
+        assert_eq!(
+            lines[3],
+            "errors: error 0 22, OutOfBounds 23, Malformed 24, Unsupported 25, Map 26, \
+             Resize 27, error 6 28, error 7 29"
+        );
+    }
+
+    #[test]
+    fn leaves_out_zero_counts() {
+        let counts = [("Drop", 0), ("Pass", 5), ("Abort", 1)];
+        let counts = counts
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count));
+        assert_eq!(histogram(counts), "Pass 5, Abort 1");
+        assert_eq!(histogram([("Drop".to_string(), 0)].into_iter()), "none");
+    }
+}
@@ -21,9 +21,14 @@ void BPFClassifier::push(int port, Packet *p) {
     int ret = this->exec(port, p_out);
     uk_rwlock_runlock(&_lock);
 
-    if (ret == -1) {
+    if (ret == BPFCLASSIFIER_ABORT) {
         uk_pr_debug("BPFClassifier: Classifier aborted\n");
-        p->kill();
+        p_out->kill();
+        return;
+    }
+
+    if (ret == BPFCLASSIFIER_DROP) {
+        p_out->kill();
         return;
     }
 
@@ -13,6 +13,9 @@
 
 CLICK_DECLS
 
+#define BPFCLASSIFIER_ABORT -1
+#define BPFCLASSIFIER_DROP -2
+
 /*
 =c
 
@@ -27,13 +30,23 @@ filters packets based on an ebpf program
 Classify packets based on an ebpf program. The output port is determined by the return value of the ebpf program.
 The ebpf program is loaded from a file.
 
+A return value of -1 aborts, -2 drops the packet. Both values are mirrored by
+bpf_element::classifier in the ebpf crate. Packets for ports the element doesn't have are dropped.
+
 Keyword arguments are:
 
 =over 8
 
 =item FILE
 
-String. Required. File name of the ebpf program defining the classifier.
+String. Required, except to reconfigure only TAIL_CALL slots. File name of the ebpf program defining the classifier.
+
+=item TAIL_CALL
+
+String, repeatable. `MAP INDEX FILE [SIGNATURE]` loads the classifier program FILE into slot INDEX of
+the program array MAP of the program, for `bpf_tail_call`; `MAP INDEX` empties the slot. The
+programs share the maps of the program by name, and a slot keeps its program if FILE can't be
+loaded.
 
  */
 class BPFClassifier : public BPFElement {
@@ -49,6 +62,11 @@ public:
 
     void push(int, Packet *) override;
 
+protected:
+
+    // output 0
+    bool migration_succeeded(uint32_t ret) const override { return ret == 0; }
+
 };
 
 CLICK_ENDDECLS
@@ -9,7 +9,10 @@
 #include <openssl/sha.h>
 #include <openssl/err.h>
 
+#include <cerrno>
 #include <cstdio>
+#include <cstdlib>
+#include <cstring>
 #include <vector>
 #include <string>
 
@@ -108,19 +111,23 @@ WITH_PKEYS(pkey1_bpf_ktime_get_ns, bpf_ktime_get_ns, 1)
 #endif
 
 void BPFElement::init_ubpf_vm() {
+    this->_bpf_map_ctx = new bpf_map_ctx();
+    this->_ubpf_vm = create_ubpf_vm();
+}
+
+// A VM for the main program or one loaded with TAIL_CALL, all relocating into the same maps
+ubpf_vm *BPFElement::create_ubpf_vm() {
     ubpf_vm *vm = ubpf_create();
     if (vm == NULL) {
-        return;
+        return NULL;
     }
 
-    this->_bpf_map_ctx = new bpf_map_ctx();
-
     ubpf_toggle_bounds_check(vm, false);
     ubpf_toggle_undefined_behavior_check(vm, false);
     ubpf_register_data_relocation(vm, this->_bpf_map_ctx, do_map_relocation);
     ubpf_set_jit_code_size(vm, 128*1024); // default is 64KB
 
-    // register bpf helpers
+    // register bpf helpers (keep in sync with ebpf/src/helpers.rs and the verifier's spec_prototypes.cpp)
     ubpf_register(vm, 1, "bpf_map_lookup_elem", as_external_function_t((void *) bpf_map_lookup_elem));
     ubpf_register(vm, 2, "bpf_map_update_elem", as_external_function_t((void *) bpf_map_update_elem));
     ubpf_register(vm, 3, "bpf_map_delete_elem", as_external_function_t((void *) bpf_map_delete_elem));
@@ -133,12 +140,12 @@ void BPFElement::init_ubpf_vm() {
     ubpf_register(vm, 6, "bpf_trace_printk", as_external_function_t((void *) bpf_trace_printk));
     ubpf_register(vm, 7, "bpf_get_prandom_u32", as_external_function_t((void*)bpf_get_prandom_u32));
     #endif
+    ubpf_register(vm, 12, "bpf_tail_call", as_external_function_t((void *) bpf_tail_call));
     ubpf_register(vm, 20, "unwind", as_external_function_t((void *) unwind));
     ubpf_set_unwind_function_index(vm, 20);
 
-    this->_ubpf_vm = vm;
-
-    register_additional_bpf_helpers();
+    register_additional_bpf_helpers(vm);
+    return vm;
 }
 
 void handle_jit_dump(ErrorHandler *errh, ubpf_vm *_ubpf_vm, uint64_t _bpfelement_id) {
@@ -170,7 +177,8 @@ RmAU5ua9+XLW8RomQQtgubMBciF2BRlzGKH6LOAxgt4RwRI6qlhVOEEegg==
 -----END PUBLIC KEY-----
 )";
 
-int BPFElement::check_bpf_verification_signature(ErrorHandler *errh) {
+int BPFElement::check_bpf_verification_signature(const String &file, const String &signature_file,
+                                                 ErrorHandler *errh) {
     // Create a BIO for the public key
     BIO *bio = BIO_new_mem_buf(pub_key_str.data(), static_cast<int>(pub_key_str.size()));
     if (!bio) {
@@ -185,14 +193,14 @@ int BPFElement::check_bpf_verification_signature(ErrorHandler *errh) {
     }
 
     // Read the file to be verified
-    std::vector <uint8_t> file_contents = read_file(_bpf_file.c_str());
+    std::vector <uint8_t> file_contents = read_file(file.c_str());
     if (file_contents.empty()) {
         EVP_PKEY_free(pkey);
         return errh->error("Failed to read file to be verified\n");
     }
 
     // Read the signature
-    std::vector <uint8_t> signature = read_file(_signature_file.c_str());
+    std::vector <uint8_t> signature = read_file(signature_file.c_str());
     if (signature.empty()) {
         EVP_PKEY_free(pkey);
         return errh->error("Failed to read signature file\n");
@@ -229,8 +237,8 @@ int BPFElement::check_bpf_verification_signature(ErrorHandler *errh) {
     EVP_MD_CTX_free(mdctx);
     EVP_PKEY_free(pkey);
 
-    uk_pr_info("Signature of BPF bytecode '%s' verified successfully with signature file '%s'\n", _bpf_file.c_str(),
-               _signature_file.c_str());
+    uk_pr_info("Signature of BPF bytecode '%s' verified successfully with signature file '%s'\n", file.c_str(),
+               signature_file.c_str());
     return 0;
 }
 
@@ -325,27 +333,121 @@ int BPFElement::allocate_jit_stack() {
     return 0;
 }
 
+// Loads, verifies and compiles a program into an unloaded VM, with the write lock held
+int BPFElement::load_program(ubpf_vm *vm, ubpf_jit_ex_fn &jit_fn, const std::vector <uint8_t> &buffer,
+                             const String &file, const String &signature_file, bool verify, load_trace &trace,
+                             ErrorHandler *errh) {
+    _bpf_map_ctx->relocated.clear();
+
+    char *error_msg = NULL;
+    ubpf_load_elf_ex(vm, buffer.data(), buffer.size(), "main", &error_msg);
+    if (error_msg != NULL) {
+        int ret = errh->error("Error loading ubpf program: %s\n", error_msg);
+        free(error_msg);
+        return ret;
+    }
+	trace.load = ukplat_monotonic_clock();
+
+#ifdef CONFIG_LIBCLICK_UBPF_VERIFY_SIGNATURE
+    if (CONFIG_LIBCLICK_UBPF_VERIFY_SIGNATURE && verify) {
+        auto return_code = check_bpf_verification_signature(file, signature_file, errh);
+        if (return_code < 0) {
+            return return_code;
+        }
+    }
+#else
+    (void) verify;
+    (void) file;
+    (void) signature_file;
+#endif
+	trace.validate = ukplat_monotonic_clock();
+
+    if (_jit) {
+        jit_fn = ubpf_compile_ex(vm, &error_msg, ExtendedJitMode);
+        if (jit_fn == NULL) {
+            int ret = errh->error("Error compiling ubpf program: %s\n", error_msg);
+            free(error_msg);
+            return ret;
+        }
+    }
+	trace.jit = ukplat_monotonic_clock();
+
+    return 0;
+}
+
+// Calls the program once with BPFELEMENT_MIGRATE_PORT and no packet, before it sees packets
+int BPFElement::migrate_maps(ErrorHandler *errh) {
+    bpf_map *map = _bpf_map_ctx->map_by_name[BPFELEMENT_SCHEMA_VERSION_MAP];
+    if (map->def.type != BPF_MAP_TYPE_ARRAY || map->def.value_size != sizeof(uint32_t)) {
+        return errh->error("Map %s must be an array of u32\n", BPFELEMENT_SCHEMA_VERSION_MAP);
+    }
+    uint32_t *version = static_cast<uint32_t *>(map->data);
+    uint32_t from = *version;
+
+    uint32_t ret = run(_ubpf_vm, _ubpf_jit_ex_fn, BPFELEMENT_MIGRATE_PORT, nullptr, nullptr);
+    if (!migration_succeeded(ret)) {
+        return errh->error("Program %s can't migrate maps from schema version %u\n", _bpf_file.c_str(), from);
+    }
+
+    if (*version != from) {
+        uk_pr_info("Migrated maps of %s (ID: %lu) from schema version %u to %u\n", this->class_name(),
+                   _bpfelement_id, from, *version);
+    }
+    return 0;
+}
+
 int BPFElement::configure(Vector <String> &conf, ErrorHandler *errh) {
     if (conf.empty()) {
         return -1;
     }
 
+    String file;
+    String signature_file;
+    Vector <String> tail_calls;
     if (Args(conf, this, errh)
                 .read("ID", _bpfelement_id)
                 .read("JIT", _jit)
                 .read("DUMP_JIT", _dump_jit)
-                .read("FILE", AnyArg(), _bpf_file)
-                .read("SIGNATURE", AnyArg(), _signature_file)
+                .read("FILE", AnyArg(), file)
+                .read("SIGNATURE", AnyArg(), signature_file)
+                .read_all("TAIL_CALL", AnyArg(), tail_calls)
                 .complete() < 0) {
         return -1;
     }
 
+    // a reconfiguration without FILE keeps the program and only changes TAIL_CALL slots
+    bool reconfigure = _ubpf_vm != NULL;
+    if (!reconfigure || file) {
+        int ret = configure_program(file, signature_file, reconfigure, errh);
+        if (ret < 0) {
+            return ret;
+        }
+    }
+
+    // after the program, which creates the program arrays, so TAIL_CALL can fill them
+    uk_rwlock_wlock(&_lock);
+    int ret = 0;
+    for (const String &tail_call: tail_calls) {
+        if ((ret = configure_tail_call(tail_call, errh)) < 0) {
+            break;
+        }
+    }
+    uk_rwlock_wunlock(&_lock);
+    return ret;
+}
+
+int BPFElement::configure_program(const String &file, const String &signature_file, bool reconfigure,
+                                  ErrorHandler *errh) {
+    String previous_file = _bpf_file;
+    String previous_signature_file = _signature_file;
+    _bpf_file = file;
+    _signature_file = signature_file;
+
 	uint64_t ts = ukplat_monotonic_clock();
 	printf("Startup trace (nsec): init ebpf vm: %llu\n", ts);
     uint64_t ts_start = ukplat_monotonic_clock();
     const char *filename = _bpf_file.c_str();
 
-    bool reconfigure = _ubpf_vm != NULL;
     if (reconfigure) {
         uk_pr_info("Reconfiguring %s (ID: %lu - JIT: %d) with program %s (signature: %s)...\n", this->class_name(), _bpfelement_id, _jit,
                    filename, _signature_file.c_str());
@@ -379,36 +481,37 @@ int BPFElement::configure(Vector <String> &conf, ErrorHandler *errh) {
         ubpf_unload_code(_ubpf_vm);
     }
 
-    char *error_msg;
-    ubpf_load_elf_ex(_ubpf_vm, buffer.data(), buffer.size(), "main", &error_msg);
-
-    if (error_msg != NULL) {
-        return errh->error("Error loading ubpf program: %s\n", error_msg);
-    }
-	uint64_t ts_load = ukplat_monotonic_clock();
-
-#ifdef CONFIG_LIBCLICK_UBPF_VERIFY_SIGNATURE
-    if (CONFIG_LIBCLICK_UBPF_VERIFY_SIGNATURE) {
-        auto return_code = check_bpf_verification_signature(errh);
-        if (return_code < 0) {
-            return return_code;
+    load_trace trace = { ts_lock, ts_lock, ts_lock };
+    int ret = load_program(_ubpf_vm, _ubpf_jit_ex_fn, buffer, _bpf_file, _signature_file, true, trace, errh);
+    if (ret == 0) {
+        if (_bpf_map_ctx->relocated.count(BPFELEMENT_SCHEMA_VERSION_MAP)) {
+            ret = migrate_maps(errh);
         }
     }
-#endif
-	uint64_t ts_validate = ukplat_monotonic_clock();
 
-    if (_jit) {
-        _ubpf_jit_ex_fn = ubpf_compile_ex(_ubpf_vm, &error_msg, ExtendedJitMode);
-        if (_ubpf_jit_ex_fn == NULL) {
-            return errh->error("Error compiling ubpf program: %s\n", error_msg);
+    if (ret < 0) {
+        // Packets keep going through the old program. The maps it uses are as it left them, as
+        // migrations only write maps of the new layout and bump SCHEMA_VERSION last.
+        if (reconfigure && !_program.empty()) {
+            _bpf_file = previous_file;
+            _signature_file = previous_signature_file;
+            ubpf_unload_code(_ubpf_vm);
+            load_trace rollback_trace;
+            if (load_program(_ubpf_vm, _ubpf_jit_ex_fn, _program, _bpf_file, _signature_file, false, rollback_trace,
+                             errh) == 0) {
+                errh->warning("Kept the previous program of %s (ID: %lu)\n", this->class_name(), _bpfelement_id);
+            }
         }
+        uk_rwlock_wunlock(&_lock);
+        return ret;
     }
+    _program = std::move(buffer);
 
     if (_dump_jit) {
         handle_jit_dump(errh, _ubpf_vm, _bpfelement_id);
     }
 
-	uint64_t ts_jit = ukplat_monotonic_clock();
+	uint64_t ts_load = trace.load, ts_validate = trace.validate, ts_jit = trace.jit;
 	printf("Startup trace (nsec): init ebpf done: %llu\n", ts_jit);
 
 	printf("Startup trace (nsec): read program: %llu\n", ts_read - ts_start);
@@ -432,6 +535,304 @@ int BPFElement::configure(Vector <String> &conf, ErrorHandler *errh) {
     return 0;
 }
 
+// TAIL_CALL MAP INDEX [FILE [SIGNATURE]]: loads FILE into slot INDEX of the program array MAP, or
+// empties the slot without FILE, with the write lock held. The slot keeps its program if FILE
+// can't be loaded.
+int BPFElement::configure_tail_call(const String &tail_call, ErrorHandler *errh) {
+    Vector <String> words;
+    cp_spacevec(tail_call, words);
+    uint32_t index;
+    if (words.size() < 2 || words.size() > 4 || !IntArg().parse(words[1], index)) {
+        return errh->error("TAIL_CALL takes MAP INDEX [FILE [SIGNATURE]], not '%s'\n", tail_call.c_str());
+    }
+
+    bpf_map *map = find_map(std::string(words[0].c_str()));
+    if (map == nullptr || map->def.type != BPF_MAP_TYPE_PROG_ARRAY) {
+        return errh->error("The program has no program array %s\n", words[0].c_str());
+    }
+    if (index >= map->def.max_entries) {
+        return errh->error("Program array %s has no slot %u\n", words[0].c_str(), index);
+    }
+
+    if (words.size() == 2) {
+        unload_tail_call(map, index);
+        uk_pr_info("Emptied slot %u of %s in %s (ID: %lu)\n", index, words[0].c_str(), this->class_name(),
+                   _bpfelement_id);
+        return 0;
+    }
+
+#ifdef CONFIG_LIBPKU
+    // ubpf_compile_ex puts JIT code at a fixed address, where the program's already is
+    if (_jit) {
+        return errh->error("TAIL_CALL programs need JIT off with LIBPKU\n");
+    }
+#endif
+
+    const String &file = words[2];
+    String signature_file = words.size() == 4 ? words[3] : String();
+    std::vector <uint8_t> buffer = read_file(file.c_str());
+    if (buffer.empty()) {
+        return errh->error("Error reading file %s\n", file.c_str());
+    }
+
+    auto *program = new tail_call_program { file, create_ubpf_vm(), nullptr };
+    if (program->vm == NULL) {
+        delete program;
+        return errh->error("Error initializing ubpf vm\n");
+    }
+    load_trace trace;
+    int ret = load_program(program->vm, program->jit_fn, buffer, file, signature_file, true, trace, errh);
+    if (ret < 0) {
+        ubpf_destroy(program->vm);
+        delete program;
+        return ret;
+    }
+
+    unload_tail_call(map, index);
+    size_t position = 0;
+    while (position < _tail_call_programs.size() && _tail_call_programs[position] != nullptr) {
+        position++;
+    }
+    if (position == _tail_call_programs.size()) {
+        _tail_call_programs.push_back(nullptr);
+    }
+    _tail_call_programs[position] = program;
+    static_cast<ProgArray *>(map->data)->programs[index] = position + 1;
+
+    uk_pr_info("Loaded %s into slot %u of %s in %s (ID: %lu)\n", file.c_str(), index, words[0].c_str(),
+               this->class_name(), _bpfelement_id);
+    return 0;
+}
+
+// Empties a slot of a program array and unloads its program, with the write lock held
+void BPFElement::unload_tail_call(bpf_map *map, uint32_t index) {
+    uint32_t &slot = static_cast<ProgArray *>(map->data)->programs[index];
+    if (slot == 0) {
+        return;
+    }
+    tail_call_program *program = _tail_call_programs[slot - 1];
+    ubpf_destroy(program->vm);
+    delete program;
+    _tail_call_programs[slot - 1] = nullptr;
+    slot = 0;
+}
+
+using HashMapType = std::unordered_map<KeyType, ValueType, VectorHash, VectorEqual>;
+
+bpf_map *BPFElement::find_map(const std::string &name) {
+    if (_bpf_map_ctx == nullptr) {
+        return nullptr;
+    }
+    auto it = _bpf_map_ctx->map_by_name.find(name);
+    return it == _bpf_map_ctx->map_by_name.end() ? nullptr : it->second;
+}
+
+// keys and values must match the map definition, and arrays have no entries past max_entries
+static int check_key(const bpf_map *map, const KeyType &key) {
+    if (key.size() != map->def.key_size) {
+        return -EINVAL;
+    }
+    if (map->def.type == BPF_MAP_TYPE_ARRAY || map->def.type == BPF_MAP_TYPE_PROG_ARRAY) {
+        uint32_t index;
+        memcpy(&index, key.data(), sizeof(index));
+        if (index >= map->def.max_entries) {
+            return -ENOENT;
+        }
+    }
+    return 0;
+}
+
+int BPFElement::map_list(std::vector <std::pair<std::string, bpf_map_def>> &maps) {
+    uk_rwlock_rlock(&_lock);
+    if (_bpf_map_ctx != nullptr) {
+        for (auto &it: _bpf_map_ctx->map_by_name) {
+            maps.emplace_back(it.first, it.second->def);
+        }
+    }
+    uk_rwlock_runlock(&_lock);
+    return 0;
+}
+
+int BPFElement::map_lookup(const std::string &name, const KeyType &key, ValueType &value) {
+    uk_rwlock_wlock(&_lock);
+    int ret = -ENOENT;
+    bpf_map *map = find_map(name);
+    if (map != nullptr && (ret = check_key(map, key)) == 0) {
+        auto *data = static_cast<uint8_t *>(bpf_map_lookup_elem(map, (void *) key.data()));
+        if (data == nullptr) {
+            ret = -ENOENT;
+        } else {
+            value.assign(data, data + map->def.value_size);
+        }
+    }
+    uk_rwlock_wunlock(&_lock);
+    return ret;
+}
+
+int BPFElement::map_update(const std::string &name, const KeyType &key, const ValueType &value) {
+    uk_rwlock_wlock(&_lock);
+    int ret = -ENOENT;
+    bpf_map *map = find_map(name);
+    if (map != nullptr && (ret = check_key(map, key)) == 0) {
+        if (value.size() != map->def.value_size) {
+            ret = -EINVAL;
+        } else {
+            ret = (int) bpf_map_update_elem(map, (void *) key.data(), value.data(), 0);
+        }
+    }
+    uk_rwlock_wunlock(&_lock);
+    return ret;
+}
+
+int BPFElement::map_delete(const std::string &name, const KeyType &key) {
+    uk_rwlock_wlock(&_lock);
+    int ret = -ENOENT;
+    bpf_map *map = find_map(name);
+    if (map != nullptr && (ret = check_key(map, key)) == 0) {
+        if (map->def.type == BPF_MAP_TYPE_LPM_TRIE) {
+            // lookups would find the longest matching prefix, deletes need the exact one
+            if (!static_cast<LpmTrie *>(map->data)->erase(key.data())) {
+                ret = -ENOENT;
+            }
+        } else if (map->def.type == BPF_MAP_TYPE_PROG_ARRAY) {
+            uint32_t index;
+            memcpy(&index, key.data(), sizeof(index));
+            unload_tail_call(map, index);
+        } else if (map->def.type != BPF_MAP_TYPE_HASH && map->def.type != BPF_MAP_TYPE_LRU_HASH) {
+            // array entries always exist
+            ret = -EINVAL;
+        } else if (bpf_map_lookup_elem(map, (void *) key.data()) == nullptr) {
+            ret = -ENOENT;
+        } else {
+            bpf_map_delete_elem(map, (void *) key.data());
+        }
+    }
+    uk_rwlock_wunlock(&_lock);
+    return ret;
+}
+
+int BPFElement::map_dump(const std::string &name, uint64_t offset, size_t max_bytes,
+                         std::vector <std::pair<KeyType, ValueType>> &entries, uint64_t &next, bool &more) {
+    uk_rwlock_wlock(&_lock);
+    bpf_map *map = find_map(name);
+    if (map == nullptr) {
+        uk_rwlock_wunlock(&_lock);
+        return -ENOENT;
+    }
+
+    size_t entry_size = map->def.key_size + map->def.value_size;
+    size_t bytes = 0;
+    next = offset;
+    more = false;
+    switch (map->def.type) {
+        case BPF_MAP_TYPE_HASH: {
+            // the order is stable as long as the program doesn't insert or delete in between
+            auto *hash_map = static_cast<HashMapType *>(map->data);
+            uint64_t position = 0;
+            for (auto &it: *hash_map) {
+                if (position++ < offset) {
+                    continue;
+                }
+                if (bytes + entry_size > max_bytes) {
+                    more = true;
+                    break;
+                }
+                entries.emplace_back(it.first, it.second);
+                bytes += entry_size;
+                next = position;
+            }
+            break;
+        }
+        case BPF_MAP_TYPE_LRU_HASH: {
+            // most recently used first, without counting as a use
+            auto *lru_map = static_cast<LruHashMap *>(map->data);
+            uint64_t position = 0;
+            for (auto &key: lru_map->order) {
+                if (position++ < offset) {
+                    continue;
+                }
+                if (bytes + entry_size > max_bytes) {
+                    more = true;
+                    break;
+                }
+                entries.emplace_back(key, lru_map->entries.at(key).value);
+                bytes += entry_size;
+                next = position;
+            }
+            break;
+        }
+        case BPF_MAP_TYPE_LPM_TRIE: {
+            // longest prefixes first
+            auto *trie = static_cast<LpmTrie *>(map->data);
+            uint64_t position = 0;
+            bool full = false;
+            for (auto &[len, prefixes]: trie->prefixes) {
+                for (auto &it: prefixes) {
+                    if (position++ < offset) {
+                        continue;
+                    }
+                    if (bytes + entry_size > max_bytes) {
+                        more = full = true;
+                        break;
+                    }
+                    KeyType key((const uint8_t *) &len, (const uint8_t *) &len + sizeof(len));
+                    key.insert(key.end(), it.first.begin(), it.first.end());
+                    entries.emplace_back(key, it.second);
+                    bytes += entry_size;
+                    next = position;
+                }
+                if (full) {
+                    break;
+                }
+            }
+            break;
+        }
+        case BPF_MAP_TYPE_ARRAY: {
+            auto *data = static_cast<uint8_t *>(map->data);
+            for (uint64_t index = offset; index < map->def.max_entries; index++) {
+                uint8_t *value = &data[index * map->def.value_size];
+                bool zero = true;
+                for (unsigned int i = 0; i < map->def.value_size; i++) {
+                    zero &= value[i] == 0;
+                }
+                if (!zero) {
+                    if (bytes + entry_size > max_bytes) {
+                        more = true;
+                        break;
+                    }
+                    uint32_t key = index;
+                    entries.emplace_back(KeyType((uint8_t *) &key, (uint8_t *) &key + sizeof(key)),
+                                         ValueType(value, value + map->def.value_size));
+                    bytes += entry_size;
+                }
+                next = index + 1;
+            }
+            break;
+        }
+        case BPF_MAP_TYPE_PROG_ARRAY: {
+            auto &programs = static_cast<ProgArray *>(map->data)->programs;
+            for (uint64_t index = offset; index < programs.size(); index++) {
+                if (programs[index] != 0) {
+                    if (bytes + entry_size > max_bytes) {
+                        more = true;
+                        break;
+                    }
+                    uint32_t key = index;
+                    uint8_t *value = (uint8_t *) &programs[index];
+                    entries.emplace_back(KeyType((uint8_t *) &key, (uint8_t *) &key + sizeof(key)),
+                                         ValueType(value, value + sizeof(programs[index])));
+                    bytes += entry_size;
+                }
+                next = index + 1;
+            }
+            break;
+        }
+    }
+
+    uk_rwlock_wunlock(&_lock);
+    return 0;
+}
+
 // inline void BPFElement::ebpf_enter_mpk() {
 // 	pkey_set_perm(PROT_READ | PROT_WRITE, _pkey_stack); // allow all
 // }
@@ -441,11 +842,29 @@ int BPFElement::configure(Vector <String> &conf, ErrorHandler *errh) {
 // }
 
 uint32_t BPFElement::exec(int port, Packet *p) {
+    bpf_pending_tail_call = 0;
+    bpf_tail_call_count = 0;
+    uint32_t ret = run(_ubpf_vm, _ubpf_jit_ex_fn, port, (void *) p->data(), (void *) p->end_data());
+
+    // a program that tail called stopped with 0, the program it jumped to has the last word
+    while (bpf_pending_tail_call != 0) {
+        tail_call_program *program = _tail_call_programs[bpf_pending_tail_call - 1];
+        bpf_pending_tail_call = 0;
+        p = tail_call_packet(p);
+        if (p == nullptr) {
+            break;
+        }
+        ret = run(program->vm, program->jit_fn, port, (void *) p->data(), (void *) p->end_data());
+    }
+    return ret;
+}
+
+uint32_t BPFElement::run(ubpf_vm *vm, ubpf_jit_ex_fn jit_fn, uint32_t port, void *data, void *data_end) {
     uint64_t ret = 0;
 
     auto ctx_ = (bpfelement_md) {
-            .data = (void *) p->data(),
-            .data_end = (void *) p->end_data(),
+            .data = data,
+            .data_end = data_end,
             .port = port,
     };
 
@@ -462,13 +881,13 @@ uint32_t BPFElement::exec(int port, Packet *p) {
 #endif*/
         // ret = (uint32_t) _ubpf_jit_fn(&ctx, sizeof(ctx));
         asm volatile("" ::: "memory");
-        ret = (uint64_t) _ubpf_jit_ex_fn(ctx, sizeof(bpfelement_md), (uint8_t*)this->_ubpf_ebpf_stack, this->_ubpf_ebpf_stack_len);
+        ret = (uint64_t) jit_fn(ctx, sizeof(bpfelement_md), (uint8_t*)this->_ubpf_ebpf_stack, this->_ubpf_ebpf_stack_len);
         asm volatile("" ::: "memory");
 /*#ifdef CONFIG_LIBCLICK_ENABLE_MPK
         mpk_ebpf_exit(_pkey_stack);
 #endif*/
     } else {
-        if (ubpf_exec(_ubpf_vm, &ctx_, sizeof(ctx_), &ret) != 0) {
+        if (ubpf_exec(vm, &ctx_, sizeof(ctx_), &ret) != 0) {
             uk_pr_err("Error executing bpf program\n");
             ret = -1;
         }
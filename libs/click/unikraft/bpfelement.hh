@@ -12,6 +12,11 @@
 
 CLICK_DECLS
 
+// Input port of the call that migrates the maps of a program using SCHEMA_VERSION after it's
+// loaded, see bpf_element::migration in the ebpf crate
+#define BPFELEMENT_MIGRATE_PORT UINT32_MAX
+#define BPFELEMENT_SCHEMA_VERSION_MAP "SCHEMA_VERSION"
+
 class BPFElement : public Element {
 public:
 
@@ -21,15 +26,34 @@ public:
 
     uint64_t bpfelement_id() const { return _bpfelement_id; }
 
+    // Map access for the Control element. These take the element's lock, so they don't race with
+    // the program, and return 0 or a negative errno (-ENOENT if there's no such map or key).
+    int map_list(std::vector <std::pair<std::string, bpf_map_def>> &maps);
+    int map_lookup(const std::string &name, const KeyType &key, ValueType &value);
+    int map_update(const std::string &name, const KeyType &key, const ValueType &value);
+    int map_delete(const std::string &name, const KeyType &key);
+    // Entries from position `offset` on, as long as their keys and values fit into `max_bytes`.
+    // Arrays and program arrays leave out all-zero entries, LRU hash maps list the most recently used first, LPM tries
+    // the longest prefixes first. `next` is where to continue, `more` whether there is anything left.
+    int map_dump(const std::string &name, uint64_t offset, size_t max_bytes,
+                 std::vector <std::pair<KeyType, ValueType>> &entries, uint64_t &next, bool &more);
+
 protected:
 
     struct uk_rwlock _lock = UK_RWLOCK_INITIALIZER(_lock, 0);
     struct ubpf_vm *_ubpf_vm = nullptr;
 
-    virtual void register_additional_bpf_helpers(void) { }
+    virtual void register_additional_bpf_helpers(ubpf_vm *vm) { (void) vm; }
 
+    // Runs the program on the packet, then the programs it tail calls into
     uint32_t exec(int port, Packet *p);
 
+    // The packet a program tail called on, as it is now, or null if it's gone
+    virtual Packet *tail_call_packet(Packet *p) { return p; }
+
+    // Whether the program's return value to the migration call is the success verdict
+    virtual bool migration_succeeded(uint32_t ret) const = 0;
+
 private:
 
     uint64_t _bpfelement_id;
@@ -37,8 +61,19 @@ private:
     bool _dump_jit;
     String _bpf_file;
     String _signature_file;
-
-    struct bpf_map_ctx *_bpf_map_ctx;
+    // the loaded program, to go back to if its replacement can't be loaded or migrated
+    std::vector <uint8_t> _program;
+
+    // a program loaded with TAIL_CALL into a slot of a program array, sharing the maps by name
+    struct tail_call_program {
+        String file;
+        struct ubpf_vm *vm;
+        ubpf_jit_ex_fn jit_fn;
+    };
+    // by the number in their slot minus 1, null once unloaded
+    std::vector <tail_call_program *> _tail_call_programs;
+
+    struct bpf_map_ctx *_bpf_map_ctx = nullptr;
     ubpf_jit_ex_fn _ubpf_jit_ex_fn;
     void* _ubpf_ebpf_stack; // stack verified by eBPF verifier
     size_t _ubpf_ebpf_stack_len;
@@ -50,8 +85,24 @@ private:
     int _pkey_stack;
 
     void init_ubpf_vm();
-    int check_bpf_verification_signature(ErrorHandler *errh);
+    ubpf_vm *create_ubpf_vm();
+    int check_bpf_verification_signature(const String &file, const String &signature_file, ErrorHandler *errh);
     int allocate_jit_stack();
+    // when loading a program finished its steps, for the startup trace
+    struct load_trace {
+        uint64_t load;
+        uint64_t validate;
+        uint64_t jit;
+    };
+
+    int load_program(ubpf_vm *vm, ubpf_jit_ex_fn &jit_fn, const std::vector <uint8_t> &buffer, const String &file,
+                     const String &signature_file, bool verify, load_trace &trace, ErrorHandler *errh);
+    int configure_program(const String &file, const String &signature_file, bool reconfigure, ErrorHandler *errh);
+    int configure_tail_call(const String &tail_call, ErrorHandler *errh);
+    void unload_tail_call(bpf_map *map, uint32_t index);
+    int migrate_maps(ErrorHandler *errh);
+    uint32_t run(ubpf_vm *vm, ubpf_jit_ex_fn jit_fn, uint32_t port, void *data, void *data_end);
+    bpf_map *find_map(const std::string &name);
 
     CLICK_COLD;
 };
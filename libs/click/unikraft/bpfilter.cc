@@ -45,6 +45,10 @@ void BPFilter::push(int port, Packet *p) {
     }
 }
 
+bool BPFilter::migration_succeeded(uint32_t ret) const {
+    return ret == XDP_PASS;
+}
+
 int
 BPFilter::write_handler(const String &s, Element *e, void *user_data,
                         ErrorHandler *errh) {
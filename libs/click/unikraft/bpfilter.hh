@@ -32,7 +32,14 @@ Keyword arguments are:
 
 =item FILE
 
-String. Required. File name of the ebpf program defining the filter rules.
+String. Required, except to reconfigure only TAIL_CALL slots. File name of the ebpf program defining the filter rules.
+
+=item TAIL_CALL
+
+String, repeatable. `MAP INDEX FILE [SIGNATURE]` loads the filter program FILE into slot INDEX of
+the program array MAP of the program, for `bpf_tail_call`; `MAP INDEX` empties the slot. The
+programs share the maps of the program by name, and a slot keeps its program if FILE can't be
+loaded.
 
 =h count read-only
 Returns the number of processed packets.
@@ -57,6 +64,10 @@ public:
 
     void push(int, Packet *) override;
 
+protected:
+
+    bool migration_succeeded(uint32_t ret) const override;
+
 private:
 
     uint64_t _count;
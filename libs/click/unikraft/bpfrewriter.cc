@@ -15,6 +15,7 @@ BPFRewriter::BPFRewriter() {
 thread_local WritablePacket* _current_packet = nullptr;
 
 void *bpf_packet_add_space(int32_t head, int32_t tail) {
+    // push and put free the packet and return null if they can't grow it
     if (head != 0) {
         if (head > 0) {
             _current_packet = _current_packet->push(head);
@@ -23,7 +24,7 @@ void *bpf_packet_add_space(int32_t head, int32_t tail) {
         }
     }
 
-    if (tail != 0) {
+    if (tail != 0 && _current_packet) {
         if (tail > 0) {
             _current_packet = _current_packet->put(tail);
         } else {
@@ -31,16 +32,22 @@ void *bpf_packet_add_space(int32_t head, int32_t tail) {
         }
     }
 
-    return _current_packet->data();
+    return _current_packet ? _current_packet->data() : nullptr;
 }
 
-void BPFRewriter::register_additional_bpf_helpers(void) {
+void BPFRewriter::register_additional_bpf_helpers(ubpf_vm *vm) {
     // TODO: bpf_skb_adjust_room?
-    ubpf_register(_ubpf_vm, 60, "bpf_packet_add_space", as_external_function_t((void *) bpf_packet_add_space));
+    ubpf_register(vm, 60, "bpf_packet_add_space", as_external_function_t((void *) bpf_packet_add_space));
+}
+
+Packet *BPFRewriter::tail_call_packet(Packet *) {
+    // bpf_packet_add_space may have moved or freed it
+    return _current_packet;
 }
 
 #define REWRITER_ABORT 0
 #define REWRITER_SUCCESS 1
+#define REWRITER_DROP 2
 
 void BPFRewriter::push(int port, Packet *p) {
     uk_pr_debug("BPFRewriter: Received packet\n");
@@ -58,17 +65,28 @@ void BPFRewriter::push(int port, Packet *p) {
 
     _current_packet = nullptr;
 
+    if (!p_out) {
+        uk_pr_err("BPFRewriter: Couldn't resize packet\n");
+        return;
+    }
+
     if (ret == REWRITER_SUCCESS) {
         output(0).push(p_out);
+    } else if (ret == REWRITER_DROP) {
+        p_out->kill();
     } else if (ret == REWRITER_ABORT) {
         uk_pr_err("BPFRewriter: Rewriter aborted\n");
         p_out->kill();
     } else {
         uk_pr_err("BPFRewriter: Unsupported action: %u\n", ret);
-        p->kill();
+        p_out->kill();
     }
 }
 
+bool BPFRewriter::migration_succeeded(uint32_t ret) const {
+    return ret == REWRITER_SUCCESS;
+}
+
 CLICK_ENDDECLS
 ELEMENT_REQUIRES(int64)
 
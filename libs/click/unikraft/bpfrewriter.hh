@@ -26,9 +26,11 @@ Rewrite packets based on an ebpf program.
 
 This element rewrites packets based on an ebpf program.
 The BPF program operates on the packet data and can modify it. The program is loaded from a file.
+It returns 1 to push the packet out, 2 to drop it, or 0 to drop it and log an error.
 
 Following additional BPF Helpers are available:
-- ID 100: `bpf_packet_add_space(int32_t head_len, int32_t tail_len)`: Adds or removes space to the packet head and tail.
+- ID 60: `bpf_packet_add_space(int32_t head_len, int32_t tail_len)`: Adds or removes space to the packet head and tail.
+  Returns the new start of the packet, or NULL if the packet couldn't grow, in which case it is dropped.
 
 Keyword arguments are:
 
@@ -36,7 +38,14 @@ Keyword arguments are:
 
 =item FILE
 
-String. Required. File name of the ebpf program defining the rewriter.
+String. Required, except to reconfigure only TAIL_CALL slots. File name of the ebpf program defining the rewriter.
+
+=item TAIL_CALL
+
+String, repeatable. `MAP INDEX FILE [SIGNATURE]` loads the rewriter program FILE into slot INDEX of
+the program array MAP of the program, for `bpf_tail_call`; `MAP INDEX` empties the slot. The
+programs share the maps of the program by name, and a slot keeps its program if FILE can't be
+loaded.
 
  */
 class BPFRewriter : public BPFElement {
@@ -52,7 +61,11 @@ public:
 
 protected:
 
-    virtual void register_additional_bpf_helpers(void) override;
+    virtual void register_additional_bpf_helpers(ubpf_vm *vm) override;
+
+    Packet *tail_call_packet(Packet *p) override;
+
+    bool migration_succeeded(uint32_t ret) const override;
 };
 
 CLICK_ENDDECLS
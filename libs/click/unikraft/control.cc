@@ -7,21 +7,115 @@
 #include "control.hh"
 #include <click/standard/scheduleinfo.hh>
 #include <click/router.hh>
+#include <clicknet/ether.h>
+#include <clicknet/ip.h>
 #include <clicknet/udp.h>
 #include "bpfelement.hh"
 
+#include <cerrno>
+#include <cstring>
+#include <string>
+#include <vector>
+
 #include <uk/print.h>
 
 CLICK_DECLS
 
+// largest reply payload, so replies fit into one Ethernet frame
+#define MAP_REPLY_MAX_LEN 1400
+
+namespace {
+
+// Little-endian fields of a control packet. Reading past the end leaves `ok` false.
+struct Reader {
+    const unsigned char *pos;
+    const unsigned char *end;
+    bool ok;
+
+    Reader(const unsigned char *pos, const unsigned char *end) : pos(pos), end(end), ok(pos <= end) {}
+
+    const unsigned char *bytes(uint64_t len) {
+        if (!ok || len > (uint64_t) (end - pos)) {
+            ok = false;
+            return nullptr;
+        }
+        const unsigned char *start = pos;
+        pos += len;
+        return start;
+    }
+
+    template<typename T>
+    T read() {
+        T value = 0;
+        const unsigned char *data = bytes(sizeof(T));
+        if (data) {
+            memcpy(&value, data, sizeof(T));
+        }
+        return value;
+    }
+
+    // uint64_t length followed by as many bytes
+    std::vector <uint8_t> blob() {
+        uint64_t len = read<uint64_t>();
+        const unsigned char *data = bytes(len);
+        return data ? std::vector<uint8_t>(data, data + len) : std::vector<uint8_t>();
+    }
+};
+
+struct Writer {
+    std::vector <uint8_t> data;
+
+    template<typename T>
+    void write(T value) {
+        const uint8_t *bytes = (const uint8_t *) &value;
+        data.insert(data.end(), bytes, bytes + sizeof(T));
+    }
+
+    void blob(const uint8_t *bytes, uint64_t len) {
+        write<uint64_t>(len);
+        data.insert(data.end(), bytes, bytes + len);
+    }
+};
+
+}
+
 Control::Control() {
 }
 
 void Control::push(int, Packet *p) {
+    const unsigned char *udp_data_ptr = p->transport_header() + sizeof(struct click_udp);
+
+    if (udp_data_ptr + 7 > p->end_data()) {
+        uk_pr_err("Received control packet with invalid length\n");
+    } else if (!memcmp(udp_data_ptr, "control", 7)) {
+        reconfigure(p, udp_data_ptr + 7);
+    } else if (!memcmp(udp_data_ptr, "ctrlmap", 7)) {
+        map_request(p, udp_data_ptr + 7);
+    } else {
+        uk_pr_err("Received control packet with not-matching prefix\n");
+    }
+
     p->kill();
+}
 
-    const unsigned char *udp_data_ptr = p->transport_header() + sizeof(struct click_udp);
+BPFElement *Control::find_bpfelement(uint64_t bpfelement_id) {
+    for (int i = 0; i < router()->nelements(); i++) {
+        Element *element = router()->element(i);
+        if (strcmp(element->class_name(), "BPFilter")
+            && strcmp(element->class_name(), "BPFClassifier")
+            && strcmp(element->class_name(), "BPFRewriter")) {
+            continue;
+        }
+
+        BPFElement *bpfelement = static_cast<BPFElement *>(element);
+        if (bpfelement->bpfelement_id() == bpfelement_id) {
+            return bpfelement;
+        }
+    }
+    return nullptr;
+}
 
+void Control::reconfigure(Packet *p, const unsigned char *udp_data_ptr) {
     // control packet format:
     // - "control"
     // - uint64_t bpfelement_id
@@ -29,22 +123,16 @@ void Control::push(int, Packet *p) {
     // - char[program_name_len] program_name
     // - uint64_t signature_len
     // - char[signature_len] signature
+    // - optionally uint64_t slot_len, char[slot_len] slot: "MAP INDEX" to load the program into
+    //   slot INDEX of the program array MAP (TAIL_CALL) instead of replacing the program
 
     uint64_t offset = 0;
 
-    // check for "control" prefix
-    if (p->udp_header()->uh_ulen < 7 + sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint64_t)) {
+    if (udp_data_ptr + sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint64_t) > p->end_data()) {
         uk_pr_err("Received control packet with invalid length\n");
         return;
     }
 
-    if (memcmp(udp_data_ptr, "control", 7)) {
-        uk_pr_err("Received control packet with not-matching prefix\n");
-        return;
-    }
-
-    offset += 7;
-
     // parse bpfelement_id
     uint64_t bpfelement_id = *(uint64_t * )(udp_data_ptr + offset);
     offset += sizeof(uint64_t);
@@ -53,7 +141,7 @@ void Control::push(int, Packet *p) {
     uint64_t program_name_len = *(uint64_t * )(udp_data_ptr + offset);
     offset += sizeof(uint64_t);
 
-    if (udp_data_ptr + offset + program_name_len > p->end_data()) {
+    if (program_name_len > (uint64_t) (p->end_data() - udp_data_ptr - offset) - sizeof(uint64_t)) {
         uk_pr_err("Received control packet with invalid program_name_len\n");
         return;
     }
@@ -66,7 +154,7 @@ void Control::push(int, Packet *p) {
     uint64_t signature_len = *(uint64_t * )(udp_data_ptr + offset);
     offset += sizeof(uint64_t);
 
-    if (udp_data_ptr + offset + signature_len > p->end_data()) {
+    if (signature_len > (uint64_t) (p->end_data() - udp_data_ptr - offset)) {
         uk_pr_err("Received control packet with invalid signature_len\n");
         return;
     }
@@ -75,6 +163,20 @@ void Control::push(int, Packet *p) {
     String signature((const char *) (udp_data_ptr + offset), signature_len);
     offset += signature_len;
 
+    // parse the slot, if any
+    String slot;
+    if ((uint64_t) (p->end_data() - udp_data_ptr - offset) >= sizeof(uint64_t)) {
+        uint64_t slot_len = *(uint64_t * )(udp_data_ptr + offset);
+        offset += sizeof(uint64_t);
+
+        if (slot_len > (uint64_t) (p->end_data() - udp_data_ptr - offset)) {
+            uk_pr_err("Received control packet with invalid slot_len\n");
+            return;
+        }
+        slot = String((const char *) (udp_data_ptr + offset), slot_len);
+        offset += slot_len;
+    }
+
     uk_pr_info("Received control packet for bpfelement_id %lu with program_name %s and signature %s \n", bpfelement_id,
                program_name.c_str(), signature.c_str());
 
@@ -100,12 +202,180 @@ void Control::push(int, Packet *p) {
         uk_pr_info("Control: %s with ID %lu found - calling config handler\n", element->class_name(), bpfelement_id);
 
         char *config;
-        asprintf(&config, "ID %lu, FILE %s, SIGNATURE %s", bpfelement_id, program_name.c_str(), signature.c_str());
+        if (slot) {
+            asprintf(&config, "ID %lu, TAIL_CALL %s %s %s", bpfelement_id, slot.c_str(), program_name.c_str(),
+                     signature.c_str());
+        } else {
+            asprintf(&config, "ID %lu, FILE %s, SIGNATURE %s", bpfelement_id, program_name.c_str(), signature.c_str());
+        }
 
         h->call_write(config, element, ErrorHandler::default_handler());
         free(config);
     }
 }
 
+void Control::map_request(Packet *p, const unsigned char *udp_data_ptr) {
+    // map request format (all integers little-endian):
+    // - "ctrlmap"
+    // - uint8_t op (CONTROL_MAP_*)
+    // - uint32_t seq, echoed in the reply
+    // - uint64_t bpfelement_id
+    // - uint64_t map_name_len, char[map_name_len] map_name (ignored by LIST)
+    // - GET, DELETE: uint64_t key_len, uint8_t[key_len] key
+    // - UPDATE: uint64_t key_len, uint8_t[key_len] key, uint64_t value_len, uint8_t[value_len] value
+    // - DUMP: uint64_t offset, 0 or the `next` of the previous reply
+    //
+    // reply format:
+    // - "ctrlmap", uint8_t op, uint32_t seq
+    // - int32_t status: 0 or a negative errno
+    // - if the status is 0:
+    //   - LIST: uint32_t count, count * (uint64_t name_len, char[name_len] name, uint32_t type,
+    //     uint32_t key_size, uint32_t value_size, uint32_t max_entries)
+    //   - GET: uint64_t value_len, uint8_t[value_len] value
+    //   - DUMP: uint64_t next, uint8_t more, uint32_t count, count * (uint8_t[key_size] key,
+    //     uint8_t[value_size] value)
+
+    Reader reader(udp_data_ptr, p->end_data());
+    uint8_t op = reader.read<uint8_t>();
+    uint32_t seq = reader.read<uint32_t>();
+    uint64_t bpfelement_id = reader.read<uint64_t>();
+    std::vector <uint8_t> name_bytes = reader.blob();
+    if (!reader.ok) {
+        uk_pr_err("Received map request with invalid length\n");
+        return;
+    }
+    std::string map_name(name_bytes.begin(), name_bytes.end());
+
+    Writer reply;
+    reply.data.insert(reply.data.end(), (const uint8_t *) "ctrlmap", (const uint8_t *) "ctrlmap" + 7);
+    reply.write<uint8_t>(op);
+    reply.write<uint32_t>(seq);
+
+    Writer body;
+    int status = 0;
+    BPFElement *bpfelement = find_bpfelement(bpfelement_id);
+    if (bpfelement == nullptr) {
+        status = -ENODEV;
+    } else {
+        switch (op) {
+            case CONTROL_MAP_LIST: {
+                std::vector <std::pair<std::string, bpf_map_def>> maps;
+                status = bpfelement->map_list(maps);
+                body.write<uint32_t>(maps.size());
+                for (auto &map: maps) {
+                    body.blob((const uint8_t *) map.first.data(), map.first.size());
+                    body.write<uint32_t>(map.second.type);
+                    body.write<uint32_t>(map.second.key_size);
+                    body.write<uint32_t>(map.second.value_size);
+                    body.write<uint32_t>(map.second.max_entries);
+                }
+                break;
+            }
+            case CONTROL_MAP_GET: {
+                KeyType key = reader.blob();
+                ValueType value;
+                status = reader.ok ? bpfelement->map_lookup(map_name, key, value) : -EINVAL;
+                body.blob(value.data(), value.size());
+                break;
+            }
+            case CONTROL_MAP_UPDATE: {
+                KeyType key = reader.blob();
+                ValueType value = reader.blob();
+                status = reader.ok ? bpfelement->map_update(map_name, key, value) : -EINVAL;
+                break;
+            }
+            case CONTROL_MAP_DELETE: {
+                KeyType key = reader.blob();
+                status = reader.ok ? bpfelement->map_delete(map_name, key) : -EINVAL;
+                break;
+            }
+            case CONTROL_MAP_DUMP: {
+                uint64_t offset = reader.read<uint64_t>();
+                std::vector <std::pair<KeyType, ValueType>> entries;
+                uint64_t next = 0;
+                bool more = false;
+                // leaves room for the header and the fields before the entries
+                status = reader.ok
+                         ? bpfelement->map_dump(map_name, offset, MAP_REPLY_MAX_LEN - 32, entries, next, more)
+                         : -EINVAL;
+                body.write<uint64_t>(next);
+                body.write<uint8_t>(more);
+                body.write<uint32_t>(entries.size());
+                for (auto &entry: entries) {
+                    body.data.insert(body.data.end(), entry.first.begin(), entry.first.end());
+                    body.data.insert(body.data.end(), entry.second.begin(), entry.second.end());
+                }
+                break;
+            }
+            default:
+                status = -EOPNOTSUPP;
+                break;
+        }
+    }
+
+    reply.write<int32_t>(status);
+    if (status == 0) {
+        if (body.data.size() > MAP_REPLY_MAX_LEN) {
+            // only LIST and GET can get here, with many maps or large values
+            reply.data.resize(reply.data.size() - sizeof(int32_t));
+            reply.write<int32_t>(-E2BIG);
+        } else {
+            reply.data.insert(reply.data.end(), body.data.begin(), body.data.end());
+        }
+    }
+
+    uk_pr_info("Control: map request %u for bpfelement_id %lu, map '%s': status %d\n", op, bpfelement_id,
+               map_name.c_str(), status);
+
+    send_reply(p, reply.data);
+}
+
+void Control::send_reply(Packet *p, const std::vector <uint8_t> &payload) {
+    if (noutputs() == 0) {
+        return;
+    }
+    if (!p->has_mac_header() || !p->has_network_header()) {
+        uk_pr_err("Control: can't reply to a packet without Ethernet and IP headers\n");
+        return;
+    }
+
+    uint32_t len = sizeof(click_ether) + sizeof(click_ip) + sizeof(click_udp) + payload.size();
+    WritablePacket *q = Packet::make(Packet::default_headroom, nullptr, len, 0);
+    if (!q) {
+        uk_pr_err("Control: couldn't allocate reply\n");
+        return;
+    }
+
+    // back to where the request came from
+    const click_ether *req_ether = reinterpret_cast<const click_ether *>(p->mac_header());
+    click_ether *ether = reinterpret_cast<click_ether *>(q->data());
+    memcpy(ether->ether_dhost, req_ether->ether_shost, 6);
+    memcpy(ether->ether_shost, req_ether->ether_dhost, 6);
+    ether->ether_type = htons(ETHERTYPE_IP);
+
+    click_ip *ip = reinterpret_cast<click_ip *>(ether + 1);
+    memset(ip, 0, sizeof(click_ip));
+    ip->ip_v = 4;
+    ip->ip_hl = sizeof(click_ip) >> 2;
+    ip->ip_len = htons(len - sizeof(click_ether));
+    ip->ip_ttl = 64;
+    ip->ip_p = IP_PROTO_UDP;
+    ip->ip_src = p->ip_header()->ip_dst;
+    ip->ip_dst = p->ip_header()->ip_src;
+    ip->ip_sum = click_in_cksum((const unsigned char *) ip, sizeof(click_ip));
+
+    // no UDP checksum, which is optional over IPv4
+    click_udp *udp = reinterpret_cast<click_udp *>(ip + 1);
+    udp->uh_sport = p->udp_header()->uh_dport;
+    udp->uh_dport = p->udp_header()->uh_sport;
+    udp->uh_ulen = htons(sizeof(click_udp) + payload.size());
+    udp->uh_sum = 0;
+    memcpy(udp + 1, payload.data(), payload.size());
+
+    q->set_mac_header(q->data(), sizeof(click_ether));
+    q->set_ip_header(ip, sizeof(click_ip));
+    output(0).push(q);
+}
+
 CLICK_ENDDECLS
 EXPORT_ELEMENT(Control)
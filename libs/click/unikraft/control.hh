@@ -6,9 +6,19 @@
 #include <click/element.hh>
 #include <click/error.hh>
 #include <click/task.hh>
+#include <vector>
 
 CLICK_DECLS
 
+class BPFElement;
+
+// map request types, see Control::map_request
+#define CONTROL_MAP_LIST 1
+#define CONTROL_MAP_GET 2
+#define CONTROL_MAP_UPDATE 3
+#define CONTROL_MAP_DELETE 4
+#define CONTROL_MAP_DUMP 5
+
 /*
 =c
 
@@ -18,17 +28,36 @@ Control([I<keywords> PROGRAM])
 
 Element that can be used to trigger live reconfiguration of other elements.
 
+=d
+
+Takes UDP packets with the Ethernet and IP headers still reachable through the annotations, e.g.
+after StripEtherVLANHeader and CheckIPHeader. "control" packets reconfigure the BPF element with
+the given ID with a new program, or load one into a slot of its program's program arrays for tail
+calls (TAIL_CALL). "ctrlmap" packets list, read, write, delete and dump the maps of
+a BPF element's program (see control.cc for the format).
+
+If output 0 is connected, the replies to map requests leave there as Ethernet frames back to the
+sender, e.g. into the ToDevice the requests came from.
+
  */
 class Control : public Element { public:
 
     Control() CLICK_COLD;
 
     const char *class_name() const override		{ return "Control"; }
-    const char *port_count() const override		{ return PORTS_1_0; }
+    const char *port_count() const override		{ return "1/0-1"; }
+    const char *processing() const override		{ return PUSH; }
     bool can_live_reconfigure() const override   { return true; }
 
     void push(int, Packet *) override;
 
+private:
+
+    BPFElement *find_bpfelement(uint64_t bpfelement_id);
+    void reconfigure(Packet *p, const unsigned char *udp_data_ptr);
+    void map_request(Packet *p, const unsigned char *udp_data_ptr);
+    void send_reply(Packet *p, const std::vector <uint8_t> &payload);
+
 };
 
 CLICK_ENDDECLS
@@ -1,10 +1,12 @@
 #include "bpf_helpers.hh"
 #include <uk/plat/time.h>
 
+#include <cerrno>
 #include <cstdarg>
 #include <cstdio>
 #include <cstdint>
 #include <cstdlib>
+#include <cstring>
 #include <chrono>
 #include <ctime>
 #include <random>
@@ -23,11 +25,32 @@ void *bpf_map_lookup_elem(void *raw_map, void *key) {
             }
             return it->second.data();
         }
+        case BPF_MAP_TYPE_LRU_HASH: {
+            auto *lru_map = static_cast<LruHashMap *>(map.data);
+            KeyType key_value(map.def.key_size);
+            std::memcpy(key_value.data(), key, map.def.key_size);
+
+            auto it = lru_map->entries.find(key_value);
+            if (it == lru_map->entries.end()) {
+                return nullptr;
+            }
+            lru_map->order.splice(lru_map->order.begin(), lru_map->order, it->second.position);
+            return it->second.value.data();
+        }
+        case BPF_MAP_TYPE_LPM_TRIE: {
+            ValueType *value = static_cast<LpmTrie *>(map.data)->lookup(static_cast<uint8_t *>(key));
+            return value == nullptr ? nullptr : value->data();
+        }
         case BPF_MAP_TYPE_ARRAY: {
             auto index = *(uint32_t *) key;
             char *data = static_cast<char *>(map.data);
             return &data[index * map.def.value_size];
         }
+        case BPF_MAP_TYPE_PROG_ARRAY: {
+            auto &programs = static_cast<ProgArray *>(map.data)->programs;
+            auto index = *(uint32_t *) key;
+            return index < programs.size() ? &programs[index] : nullptr;
+        }
         default: {
             fprintf(stderr, "bpf_map_lookup_elem: unsupported map type %d\n", map.def.type);
             return nullptr;
@@ -50,6 +73,39 @@ long bpf_map_update_elem(void *raw_map, void *key, const void *value, uint64_t f
             (*hash_map)[key_value] = value_value;
             break;
         }
+        case BPF_MAP_TYPE_LRU_HASH: {
+            auto *lru_map = static_cast<LruHashMap *>(map.data);
+
+            KeyType key_value(map.def.key_size);
+            std::memcpy(key_value.data(), key, map.def.key_size);
+
+            auto it = lru_map->entries.find(key_value);
+            if (it != lru_map->entries.end()) {
+                // in place, so pointers from lookups stay valid
+                std::memcpy(it->second.value.data(), value, map.def.value_size);
+                lru_map->order.splice(lru_map->order.begin(), lru_map->order, it->second.position);
+                break;
+            }
+
+            if (lru_map->entries.size() >= map.def.max_entries && !lru_map->order.empty()) {
+                lru_map->entries.erase(lru_map->order.back());
+                lru_map->order.pop_back();
+            }
+            ValueType value_value(map.def.value_size);
+            std::memcpy(value_value.data(), value, map.def.value_size);
+            lru_map->order.push_front(key_value);
+            lru_map->entries.emplace(key_value, LruHashMap::Entry{value_value, lru_map->order.begin()});
+            break;
+        }
+        case BPF_MAP_TYPE_LPM_TRIE: {
+            auto *trie = static_cast<LpmTrie *>(map.data);
+            long err = trie->update(static_cast<uint8_t *>(key), static_cast<const uint8_t *>(value),
+                                    map.def.value_size, map.def.max_entries);
+            if (err) {
+                return err;
+            }
+            break;
+        }
         case BPF_MAP_TYPE_ARRAY: {
             auto index = *(uint32_t *) key;
             char *data = static_cast<char *>(map.data);
@@ -57,6 +113,10 @@ long bpf_map_update_elem(void *raw_map, void *key, const void *value, uint64_t f
             std::memcpy(value_position, value, map.def.value_size);
             break;
         }
+        case BPF_MAP_TYPE_PROG_ARRAY: {
+            // only the element puts programs into slots, with TAIL_CALL
+            return -EINVAL;
+        }
         default: {
             fprintf(stderr, "bpf_map_update_elem: unsupported map type %d\n", map.def.type);
             return 0;
@@ -76,6 +136,30 @@ long bpf_map_delete_elem(void *raw_map, void *key) {
             hash_map->erase(key_value);
             return 0;
         }
+        case BPF_MAP_TYPE_LRU_HASH: {
+            auto *lru_map = static_cast<LruHashMap *>(map.data);
+            KeyType key_value(map.def.key_size);
+            std::memcpy(key_value.data(), key, map.def.key_size);
+            auto it = lru_map->entries.find(key_value);
+            if (it != lru_map->entries.end()) {
+                lru_map->order.erase(it->second.position);
+                lru_map->entries.erase(it);
+            }
+            return 0;
+        }
+        case BPF_MAP_TYPE_LPM_TRIE: {
+            static_cast<LpmTrie *>(map.data)->erase(static_cast<uint8_t *>(key));
+            return 0;
+        }
+        case BPF_MAP_TYPE_PROG_ARRAY: {
+            auto &programs = static_cast<ProgArray *>(map.data)->programs;
+            auto index = *(uint32_t *) key;
+            if (index >= programs.size()) {
+                return -EINVAL;
+            }
+            programs[index] = 0;
+            return 0;
+        }
         default: {
             fprintf(stderr, "bpf_map_delete_elem: unsupported map type %d\n", map.def.type);
             return 0;
@@ -83,6 +167,85 @@ long bpf_map_delete_elem(void *raw_map, void *key) {
     }
 }
 
+// `data_size` bytes of `data` with the bits past `prefix_len` cleared
+static KeyType masked_prefix(const uint8_t *data, size_t data_size, uint32_t prefix_len) {
+    KeyType prefix(data, data + data_size);
+    for (size_t i = 0; i < data_size; i++) {
+        if (prefix_len >= 8 * (i + 1)) {
+            continue;
+        }
+        uint32_t bits = prefix_len > 8 * i ? prefix_len - 8 * i : 0;
+        prefix[i] &= static_cast<uint8_t>(0xff00 >> bits);
+    }
+    return prefix;
+}
+
+ValueType *LpmTrie::lookup(const uint8_t *key) {
+    uint32_t prefix_len;
+    std::memcpy(&prefix_len, key, sizeof(prefix_len));
+    for (auto &[len, entries]: prefixes) {
+        if (len > prefix_len) {
+            continue;
+        }
+        auto it = entries.find(masked_prefix(key + sizeof(prefix_len), data_size, len));
+        if (it != entries.end()) {
+            return &it->second;
+        }
+    }
+    return nullptr;
+}
+
+ValueType *LpmTrie::find(const uint8_t *key) {
+    uint32_t prefix_len;
+    std::memcpy(&prefix_len, key, sizeof(prefix_len));
+    auto entries = prefixes.find(prefix_len);
+    if (entries == prefixes.end()) {
+        return nullptr;
+    }
+    auto it = entries->second.find(masked_prefix(key + sizeof(prefix_len), data_size, prefix_len));
+    return it == entries->second.end() ? nullptr : &it->second;
+}
+
+long LpmTrie::update(const uint8_t *key, const uint8_t *value, size_t value_size, size_t max_entries) {
+    uint32_t prefix_len;
+    std::memcpy(&prefix_len, key, sizeof(prefix_len));
+    if (prefix_len > 8 * data_size) {
+        return -EINVAL;
+    }
+    KeyType prefix = masked_prefix(key + sizeof(prefix_len), data_size, prefix_len);
+    auto &entries = prefixes[prefix_len];
+    auto it = entries.find(prefix);
+    if (it == entries.end()) {
+        if (size >= max_entries) {
+            if (entries.empty()) {
+                prefixes.erase(prefix_len);
+            }
+            return -ENOSPC;
+        }
+        it = entries.emplace(prefix, ValueType()).first;
+        size++;
+    }
+    // in place, so pointers from lookups stay valid
+    it->second.resize(value_size);
+    std::memcpy(it->second.data(), value, value_size);
+    return 0;
+}
+
+bool LpmTrie::erase(const uint8_t *key) {
+    uint32_t prefix_len;
+    std::memcpy(&prefix_len, key, sizeof(prefix_len));
+    auto entries = prefixes.find(prefix_len);
+    if (entries == prefixes.end() ||
+        entries->second.erase(masked_prefix(key + sizeof(prefix_len), data_size, prefix_len)) == 0) {
+        return false;
+    }
+    size--;
+    if (entries->second.empty()) {
+        prefixes.erase(entries);
+    }
+    return true;
+}
+
 #pragma GCC diagnostic push
 #pragma GCC diagnostic ignored "-Wformat-security"
 void bpf_trace_printk(const char *fmt, int fmt_size, ...) {
@@ -146,6 +309,29 @@ uint64_t unwind(uint64_t i) {
     return i;
 }
 
+thread_local uint32_t bpf_pending_tail_call = 0;
+thread_local uint32_t bpf_tail_call_count = 0;
+
+long bpf_tail_call(void *ctx, void *raw_map, uint32_t index) {
+    (void) ctx; // the element runs the next program on the packet it is processing
+    bpf_map &map = *reinterpret_cast<bpf_map *>(raw_map);
+    if (map.def.type != BPF_MAP_TYPE_PROG_ARRAY) {
+        fprintf(stderr, "bpf_tail_call: map type %d isn't a program array\n", map.def.type);
+        return -EINVAL;
+    }
+
+    auto *array = static_cast<ProgArray *>(map.data);
+    if (index >= array->programs.size() || array->programs[index] == 0) {
+        return -ENOENT;
+    }
+    if (bpf_tail_call_count >= BPF_MAX_TAIL_CALLS) {
+        return -E2BIG;
+    }
+    bpf_tail_call_count++;
+    bpf_pending_tail_call = array->programs[index];
+    return 0;
+}
+
 uint64_t do_data_relocation(
         void *user_context,
         const uint8_t *data,
@@ -204,6 +390,7 @@ uint64_t do_map_relocation(
             return 0;
         }
 
+        ctx->relocated.insert(symbol_name);
         return reinterpret_cast<uint64_t>(it->second);
     }
 
@@ -214,6 +401,24 @@ uint64_t do_map_relocation(
             data = reinterpret_cast<void *>(hash_map);
             break;
         }
+        case BPF_MAP_TYPE_LRU_HASH: {
+            if (map_definition.max_entries == 0) {
+                fprintf(stderr, "LRU hash map %s needs max_entries\n", symbol_name);
+                return 0;
+            }
+            data = reinterpret_cast<void *>(new LruHashMap());
+            break;
+        }
+        case BPF_MAP_TYPE_LPM_TRIE: {
+            if (map_definition.key_size <= sizeof(uint32_t)) {
+                fprintf(stderr, "LPM trie %s has no room for prefixes in its keys\n", symbol_name);
+                return 0;
+            }
+            auto *trie = new LpmTrie();
+            trie->data_size = map_definition.key_size - sizeof(uint32_t);
+            data = reinterpret_cast<void *>(trie);
+            break;
+        }
         case BPF_MAP_TYPE_ARRAY: {
             if (map_definition.key_size != sizeof(uint32_t)) {
                 fprintf(stderr, "Unsupported key size %d\n", map_definition.key_size);
@@ -223,6 +428,16 @@ uint64_t do_map_relocation(
             data = std::calloc(map_definition.max_entries, map_definition.value_size);
             break;
         }
+        case BPF_MAP_TYPE_PROG_ARRAY: {
+            if (map_definition.key_size != sizeof(uint32_t) || map_definition.value_size != sizeof(uint32_t)) {
+                fprintf(stderr, "Program array %s needs 4-byte keys and values\n", symbol_name);
+                return 0;
+            }
+            auto *array = new ProgArray();
+            array->programs.resize(map_definition.max_entries);
+            data = reinterpret_cast<void *>(array);
+            break;
+        }
         default: {
             fprintf(stderr, "Unsupported map type %d\n", map_definition.type);
             return 0;
@@ -234,6 +449,7 @@ uint64_t do_map_relocation(
     map->data = data;
 
     ctx->map_by_name[symbol_name] = map;
+    ctx->relocated.insert(symbol_name);
 
 	printf("Startup trace (nsec): load elf > relocate map: %llu\n", ukplat_monotonic_clock() - ts);
     return reinterpret_cast<uint64_t>(map);
@@ -2,7 +2,11 @@
 #define UBPF_HELPERS_HH
 
 #include <cstdint>
+#include <functional>
+#include <list>
+#include <map>
 #include <unordered_map>
+#include <unordered_set>
 #include <vector>
 #include <string>
 
@@ -20,6 +24,18 @@ uint32_t bpf_get_prandom_u32(void);
 
 uint64_t unwind(uint64_t i);
 
+// Schedules program `index` of the BPF_MAP_TYPE_PROG_ARRAY `map` to run on the packet once the
+// calling program stopped. 0, or -ENOENT for an empty slot and -E2BIG past BPF_MAX_TAIL_CALLS.
+long bpf_tail_call(void *ctx, void *map, uint32_t index);
+
+// tail calls a packet can go through, as in the kernel
+#define BPF_MAX_TAIL_CALLS 33
+
+// Program bpf_tail_call scheduled on this thread, 0 for none, and tail calls the packet went
+// through. The element runs the program and clears the first, and resets both for every packet.
+extern thread_local uint32_t bpf_pending_tail_call;
+extern thread_local uint32_t bpf_tail_call_count;
+
 enum bpf_map_type {
     BPF_MAP_TYPE_UNSPEC,
     BPF_MAP_TYPE_HASH,
@@ -58,7 +74,10 @@ struct bpf_map {
 };
 
 struct bpf_map_ctx {
+    // maps outlive the programs using them, so a reloaded program finds them by name
     std::unordered_map<std::string, struct bpf_map *> map_by_name;
+    // names of the maps the last loaded program uses; clear before loading a program
+    std::unordered_set<std::string> relocated;
     std::unordered_map<uint64_t, void *> global_data;
 };
 
@@ -84,6 +103,51 @@ struct VectorEqual {
     }
 };
 
+// Data of a BPF_MAP_TYPE_LRU_HASH map. Inserting a new key into a full map evicts the least
+// recently looked up or updated entry, which invalidates pointers to its value.
+struct LruHashMap {
+    using Order = std::list<KeyType>;
+
+    struct Entry {
+        ValueType value;
+        // position in `order`
+        Order::iterator position;
+    };
+
+    std::unordered_map<KeyType, Entry, VectorHash, VectorEqual> entries;
+    // keys, most recently used first
+    Order order;
+};
+
+// Data of a BPF_MAP_TYPE_LPM_TRIE map. Keys are a 4-byte prefix length followed by the prefix,
+// most significant byte first, as in the kernel. Lookups find the value of the longest stored
+// prefix of the key's first `prefix length` bits, updates and deletes work on exact prefixes.
+struct LpmTrie {
+    // key size minus the prefix length
+    size_t data_size;
+    // by prefix length, longest first; each prefix with the bits past its length cleared
+    std::map<uint32_t, std::unordered_map<KeyType, ValueType, VectorHash, VectorEqual>, std::greater<uint32_t>> prefixes;
+    // number of stored prefixes, of all lengths
+    size_t size = 0;
+
+    // nullptr if no stored prefix matches
+    ValueType *lookup(const uint8_t *key);
+    // nullptr unless exactly this prefix is stored
+    ValueType *find(const uint8_t *key);
+    // 0, -EINVAL if the prefix length is longer than the data, -ENOSPC if the prefix is new and
+    // max_entries are stored already
+    long update(const uint8_t *key, const uint8_t *value, size_t value_size, size_t max_entries);
+    // false if the prefix isn't stored
+    bool erase(const uint8_t *key);
+};
+
+// Data of a BPF_MAP_TYPE_PROG_ARRAY map. Slots hold the numbers the element gave the programs it
+// loaded for tail calls, 0 if empty. Programs can only read them, the control plane can only
+// empty them.
+struct ProgArray {
+    std::vector<uint32_t> programs;
+};
+
 uint64_t do_map_relocation(
         void *user_context,
         const uint8_t *map_data,
@@ -0,0 +1,213 @@
+use std::env::args;
+use std::fs;
+use std::process::ExitCode;
+
+use anyhow::Context;
+use morphos_run::elf::{MapDef, MapSymbol, Program, BPF_MAP_TYPE_ARRAY};
+
+const USAGE: &str = "\
+usage: morphos-check-maps <old program> <new program>
+
+Checks whether a MorphOS BPF element running <old program> can be reconfigured with <new program>
+without losing map state. Maps are kept across reconfigurations by name, so a map both programs
+define must have the same type, key and value size and number of entries, or the element refuses
+the new program. Exits with 1 if it would.
+";
+
+/// The map bpf_element::migration keeps the schema version of the maps in.
+const SCHEMA_VERSION: &str = "SCHEMA_VERSION";
+
+fn main() -> anyhow::Result<ExitCode> {
+    let args: Vec<String> = args().skip(1).collect();
+    let [old, new] = &args[..] else {
+        eprint!("{USAGE}");
+        return Ok(ExitCode::from(2));
+    };
+    let old = load(old)?;
+    let new = load(new)?;
+
+    let (lines, compatible) = check(&old.maps, &new.maps);
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(if compatible {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// What happens to the maps when `new` replaces `old`, as lines for the user, and whether the
+/// element accepts the new program.
+fn check(old: &[MapSymbol], new: &[MapSymbol]) -> (Vec<String>, bool) {
+    let mut lines = Vec::new();
+    let mut compatible = true;
+    for map in new {
+        match old.iter().find(|old| old.name == map.name) {
+            Some(old) if map.def.compatible(&old.def) => {
+                lines.push(format!("kept     {}", map.name))
+            }
+            Some(old) => {
+                compatible = false;
+                lines.push(format!(
+                    "changed  {}: {} -> {}",
+                    map.name,
+                    describe(&old.def),
+                    describe(&map.def)
+                ));
+            }
+            None => lines.push(format!(
+                "new      {}: {}, starts empty",
+                map.name,
+                describe(&map.def)
+            )),
+        }
+    }
+    for map in old {
+        if !new.iter().any(|new| new.name == map.name) {
+            lines.push(format!(
+                "unused   {}: left as it is for programs that use it again",
+                map.name
+            ));
+        }
+    }
+
+    let schema = new.iter().find(|map| map.name == SCHEMA_VERSION);
+    if let Some(schema) = schema {
+        if schema.def.map_type != BPF_MAP_TYPE_ARRAY || schema.def.value_size != 4 {
+            compatible = false;
+            lines.push(format!("error: {SCHEMA_VERSION} must be an array of u32"));
+        }
+    }
+
+    let added = new
+        .iter()
+        .any(|map| !old.iter().any(|old| old.name == map.name));
+    let summary: &[&str] = if !compatible {
+        &[
+            "The element refuses the new program: rename the changed maps and copy their contents",
+            "in a bpf_element::migration::Schema.",
+        ]
+    } else if schema.is_some() {
+        &[
+            "Compatible. The new program migrates the maps when it's loaded; if that fails, the",
+            "element keeps the old one.",
+        ]
+    } else if added {
+        &["Compatible. The new program has no schema, so its new maps start empty."]
+    } else {
+        &["Compatible."]
+    };
+    lines.push(String::new());
+    lines.extend(summary.iter().map(|line| line.to_string()));
+    (lines, compatible)
+}
+
+fn load(path: &str) -> anyhow::Result<Program> {
+    let elf = fs::read(path).with_context(|| format!("couldn't read {path}"))?;
+    Program::load(&elf).with_context(|| format!("couldn't load {path}"))
+}
+
+fn describe(def: &MapDef) -> String {
+    format!(
+        "{}, {}-byte keys, {}-byte values, {} entries",
+        def.type_name(),
+        def.key_size,
+        def.value_size,
+        def.max_entries
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use morphos_run::elf::{BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_LRU_HASH, BPF_MAP_TYPE_PROG_ARRAY};
+
+    use super::*;
+
+    fn map(name: &str, map_type: u32, value_size: u32) -> MapSymbol {
+        MapSymbol {
+            name: name.to_string(),
+            def: MapDef {
+                map_type,
+                key_size: 4,
+                value_size,
+                max_entries: 1,
+                map_flags: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn reports_what_happens_to_each_map() {
+        let old = [
+            map("KEPT", BPF_MAP_TYPE_HASH, 4),
+            map("CHANGED", BPF_MAP_TYPE_HASH, 4),
+            map("UNUSED", BPF_MAP_TYPE_HASH, 4),
+        ];
+        let new = [
+            map("KEPT", BPF_MAP_TYPE_HASH, 4),
+            map("CHANGED", BPF_MAP_TYPE_HASH, 8),
+            map("NEW", BPF_MAP_TYPE_LRU_HASH, 4),
+        ];
+        let (lines, compatible) = check(&old, &new);
+        assert!(!compatible);
+        assert_eq!(
+            lines[..4],
+            [
+                "kept     KEPT",
+                "changed  CHANGED: hash, 4-byte keys, 4-byte values, 1 entries -> hash, 4-byte keys, \
+                 8-byte values, 1 entries",
+                "new      NEW: LRU hash, 4-byte keys, 4-byte values, 1 entries, starts empty",
+                "unused   UNUSED: left as it is for programs that use it again",
+            ]
+        );
+        assert!(lines[5].starts_with("The element refuses the new program"));
+    }
+
+    #[test]
+    fn new_maps_start_empty_without_a_schema() {
+        let old = [map("KEPT", BPF_MAP_TYPE_HASH, 4)];
+        let (lines, compatible) = check(&old, &old);
+        assert!(compatible);
+        assert_eq!(lines.last().unwrap(), "Compatible.");
+
+        let new = [
+            map("KEPT", BPF_MAP_TYPE_HASH, 4),
+            map("NEW", BPF_MAP_TYPE_HASH, 4),
+        ];
+        let (lines, compatible) = check(&old, &new);
+        assert!(compatible);
+        assert!(lines.last().unwrap().contains("its new maps start empty"));
+
+        let new = [
+            map("NEW", BPF_MAP_TYPE_HASH, 4),
+            map(SCHEMA_VERSION, BPF_MAP_TYPE_ARRAY, 4),
+        ];
+        let (lines, compatible) = check(&old, &new);
+        assert!(compatible);
+        assert!(lines[lines.len() - 2].contains("migrates the maps when it's loaded"));
+    }
+
+    #[test]
+    fn refuses_schema_versions_other_than_u32_arrays() {
+        for schema in [
+            map(SCHEMA_VERSION, BPF_MAP_TYPE_ARRAY, 8),
+            map(SCHEMA_VERSION, BPF_MAP_TYPE_HASH, 4),
+        ] {
+            let (lines, compatible) = check(&[], &[schema]);
+            assert!(!compatible);
+            assert!(lines.contains(&format!("error: {SCHEMA_VERSION} must be an array of u32")));
+        }
+    }
+
+    #[test]
+    fn names_map_types() {
+        let (lines, _) = check(&[], &[map("HANDLERS", BPF_MAP_TYPE_PROG_ARRAY, 4)]);
+        assert_eq!(
+            lines[0],
+            "new      HANDLERS: program array, 4-byte keys, 4-byte values, 1 entries, starts empty"
+        );
+        let (lines, _) = check(&[], &[map("QUEUE", 22, 4)]);
+        assert!(lines[0].contains("type 22, "), "{}", lines[0]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::env::args;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+
+use anyhow::{bail, Context};
+use morphos_run::elf::Program;
+use morphos_run::firewall::{self, RuleSet, GENERATED, RULE_SIZE};
+
+const USAGE: &str = "\
+usage: morphos-firewall check <rules>
+       morphos-firewall program <rules> <name> [options]
+       morphos-firewall table <rules> [options]
+
+Compiles a firewall rule file into a program for a MorphOS BPF element (see runner/src/firewall.rs
+for the format):
+
+  check     print the rules in the order they're tried, and those that never match
+  program   write ebpf/src/bin/<name>.rs, a program with the rules built in, then build, verify and
+            sign it
+  table     print the rule table of the generic firewall program as morphos-run --update
+            arguments, then build, verify and sign the firewall program
+
+options:
+  -n, --no-build    only write the program or print the table
+  -k, --key <pem>   signing key (default: verifier/keys/ec_private_key.pem)
+  -c, --control     table: print `map update` arguments for the control plane instead
+";
+
+const EBPF_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../ebpf");
+const VERIFIER: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../verifier/build/ubpf_verifier"
+);
+const KEY: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../verifier/keys/ec_private_key.pem"
+);
+
+/// The generic program, and the map and length of its rule table if it isn't built yet.
+const FIREWALL: &str = "firewall";
+const RULE_TABLE: &str = "RULE_TABLE";
+const MAX_RULES: u32 = 1024;
+
+struct Options {
+    command: String,
+    rules: PathBuf,
+    name: Option<String>,
+    build: bool,
+    key: PathBuf,
+    control: bool,
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let Some(options) = parse_args()? else {
+        eprint!("{USAGE}");
+        return Ok(ExitCode::from(2));
+    };
+    let text = fs::read_to_string(&options.rules)
+        .with_context(|| format!("couldn't read {}", options.rules.display()))?;
+    let rules = firewall::parse(&text).with_context(|| format!("{}", options.rules.display()))?;
+
+    match (options.command.as_str(), &options.name) {
+        ("check", None) => {
+            for rule in &rules.rules {
+                println!("{rule}");
+            }
+            println!("default {}", rules.default);
+            warn_shadowed(&rules, &options.rules);
+        }
+        ("program", Some(name)) => {
+            warn_shadowed(&rules, &options.rules);
+            let path = Path::new(EBPF_DIR).join(format!("src/bin/{name}.rs"));
+            if fs::read_to_string(&path).is_ok_and(|source| !source.starts_with(GENERATED)) {
+                bail!(
+                    "{} exists and wasn't generated, pick another name",
+                    path.display()
+                );
+            }
+            let source = options
+                .rules
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            fs::write(&path, rules.program(&source))
+                .with_context(|| format!("couldn't write {}", path.display()))?;
+            eprintln!("wrote {}", path.display());
+            if options.build {
+                build(name, &options.key)?;
+            }
+        }
+        ("table", None) => {
+            warn_shadowed(&rules, &options.rules);
+            if options.build {
+                build(FIREWALL, &options.key)?;
+            }
+            let table = rules.table(max_rules()?)?;
+            let default = u32::from(rules.default.filter_result()).to_le_bytes();
+            if options.control {
+                for (index, entry) in table.iter().enumerate() {
+                    println!("map update {RULE_TABLE} {index} 0x{}", hex(entry));
+                }
+                println!("map update CONFIG 0 0x{}", hex(&default));
+            } else {
+                for (index, entry) in table.iter().enumerate() {
+                    let key = (index as u32).to_le_bytes();
+                    println!("{RULE_TABLE}:{}={}", hex(&key), hex(entry));
+                }
+                println!("CONFIG:00000000={}", hex(&default));
+            }
+        }
+        _ => {
+            eprint!("{USAGE}");
+            return Ok(ExitCode::from(2));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// `None` if the arguments don't make sense.
+fn parse_args() -> anyhow::Result<Option<Options>> {
+    let mut positional = Vec::new();
+    let mut build = true;
+    let mut key = PathBuf::from(KEY);
+    let mut control = false;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-n" | "--no-build" => build = false,
+            "-k" | "--key" => key = args.next().context("--key needs a value")?.into(),
+            "-c" | "--control" => control = true,
+            flag if flag.starts_with('-') => bail!("unknown option {flag}"),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let (Some(command), Some(rules)) = (positional.next(), positional.next()) else {
+        return Ok(None);
+    };
+    let name = positional.next();
+    if positional.next().is_some() {
+        return Ok(None);
+    }
+    if let Some(name) = &name {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("invalid program name `{name}`");
+        }
+    }
+    Ok(Some(Options {
+        command,
+        rules: rules.into(),
+        name,
+        build,
+        key,
+        control,
+    }))
+}
+
+fn warn_shadowed(rules: &RuleSet, path: &Path) {
+    for (rule, earlier) in rules.shadowed() {
+        let (rule, earlier) = (&rules.rules[rule], &rules.rules[earlier]);
+        eprintln!(
+            "warning: {}:{}: `{rule}` never matches, `{earlier}` (line {}) matches all of its packets first",
+            path.display(),
+            rule.line,
+            earlier.line
+        );
+    }
+}
+
+/// Builds a program like `make <name> VERIFY=1` does in ebpf/.
+fn build(name: &str, key: &Path) -> anyhow::Result<()> {
+    // cargo run sets the toolchain of this crate, ebpf/ has its own
+    let status = Command::new("cargo")
+        .args([
+            "build",
+            "--bin",
+            name,
+            "--target",
+            "bpfel-unknown-none",
+            "--release",
+        ])
+        .args(["-Z", "build-std=core"])
+        .current_dir(EBPF_DIR)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .status()
+        .context("couldn't run cargo")?;
+    if !status.success() {
+        bail!("building {name} failed");
+    }
+
+    let elf = elf_path(name);
+    let signature = elf.with_extension("sig");
+    let status = Command::new(VERIFIER)
+        .arg("-f")
+        .arg(&elf)
+        .arg("-k")
+        .arg(key)
+        .arg("-o")
+        .arg(&signature)
+        .status()
+        .with_context(|| format!("couldn't run {VERIFIER}, is the verifier built?"))?;
+    if !status.success() {
+        bail!("{name} didn't pass the verifier");
+    }
+    eprintln!("built {} and {}", elf.display(), signature.display());
+    Ok(())
+}
+
+fn elf_path(name: &str) -> PathBuf {
+    Path::new(EBPF_DIR).join(format!("target/bpfel-unknown-none/release/{name}"))
+}
+
+/// The length of the built firewall program's rule table, [`MAX_RULES`] if it isn't built.
+fn max_rules() -> anyhow::Result<u32> {
+    let Ok(elf) = fs::read(elf_path(FIREWALL)) else {
+        return Ok(MAX_RULES);
+    };
+    let program = Program::load(&elf).context("couldn't load the firewall program")?;
+    let map = program
+        .maps
+        .iter()
+        .find(|map| map.name == RULE_TABLE)
+        .context("the firewall program has no rule table")?;
+    if map.def.value_size as usize != RULE_SIZE {
+        bail!(
+            "the firewall program's rules have {} bytes, this tool writes {RULE_SIZE}",
+            map.def.value_size
+        );
+    }
+    Ok(map.def.max_entries)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,442 @@
+//! Loads `bpffilter` ELFs the way `ubpf_load_elf_ex` and `do_map_relocation` do in MorphOS.
+//!
+//! Every function symbol of an executable section is linked into one instruction stream, `main`
+//! first. `R_BPF_64_64` relocations against symbols in the `maps` section turn into map handles,
+//! relocations against section symbols (`.rodata`, `.data`, ...) into pointers to a copy of that
+//! section.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use object::elf::{R_BPF_64_32, R_BPF_64_64, SHF_EXECINSTR, SHF_WRITE};
+use object::{
+    Architecture, Object, ObjectKind, ObjectSection, ObjectSymbol, RelocationFlags,
+    RelocationTarget, SectionFlags, SectionIndex, SymbolKind,
+};
+
+use crate::helpers;
+use crate::vm::{data_addr, map_handle};
+
+pub const OP_LDDW: u8 = 0x18;
+pub const OP_CALL: u8 = 0x85;
+
+/// Name of the function `BPFElement::configure` starts programs at.
+const MAIN: &str = "main";
+
+/// Section aya puts map definitions into.
+const MAPS_SECTION: &str = "maps";
+
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_PROG_ARRAY: u32 = 3;
+pub const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+pub const BPF_MAP_TYPE_LPM_TRIE: u32 = 11;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Insn {
+    pub opcode: u8,
+    pub dst: u8,
+    pub src: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl Insn {
+    fn decode(bytes: &[u8]) -> Insn {
+        Insn {
+            opcode: bytes[0],
+            dst: bytes[1] & 0x0f,
+            src: bytes[1] >> 4,
+            off: i16::from_le_bytes([bytes[2], bytes[3]]),
+            imm: i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+/// `struct bpf_map_def` from `libs/ubpf/helper/include/bpf_helpers.hh`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MapDef {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+}
+
+impl MapDef {
+    /// `sizeof(struct bpf_map_def)`: seven `unsigned int`s.
+    pub const SIZE: usize = 28;
+
+    /// Whether a program defining this map can use `other`, the map of the same name an earlier
+    /// program left behind. `do_map_relocation` compares these fields and refuses to load otherwise.
+    pub fn compatible(&self, other: &MapDef) -> bool {
+        self.map_type == other.map_type
+            && self.key_size == other.key_size
+            && self.value_size == other.value_size
+            && self.max_entries == other.max_entries
+    }
+
+    /// Name of the map's type, e.g. "LRU hash".
+    pub fn type_name(&self) -> String {
+        match self.map_type {
+            BPF_MAP_TYPE_HASH => "hash".to_string(),
+            BPF_MAP_TYPE_ARRAY => "array".to_string(),
+            BPF_MAP_TYPE_PROG_ARRAY => "program array".to_string(),
+            BPF_MAP_TYPE_LRU_HASH => "LRU hash".to_string(),
+            BPF_MAP_TYPE_LPM_TRIE => "LPM trie".to_string(),
+            other => format!("type {other}"),
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> MapDef {
+        let field = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        MapDef {
+            map_type: field(0),
+            key_size: field(1),
+            value_size: field(2),
+            max_entries: field(3),
+            map_flags: field(4),
+        }
+    }
+}
+
+pub struct MapSymbol {
+    pub name: String,
+    pub def: MapDef,
+}
+
+pub struct DataSection {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub writable: bool,
+}
+
+pub struct Program {
+    pub insts: Vec<Insn>,
+    /// Maps in the order of their handles, see [`map_handle`].
+    pub maps: Vec<MapSymbol>,
+    /// Sections the program points into, see [`data_addr`].
+    pub data: Vec<DataSection>,
+}
+
+struct Function {
+    section: SectionIndex,
+    start: u64,
+    size: u64,
+    /// Index of the function's first instruction in the linked program.
+    landed: usize,
+}
+
+impl Program {
+    pub fn load(elf: &[u8]) -> anyhow::Result<Program> {
+        let file = object::File::parse(elf).context("not an ELF file")?;
+        if file.architecture() != Architecture::Bpf || !file.is_little_endian() || !file.is_64() {
+            bail!("not a 64-bit little-endian BPF object");
+        }
+        if file.kind() != ObjectKind::Relocatable {
+            bail!("wrong type, expected relocatable");
+        }
+
+        let executable = |index: SectionIndex| {
+            file.section_by_index(index).is_ok_and(|section| {
+                matches!(section.flags(), SectionFlags::Elf { sh_flags } if sh_flags & SHF_EXECINSTR as u64 != 0)
+            })
+        };
+
+        // link all functions, `main` first
+        let mut symbols: Vec<_> = file
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text)
+            .filter(|symbol| symbol.section_index().is_some_and(executable))
+            .collect();
+        let main = symbols
+            .iter()
+            .position(|symbol| symbol.name() == Ok(MAIN))
+            .with_context(|| format!("{MAIN} function not found"))?;
+        symbols[..=main].rotate_right(1);
+
+        let mut insts = Vec::new();
+        let mut functions = Vec::new();
+        for symbol in &symbols {
+            let name = symbol.name()?;
+            let section = file.section_by_index(symbol.section_index().unwrap())?;
+            let data = section.data()?;
+            let (start, size) = (symbol.address(), symbol.size());
+            let code = data
+                .get(start as usize..(start + size) as usize)
+                .with_context(|| format!("function {name} is out of its section"))?;
+            if size % 8 != 0 {
+                bail!("function {name} isn't made of whole instructions");
+            }
+            functions.push(Function {
+                section: section.index(),
+                start,
+                size,
+                landed: insts.len(),
+            });
+            insts.extend(code.chunks_exact(8).map(Insn::decode));
+        }
+
+        // where an instruction of a section ended up in the linked program
+        let linked = |section: SectionIndex, offset: u64| {
+            functions
+                .iter()
+                .find(|f| f.section == section && offset >= f.start && offset < f.start + f.size)
+                .map(|f| f.landed + ((offset - f.start) / 8) as usize)
+        };
+
+        let mut maps = Vec::<MapSymbol>::new();
+        let mut data = Vec::<DataSection>::new();
+        let mut data_by_section = HashMap::new();
+        let mut relocated_calls = Vec::new();
+
+        for section in file
+            .sections()
+            .filter(|section| executable(section.index()))
+        {
+            for (offset, relocation) in section.relocations() {
+                let Some(index) = linked(section.index(), offset) else {
+                    // belongs to code that isn't a function symbol, so it isn't loaded either
+                    continue;
+                };
+                let RelocationTarget::Symbol(symbol) = relocation.target() else {
+                    bail!("relocation at {offset:#x} doesn't target a symbol");
+                };
+                let symbol = file.symbol_by_index(symbol)?;
+                let name = symbol.name().unwrap_or_default();
+                let RelocationFlags::Elf { r_type } = relocation.flags() else {
+                    unreachable!("ELF relocation");
+                };
+
+                match r_type {
+                    R_BPF_64_64 => {
+                        if insts[index].opcode != OP_LDDW || index + 1 >= insts.len() {
+                            bail!("bad R_BPF_64_64 relocation instruction for {name}");
+                        }
+                        let target = symbol
+                            .section_index()
+                            .with_context(|| format!("{name} isn't defined in the program"))?;
+                        let target = file.section_by_index(target)?;
+
+                        let addr = if symbol.size() == 0 {
+                            // section symbol: .rodata, .data, .bss
+                            let slot = match data_by_section.get(&target.index()) {
+                                Some(&slot) => slot,
+                                None => {
+                                    let writable = matches!(target.flags(), SectionFlags::Elf { sh_flags } if sh_flags & SHF_WRITE as u64 != 0);
+                                    data.push(DataSection {
+                                        name: target.name()?.to_string(),
+                                        data: target.uncompressed_data()?.into_owned(),
+                                        writable,
+                                    });
+                                    data_by_section.insert(target.index(), data.len() - 1);
+                                    data.len() - 1
+                                }
+                            };
+                            data_addr(slot).wrapping_add_signed(
+                                insts[index].imm as i64 + symbol.address() as i64,
+                            )
+                        } else if target.name()? == MAPS_SECTION {
+                            let slot = match maps.iter().position(|map| map.name == name) {
+                                Some(slot) => slot,
+                                None => {
+                                    let start = symbol.address() as usize;
+                                    let bytes = target
+                                        .data()?
+                                        .get(start..start + MapDef::SIZE)
+                                        .with_context(|| {
+                                            format!("invalid map definition of {name}")
+                                        })?;
+                                    let def = MapDef::parse(bytes);
+                                    check_map(name, &def)?;
+                                    maps.push(MapSymbol {
+                                        name: name.to_string(),
+                                        def,
+                                    });
+                                    maps.len() - 1
+                                }
+                            };
+                            map_handle(slot)
+                        } else {
+                            bail!(
+                                "{name} in {} isn't a map, but MorphOS would load it as one; \
+                                 only section symbols may point to data",
+                                target.name()?
+                            );
+                        };
+
+                        insts[index].imm = addr as u32 as i32;
+                        insts[index + 1].imm = (addr >> 32) as u32 as i32;
+                    }
+                    R_BPF_64_32 | 2 if insts[index].opcode == OP_CALL => {
+                        if insts[index].src == 1 {
+                            let target = symbol
+                                .section_index()
+                                .with_context(|| format!("{name} isn't defined in the program"))?;
+                            let target_offset = if symbol.kind() == SymbolKind::Text {
+                                symbol.address()
+                            } else {
+                                // section symbol, the call's immediate is relative to the section
+                                symbol.address() + ((insts[index].imm as i64 + 1) * 8) as u64
+                            };
+                            let target = linked(target, target_offset)
+                                .context("relocated target of a function call does not point to a known function")?;
+                            insts[index].imm = (target as i64 - index as i64 - 1) as i32;
+                            relocated_calls.push(index);
+                        } else {
+                            insts[index].imm = helpers::id_by_name(name)
+                                .with_context(|| format!("function '{name}' not found"))?;
+                        }
+                    }
+                    _ => eprintln!("Warning: bad relocation type {r_type}; skipping."),
+                }
+            }
+        }
+
+        // Local calls the linker resolved are relative to the original section. Functions moved
+        // while linking, so point them at the function's new place.
+        for function in &functions {
+            for i in 0..(function.size / 8) as usize {
+                let index = function.landed + i;
+                let insn = insts[index];
+                if insn.opcode != OP_CALL || insn.src != 1 || relocated_calls.contains(&index) {
+                    continue;
+                }
+                let target_offset = function.start as i64 + (i as i64 + 1 + insn.imm as i64) * 8;
+                let target = linked(function.section, target_offset as u64)
+                    .with_context(|| format!("call at instruction {index} leaves the program"))?;
+                insts[index].imm = (target as i64 - index as i64 - 1) as i32;
+            }
+        }
+
+        Ok(Program { insts, maps, data })
+    }
+}
+
+/// Rejects what `do_map_relocation` can't create.
+fn check_map(name: &str, def: &MapDef) -> anyhow::Result<()> {
+    match def.map_type {
+        BPF_MAP_TYPE_HASH => Ok(()),
+        BPF_MAP_TYPE_LRU_HASH if def.max_entries > 0 => Ok(()),
+        BPF_MAP_TYPE_LRU_HASH => bail!("LRU hash map {name} has no max_entries"),
+        BPF_MAP_TYPE_LPM_TRIE if def.key_size > 4 => Ok(()),
+        BPF_MAP_TYPE_LPM_TRIE => bail!("LPM trie {name} has no room for prefixes in its keys"),
+        BPF_MAP_TYPE_ARRAY if def.key_size == 4 => Ok(()),
+        BPF_MAP_TYPE_ARRAY => bail!("array map {name} has unsupported key size {}", def.key_size),
+        BPF_MAP_TYPE_PROG_ARRAY if def.key_size == 4 && def.value_size == 4 => Ok(()),
+        BPF_MAP_TYPE_PROG_ARRAY => bail!("program array {name} needs 4-byte keys and values"),
+        other => bail!("map {name} has unsupported map type {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: MapDef = MapDef {
+        map_type: BPF_MAP_TYPE_HASH,
+        key_size: 4,
+        value_size: 8,
+        max_entries: 1024,
+        map_flags: 0,
+    };
+
+    #[test]
+    fn maps_are_compatible_if_do_map_relocation_would_reuse_them() {
+        assert!(HASH.compatible(&HASH));
+        // flags aren't compared
+        assert!(HASH.compatible(&MapDef {
+            map_flags: 1,
+            ..HASH
+        }));
+
+        let changed = [
+            MapDef {
+                map_type: BPF_MAP_TYPE_LRU_HASH,
+                ..HASH
+            },
+            MapDef {
+                key_size: 8,
+                ..HASH
+            },
+            MapDef {
+                value_size: 16,
+                ..HASH
+            },
+            MapDef {
+                max_entries: 2048,
+                ..HASH
+            },
+        ];
+        for def in changed {
+            assert!(!HASH.compatible(&def), "{def:?}");
+            assert!(!def.compatible(&HASH), "{def:?}");
+        }
+    }
+
+    #[test]
+    fn parses_map_definitions() {
+        let mut bytes = [0; MapDef::SIZE];
+        for (i, field) in [1u32, 4, 8, 1024, 0, 0, 0].iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        assert_eq!(MapDef::parse(&bytes), HASH);
+    }
+
+    #[test]
+    fn decodes_instructions() {
+        // r3 = *(u16 *)(r1 - 2)
+        let insn = Insn::decode(&[0x69, 0x13, 0xfe, 0xff, 0x01, 0x00, 0x00, 0x80]);
+        assert_eq!((insn.opcode, insn.dst, insn.src), (0x69, 3, 1));
+        assert_eq!((insn.off, insn.imm), (-2, i32::MIN + 1));
+    }
+
+    #[test]
+    fn rejects_maps_morphos_cant_create() {
+        let error = |def| check_map("MAP", &def).unwrap_err().to_string();
+        assert!(check_map("MAP", &HASH).is_ok());
+        assert_eq!(
+            error(MapDef {
+                map_type: BPF_MAP_TYPE_LRU_HASH,
+                max_entries: 0,
+                ..HASH
+            }),
+            "LRU hash map MAP has no max_entries"
+        );
+        assert_eq!(
+            error(MapDef {
+                map_type: BPF_MAP_TYPE_LPM_TRIE,
+                ..HASH
+            }),
+            "LPM trie MAP has no room for prefixes in its keys"
+        );
+        assert_eq!(
+            error(MapDef {
+                map_type: BPF_MAP_TYPE_ARRAY,
+                key_size: 8,
+                ..HASH
+            }),
+            "array map MAP has unsupported key size 8"
+        );
+        assert_eq!(
+            error(MapDef {
+                map_type: BPF_MAP_TYPE_PROG_ARRAY,
+                ..HASH
+            }),
+            "program array MAP needs 4-byte keys and values"
+        );
+        assert_eq!(
+            error(MapDef {
+                map_type: 27,
+                ..HASH
+            }),
+            "map MAP has unsupported map type 27"
+        );
+    }
+
+    #[test]
+    fn rejects_files_other_than_bpf_objects() {
+        let error = Program::load(b"#!/bin/sh\n").err().unwrap();
+        assert_eq!(error.to_string(), "not an ELF file");
+    }
+}
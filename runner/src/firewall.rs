@@ -0,0 +1,718 @@
+//! Rule files of `morphos-firewall`, and what it compiles them into: a program with the rules built
+//! in, or the rule table of the generic `firewall` program. Rules match like
+//! `bpf_element::firewall::Rule`, whose layout [`table_entry`] writes.
+//!
+//! A rule file holds a default action and one rule per line, `#` starts a comment:
+//!
+//! ```text
+//! default deny
+//! # priority  action  protocol  source      destination     source port  destination port
+//! 10          allow   tcp       10.0.0.0/8  any             any          22
+//! 20          deny    udp       any         2001:db8::/32   any          1000-1999
+//! ```
+//!
+//! Packets get the action of the matching rule with the lowest priority, or the default action.
+//! Protocols are `any`, `tcp`, `udp`, `icmp`, `icmpv6` or a protocol number, addresses `any` or a
+//! prefix (a bare address is a host) and ports `any`, a port or a range. Rules with ports only
+//! match TCP and UDP packets.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr};
+
+use anyhow::{bail, Context};
+
+/// Size of `bpf_element::firewall::Rule`.
+pub const RULE_SIZE: usize = 80;
+
+/// First line of generated programs, which `morphos-firewall` overwrites without asking.
+pub const GENERATED: &str = "// Generated by morphos-firewall";
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl Action {
+    /// As `bpf_element::filter::FilterResult`.
+    pub fn filter_result(self) -> u8 {
+        match self {
+            Action::Deny => 1,
+            Action::Allow => 2,
+        }
+    }
+
+    fn variant(self) -> &'static str {
+        match self {
+            Action::Deny => "FilterResult::Drop",
+            Action::Allow => "FilterResult::Pass",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        })
+    }
+}
+
+/// An address prefix, without host bits.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Prefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl Prefix {
+    /// Address and length as an IPv6 prefix, IPv4 ones IPv4-mapped, like the programs match them.
+    fn mapped(&self) -> (u128, u32) {
+        match self.addr {
+            IpAddr::V4(addr) => (addr.to_ipv6_mapped().to_bits(), 96 + self.len as u32),
+            IpAddr::V6(addr) => (addr.to_bits(), self.len as u32),
+        }
+    }
+
+    fn covers(&self, other: &Prefix) -> bool {
+        let (addr, len) = self.mapped();
+        let (other_addr, other_len) = other.mapped();
+        len <= other_len && other_addr & mask(len) == addr
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+fn mask(len: u32) -> u128 {
+    u128::MAX.checked_shl(128 - len).unwrap_or(0)
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// Line in the rule file, for messages.
+    pub line: usize,
+    pub priority: u32,
+    pub action: Action,
+    /// `None` for any protocol.
+    pub protocol: Option<u8>,
+    pub src: Option<Prefix>,
+    pub dst: Option<Prefix>,
+    pub src_ports: Option<(u16, u16)>,
+    pub dst_ports: Option<(u16, u16)>,
+}
+
+impl Rule {
+    /// Whether every packet `other` matches also matches this rule.
+    fn covers(&self, other: &Rule) -> bool {
+        fn prefix(prefix: &Option<Prefix>, other: &Option<Prefix>) -> bool {
+            match (prefix, other) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(prefix), Some(other)) => prefix.covers(other),
+            }
+        }
+        fn ports(ports: Option<(u16, u16)>, other: Option<(u16, u16)>) -> bool {
+            let (first, last) = ports.unwrap_or((0, u16::MAX));
+            let (other_first, other_last) = other.unwrap_or((0, u16::MAX));
+            first <= other_first && other_last <= last
+        }
+
+        let has_ports = self.src_ports.is_some() || self.dst_ports.is_some();
+        let other_has_ports = other.src_ports.is_some() || other.dst_ports.is_some();
+        (self.protocol.is_none() || self.protocol == other.protocol)
+            && prefix(&self.src, &other.src)
+            && prefix(&self.dst, &other.dst)
+            && (!has_ports
+                || other_has_ports
+                    && ports(self.src_ports, other.src_ports)
+                    && ports(self.dst_ports, other.dst_ports))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn any<T: fmt::Display>(value: Option<T>) -> String {
+            value.map_or("any".to_string(), |value| value.to_string())
+        }
+        fn ports(ports: Option<(u16, u16)>) -> String {
+            match ports {
+                None => "any".to_string(),
+                Some((first, last)) if first == last => first.to_string(),
+                Some((first, last)) => format!("{first}-{last}"),
+            }
+        }
+        let protocol = match self.protocol {
+            None => "any".to_string(),
+            Some(IPPROTO_TCP) => "tcp".to_string(),
+            Some(IPPROTO_UDP) => "udp".to_string(),
+            Some(IPPROTO_ICMP) => "icmp".to_string(),
+            Some(IPPROTO_ICMPV6) => "icmpv6".to_string(),
+            Some(protocol) => protocol.to_string(),
+        };
+        write!(
+            f,
+            "{} {} {} {} {} {} {}",
+            self.priority,
+            self.action,
+            protocol,
+            any(self.src),
+            any(self.dst),
+            ports(self.src_ports),
+            ports(self.dst_ports)
+        )
+    }
+}
+
+/// A parsed rule file.
+#[derive(Clone, Debug)]
+pub struct RuleSet {
+    pub default: Action,
+    /// By priority.
+    pub rules: Vec<Rule>,
+}
+
+pub fn parse(text: &str) -> anyhow::Result<RuleSet> {
+    let mut default = None;
+    let mut rules: Vec<Rule> = Vec::new();
+    let mut priorities = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [] => {}
+            ["default", action] => {
+                if default.is_some() {
+                    bail!("line {line_number}: second default action");
+                }
+                default = Some(parse_action(action).with_context(|| format!("line {line_number}"))?);
+            }
+            [_, _, _, _, _, _, _] => {
+                let rule =
+                    parse_rule(line_number, &fields).with_context(|| format!("line {line_number}"))?;
+                if let Some(other) = priorities.insert(rule.priority, line_number) {
+                    bail!(
+                        "line {line_number}: priority {} is taken by line {other}",
+                        rule.priority
+                    );
+                }
+                rules.push(rule);
+            }
+            _ => bail!(
+                "line {line_number}: expected `default <action>` or `<priority> <action> <protocol> \
+                 <source> <destination> <source port> <destination port>`"
+            ),
+        }
+    }
+    let default = default.context("no default action, e.g. `default deny`")?;
+    rules.sort_by_key(|rule| rule.priority);
+    Ok(RuleSet { default, rules })
+}
+
+fn parse_rule(line: usize, fields: &[&str]) -> anyhow::Result<Rule> {
+    let [priority, action, protocol, src, dst, src_ports, dst_ports] = fields[..] else {
+        unreachable!("rules have 7 fields");
+    };
+    let rule = Rule {
+        line,
+        priority: priority
+            .parse()
+            .with_context(|| format!("invalid priority `{priority}`"))?,
+        action: parse_action(action)?,
+        protocol: parse_protocol(protocol)?,
+        src: parse_prefix(src)?,
+        dst: parse_prefix(dst)?,
+        src_ports: parse_ports(src_ports)?,
+        dst_ports: parse_ports(dst_ports)?,
+    };
+    if (rule.src_ports.is_some() || rule.dst_ports.is_some())
+        && !matches!(rule.protocol, None | Some(IPPROTO_TCP) | Some(IPPROTO_UDP))
+    {
+        bail!("only tcp and udp have ports");
+    }
+    if let (Some(src), Some(dst)) = (rule.src, rule.dst) {
+        if src.addr.is_ipv4() != dst.addr.is_ipv4() {
+            bail!("{src} and {dst} are of different address families, the rule would never match");
+        }
+    }
+    Ok(rule)
+}
+
+fn parse_action(action: &str) -> anyhow::Result<Action> {
+    match action {
+        "allow" => Ok(Action::Allow),
+        "deny" => Ok(Action::Deny),
+        _ => bail!("invalid action `{action}`, expected allow or deny"),
+    }
+}
+
+fn parse_protocol(protocol: &str) -> anyhow::Result<Option<u8>> {
+    Ok(Some(match protocol {
+        "any" => return Ok(None),
+        "tcp" => IPPROTO_TCP,
+        "udp" => IPPROTO_UDP,
+        "icmp" => IPPROTO_ICMP,
+        "icmpv6" => IPPROTO_ICMPV6,
+        // 0 stands for any protocol in the programs
+        number => match number.parse() {
+            Ok(0) | Err(_) => bail!("invalid protocol `{protocol}`"),
+            Ok(number) => number,
+        },
+    }))
+}
+
+fn parse_prefix(prefix: &str) -> anyhow::Result<Option<Prefix>> {
+    if prefix == "any" {
+        return Ok(None);
+    }
+    let (addr, len) = match prefix.split_once('/') {
+        Some((addr, len)) => (addr, Some(len)),
+        None => (prefix, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .with_context(|| format!("invalid address `{addr}`"))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let len = match len {
+        None => max,
+        Some(len) => match len.parse() {
+            Ok(len) if len <= max => len,
+            _ => bail!("invalid prefix length in `{prefix}`"),
+        },
+    };
+    let prefix = Prefix { addr, len };
+    let (mapped, mapped_len) = prefix.mapped();
+    if mapped & !mask(mapped_len) != 0 {
+        let network = match addr {
+            IpAddr::V4(_) => IpAddr::V4((mapped as u32 & mask(mapped_len) as u32).into()),
+            IpAddr::V6(_) => IpAddr::V6((mapped & mask(mapped_len)).into()),
+        };
+        bail!("`{prefix}` has host bits set, did you mean {network}/{len}?");
+    }
+    // programs match IPv4 addresses as IPv4-mapped IPv6 addresses
+    let ipv4 = Prefix {
+        addr: IpAddr::V6(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped()),
+        len: 96,
+    };
+    if addr.is_ipv6() && prefix.covers(&ipv4) {
+        bail!("`{prefix}` would match IPv4 packets too, use `any` for all addresses");
+    }
+    if addr.is_ipv6() && ipv4.covers(&prefix) {
+        bail!("`{prefix}` only matches IPv4 packets, write it as an IPv4 prefix");
+    }
+    Ok(Some(prefix))
+}
+
+fn parse_ports(ports: &str) -> anyhow::Result<Option<(u16, u16)>> {
+    if ports == "any" {
+        return Ok(None);
+    }
+    let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+    let port = |port: &str| -> anyhow::Result<u16> {
+        port.parse()
+            .with_context(|| format!("invalid port `{port}`"))
+    };
+    let (first, last) = (port(first)?, port(last)?);
+    if first > last {
+        bail!("empty port range `{ports}`");
+    }
+    Ok(Some((first, last)))
+}
+
+impl RuleSet {
+    /// Rules that never match because a rule before them matches all their packets, as pairs of
+    /// indices into `rules`: the shadowed rule and the first rule shadowing it.
+    pub fn shadowed(&self) -> Vec<(usize, usize)> {
+        (0..self.rules.len())
+            .filter_map(|rule| {
+                (0..rule)
+                    .find(|&earlier| self.rules[earlier].covers(&self.rules[rule]))
+                    .map(|earlier| (rule, earlier))
+            })
+            .collect()
+    }
+
+    /// Source of a `bpf_filter` program with the rules built in. `source` names the rule file.
+    pub fn program(&self, source: &str) -> String {
+        let mut program = String::new();
+        let out = &mut program;
+        let prefixes = self
+            .rules
+            .iter()
+            .any(|rule| rule.src.is_some() || rule.dst.is_some());
+        // writing to a String doesn't fail
+        let _ = writeln!(
+            out,
+            "{GENERATED} from {source}. Edit the rules and generate it again instead."
+        );
+        let _ = write!(
+            out,
+            "
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+
+use bpf_element::filter::FilterResult;
+use bpf_element::firewall::{{Fields, {}Rule}};
+use bpf_element::macros::bpf_filter;
+use bpf_element::parse::LinkLayer;
+use bpf_element::{{Error, Packet}};
+",
+            if prefixes { "Prefix, " } else { "" }
+        );
+        for rule in &self.rules {
+            let _ = write!(
+                out,
+                "\n/// `{rule}`, line {}\nconst RULE_{}: Rule = Rule::new({})",
+                rule.line,
+                rule.priority,
+                rule.action.variant()
+            );
+            if let Some(protocol) = rule.protocol {
+                let _ = write!(out, "\n    .protocol({protocol})");
+            }
+            if let Some(src) = rule.src {
+                let _ = write!(out, "\n    .src({})", prefix_expr(&src));
+            }
+            if let Some(dst) = rule.dst {
+                let _ = write!(out, "\n    .dst({})", prefix_expr(&dst));
+            }
+            if let Some((first, last)) = rule.src_ports {
+                let _ = write!(out, "\n    .src_ports({first}, {last})");
+            }
+            if let Some((first, last)) = rule.dst_ports {
+                let _ = write!(out, "\n    .dst_ports({first}, {last})");
+            }
+            let _ = writeln!(out, ";");
+        }
+        let _ = write!(
+            out,
+            "
+#[bpf_filter(on_error = Drop, telemetry = false)]
+fn try_filter(packet: &mut Packet) -> Result<FilterResult, Error> {{
+    let headers = packet.parse(LinkLayer::Ethernet)?;
+    // IPv4 or IPv6 only
+    let {} = Fields::of(&headers).ok_or(Error::Unsupported)?;
+",
+            if self.rules.is_empty() { "_" } else { "fields" }
+        );
+        for rule in &self.rules {
+            let _ = write!(
+                out,
+                "
+    if RULE_{}.matches(&fields) {{
+        return Ok({});
+    }}",
+                rule.priority,
+                rule.action.variant()
+            );
+        }
+        let _ = writeln!(out, "\n    Ok({})\n}}", self.default.variant());
+        program
+    }
+
+    /// The `firewall` program's `RULE_TABLE` entries, followed by the zeroed entry that ends the
+    /// table unless the rules fill all of `max_entries`.
+    pub fn table(&self, max_entries: u32) -> anyhow::Result<Vec<[u8; RULE_SIZE]>> {
+        if self.rules.len() > max_entries as usize {
+            bail!(
+                "{} rules don't fit into the rule table of {max_entries}",
+                self.rules.len()
+            );
+        }
+        let mut table: Vec<_> = self.rules.iter().map(table_entry).collect();
+        if table.len() < max_entries as usize {
+            table.push([0; RULE_SIZE]);
+        }
+        Ok(table)
+    }
+}
+
+/// `Prefix::v4(...)` or `Prefix::v6(...)`.
+fn prefix_expr(prefix: &Prefix) -> String {
+    match prefix.addr {
+        IpAddr::V4(addr) => format!("Prefix::v4({:?}, {})", addr.octets(), prefix.len),
+        IpAddr::V6(addr) => {
+            let octets: Vec<_> = addr.octets().iter().map(|b| format!("{b:#04x}")).collect();
+            format!("Prefix::v6([{}], {})", octets.join(", "), prefix.len)
+        }
+    }
+}
+
+/// A rule in the layout of `bpf_element::firewall::Rule`: source and destination prefix as
+/// address and mask, each two big-endian halves stored as little-endian `u64`s, source and
+/// destination port range, protocol (0 for any) and action.
+pub fn table_entry(rule: &Rule) -> [u8; RULE_SIZE] {
+    let mut entry = [0; RULE_SIZE];
+    for (offset, prefix) in [(0, rule.src), (32, rule.dst)] {
+        let (addr, len) = prefix.map_or((0, 0), |prefix| prefix.mapped());
+        let mask = mask(len);
+        for (i, word) in [addr >> 64, addr, mask >> 64, mask].into_iter().enumerate() {
+            let at = offset + 8 * i;
+            entry[at..at + 8].copy_from_slice(&(word as u64).to_le_bytes());
+        }
+    }
+    for (offset, ports) in [(64, rule.src_ports), (68, rule.dst_ports)] {
+        let (first, last) = ports.unwrap_or((0, u16::MAX));
+        entry[offset..offset + 2].copy_from_slice(&first.to_le_bytes());
+        entry[offset + 2..offset + 4].copy_from_slice(&last.to_le_bytes());
+    }
+    entry[72] = rule.protocol.unwrap_or(0);
+    entry[73] = rule.action.filter_result();
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use bpf_element::filter::FilterResult;
+    use bpf_element::firewall::{Fields, Prefix as RulePrefix, Rule as TableRule};
+
+    use super::*;
+
+    const RULES: &str = "
+        default deny
+        # priority  action  protocol  source      destination     source port  destination port
+        30          allow   any       192.0.2.1   any             any          any
+        10          allow   tcp       10.0.0.0/8  any             any          22
+        20          deny    udp       any         2001:db8::/32   any          1000-1999
+    ";
+
+    /// The error of parsing `rules`, with its context.
+    fn error(rules: &str) -> String {
+        format!("{:#}", parse(rules).unwrap_err())
+    }
+
+    fn rule(rule: &str) -> anyhow::Result<RuleSet> {
+        parse(&format!("default deny\n{rule}"))
+    }
+
+    fn addr(addr: IpAddr) -> [u64; 2] {
+        let bits = match addr {
+            IpAddr::V4(addr) => addr.to_ipv6_mapped().to_bits(),
+            IpAddr::V6(addr) => addr.to_bits(),
+        };
+        [(bits >> 64) as u64, bits as u64]
+    }
+
+    fn fields(src: IpAddr, dst: IpAddr, protocol: u8, ports: Option<(u16, u16)>) -> Fields {
+        Fields {
+            src: addr(src),
+            dst: addr(dst),
+            protocol,
+            ports,
+        }
+    }
+
+    /// What the `firewall` program decides for `fields` with `table` as its `RULE_TABLE`.
+    fn verdict(table: &[[u8; RULE_SIZE]], default: Action, fields: &Fields) -> u8 {
+        for entry in table {
+            let rule = decode(entry);
+            if rule.action == 0 {
+                break;
+            }
+            if rule.matches(fields) {
+                return rule.action;
+            }
+        }
+        default.filter_result()
+    }
+
+    fn decode(entry: &[u8; RULE_SIZE]) -> TableRule {
+        assert_eq!(std::mem::size_of::<TableRule>(), RULE_SIZE);
+        unsafe { std::ptr::read_unaligned(entry.as_ptr().cast()) }
+    }
+
+    #[test]
+    fn parses_rules_by_priority() {
+        let rules = parse(RULES).unwrap();
+        assert_eq!(rules.default, Action::Deny);
+        let priorities: Vec<_> = rules.rules.iter().map(|rule| rule.priority).collect();
+        assert_eq!(priorities, [10, 20, 30]);
+        assert_eq!(
+            rules.rules[0].to_string(),
+            "10 allow tcp 10.0.0.0/8 any any 22"
+        );
+        assert_eq!(rules.rules[0].line, 5);
+        assert_eq!(rules.rules[1].dst_ports, Some((1000, 1999)));
+    }
+
+    #[test]
+    fn reports_invalid_rule_files() {
+        assert!(error("10 allow tcp any any any 22").contains("no default action"));
+        assert!(error("default deny\ndefault allow").contains("line 2: second default action"));
+        assert!(error("default deny\n10 allow tcp any any any").contains("line 2: expected"));
+        let taken = error("default deny\n10 allow tcp any any any 22\n10 deny any any any any any");
+        assert!(taken.contains("line 3: priority 10 is taken by line 2"));
+
+        let errors = [
+            ("x allow tcp any any any 22", "invalid priority `x`"),
+            ("10 reject tcp any any any 22", "invalid action `reject`"),
+            ("10 allow 0 any any any any", "invalid protocol `0`"),
+            (
+                "10 allow tcp 10.0.0.0/33 any any any",
+                "invalid prefix length in `10.0.0.0/33`",
+            ),
+            (
+                "10 allow tcp 10.1.0.0/8 any any any",
+                "did you mean 10.0.0.0/8?",
+            ),
+            (
+                "10 allow tcp 10.0.0.256 any any any",
+                "invalid address `10.0.0.256`",
+            ),
+            (
+                "10 allow icmp any any any 22",
+                "only tcp and udp have ports",
+            ),
+            ("10 allow tcp any any any 80-22", "empty port range `80-22`"),
+            (
+                "10 allow tcp 10.0.0.0/8 2001:db8::/32 any any",
+                "different address families",
+            ),
+        ];
+        for (line, message) in errors {
+            let error = format!("{:#}", rule(line).unwrap_err());
+            assert!(error.contains(message), "{line}: {error}");
+        }
+    }
+
+    #[test]
+    fn refuses_ipv6_prefixes_overlapping_ipv4() {
+        // containing all IPv4-mapped addresses
+        for prefix in ["::/0", "::/1", "::ffff:0:0/96"] {
+            let error = format!(
+                "{:#}",
+                rule(&format!("10 deny any {prefix} any any any")).unwrap_err()
+            );
+            assert!(
+                error.contains("would match IPv4 packets too"),
+                "{prefix}: {error}"
+            );
+        }
+        // inside them
+        for prefix in ["::ffff:10.0.0.0/104", "::ffff:192.0.2.1"] {
+            let error = format!(
+                "{:#}",
+                rule(&format!("10 deny any {prefix} any any any")).unwrap_err()
+            );
+            assert!(
+                error.contains("only matches IPv4 packets"),
+                "{prefix}: {error}"
+            );
+        }
+        for prefix in ["2001:db8::/32", "::fffe:0:0/96", "::1"] {
+            assert!(
+                rule(&format!("10 deny any {prefix} any any any")).is_ok(),
+                "{prefix}"
+            );
+        }
+    }
+
+    #[test]
+    fn table_entries_are_firewall_rules() {
+        let rules = parse(RULES).unwrap();
+        let table = rules.table(4).unwrap();
+        assert_eq!(table.len(), 4);
+        let expected = [
+            TableRule::new(FilterResult::Pass)
+                .protocol(IPPROTO_TCP)
+                .src(RulePrefix::v4([10, 0, 0, 0], 8))
+                .dst_ports(22, 22),
+            TableRule::new(FilterResult::Drop)
+                .protocol(IPPROTO_UDP)
+                .dst(RulePrefix::v6(
+                    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0).octets(),
+                    32,
+                ))
+                .dst_ports(1000, 1999),
+            TableRule::new(FilterResult::Pass).src(RulePrefix::v4([192, 0, 2, 1], 32)),
+        ];
+        for (entry, expected) in table.iter().zip(expected) {
+            assert_eq!(decode(entry), expected);
+        }
+        assert_eq!(decode(&table[3]).action, 0);
+    }
+
+    #[test]
+    fn table_gives_the_verdicts_of_the_rules() {
+        let rules = parse(RULES).unwrap();
+        let table = rules.table(16).unwrap();
+        let v4 = |addr: [u8; 4]| IpAddr::V4(addr.into());
+        let v6 = |addr: &str| IpAddr::V6(addr.parse().unwrap());
+        let (allow, deny) = (Action::Allow.filter_result(), Action::Deny.filter_result());
+
+        let verdicts = [
+            (
+                fields(
+                    v4([10, 1, 2, 3]),
+                    v4([198, 51, 100, 1]),
+                    IPPROTO_TCP,
+                    Some((40000, 22)),
+                ),
+                allow,
+            ),
+            (
+                fields(
+                    v4([10, 1, 2, 3]),
+                    v4([198, 51, 100, 1]),
+                    IPPROTO_TCP,
+                    Some((40000, 23)),
+                ),
+                deny,
+            ),
+            (
+                fields(
+                    v4([11, 1, 2, 3]),
+                    v4([198, 51, 100, 1]),
+                    IPPROTO_TCP,
+                    Some((40000, 22)),
+                ),
+                deny,
+            ),
+            (
+                fields(v6("::1"), v6("2001:db8::5"), IPPROTO_UDP, Some((53, 1500))),
+                deny,
+            ),
+            // IPv4 prefixes don't match IPv6 packets
+            (
+                fields(v6("2001:db8::1"), v6("::1"), IPPROTO_TCP, Some((40000, 22))),
+                deny,
+            ),
+            (
+                fields(v4([192, 0, 2, 1]), v4([10, 0, 0, 1]), IPPROTO_ICMP, None),
+                allow,
+            ),
+            (
+                fields(v4([192, 0, 2, 2]), v4([10, 0, 0, 1]), IPPROTO_ICMP, None),
+                deny,
+            ),
+        ];
+        for (fields, expected) in verdicts {
+            assert_eq!(
+                verdict(&table, rules.default, &fields),
+                expected,
+                "{fields:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_tables_too_small_for_the_rules() {
+        let rules = parse(RULES).unwrap();
+        assert_eq!(rules.table(3).unwrap().len(), 3);
+        assert!(rules.table(2).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! The helper table of `BPFElement::init_ubpf_vm` and `BPFRewriter::register_additional_bpf_helpers`
+//! (keep in sync with `ebpf/src/helpers.rs`).
+
+use crate::vm::Element;
+
+pub const MAP_LOOKUP_ELEM: i32 = 1;
+pub const MAP_UPDATE_ELEM: i32 = 2;
+pub const MAP_DELETE_ELEM: i32 = 3;
+pub const KTIME_GET_NS: i32 = 5;
+pub const TRACE_PRINTK: i32 = 6;
+pub const GET_PRANDOM_U32: i32 = 7;
+pub const TAIL_CALL: i32 = 12;
+pub const UNWIND: i32 = 20;
+pub const PACKET_ADD_SPACE: i32 = 60;
+
+const TABLE: &[(i32, &str)] = &[
+    (MAP_LOOKUP_ELEM, "bpf_map_lookup_elem"),
+    (MAP_UPDATE_ELEM, "bpf_map_update_elem"),
+    (MAP_DELETE_ELEM, "bpf_map_delete_elem"),
+    (KTIME_GET_NS, "bpf_ktime_get_ns"),
+    (TRACE_PRINTK, "bpf_trace_printk"),
+    (GET_PRANDOM_U32, "bpf_get_prandom_u32"),
+    (TAIL_CALL, "bpf_tail_call"),
+    (UNWIND, "unwind"),
+    (PACKET_ADD_SPACE, "bpf_packet_add_space"),
+];
+
+/// ID of a helper called by name, see `ubpf_lookup_registered_function`.
+pub fn id_by_name(name: &str) -> Option<i32> {
+    TABLE.iter().find(|(_, n)| *n == name).map(|(id, _)| *id)
+}
+
+pub fn name(id: i32) -> Option<&'static str> {
+    TABLE.iter().find(|(i, _)| *i == id).map(|(_, name)| *name)
+}
+
+/// Whether the element registers helper `id`.
+pub fn registered(element: Element, id: i32) -> bool {
+    match id {
+        PACKET_ADD_SPACE => element == Element::Rewriter,
+        _ => name(id).is_some(),
+    }
+}
+
+/// Formats like `bpf_trace_printk`, which passes the arguments to `printf` as `int`s.
+///
+/// `arg` fetches the `n`th argument. Conversions other than `d`, `i`, `u`, `x` and `%` are copied
+/// as they are.
+pub fn format_trace(fmt: &[u8], mut arg: impl FnMut(usize) -> Option<i32>) -> String {
+    let fmt = match fmt.iter().position(|&b| b == 0) {
+        Some(end) => &fmt[..end],
+        None => fmt,
+    };
+    let fmt = String::from_utf8_lossy(fmt);
+
+    let mut out = String::new();
+    let mut next = 0;
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let conversion = chars.next();
+        let mut value = || {
+            let value = arg(next);
+            next += 1;
+            value
+        };
+        match conversion {
+            Some('%') => out.push('%'),
+            Some('d' | 'i') => push_arg(&mut out, value().map(|v| v.to_string())),
+            Some('u') => push_arg(&mut out, value().map(|v| (v as u32).to_string())),
+            Some('x') => push_arg(&mut out, value().map(|v| format!("{:x}", v as u32))),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out.trim_end_matches('\n').to_string()
+}
+
+fn push_arg(out: &mut String, value: Option<String>) {
+    out.push_str(value.as_deref().unwrap_or("<bad pointer>"));
+}
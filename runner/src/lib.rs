@@ -0,0 +1,12 @@
+//! Runs `bpffilter` programs on the host, the way a MorphOS `BPFElement` runs them.
+//!
+//! [`elf::Program`] loads and links a program like uBPF's ELF loader does, [`vm::Vm`] interprets it
+//! with MorphOS' helpers and maps, and [`pcap`] feeds it packets. [`firewall`] compiles the rule
+//! files of `morphos-firewall`.
+
+pub mod elf;
+pub mod firewall;
+pub mod helpers;
+pub mod maps;
+pub mod pcap;
+pub mod vm;
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::env::args;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use morphos_run::elf::Program;
+use morphos_run::maps::UpdateError;
+use morphos_run::pcap;
+use morphos_run::vm::{Element, Vm};
+
+const USAGE: &str = "\
+usage: morphos-run [options] <program> <input.pcap>
+
+Runs a bpffilter program on every packet of a pcap, like a MorphOS BPF element would.
+
+  <program>                 ELF file, or the name of a program in benchmark/bpfilters
+
+options:
+  -e, --element <element>   filter (default), classifier or rewriter
+  -p, --port <n>            input port the packets arrive on (default: 0)
+  -o, --output <file.pcap>  write the packets that leave the element
+  -l, --output-link <link>  link type of the output, ethernet or raw (IP), e.g. after
+                            strip-ether-vlan-header (default: the input's)
+  -u, --update <map>:<key>=<value>
+                            write a map entry before the first packet, key and value in hex,
+                            e.g. CONFIG:00000000=3039 (may be repeated)
+  -t, --tail-call <map> <index> <program>
+                            load a program into a slot of a program array, like TAIL_CALL in
+                            the element's configuration (may be repeated)
+  -s, --seed <n>            seed of bpf_get_prandom_u32
+  -m, --dump-maps           print the maps' contents after the last packet
+  -q, --quiet               only print the summary
+";
+
+/// A map entry given with `--update`.
+struct Update {
+    map: String,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+/// A program given with `--tail-call`.
+struct TailCall {
+    map: String,
+    index: u32,
+    program: PathBuf,
+}
+
+/// Map and input port of bpf_element::migration, see `BPFElement::migrate_maps`.
+const SCHEMA_VERSION: &str = "SCHEMA_VERSION";
+const MIGRATE_PORT: u32 = u32::MAX;
+
+/// Where `make sync` puts the programs used by the benchmarks.
+const BPFILTERS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../benchmark/bpfilters");
+
+struct Options {
+    program: PathBuf,
+    input: PathBuf,
+    element: Element,
+    port: u32,
+    output: Option<PathBuf>,
+    output_link: Option<u32>,
+    updates: Vec<Update>,
+    tail_calls: Vec<TailCall>,
+    seed: Option<u32>,
+    dump_maps: bool,
+    quiet: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = parse_args()?;
+
+    let mut vm = Vm::new(load(&options.program)?, options.element)?;
+    if let Some(seed) = options.seed {
+        vm.set_prandom_seed(seed);
+    }
+    if vm.maps.iter().any(|map| map.name == SCHEMA_VERSION) {
+        // the element migrates the maps of programs with a schema before the first packet
+        match vm.run(&[], MIGRATE_PORT, 0).0 {
+            Ok(ret) if migrated(options.element, ret) => {}
+            Ok(ret) => bail!(
+                "migrating the maps failed: {}",
+                verdict(options.element, ret).0
+            ),
+            Err(fault) => bail!("migrating the maps failed: {fault}"),
+        }
+    }
+    for tail_call in &options.tail_calls {
+        vm.load_tail_call(&tail_call.map, tail_call.index, load(&tail_call.program)?)
+            .with_context(|| format!("couldn't load {}", tail_call.program.display()))?;
+    }
+    for update in &options.updates {
+        let map = vm
+            .maps
+            .iter_mut()
+            .find(|map| map.name == update.map)
+            .with_context(|| format!("no map {}", update.map))?;
+        if update.key.len() != map.def.key_size as usize
+            || update.value.len() != map.def.value_size as usize
+        {
+            bail!(
+                "map {} has {}-byte keys and {}-byte values",
+                map.name,
+                map.def.key_size,
+                map.def.value_size
+            );
+        }
+        match map.update(&update.key, &update.value) {
+            Ok(()) => {}
+            Err(UpdateError::Invalid) => bail!("index out of range of map {}", map.name),
+            Err(UpdateError::Full) => bail!("map {} is full", map.name),
+        }
+    }
+
+    let mut input = pcap::Reader::open(&options.input)?;
+    let mut output = match &options.output {
+        Some(path) => {
+            let link_type = options.output_link.unwrap_or(input.link_type());
+            Some(pcap::Writer::create(path, link_type, input.nanos())?)
+        }
+        None => None,
+    };
+
+    let mut verdicts = BTreeMap::<String, usize>::new();
+    let mut index = 0;
+    while let Some(record) = input.next_record()? {
+        index += 1;
+        let (result, trace) = vm.run(&record.data, options.port, record.timestamp_ns);
+        let (verdict, passed) = match result {
+            Ok(ret) => verdict(options.element, ret),
+            Err(fault) => (format!("error: {fault}"), false),
+        };
+
+        if !options.quiet {
+            println!("{index:>6} {:>5}B  {verdict}", record.data.len());
+            for line in &trace {
+                println!("{:>15}{line}", "trace: ");
+            }
+        }
+        if passed {
+            if let Some(output) = &mut output {
+                output.write(record.timestamp_ns, vm.packet())?;
+            }
+        }
+
+        let key = if verdict.starts_with("error") {
+            "error"
+        } else {
+            &verdict
+        };
+        *verdicts.entry(key.to_string()).or_default() += 1;
+    }
+
+    if let Some(output) = output {
+        output.finish()?;
+    }
+
+    println!("{index} packets");
+    for (verdict, count) in &verdicts {
+        println!("{count:>8} {verdict}");
+    }
+
+    if options.dump_maps {
+        dump_maps(&vm);
+    }
+
+    Ok(())
+}
+
+fn load(path: &Path) -> anyhow::Result<Program> {
+    let elf = fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    Program::load(&elf).with_context(|| format!("couldn't load {}", path.display()))
+}
+
+/// What the element does with the packet, and whether the packet leaves the element.
+fn verdict(element: Element, ret: u64) -> (String, bool) {
+    match element {
+        // XDP_* in bpfilter.cc
+        Element::Filter => match ret as u32 {
+            0 => ("abort".to_string(), false),
+            1 => ("drop".to_string(), false),
+            2 => ("pass".to_string(), true),
+            other => (format!("unsupported action {other}"), false),
+        },
+        // BPFCLASSIFIER_* in bpfclassifier.hh
+        Element::Classifier => match ret as u32 as i32 {
+            -1 => ("abort".to_string(), false),
+            -2 => ("drop".to_string(), false),
+            port => (format!("port {}", port as u32), true),
+        },
+        // REWRITER_* in bpfrewriter.cc
+        Element::Rewriter => match ret as u32 {
+            0 => ("abort".to_string(), false),
+            1 => ("success".to_string(), true),
+            2 => ("drop".to_string(), false),
+            other => (format!("unsupported result {other}"), false),
+        },
+    }
+}
+
+/// Whether the program's result of the migration call is the success verdict.
+fn migrated(element: Element, ret: u64) -> bool {
+    match element {
+        Element::Filter => ret as u32 == 2,
+        Element::Classifier => ret as u32 == 0,
+        Element::Rewriter => ret as u32 == 1,
+    }
+}
+
+fn dump_maps(vm: &Vm) {
+    for map in &vm.maps {
+        let entries = map.entries();
+        println!();
+        println!(
+            "map {} ({}, {} of {} entries)",
+            map.name,
+            map.def.type_name(),
+            entries.len(),
+            map.def.max_entries
+        );
+        for (key, value) in entries {
+            println!("  {} => {}", hex(&key), hex(value));
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_args() -> anyhow::Result<Options> {
+    let mut positional = Vec::new();
+    let mut element = Element::Filter;
+    let mut port = 0;
+    let mut output = None;
+    let mut output_link = None;
+    let mut updates = Vec::new();
+    let mut tail_calls = Vec::new();
+    let mut seed = None;
+    let mut dump_maps = false;
+    let mut quiet = false;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-e" | "--element" => {
+                element = match value()?.as_str() {
+                    "filter" => Element::Filter,
+                    "classifier" => Element::Classifier,
+                    "rewriter" => Element::Rewriter,
+                    other => {
+                        bail!("unknown element {other}, expected filter, classifier or rewriter")
+                    }
+                }
+            }
+            "-p" | "--port" => port = value()?.parse().context("invalid port")?,
+            "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+            "-l" | "--output-link" => {
+                output_link = Some(match value()?.as_str() {
+                    "ethernet" => pcap::LINKTYPE_ETHERNET,
+                    "raw" => pcap::LINKTYPE_RAW,
+                    other => bail!("unknown link type {other}, expected ethernet or raw"),
+                })
+            }
+            "-u" | "--update" => updates.push(parse_update(&value()?)?),
+            "-t" | "--tail-call" => tail_calls.push(TailCall {
+                map: value()?,
+                index: value()?.parse().context("invalid slot index")?,
+                program: find_program(&value()?),
+            }),
+            "-s" | "--seed" => seed = Some(value()?.parse().context("invalid seed")?),
+            "-m" | "--dump-maps" => dump_maps = true,
+            "-q" | "--quiet" => quiet = true,
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ if arg.starts_with('-') => bail!("unknown option {arg}\n\n{USAGE}"),
+            _ => positional.push(arg),
+        }
+    }
+
+    let [program, input] = <[String; 2]>::try_from(positional)
+        .map_err(|_| anyhow::anyhow!("expected a program and a pcap file\n\n{USAGE}"))?;
+
+    Ok(Options {
+        program: find_program(&program),
+        input: PathBuf::from(input),
+        element,
+        port,
+        output,
+        output_link,
+        updates,
+        tail_calls,
+        seed,
+        dump_maps,
+        quiet,
+    })
+}
+
+/// `<map>:<key>=<value>`, with key and value in hex.
+fn parse_update(update: &str) -> anyhow::Result<Update> {
+    let (map, entry) = update
+        .split_once(':')
+        .with_context(|| format!("expected <map>:<key>=<value>, got {update}"))?;
+    let (key, value) = entry
+        .split_once('=')
+        .with_context(|| format!("expected <map>:<key>=<value>, got {update}"))?;
+    Ok(Update {
+        map: map.to_string(),
+        key: parse_hex(key).with_context(|| format!("invalid key {key}"))?,
+        value: parse_hex(value).with_context(|| format!("invalid value {value}"))?,
+    })
+}
+
+fn parse_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            Ok(u8::from_str_radix(
+                hex.get(i..i + 2).context("not hex")?,
+                16,
+            )?)
+        })
+        .collect()
+}
+
+/// Paths are taken as they are, bare names are looked up in benchmark/bpfilters.
+fn find_program(program: &str) -> PathBuf {
+    let path = Path::new(program);
+    if path.exists() || program.contains('/') {
+        return path.to_path_buf();
+    }
+    Path::new(BPFILTERS_DIR).join(program)
+}
@@ -0,0 +1,365 @@
+//! Maps with the semantics of `libs/ubpf/helper/bpf_helpers.cc`.
+//!
+//! Like there, hash maps don't enforce `max_entries`, LRU hash maps evict the least recently used
+//! entry when a new key doesn't fit, LPM tries refuse new prefixes instead and find the longest
+//! stored prefix of a key, and overwriting a key keeps the address of its value. Array indices past
+//! `max_entries`, which MorphOS doesn't check, fail here and are reported by the
+//! [`crate::vm::Vm`]. Program arrays are arrays of zeros, the `Vm` keeps the programs loaded into
+//! their slots.
+
+use std::collections::BTreeMap;
+
+use crate::elf::{
+    MapDef, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_LPM_TRIE, BPF_MAP_TYPE_LRU_HASH,
+    BPF_MAP_TYPE_PROG_ARRAY,
+};
+
+enum Storage {
+    Hash {
+        /// Key to slot in `slots`.
+        entries: BTreeMap<Vec<u8>, usize>,
+        slots: Vec<Option<Vec<u8>>>,
+        free: Vec<usize>,
+        /// For LRU hash maps, the tick of each slot's last use, and the current tick.
+        lru: Option<(Vec<u64>, u64)>,
+        /// For LPM tries, whose keys are stored with the bits past their prefix length cleared.
+        lpm: bool,
+    },
+    Array(Vec<u8>),
+}
+
+/// Why [`Map::update`] refused a value.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UpdateError {
+    /// An array index out of range, or a prefix longer than an LPM trie's keys.
+    Invalid,
+    /// A new prefix for an LPM trie that holds `max_entries` already.
+    Full,
+}
+
+pub struct Map {
+    pub name: String,
+    pub def: MapDef,
+    storage: Storage,
+}
+
+impl Map {
+    pub fn new(name: &str, def: MapDef) -> Map {
+        let storage = match def.map_type {
+            BPF_MAP_TYPE_HASH | BPF_MAP_TYPE_LRU_HASH | BPF_MAP_TYPE_LPM_TRIE => Storage::Hash {
+                entries: BTreeMap::new(),
+                slots: Vec::new(),
+                free: Vec::new(),
+                lru: (def.map_type == BPF_MAP_TYPE_LRU_HASH).then(|| (Vec::new(), 0)),
+                lpm: def.map_type == BPF_MAP_TYPE_LPM_TRIE,
+            },
+            BPF_MAP_TYPE_ARRAY | BPF_MAP_TYPE_PROG_ARRAY => {
+                Storage::Array(vec![0; def.max_entries as usize * def.value_size as usize])
+            }
+            other => unreachable!("map type {other} is rejected while loading"),
+        };
+        Map {
+            name: name.to_string(),
+            def,
+            storage,
+        }
+    }
+
+    /// Distance between two values in the map's address range.
+    fn stride(&self) -> u64 {
+        match self.storage {
+            Storage::Hash { .. } => (self.def.value_size as u64).next_multiple_of(8).max(8),
+            Storage::Array(_) => self.def.value_size as u64,
+        }
+    }
+
+    /// Offset of the key's value in the map's address range. Counts as a use of LRU entries.
+    pub fn lookup(&mut self, key: &[u8]) -> Option<u64> {
+        let stride = self.stride();
+        match &mut self.storage {
+            Storage::Hash {
+                entries, lpm: true, ..
+            } => {
+                // the longest prefix first
+                let (len, data) = key.split_at(4);
+                let len = u32::from_le_bytes(len.try_into().unwrap()).min(8 * data.len() as u32);
+                let slot = (0..=len)
+                    .rev()
+                    .find_map(|len| entries.get(&prefix_key(len, data)))?;
+                Some(*slot as u64 * stride)
+            }
+            Storage::Hash { entries, lru, .. } => {
+                let slot = *entries.get(key)?;
+                if let Some((used, tick)) = lru {
+                    *tick += 1;
+                    used[slot] = *tick;
+                }
+                Some(slot as u64 * stride)
+            }
+            Storage::Array(_) => {
+                let index = u32::from_le_bytes(key.try_into().ok()?);
+                (index < self.def.max_entries).then(|| index as u64 * stride)
+            }
+        }
+    }
+
+    pub fn update(&mut self, key: &[u8], value: &[u8]) -> Result<(), UpdateError> {
+        let value_size = self.def.value_size as usize;
+        let max_entries = self.def.max_entries as usize;
+        match &mut self.storage {
+            Storage::Hash {
+                entries,
+                slots,
+                free,
+                lru,
+                lpm,
+            } => {
+                let normalized;
+                let key = match *lpm {
+                    true => match lpm_key(key) {
+                        Some(key) => {
+                            normalized = key;
+                            &normalized[..]
+                        }
+                        None => return Err(UpdateError::Invalid),
+                    },
+                    false => key,
+                };
+                let slot = match entries.get(key) {
+                    Some(&slot) => {
+                        slots[slot] = Some(value.to_vec());
+                        slot
+                    }
+                    None => {
+                        if *lpm && entries.len() >= max_entries {
+                            return Err(UpdateError::Full);
+                        }
+                        if let Some((used, _)) =
+                            lru.as_ref().filter(|_| entries.len() >= max_entries)
+                        {
+                            // evict the least recently used entry
+                            let oldest = entries.iter().min_by_key(|(_, &slot)| used[slot]);
+                            if let Some((oldest, &slot)) =
+                                oldest.map(|(key, slot)| (key.clone(), slot))
+                            {
+                                entries.remove(&oldest);
+                                slots[slot] = None;
+                                free.push(slot);
+                            }
+                        }
+                        let slot = match free.pop() {
+                            Some(slot) => {
+                                slots[slot] = Some(value.to_vec());
+                                slot
+                            }
+                            None => {
+                                slots.push(Some(value.to_vec()));
+                                slots.len() - 1
+                            }
+                        };
+                        entries.insert(key.to_vec(), slot);
+                        slot
+                    }
+                };
+                if let Some((used, tick)) = lru {
+                    used.resize(slots.len(), 0);
+                    *tick += 1;
+                    used[slot] = *tick;
+                }
+                Ok(())
+            }
+            Storage::Array(data) => {
+                let index = u32::from_le_bytes(key.try_into().unwrap()) as usize;
+                match data.get_mut(index * value_size..(index + 1) * value_size) {
+                    Some(slot) => {
+                        slot.copy_from_slice(value);
+                        Ok(())
+                    }
+                    None => Err(UpdateError::Invalid),
+                }
+            }
+        }
+    }
+
+    /// Array entries can't be deleted, as in MorphOS.
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        match &mut self.storage {
+            Storage::Hash {
+                entries,
+                slots,
+                free,
+                lpm,
+                ..
+            } => {
+                let key = match *lpm {
+                    true => lpm_key(key),
+                    false => Some(key.to_vec()),
+                };
+                match key.and_then(|key| entries.remove(&key)) {
+                    Some(slot) => {
+                        slots[slot] = None;
+                        free.push(slot);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Storage::Array(_) => false,
+        }
+    }
+
+    /// `len` bytes at `offset` in the map's address range, if they are within one value.
+    pub fn value_mut(&mut self, offset: u64, len: usize) -> Option<&mut [u8]> {
+        let stride = self.stride();
+        let value_size = self.def.value_size as u64;
+        if stride == 0 || (offset % stride) + len as u64 > value_size {
+            return None;
+        }
+        match &mut self.storage {
+            Storage::Hash { slots, .. } => {
+                let value = slots.get_mut((offset / stride) as usize)?.as_mut()?;
+                let start = (offset % stride) as usize;
+                value.get_mut(start..start + len)
+            }
+            Storage::Array(data) => data.get_mut(offset as usize..offset as usize + len),
+        }
+    }
+
+    /// Keys and values, in key order for hash maps. Arrays leave out all-zero entries.
+    pub fn entries(&self) -> Vec<(Vec<u8>, &[u8])> {
+        match &self.storage {
+            Storage::Hash { entries, slots, .. } => entries
+                .iter()
+                .map(|(key, &slot)| (key.clone(), slots[slot].as_deref().unwrap()))
+                .collect(),
+            Storage::Array(data) => data
+                .chunks(self.def.value_size.max(1) as usize)
+                .enumerate()
+                .filter(|(_, value)| value.iter().any(|&b| b != 0))
+                .map(|(index, value)| ((index as u32).to_le_bytes().to_vec(), value))
+                .collect(),
+        }
+    }
+}
+
+/// An LPM trie key as it's stored, `None` if its prefix is longer than its data.
+fn lpm_key(key: &[u8]) -> Option<Vec<u8>> {
+    let (len, data) = key.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap());
+    (len <= 8 * data.len() as u32).then(|| prefix_key(len, data))
+}
+
+/// LPM trie key of the first `len` bits of `data`.
+fn prefix_key(len: u32, data: &[u8]) -> Vec<u8> {
+    let mut key = len.to_le_bytes().to_vec();
+    key.extend(data.iter().enumerate().map(|(i, &byte)| {
+        let bits = len.saturating_sub(8 * i as u32).min(8);
+        byte & !(0xffu8.checked_shr(bits).unwrap_or(0))
+    }));
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(map_type: u32, key_size: u32, max_entries: u32) -> Map {
+        let def = MapDef {
+            map_type,
+            key_size,
+            value_size: 4,
+            max_entries,
+            map_flags: 0,
+        };
+        Map::new("TEST", def)
+    }
+
+    /// An LPM trie key of an IPv4 prefix.
+    fn prefix(addr: [u8; 4], len: u32) -> Vec<u8> {
+        let mut key = len.to_le_bytes().to_vec();
+        key.extend_from_slice(&addr);
+        key
+    }
+
+    #[test]
+    fn lpm_tries_refuse_new_prefixes_when_full() {
+        let mut trie = map(BPF_MAP_TYPE_LPM_TRIE, 8, 2);
+        assert_eq!(trie.update(&prefix([10, 0, 0, 0], 8), &[1; 4]), Ok(()));
+        assert_eq!(trie.update(&prefix([10, 1, 0, 0], 16), &[2; 4]), Ok(()));
+        assert_eq!(
+            trie.update(&prefix([10, 2, 0, 0], 16), &[3; 4]),
+            Err(UpdateError::Full)
+        );
+        // stored prefixes can still be overwritten, also through keys with host bits
+        assert_eq!(trie.update(&prefix([10, 1, 2, 3], 16), &[4; 4]), Ok(()));
+        assert_eq!(
+            trie.update(&prefix([10, 0, 0, 0], 33), &[5; 4]),
+            Err(UpdateError::Invalid)
+        );
+
+        assert!(trie.delete(&prefix([10, 0, 0, 0], 8)));
+        assert_eq!(trie.update(&prefix([10, 2, 0, 0], 16), &[3; 4]), Ok(()));
+        assert_eq!(trie.entries().len(), 2);
+    }
+
+    #[test]
+    fn lru_hash_maps_evict_the_least_recently_used_entry() {
+        let mut lru = map(BPF_MAP_TYPE_LRU_HASH, 4, 2);
+        assert_eq!(lru.update(&[1; 4], &[1; 4]), Ok(()));
+        assert_eq!(lru.update(&[2; 4], &[2; 4]), Ok(()));
+        // a lookup counts as a use
+        assert!(lru.lookup(&[1; 4]).is_some());
+        assert_eq!(lru.update(&[3; 4], &[3; 4]), Ok(()));
+        assert!(lru.lookup(&[2; 4]).is_none());
+        let keys: Vec<_> = lru.entries().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, [[1; 4], [3; 4]]);
+    }
+
+    #[test]
+    fn overwriting_keeps_the_address_of_values() {
+        let mut hash = map(BPF_MAP_TYPE_HASH, 4, 1);
+        assert_eq!(hash.update(&[1; 4], &[1; 4]), Ok(()));
+        let offset = hash.lookup(&[1; 4]).unwrap();
+        assert_eq!(hash.update(&[1; 4], &[2; 4]), Ok(()));
+        assert_eq!(hash.lookup(&[1; 4]), Some(offset));
+        assert_eq!(hash.value_mut(offset, 4).unwrap(), [2; 4]);
+        // hash maps don't enforce max_entries
+        assert_eq!(hash.update(&[2; 4], &[2; 4]), Ok(()));
+        // values are 8-byte aligned, and reads don't reach into the next one
+        assert!(hash.value_mut(offset + 2, 4).is_none());
+    }
+
+    #[test]
+    fn arrays_have_max_entries_zeroed_values() {
+        let mut array = map(BPF_MAP_TYPE_ARRAY, 4, 2);
+        assert_eq!(array.lookup(&1u32.to_le_bytes()), Some(4));
+        assert_eq!(array.lookup(&2u32.to_le_bytes()), None);
+        assert_eq!(
+            array.update(&2u32.to_le_bytes(), &[1; 4]),
+            Err(UpdateError::Invalid)
+        );
+        assert!(array.entries().is_empty());
+        assert_eq!(array.update(&1u32.to_le_bytes(), &[1; 4]), Ok(()));
+        assert_eq!(
+            array.entries(),
+            [(1u32.to_le_bytes().to_vec(), &[1u8; 4][..])]
+        );
+        assert!(!array.delete(&1u32.to_le_bytes()));
+    }
+
+    #[test]
+    fn lpm_tries_find_the_longest_prefix() {
+        let mut trie = map(BPF_MAP_TYPE_LPM_TRIE, 8, 8);
+        assert_eq!(trie.update(&prefix([10, 0, 0, 0], 8), &[1; 4]), Ok(()));
+        assert_eq!(trie.update(&prefix([10, 1, 0, 0], 16), &[2; 4]), Ok(()));
+        let value = |trie: &mut Map, key: Vec<u8>| {
+            let offset = trie.lookup(&key)?;
+            Some(trie.value_mut(offset, 4)?[0])
+        };
+        assert_eq!(value(&mut trie, prefix([10, 1, 2, 3], 32)), Some(2));
+        assert_eq!(value(&mut trie, prefix([10, 2, 2, 3], 32)), Some(1));
+        // only the key's first prefix length bits count
+        assert_eq!(value(&mut trie, prefix([10, 1, 2, 3], 12)), Some(1));
+        assert_eq!(value(&mut trie, prefix([11, 1, 2, 3], 32)), None);
+    }
+}
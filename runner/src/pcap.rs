@@ -0,0 +1,267 @@
+//! Classic libpcap files (not pcapng), as written by `tcpdump -w` and pktgen setups.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_MAGIC: u32 = 0x0a0d0d0a;
+
+/// Link type of packets that start with an Ethernet header.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// Link type of packets that start with an IPv4 or IPv6 header.
+pub const LINKTYPE_RAW: u32 = 101;
+
+pub struct Record {
+    /// Capture time in nanoseconds since the epoch.
+    pub timestamp_ns: u64,
+    pub data: Vec<u8>,
+}
+
+pub struct Reader {
+    input: BufReader<File>,
+    swapped: bool,
+    nanos: bool,
+    link_type: u32,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> anyhow::Result<Reader> {
+        let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+        let mut input = BufReader::new(file);
+
+        let mut header = [0u8; 24];
+        input
+            .read_exact(&mut header)
+            .context("pcap file too short")?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            PCAPNG_MAGIC => bail!(
+                "{} is a pcapng file, convert it with `editcap -F pcap`",
+                path.display()
+            ),
+            _ => bail!("{} is not a pcap file", path.display()),
+        };
+
+        let mut reader = Reader {
+            input,
+            swapped,
+            nanos,
+            link_type: 0,
+        };
+        reader.link_type = reader.u32(&header[20..24]);
+        Ok(reader)
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    pub fn nanos(&self) -> bool {
+        self.nanos
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let value = u32::from_le_bytes(bytes.try_into().unwrap());
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+
+    /// Reads the next packet, `None` at the end of the file.
+    pub fn next_record(&mut self) -> anyhow::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err).context("couldn't read pcap record"),
+        }
+        let seconds = self.u32(&header[0..4]) as u64;
+        let fraction = self.u32(&header[4..8]) as u64;
+        let captured = self.u32(&header[8..12]) as usize;
+
+        let mut data = vec![0u8; captured];
+        self.input
+            .read_exact(&mut data)
+            .context("truncated pcap record")?;
+
+        let fraction_ns = if self.nanos {
+            fraction
+        } else {
+            fraction * 1000
+        };
+        Ok(Some(Record {
+            timestamp_ns: seconds * 1_000_000_000 + fraction_ns,
+            data,
+        }))
+    }
+}
+
+pub struct Writer {
+    output: BufWriter<File>,
+    nanos: bool,
+}
+
+impl Writer {
+    pub fn create(path: &Path, link_type: u32, nanos: bool) -> anyhow::Result<Writer> {
+        let file =
+            File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+        let mut output = BufWriter::new(file);
+
+        let magic = if nanos { MAGIC_NANOS } else { MAGIC_MICROS };
+        output.write_all(&magic.to_le_bytes())?;
+        output.write_all(&2u16.to_le_bytes())?; // version 2.4
+        output.write_all(&4u16.to_le_bytes())?;
+        output.write_all(&0i32.to_le_bytes())?; // GMT offset
+        output.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+        output.write_all(&65535u32.to_le_bytes())?; // snap length
+        output.write_all(&link_type.to_le_bytes())?;
+
+        Ok(Writer { output, nanos })
+    }
+
+    pub fn write(&mut self, timestamp_ns: u64, data: &[u8]) -> anyhow::Result<()> {
+        let seconds = (timestamp_ns / 1_000_000_000) as u32;
+        let fraction_ns = (timestamp_ns % 1_000_000_000) as u32;
+        let fraction = if self.nanos {
+            fraction_ns
+        } else {
+            fraction_ns / 1000
+        };
+
+        self.output.write_all(&seconds.to_le_bytes())?;
+        self.output.write_all(&fraction.to_le_bytes())?;
+        self.output.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.output.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.output.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.output.flush().context("couldn't write pcap file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A file in the temporary directory that's removed again after the test.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let name = format!("morphos-run-{}-{name}.pcap", std::process::id());
+            TempFile(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn records(path: &Path) -> Vec<(u64, Vec<u8>)> {
+        let mut reader = Reader::open(path).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push((record.timestamp_ns, record.data));
+        }
+        records
+    }
+
+    #[test]
+    fn reads_what_it_wrote() {
+        for nanos in [false, true] {
+            let file = TempFile::new(&format!("round-trip-{nanos}"));
+            let mut writer = Writer::create(&file.0, LINKTYPE_RAW, nanos).unwrap();
+            writer
+                .write(1_700_000_000_123_456_789, &[0x45, 0, 0, 20])
+                .unwrap();
+            writer.write(1_700_000_001_000_000_000, &[]).unwrap();
+            writer.finish().unwrap();
+
+            let reader = Reader::open(&file.0).unwrap();
+            assert_eq!(reader.link_type(), LINKTYPE_RAW);
+            assert_eq!(reader.nanos(), nanos);
+            // microsecond files lose the nanoseconds
+            let first = if nanos {
+                1_700_000_000_123_456_789
+            } else {
+                1_700_000_000_123_456_000
+            };
+            assert_eq!(
+                records(&file.0),
+                [
+                    (first, vec![0x45, 0, 0, 20]),
+                    (1_700_000_001_000_000_000, vec![])
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn reads_big_endian_files() {
+        let file = TempFile::new("big-endian");
+        let mut bytes = Vec::new();
+        for field in [MAGIC_MICROS, 0x0002_0004, 0, 0, 65535, LINKTYPE_ETHERNET] {
+            bytes.extend_from_slice(&field.to_be_bytes());
+        }
+        for field in [2u32, 5, 3, 3] {
+            bytes.extend_from_slice(&field.to_be_bytes());
+        }
+        bytes.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(&file.0, bytes).unwrap();
+
+        assert_eq!(
+            Reader::open(&file.0).unwrap().link_type(),
+            LINKTYPE_ETHERNET
+        );
+        assert_eq!(records(&file.0), [(2_000_005_000, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let error = |name: &str, bytes: &[u8]| {
+            let file = TempFile::new(name);
+            std::fs::write(&file.0, bytes).unwrap();
+            let error = Reader::open(&file.0).err().unwrap().to_string();
+            error.replace(&file.0.display().to_string(), "FILE")
+        };
+        let mut pcapng = PCAPNG_MAGIC.to_le_bytes().to_vec();
+        pcapng.resize(24, 0);
+        assert_eq!(
+            error("pcapng", &pcapng),
+            "FILE is a pcapng file, convert it with `editcap -F pcap`"
+        );
+        assert_eq!(error("text", &[b'#'; 24]), "FILE is not a pcap file");
+        assert_eq!(error("short", &[0; 4]), "pcap file too short");
+    }
+
+    #[test]
+    fn reports_truncated_records() {
+        let file = TempFile::new("truncated");
+        let mut writer = Writer::create(&file.0, LINKTYPE_RAW, false).unwrap();
+        writer.write(0, &[1, 2, 3, 4]).unwrap();
+        writer.finish().unwrap();
+        let mut bytes = std::fs::read(&file.0).unwrap();
+        bytes.pop();
+        std::fs::write(&file.0, bytes).unwrap();
+
+        let mut reader = Reader::open(&file.0).unwrap();
+        let error = reader.next_record().err().unwrap();
+        assert_eq!(error.to_string(), "truncated pcap record");
+    }
+}
@@ -0,0 +1,1172 @@
+//! Interpreter for linked programs, with the memory and helpers a `BPFElement` gives them.
+//!
+//! Programs see a virtual address space instead of host memory, so a program that reads past its
+//! packet or a map value faults instead of reading whatever lies there:
+//!
+//! | address                     | contents                                  |
+//! |-----------------------------|-------------------------------------------|
+//! | `0x1000_0000`               | `bpfelement_md` (`data`, `data_end`, `port`) |
+//! | `0x2000_0000`               | stack, `r10` starts at its end            |
+//! | `0x3000_0000`               | packet buffer, only `data..data_end` is accessible |
+//! | `0x4000_0000 + i * 16 MiB`  | handle of map `i`, only passed to helpers |
+//! | `0x5000_0000 + i * 16 MiB`  | copy of data section `i`                  |
+//! | `0x1_0000_0000 + i << 32`   | values of map `i`                         |
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Context};
+
+use crate::elf::{
+    DataSection, Insn, Program, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_PROG_ARRAY, OP_CALL, OP_LDDW,
+};
+use crate::helpers::{self, format_trace};
+use crate::maps::{Map, UpdateError};
+
+/// `UBPF_EBPF_STACK_SIZE`. Every local function call gets a frame of this size.
+pub const STACK_SIZE: usize = 512;
+/// `UBPF_MAX_CALL_DEPTH`.
+const MAX_CALL_DEPTH: usize = 10;
+/// `BPF_MAX_TAIL_CALLS`, per packet.
+const MAX_TAIL_CALLS: u32 = 33;
+/// Catches endless loops. uBPF doesn't limit instructions, the verifier rejects such programs.
+const INSTRUCTION_LIMIT: u64 = 100_000_000;
+
+/// Room in front of the packet for `bpf_packet_add_space` before the buffer has to grow.
+const HEADROOM: usize = 64;
+
+const CTX_ADDR: u64 = 0x1000_0000;
+const CTX_SIZE: usize = 24;
+const STACK_ADDR: u64 = 0x2000_0000;
+const PACKET_ADDR: u64 = 0x3000_0000;
+const MAP_ADDR: u64 = 0x4000_0000;
+const DATA_ADDR: u64 = 0x5000_0000;
+const REGION: u64 = 0x100_0000;
+const VALUE_ADDR: u64 = 0x1_0000_0000;
+
+/// Address the program gets for map `slot`.
+pub fn map_handle(slot: usize) -> u64 {
+    MAP_ADDR + slot as u64 * REGION
+}
+
+/// Address of data section `slot`.
+pub fn data_addr(slot: usize) -> u64 {
+    DATA_ADDR + slot as u64 * REGION
+}
+
+fn value_addr(map: usize, offset: u64) -> u64 {
+    VALUE_ADDR + ((map as u64) << 32) + offset
+}
+
+/// The Click element a program is written for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Element {
+    Filter,
+    Classifier,
+    Rewriter,
+}
+
+/// A program run that MorphOS would have crashed on, or that uBPF would have stopped.
+#[derive(Debug)]
+pub struct Fault {
+    pub pc: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (PC {})", self.message, self.pc)
+    }
+}
+
+struct Frame {
+    return_pc: usize,
+    saved: [u64; 4],
+}
+
+struct PacketBuffer {
+    buf: Vec<u8>,
+    head: usize,
+    len: usize,
+}
+
+/// `bpf_map_update_elem`'s error for invalid keys.
+const EINVAL: i64 = 22;
+/// `bpf_map_update_elem`'s error for new prefixes in full LPM tries.
+const ENOSPC: i64 = 28;
+/// `bpf_tail_call`'s error for empty slots.
+const ENOENT: i64 = 2;
+/// `bpf_tail_call`'s error after [`MAX_TAIL_CALLS`].
+const E2BIG: i64 = 7;
+
+/// The main program, or one loaded into a program array slot, with its own data sections.
+struct Code {
+    insts: Vec<Insn>,
+    /// Index in [`Vm::maps`] of each of the program's map handles.
+    maps: Vec<usize>,
+    data: Vec<DataSection>,
+}
+
+pub struct Vm {
+    element: Element,
+    /// The main program first.
+    programs: Vec<Code>,
+    /// The program running.
+    current: usize,
+    pub maps: Vec<Map>,
+    /// Program of each filled slot, by program array and index.
+    slots: BTreeMap<(usize, u32), usize>,
+    /// `bpf_pending_tail_call` and `bpf_tail_call_count`.
+    pending_tail_call: Option<usize>,
+    tail_calls: u32,
+    stack: Vec<u8>,
+    ctx: [u8; CTX_SIZE],
+    packet: PacketBuffer,
+    time_ns: u64,
+    prandom: u32,
+    trace: Vec<String>,
+}
+
+impl Vm {
+    /// Rejects programs `ubpf_load` would reject.
+    pub fn new(program: Program, element: Element) -> anyhow::Result<Vm> {
+        check(&program.insts, element)?;
+        let maps: Vec<Map> = program
+            .maps
+            .iter()
+            .map(|map| Map::new(&map.name, map.def))
+            .collect();
+
+        Ok(Vm {
+            element,
+            programs: vec![Code {
+                insts: program.insts,
+                maps: (0..maps.len()).collect(),
+                data: program.data,
+            }],
+            current: 0,
+            maps,
+            slots: BTreeMap::new(),
+            pending_tail_call: None,
+            tail_calls: 0,
+            stack: vec![0; STACK_SIZE * (MAX_CALL_DEPTH + 1)],
+            ctx: [0; CTX_SIZE],
+            packet: PacketBuffer {
+                buf: Vec::new(),
+                head: 0,
+                len: 0,
+            },
+            time_ns: 0,
+            prandom: 0x2545_f491,
+            trace: Vec::new(),
+        })
+    }
+
+    /// Loads `program` into slot `index` of the program array `map`, as `TAIL_CALL` does in the
+    /// element's configuration. The program uses the maps of the same name, and adds the others.
+    pub fn load_tail_call(
+        &mut self,
+        map: &str,
+        index: u32,
+        program: Program,
+    ) -> anyhow::Result<()> {
+        let array = self
+            .maps
+            .iter()
+            .position(|m| m.name == map && m.def.map_type == BPF_MAP_TYPE_PROG_ARRAY)
+            .with_context(|| format!("the program has no program array {map}"))?;
+        if index >= self.maps[array].def.max_entries {
+            bail!("program array {map} has no slot {index}");
+        }
+        check(&program.insts, self.element)?;
+        for symbol in &program.maps {
+            if let Some(map) = self.maps.iter().find(|map| map.name == symbol.name) {
+                if !symbol.def.compatible(&map.def) {
+                    bail!(
+                        "map {} already exists with a different definition",
+                        symbol.name
+                    );
+                }
+            }
+        }
+
+        let mut maps = Vec::new();
+        for symbol in &program.maps {
+            let slot = match self.maps.iter().position(|map| map.name == symbol.name) {
+                Some(slot) => slot,
+                None => {
+                    self.maps.push(Map::new(&symbol.name, symbol.def));
+                    self.maps.len() - 1
+                }
+            };
+            maps.push(slot);
+        }
+        self.programs.push(Code {
+            insts: program.insts,
+            maps,
+            data: program.data,
+        });
+        self.slots.insert((array, index), self.programs.len() - 1);
+        Ok(())
+    }
+
+    pub fn set_prandom_seed(&mut self, seed: u32) {
+        // xorshift gets stuck at 0
+        self.prandom = seed.max(1);
+    }
+
+    /// Runs the program on one packet arriving on `port` at `time_ns`, which is what
+    /// `bpf_ktime_get_ns` returns, and the programs it tail calls after it, as
+    /// `BPFElement::exec` does. Also returns the lines the programs traced.
+    pub fn run(
+        &mut self,
+        data: &[u8],
+        port: u32,
+        time_ns: u64,
+    ) -> (Result<u64, Fault>, Vec<String>) {
+        let mut buf = vec![0; HEADROOM + data.len()];
+        buf[HEADROOM..].copy_from_slice(data);
+        self.packet = PacketBuffer {
+            buf,
+            head: HEADROOM,
+            len: data.len(),
+        };
+        self.ctx = [0; CTX_SIZE];
+        self.ctx[16..20].copy_from_slice(&port.to_le_bytes());
+        self.update_ctx();
+        self.time_ns = time_ns;
+        self.current = 0;
+        self.pending_tail_call = None;
+        self.tail_calls = 0;
+
+        let mut result = self.execute();
+        // a program that tail called stopped with 0, the program it jumped to has the last word
+        while result.is_ok() {
+            let Some(program) = self.pending_tail_call.take() else {
+                break;
+            };
+            self.current = program;
+            result = self.execute();
+        }
+        (result, std::mem::take(&mut self.trace))
+    }
+
+    /// The packet as the element passes it on, after `bpf_packet_add_space`.
+    pub fn packet(&self) -> &[u8] {
+        &self.packet.buf[self.packet.head..self.packet.head + self.packet.len]
+    }
+
+    fn update_ctx(&mut self) {
+        let data = PACKET_ADDR + self.packet.head as u64;
+        self.ctx[0..8].copy_from_slice(&data.to_le_bytes());
+        self.ctx[8..16].copy_from_slice(&(data + self.packet.len as u64).to_le_bytes());
+    }
+
+    fn memory(&mut self, addr: u64, len: usize, write: bool) -> Option<&mut [u8]> {
+        addr.checked_add(len as u64)?;
+        if addr >= VALUE_ADDR {
+            let map = ((addr - VALUE_ADDR) >> 32) as usize;
+            return self.maps.get_mut(map)?.value_mut(addr & 0xffff_ffff, len);
+        }
+
+        let base = addr & !(REGION - 1);
+        let offset = (addr - base) as usize;
+        match base {
+            CTX_ADDR => self.ctx.get_mut(offset..offset + len),
+            STACK_ADDR => self.stack.get_mut(offset..offset + len),
+            PACKET_ADDR => {
+                let PacketBuffer {
+                    buf,
+                    head,
+                    len: packet_len,
+                } = &mut self.packet;
+                if offset < *head || offset + len > *head + *packet_len {
+                    return None;
+                }
+                buf.get_mut(offset..offset + len)
+            }
+            _ if base >= DATA_ADDR => {
+                let data = &mut self.programs[self.current].data;
+                let section = data.get_mut(((base - DATA_ADDR) / REGION) as usize)?;
+                if write && !section.writable {
+                    return None;
+                }
+                section.data.get_mut(offset..offset + len)
+            }
+            _ => None,
+        }
+    }
+
+    fn read(&mut self, pc: usize, addr: u64, len: usize) -> Result<Vec<u8>, Fault> {
+        match self.memory(addr, len, false) {
+            Some(bytes) => Ok(bytes.to_vec()),
+            None => Err(Fault {
+                pc,
+                message: format!("invalid read of {len} bytes at {addr:#x}"),
+            }),
+        }
+    }
+
+    fn load(&mut self, pc: usize, addr: u64, size: usize) -> Result<u64, Fault> {
+        let bytes = self.read(pc, addr, size)?;
+        let mut value = [0u8; 8];
+        value[..size].copy_from_slice(&bytes);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn store(&mut self, pc: usize, addr: u64, size: usize, value: u64) -> Result<(), Fault> {
+        match self.memory(addr, size, true) {
+            Some(bytes) => {
+                bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+                Ok(())
+            }
+            None => Err(Fault {
+                pc,
+                message: format!("invalid write of {size} bytes at {addr:#x}"),
+            }),
+        }
+    }
+
+    fn execute(&mut self) -> Result<u64, Fault> {
+        self.stack.fill(0);
+        let mut reg = [0u64; 11];
+        reg[1] = CTX_ADDR;
+        reg[2] = CTX_SIZE as u64;
+        reg[10] = STACK_ADDR + self.stack.len() as u64;
+
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut pc = 0usize;
+        let mut executed = 0u64;
+
+        loop {
+            let cur = pc;
+            let fault = |message: String| Fault { pc: cur, message };
+            let insn = *self.programs[self.current]
+                .insts
+                .get(pc)
+                .ok_or_else(|| fault("jumped out of the program".to_string()))?;
+            executed += 1;
+            if executed > INSTRUCTION_LIMIT {
+                return Err(fault(format!(
+                    "executed more than {INSTRUCTION_LIMIT} instructions"
+                )));
+            }
+            pc += 1;
+
+            let (dst, src) = (insn.dst as usize, insn.src as usize);
+            if dst > 10 || src > 10 {
+                return Err(fault(format!(
+                    "invalid register in opcode {:#04x}",
+                    insn.opcode
+                )));
+            }
+            let class = insn.opcode & 0x07;
+            let uses_reg = insn.opcode & 0x08 != 0;
+            let size = match insn.opcode & 0x18 {
+                0x00 => 4,
+                0x08 => 2,
+                0x10 => 1,
+                _ => 8,
+            };
+
+            match class {
+                // ALU, ALU64
+                0x04 | 0x07 => {
+                    if dst == 10 {
+                        return Err(fault("write to r10".to_string()));
+                    }
+                    let operand = if uses_reg {
+                        reg[src]
+                    } else {
+                        insn.imm as i64 as u64
+                    };
+                    reg[dst] = alu(class == 0x07, insn, reg[dst], operand)
+                        .ok_or_else(|| fault(format!("unknown opcode {:#04x}", insn.opcode)))?;
+                }
+                // JMP, JMP32
+                0x05 | 0x06 => match insn.opcode & 0xf0 {
+                    0x00 => {
+                        let offset = if class == 0x06 {
+                            insn.imm as i64
+                        } else {
+                            insn.off as i64
+                        };
+                        pc = jump(pc, offset)
+                            .ok_or_else(|| fault("jumped out of the program".to_string()))?;
+                    }
+                    0x80 if class == 0x05 => match insn.src {
+                        0 => {
+                            reg[0] = self.helper(cur, insn.imm, &reg)?;
+                            if insn.imm == helpers::UNWIND && reg[0] == 0 {
+                                return Ok(0);
+                            }
+                        }
+                        1 => {
+                            if frames.len() >= MAX_CALL_DEPTH {
+                                return Err(fault(format!(
+                                    "number of nested functions calls exceeds max ({MAX_CALL_DEPTH})"
+                                )));
+                            }
+                            frames.push(Frame {
+                                return_pc: pc,
+                                saved: [reg[6], reg[7], reg[8], reg[9]],
+                            });
+                            reg[10] -= STACK_SIZE as u64;
+                            pc = jump(pc, insn.imm as i64)
+                                .ok_or_else(|| fault("call out of the program".to_string()))?;
+                        }
+                        _ => return Err(fault("calls by BTF ID are not supported".to_string())),
+                    },
+                    0x90 if class == 0x05 => match frames.pop() {
+                        Some(frame) => {
+                            reg[6..10].copy_from_slice(&frame.saved);
+                            reg[10] += STACK_SIZE as u64;
+                            pc = frame.return_pc;
+                        }
+                        None => return Ok(reg[0]),
+                    },
+                    op => {
+                        let operand = if uses_reg {
+                            reg[src]
+                        } else {
+                            insn.imm as i64 as u64
+                        };
+                        let taken = if class == 0x06 {
+                            compare32(op, reg[dst] as u32, operand as u32)
+                        } else {
+                            compare(op, reg[dst], operand)
+                        }
+                        .ok_or_else(|| fault(format!("unknown opcode {:#04x}", insn.opcode)))?;
+                        if taken {
+                            pc = jump(pc, insn.off as i64)
+                                .ok_or_else(|| fault("jumped out of the program".to_string()))?;
+                        }
+                    }
+                },
+                // LD
+                0x00 if insn.opcode == OP_LDDW => {
+                    let next = self.programs[self.current].insts[pc];
+                    reg[dst] = insn.imm as u32 as u64 | (next.imm as u32 as u64) << 32;
+                    pc += 1;
+                }
+                // LDX
+                0x01 => {
+                    let addr = reg[src].wrapping_add_signed(insn.off as i64);
+                    let value = self.load(cur, addr, size)?;
+                    reg[dst] = match insn.opcode & 0xe0 {
+                        0x60 => value,
+                        // sign-extending load
+                        0x80 => sign_extend(value, size),
+                        _ => return Err(fault(format!("unknown opcode {:#04x}", insn.opcode))),
+                    };
+                }
+                // ST
+                0x02 if insn.opcode & 0xe0 == 0x60 => {
+                    let addr = reg[dst].wrapping_add_signed(insn.off as i64);
+                    self.store(cur, addr, size, insn.imm as i64 as u64)?;
+                }
+                // STX
+                0x03 => {
+                    let addr = reg[dst].wrapping_add_signed(insn.off as i64);
+                    match insn.opcode & 0xe0 {
+                        0x60 => self.store(cur, addr, size, reg[src])?,
+                        0xc0 if size >= 4 => {
+                            let old = self.load(cur, addr, size)?;
+                            let mask = if size == 4 { u32::MAX as u64 } else { u64::MAX };
+                            let operand = reg[src] & mask;
+                            let (new, fetch) = match insn.imm & !0x01 {
+                                0x00 => (old.wrapping_add(operand), insn.imm & 0x01 != 0),
+                                0x40 => (old | operand, insn.imm & 0x01 != 0),
+                                0x50 => (old & operand, insn.imm & 0x01 != 0),
+                                0xa0 => (old ^ operand, insn.imm & 0x01 != 0),
+                                // xchg
+                                0xe0 => (operand, true),
+                                // cmpxchg, returns the old value in r0
+                                0xf0 => {
+                                    let new = if old == reg[0] & mask { operand } else { old };
+                                    self.store(cur, addr, size, new)?;
+                                    reg[0] = old;
+                                    continue;
+                                }
+                                _ => {
+                                    return Err(fault(format!(
+                                        "unknown atomic operation {:#x}",
+                                        insn.imm
+                                    )))
+                                }
+                            };
+                            self.store(cur, addr, size, new & mask)?;
+                            if fetch {
+                                reg[src] = old;
+                            }
+                        }
+                        _ => return Err(fault(format!("unknown opcode {:#04x}", insn.opcode))),
+                    }
+                }
+                _ => return Err(fault(format!("unknown opcode {:#04x}", insn.opcode))),
+            }
+        }
+    }
+
+    /// Index in [`Vm::maps`] of a handle of the running program.
+    fn map(&self, pc: usize, handle: u64) -> Result<usize, Fault> {
+        let maps = &self.programs[self.current].maps;
+        let slot = handle.wrapping_sub(MAP_ADDR) / REGION;
+        if handle < MAP_ADDR || !handle.is_multiple_of(REGION) || slot >= maps.len() as u64 {
+            return Err(Fault {
+                pc,
+                message: format!("{handle:#x} is not a map"),
+            });
+        }
+        Ok(maps[slot as usize])
+    }
+
+    /// Array indices past `max_entries` are out of the array's allocation in MorphOS.
+    fn check_index(&self, pc: usize, map: usize, key: &[u8]) -> Result<(), Fault> {
+        let map = &self.maps[map];
+        if map.def.map_type != BPF_MAP_TYPE_ARRAY {
+            return Ok(());
+        }
+        let index = u32::from_le_bytes(key.try_into().unwrap());
+        if index >= map.def.max_entries {
+            return Err(Fault {
+                pc,
+                message: format!(
+                    "index {index} is out of bounds of array {} with {} entries",
+                    map.name, map.def.max_entries
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn helper(&mut self, pc: usize, id: i32, reg: &[u64; 11]) -> Result<u64, Fault> {
+        match id {
+            helpers::MAP_LOOKUP_ELEM => {
+                let map = self.map(pc, reg[1])?;
+                let key = self.read(pc, reg[2], self.maps[map].def.key_size as usize)?;
+                self.check_index(pc, map, &key)?;
+                Ok(match self.maps[map].lookup(&key) {
+                    Some(offset) => value_addr(map, offset),
+                    None => 0,
+                })
+            }
+            helpers::MAP_UPDATE_ELEM => {
+                let map = self.map(pc, reg[1])?;
+                let key = self.read(pc, reg[2], self.maps[map].def.key_size as usize)?;
+                let value = self.read(pc, reg[3], self.maps[map].def.value_size as usize)?;
+                self.check_index(pc, map, &key)?;
+                // only the element puts programs into program arrays
+                if self.maps[map].def.map_type == BPF_MAP_TYPE_PROG_ARRAY {
+                    return Ok(-EINVAL as u64);
+                }
+                // fails for LPM trie prefixes longer than the keys or full tries, like in MorphOS
+                match self.maps[map].update(&key, &value) {
+                    Ok(()) => Ok(0),
+                    Err(UpdateError::Invalid) => Ok(-EINVAL as u64),
+                    Err(UpdateError::Full) => Ok(-ENOSPC as u64),
+                }
+            }
+            helpers::MAP_DELETE_ELEM => {
+                let map = self.map(pc, reg[1])?;
+                let key = self.read(pc, reg[2], self.maps[map].def.key_size as usize)?;
+                self.maps[map].delete(&key);
+                Ok(0)
+            }
+            helpers::KTIME_GET_NS => Ok(self.time_ns),
+            helpers::TRACE_PRINTK => {
+                let fmt = self.read(pc, reg[1], reg[2] as u32 as usize)?;
+                let args = [reg[3], reg[4], reg[5]];
+                let line = format_trace(&fmt, |n| {
+                    let addr = *args.get(n)?;
+                    let bytes = self.memory(addr, 4, false)?;
+                    Some(i32::from_le_bytes((&*bytes).try_into().unwrap()))
+                });
+                self.trace.push(line);
+                Ok(0)
+            }
+            helpers::GET_PRANDOM_U32 => {
+                let mut x = self.prandom;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.prandom = x;
+                Ok(x as u64)
+            }
+            // the program unwinds after a successful call, then `run` starts the pending one
+            helpers::TAIL_CALL => {
+                let map = self.map(pc, reg[2])?;
+                if self.maps[map].def.map_type != BPF_MAP_TYPE_PROG_ARRAY {
+                    return Ok(-EINVAL as u64);
+                }
+                let Some(&program) = self.slots.get(&(map, reg[3] as u32)) else {
+                    return Ok(-ENOENT as u64);
+                };
+                if self.tail_calls >= MAX_TAIL_CALLS {
+                    return Ok(-E2BIG as u64);
+                }
+                self.tail_calls += 1;
+                self.pending_tail_call = Some(program);
+                Ok(0)
+            }
+            helpers::UNWIND => Ok(reg[1]),
+            helpers::PACKET_ADD_SPACE if self.element == Element::Rewriter => {
+                self.add_space(pc, reg[1] as i32, reg[2] as i32)?;
+                Ok(PACKET_ADDR + self.packet.head as u64)
+            }
+            _ => Err(Fault {
+                pc,
+                message: format!("call to nonexistent function {id}"),
+            }),
+        }
+    }
+
+    /// Click's `push`, `pull`, `put` and `take`, as `bpf_packet_add_space` in `bpfrewriter.cc`.
+    fn add_space(&mut self, pc: usize, head: i32, tail: i32) -> Result<(), Fault> {
+        let packet = &mut self.packet;
+        if head > 0 {
+            let head = head as usize;
+            if head > packet.head {
+                let grow = head - packet.head + HEADROOM;
+                packet.buf.splice(0..0, std::iter::repeat_n(0, grow));
+                packet.head += grow;
+            }
+            packet.head -= head;
+            packet.len += head;
+        } else if head < 0 {
+            let mut pull = head.unsigned_abs() as usize;
+            if pull > packet.len {
+                self.trace.push(format!(
+                    "WARNING: .pull({pull}) on {}-byte packet",
+                    packet.len
+                ));
+                pull = packet.len;
+            }
+            packet.head += pull;
+            packet.len -= pull;
+        }
+
+        if tail > 0 {
+            let end = packet.head + packet.len + tail as usize;
+            if end > packet.buf.len() {
+                packet.buf.resize(end, 0);
+            }
+            packet.len += tail as usize;
+        } else if tail < 0 {
+            let mut take = tail.unsigned_abs() as usize;
+            if take > packet.len {
+                self.trace.push(format!(
+                    "WARNING: .take({take}) on {}-byte packet",
+                    packet.len
+                ));
+                take = packet.len;
+            }
+            packet.len -= take;
+        }
+
+        if packet.buf.len() > REGION as usize {
+            return Err(Fault {
+                pc,
+                message: format!("packet grew to {} bytes", packet.len),
+            });
+        }
+        self.update_ctx();
+        Ok(())
+    }
+}
+
+/// The checks of `ubpf_load`.
+fn check(insts: &[Insn], element: Element) -> anyhow::Result<()> {
+    for (pc, insn) in insts.iter().enumerate() {
+        if insn.opcode == OP_LDDW && pc + 1 >= insts.len() {
+            bail!("incomplete lddw at PC {pc}");
+        }
+        if insn.opcode == OP_CALL && insn.src == 0 && !helpers::registered(element, insn.imm) {
+            bail!("call to nonexistent function {} at PC {pc}", insn.imm);
+        }
+    }
+    Ok(())
+}
+
+fn jump(pc: usize, offset: i64) -> Option<usize> {
+    usize::try_from(pc as i64 + offset).ok()
+}
+
+fn sign_extend(value: u64, size: usize) -> u64 {
+    match size {
+        1 => value as i8 as i64 as u64,
+        2 => value as i16 as i64 as u64,
+        4 => value as i32 as i64 as u64,
+        _ => value,
+    }
+}
+
+/// `None` for unknown operations. 32-bit operations zero the upper half of the result.
+fn alu(is64: bool, insn: Insn, dst: u64, src: u64) -> Option<u64> {
+    let op = insn.opcode & 0xf0;
+    let signed = insn.off == 1;
+
+    if op == 0xd0 {
+        // byte swaps, the immediate is the width
+        let width = insn.imm;
+        let to_big_endian = is64 || insn.opcode & 0x08 != 0;
+        return Some(match (width, to_big_endian) {
+            (16, false) => dst as u16 as u64,
+            (32, false) => dst as u32 as u64,
+            (64, false) => dst,
+            (16, true) => (dst as u16).swap_bytes() as u64,
+            (32, true) => (dst as u32).swap_bytes() as u64,
+            (64, true) => dst.swap_bytes(),
+            _ => return None,
+        });
+    }
+
+    if is64 {
+        Some(match op {
+            0x00 => dst.wrapping_add(src),
+            0x10 => dst.wrapping_sub(src),
+            0x20 => dst.wrapping_mul(src),
+            0x30 if src == 0 => 0,
+            0x30 if signed => (dst as i64).wrapping_div(src as i64) as u64,
+            0x30 => dst / src,
+            0x40 => dst | src,
+            0x50 => dst & src,
+            0x60 => dst << (src & 63),
+            0x70 => dst >> (src & 63),
+            0x80 => (dst as i64).wrapping_neg() as u64,
+            0x90 if src == 0 => dst,
+            0x90 if signed => (dst as i64).wrapping_rem(src as i64) as u64,
+            0x90 => dst % src,
+            0xa0 => dst ^ src,
+            0xb0 => match insn.off {
+                0 => src,
+                8 | 16 | 32 => sign_extend(src, insn.off as usize / 8),
+                _ => return None,
+            },
+            0xc0 => ((dst as i64) >> (src & 63)) as u64,
+            _ => return None,
+        })
+    } else {
+        let (dst, src) = (dst as u32, src as u32);
+        Some(match op {
+            0x00 => dst.wrapping_add(src),
+            0x10 => dst.wrapping_sub(src),
+            0x20 => dst.wrapping_mul(src),
+            0x30 if src == 0 => 0,
+            0x30 if signed => (dst as i32).wrapping_div(src as i32) as u32,
+            0x30 => dst / src,
+            0x40 => dst | src,
+            0x50 => dst & src,
+            0x60 => dst << (src & 31),
+            0x70 => dst >> (src & 31),
+            0x80 => (dst as i32).wrapping_neg() as u32,
+            0x90 if src == 0 => dst,
+            0x90 if signed => (dst as i32).wrapping_rem(src as i32) as u32,
+            0x90 => dst % src,
+            0xa0 => dst ^ src,
+            0xb0 => match insn.off {
+                0 => src,
+                8 => src as i8 as i32 as u32,
+                16 => src as i16 as i32 as u32,
+                _ => return None,
+            },
+            0xc0 => ((dst as i32) >> (src & 31)) as u32,
+            _ => return None,
+        } as u64)
+    }
+}
+
+fn compare(op: u8, a: u64, b: u64) -> Option<bool> {
+    Some(match op {
+        0x10 => a == b,
+        0x20 => a > b,
+        0x30 => a >= b,
+        0x40 => a & b != 0,
+        0x50 => a != b,
+        0x60 => (a as i64) > (b as i64),
+        0x70 => (a as i64) >= (b as i64),
+        0xa0 => a < b,
+        0xb0 => a <= b,
+        0xc0 => (a as i64) < (b as i64),
+        0xd0 => (a as i64) <= (b as i64),
+        _ => return None,
+    })
+}
+
+fn compare32(op: u8, a: u32, b: u32) -> Option<bool> {
+    Some(match op {
+        0x10 => a == b,
+        0x20 => a > b,
+        0x30 => a >= b,
+        0x40 => a & b != 0,
+        0x50 => a != b,
+        0x60 => (a as i32) > (b as i32),
+        0x70 => (a as i32) >= (b as i32),
+        0xa0 => a < b,
+        0xb0 => a <= b,
+        0xc0 => (a as i32) < (b as i32),
+        0xd0 => (a as i32) <= (b as i32),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{MapDef, MapSymbol};
+
+    const EXIT: Insn = insn(0x95, 0, 0, 0, 0);
+
+    const fn insn(opcode: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+        Insn {
+            opcode,
+            dst,
+            src,
+            off,
+            imm,
+        }
+    }
+
+    fn vm(insts: &[Insn], maps: Vec<MapSymbol>, data: Vec<DataSection>) -> Vm {
+        let program = Program {
+            insts: insts.to_vec(),
+            maps,
+            data,
+        };
+        Vm::new(program, Element::Filter).unwrap()
+    }
+
+    /// Runs `insts` on a 4-byte packet.
+    fn run(insts: &[Insn]) -> Result<u64, Fault> {
+        vm(insts, Vec::new(), Vec::new()).run(&[1, 2, 3, 4], 0, 0).0
+    }
+
+    /// `r0 = a`, `r0 <op> b` in 64 or 32 bits, exit.
+    fn alu(opcode: u8, a: i32, b: i32) -> u64 {
+        run(&[insn(0xb7, 0, 0, 0, a), insn(opcode, 0, 0, 0, b), EXIT]).unwrap()
+    }
+
+    fn fault(result: Result<u64, Fault>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn computes_in_64_and_32_bits() {
+        assert_eq!(alu(0x07, -1, 1), 0); // add
+        assert_eq!(alu(0x17, 0, 1), u64::MAX); // sub
+        assert_eq!(alu(0x67, 1, 63), 1 << 63); // lsh
+        assert_eq!(alu(0x77, -1, 60), 0xf); // rsh
+        assert_eq!(alu(0xc7, -16, 2), -4i64 as u64); // arsh
+        assert_eq!(alu(0x87, 5, 0), -5i64 as u64); // neg
+
+        // 32-bit operations zero the upper half
+        assert_eq!(alu(0x04, -1, 1), 0);
+        assert_eq!(alu(0x14, 0, 1), u32::MAX as u64);
+        assert_eq!(alu(0xc4, -16, 2), (-4i32) as u32 as u64);
+        assert_eq!(alu(0xb4, 0, -1), u32::MAX as u64);
+    }
+
+    #[test]
+    fn divides_by_zero_like_the_kernel() {
+        assert_eq!(alu(0x37, 7, 0), 0); // div
+        assert_eq!(alu(0x97, 7, 0), 7); // mod
+        assert_eq!(alu(0x34, 7, 0), 0);
+        assert_eq!(alu(0x94, 7, 0), 7);
+        assert_eq!(alu(0x37, 7, 2), 3);
+        assert_eq!(alu(0x97, 7, 2), 1);
+
+        // signed division and modulo have an offset of 1
+        let sdiv =
+            |opcode, a, b| run(&[insn(0xb7, 0, 0, 0, a), insn(opcode, 0, 0, 1, b), EXIT]).unwrap();
+        assert_eq!(sdiv(0x37, -7, 2), -3i64 as u64);
+        assert_eq!(sdiv(0x97, -7, 2), -1i64 as u64);
+    }
+
+    #[test]
+    fn swaps_bytes() {
+        let swap = |opcode, imm| {
+            let insts = [
+                insn(0x18, 0, 0, 0, 0x0506_0708),
+                insn(0, 0, 0, 0, 0x0102_0304),
+                insn(opcode, 0, 0, 0, imm),
+                EXIT,
+            ];
+            run(&insts).unwrap()
+        };
+        assert_eq!(swap(0xdc, 16), 0x0807); // be16
+        assert_eq!(swap(0xdc, 32), 0x0807_0605);
+        assert_eq!(swap(0xdc, 64), 0x0807_0605_0403_0201);
+        assert_eq!(swap(0xd4, 16), 0x0708); // le16, truncates on little-endian hosts
+        assert_eq!(swap(0xd4, 64), 0x0102_0304_0506_0708);
+    }
+
+    #[test]
+    fn compares_signed_and_unsigned() {
+        // r0 = 1 if `r1 <op> imm` jumps, else 0
+        let jumps = |opcode, r1, imm| {
+            let insts = [
+                insn(0x18, 1, 0, 0, r1 as i32),
+                insn(0, 0, 0, 0, (r1 >> 32) as i32),
+                insn(0xb7, 0, 0, 0, 1),
+                insn(opcode, 1, 0, 1, imm),
+                insn(0xb7, 0, 0, 0, 0),
+                EXIT,
+            ];
+            run(&insts).unwrap() == 1
+        };
+        assert!(jumps(0x15, 3, 3)); // jeq
+        assert!(!jumps(0x55, 3, 3)); // jne
+        assert!(jumps(0x25, -1i64 as u64, 1)); // jgt: u64::MAX > 1
+        assert!(!jumps(0x65, -1i64 as u64, 1)); // jsgt: -1 > 1
+        assert!(jumps(0xc5, -1i64 as u64, 1)); // jslt
+        assert!(jumps(0x45, 6, 2)); // jset
+
+        // jmp32 only compares the lower halves
+        assert!(jumps(0x16, 1 << 32 | 3, 3));
+        assert!(!jumps(0x15, 1 << 32 | 3, 3));
+        assert!(jumps(0x65, 1 << 31, 1));
+        assert!(!jumps(0x66, 1 << 31, 1));
+    }
+
+    #[test]
+    fn calls_local_functions() {
+        let insts = [
+            insn(0xb7, 6, 0, 0, 40),
+            insn(0x85, 0, 1, 0, 2), // call pc 4
+            insn(0x0f, 0, 6, 0, 0), // r0 += r6
+            EXIT,
+            // the callee clobbers r6, which the caller gets back
+            insn(0xb7, 6, 0, 0, 0),
+            insn(0xb7, 0, 0, 0, 2),
+            EXIT,
+        ];
+        assert_eq!(run(&insts).unwrap(), 42);
+    }
+
+    #[test]
+    fn reads_the_packet_within_its_bounds() {
+        // r2 = data, r0 = *(u32 *)(r2 + off)
+        let read = |off| run(&[insn(0x79, 2, 1, 0, 0), insn(0x61, 0, 2, off, 0), EXIT]);
+        assert_eq!(read(0).unwrap(), 0x0403_0201);
+        let error = fault(read(1));
+        assert!(
+            error.starts_with("invalid read of 4 bytes at 0x30000041"),
+            "{error}"
+        );
+        assert!(error.ends_with("(PC 1)"), "{error}");
+        // nor in front of it, in the headroom
+        assert!(fault(read(-1)).starts_with("invalid read"));
+    }
+
+    #[test]
+    fn faults_on_invalid_memory_and_code() {
+        // stores past the stack, to read-only data and to unmapped addresses
+        let store = |dst_insts: &[Insn]| {
+            let mut insts = dst_insts.to_vec();
+            insts.extend([insn(0x62, 2, 0, 0, 1), EXIT]);
+            let rodata = DataSection {
+                name: ".rodata".to_string(),
+                data: vec![0; 8],
+                writable: false,
+            };
+            vm(&insts, Vec::new(), vec![rodata]).run(&[0; 4], 0, 0).0
+        };
+        assert!(fault(store(&[insn(0xbf, 2, 10, 0, 0)])).starts_with("invalid write of 4 bytes"));
+        let rodata = data_addr(0);
+        let to_rodata = [
+            insn(0x18, 2, 0, 0, rodata as i32),
+            insn(0, 0, 0, 0, (rodata >> 32) as i32),
+        ];
+        assert!(fault(store(&to_rodata)).starts_with("invalid write"));
+        assert!(fault(store(&[insn(0xb7, 2, 0, 0, 0)])).starts_with("invalid write"));
+
+        assert_eq!(
+            fault(run(&[insn(0x05, 0, 0, 5, 0), EXIT])),
+            "jumped out of the program (PC 6)"
+        );
+        assert_eq!(
+            fault(run(&[insn(0x05, 0, 0, -2, 0), EXIT])),
+            "jumped out of the program (PC 0)"
+        );
+        assert_eq!(
+            fault(run(&[insn(0xb7, 0, 0, 0, 0)])),
+            "jumped out of the program (PC 1)"
+        );
+        assert_eq!(
+            fault(run(&[insn(0xb7, 10, 0, 0, 0), EXIT])),
+            "write to r10 (PC 0)"
+        );
+        assert_eq!(
+            fault(run(&[insn(0xe7, 0, 0, 0, 0), EXIT])),
+            "unknown opcode 0xe7 (PC 0)"
+        );
+    }
+
+    #[test]
+    fn faults_on_array_indices_out_of_bounds() {
+        let array = || MapSymbol {
+            name: "ARRAY".to_string(),
+            def: MapDef {
+                map_type: BPF_MAP_TYPE_ARRAY,
+                key_size: 4,
+                value_size: 8,
+                max_entries: 2,
+                map_flags: 0,
+            },
+        };
+        let handle = map_handle(0);
+        // *(u32 *)(r10 - 4) = index, r0 = bpf_map_lookup_elem(ARRAY, r10 - 4)
+        let lookup = |index| {
+            let insts = [
+                insn(0x62, 10, 0, -4, index),
+                insn(0x18, 1, 0, 0, handle as i32),
+                insn(0, 0, 0, 0, (handle >> 32) as i32),
+                insn(0xbf, 2, 10, 0, 0),
+                insn(0x07, 2, 0, 0, -4),
+                insn(0x85, 0, 0, 0, helpers::MAP_LOOKUP_ELEM),
+                EXIT,
+            ];
+            vm(&insts, vec![array()], Vec::new()).run(&[0; 4], 0, 0).0
+        };
+        assert_eq!(lookup(1).unwrap(), value_addr(0, 8));
+        assert_eq!(
+            fault(lookup(2)),
+            "index 2 is out of bounds of array ARRAY with 2 entries (PC 5)"
+        );
+    }
+
+    #[test]
+    fn refuses_calls_to_unregistered_helpers() {
+        let program = |id| Program {
+            insts: vec![insn(0x85, 0, 0, 0, id), EXIT],
+            maps: Vec::new(),
+            data: Vec::new(),
+        };
+        let error = Vm::new(program(helpers::PACKET_ADD_SPACE), Element::Filter)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "call to nonexistent function 60 at PC 0");
+        assert!(Vm::new(program(helpers::PACKET_ADD_SPACE), Element::Rewriter).is_ok());
+        assert!(Vm::new(program(99), Element::Rewriter).is_err());
+    }
+
+    fn map_symbol(name: &str, map_type: u32, max_entries: u32) -> MapSymbol {
+        MapSymbol {
+            name: name.to_string(),
+            def: MapDef {
+                map_type,
+                key_size: 4,
+                value_size: 4,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// `bpf_tail_call(ctx, map slot, index)`, unwinds with 0 if it succeeded and returns its error
+    /// otherwise.
+    fn tail_call(map: usize, index: i32) -> Vec<Insn> {
+        let handle = map_handle(map);
+        vec![
+            insn(0x18, 2, 0, 0, handle as i32),
+            insn(0, 0, 0, 0, (handle >> 32) as i32),
+            insn(0xb7, 3, 0, 0, index),
+            insn(0x85, 0, 0, 0, helpers::TAIL_CALL),
+            insn(0x55, 0, 0, 2, 0),
+            insn(0xb7, 1, 0, 0, 0),
+            insn(0x85, 0, 0, 0, helpers::UNWIND),
+            EXIT,
+        ]
+    }
+
+    /// Returns the address of the value at index 0 of its first map.
+    fn lookup_first() -> Program {
+        let handle = map_handle(0);
+        Program {
+            insts: vec![
+                insn(0x62, 10, 0, -4, 0),
+                insn(0x18, 1, 0, 0, handle as i32),
+                insn(0, 0, 0, 0, (handle >> 32) as i32),
+                insn(0xbf, 2, 10, 0, 0),
+                insn(0x07, 2, 0, 0, -4),
+                insn(0x85, 0, 0, 0, helpers::MAP_LOOKUP_ELEM),
+                EXIT,
+            ],
+            maps: vec![map_symbol("VALUES", BPF_MAP_TYPE_ARRAY, 1)],
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn runs_tail_calls_after_the_caller_unwinds() {
+        let maps = || {
+            vec![
+                map_symbol("PROGRAMS", BPF_MAP_TYPE_PROG_ARRAY, 4),
+                map_symbol("VALUES", BPF_MAP_TYPE_ARRAY, 1),
+            ]
+        };
+        let run = |index| {
+            let mut vm = vm(&tail_call(0, index), maps(), Vec::new());
+            vm.load_tail_call("PROGRAMS", 1, lookup_first()).unwrap();
+            vm.run(&[0; 4], 0, 0).0.unwrap()
+        };
+        // the program in the slot uses VALUES of the main program, map 1
+        assert_eq!(run(1), value_addr(1, 0));
+        assert_eq!(run(2), -ENOENT as u64);
+        assert_eq!(run(4), -ENOENT as u64);
+
+        // only program arrays hold programs
+        let mut vm = vm(&tail_call(1, 0), maps(), Vec::new());
+        assert_eq!(vm.run(&[0; 4], 0, 0).0.unwrap(), -EINVAL as u64);
+    }
+
+    #[test]
+    fn stops_tail_calls_after_the_limit() {
+        let programs = || vec![map_symbol("PROGRAMS", BPF_MAP_TYPE_PROG_ARRAY, 1)];
+        let mut vm = vm(&tail_call(0, 0), programs(), Vec::new());
+        let program = Program {
+            insts: tail_call(0, 0),
+            maps: programs(),
+            data: Vec::new(),
+        };
+        vm.load_tail_call("PROGRAMS", 0, program).unwrap();
+        assert_eq!(vm.run(&[0; 4], 0, 0).0.unwrap(), -E2BIG as u64);
+        assert_eq!(vm.tail_calls, MAX_TAIL_CALLS);
+    }
+
+    #[test]
+    fn loads_tail_calls_like_the_element() {
+        let mut vm = vm(
+            &[EXIT],
+            vec![
+                map_symbol("PROGRAMS", BPF_MAP_TYPE_PROG_ARRAY, 4),
+                map_symbol("VALUES", BPF_MAP_TYPE_ARRAY, 2),
+            ],
+            Vec::new(),
+        );
+        let error = |vm: &mut Vm, map, index| {
+            vm.load_tail_call(map, index, lookup_first())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&mut vm, "VALUES", 0),
+            "the program has no program array VALUES"
+        );
+        assert_eq!(
+            error(&mut vm, "PROGRAMS", 4),
+            "program array PROGRAMS has no slot 4"
+        );
+        assert_eq!(
+            error(&mut vm, "PROGRAMS", 0),
+            "map VALUES already exists with a different definition"
+        );
+        assert_eq!(vm.maps.len(), 2);
+
+        // maps the main program doesn't have are added
+        let mut program = lookup_first();
+        program.maps[0].name = "OTHER".to_string();
+        vm.load_tail_call("PROGRAMS", 0, program).unwrap();
+        assert_eq!(vm.maps[2].name, "OTHER");
+    }
+}
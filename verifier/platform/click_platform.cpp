@@ -69,8 +69,18 @@ static const EbpfMapType click_map_types[] = {
     {BPF_MAP_TYPE(ARRAY), true},
 };
 
+// bpf_tail_call takes only maps holding programs
+#define CLICK_MAP_TYPE_PROG_ARRAY 3
+static const EbpfMapType click_prog_array_type = {BPF_MAP_TYPE(PROG_ARRAY), true, EbpfMapValueType::PROGRAM};
+
 EbpfMapType get_map_type_click(uint32_t platform_specific_type)
 {
+    if (platform_specific_type == CLICK_MAP_TYPE_PROG_ARRAY) {
+        EbpfMapType type = click_prog_array_type;
+        type.platform_specific_type = platform_specific_type;
+        return type;
+    }
+
     uint32_t index = platform_specific_type;
     if ((index == 0) || (index >= sizeof(click_map_types) / sizeof(click_map_types[0]))) {
         return click_map_types[0];
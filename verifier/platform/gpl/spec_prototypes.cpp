@@ -82,6 +82,24 @@ static const struct EbpfHelperPrototype bpf_get_prandom_u32_proto = {
         .return_type = EBPF_RETURN_TYPE_INTEGER,
 };
 
+// the context is the program's copy, not the one it was called with, so the element ignores it
+static const struct EbpfHelperPrototype bpf_tail_call_proto = {
+        .name = "tail_call",
+        .return_type = EBPF_RETURN_TYPE_INTEGER,
+        .argument_type = {
+                EBPF_ARGUMENT_TYPE_ANYTHING,
+                EBPF_ARGUMENT_TYPE_PTR_TO_MAP_OF_PROGRAMS,
+                EBPF_ARGUMENT_TYPE_ANYTHING,
+        },
+};
+
+static const struct EbpfHelperPrototype bpf_unwind_proto = {
+        .name = "unwind",
+        .return_type = EBPF_RETURN_TYPE_INTEGER,
+        .argument_type = {
+                EBPF_ARGUMENT_TYPE_ANYTHING,
+        },
+};
 
 static const struct EbpfHelperPrototype bpf_packet_add_space_proto = {
         .name = "packet_add_space",
@@ -102,9 +120,12 @@ const struct EbpfHelperPrototype prototypes[] = {
         FN(ktime_get_ns),
         FN(trace_printk),
         FN(get_prandom_u32),
+        FN(tail_call),
+        FN(unwind),
         FN(packet_add_space),
 };
 
+// IDs as registered by BPFElement::init_ubpf_vm, see ebpf/src/helpers.rs
 EbpfHelperPrototype get_helper_prototype_unchecked(int32_t n) {
     switch (n) {
         case 1:
@@ -119,6 +140,10 @@ EbpfHelperPrototype get_helper_prototype_unchecked(int32_t n) {
             return FN(trace_printk);
         case 7:
             return FN(get_prandom_u32);
+        case 12:
+            return FN(tail_call);
+        case 20:
+            return FN(unwind);
         case 60:
             return FN(packet_add_space);
         default: